@@ -0,0 +1,78 @@
+use crate::includes::expand_includes;
+use crate::pipeline::plantuml_diagrams;
+use crate::renderer::diagram_title;
+use mdbook::book::{Book, BookItem};
+use std::borrow::Cow;
+use std::path::Path;
+
+/// Find the PlantUML diagrams without alt text, i.e. without `alt=` in the info string and
+/// without a title (or diagram name) to use as alt text. Returns the locations of these
+/// diagrams (e.g. "diagram 2 in chapter 'Intro' ("intro.md")").
+/// # Arguments
+/// * `book` - The book to check
+/// * `src_dir` - The book's source directory
+/// * `strict_info_string` - Parse the info strings in strict-info-string mode
+pub fn diagrams_without_alt_text(
+    book: &Book,
+    src_dir: &Path,
+    strict_info_string: bool,
+) -> Vec<String> {
+    let mut missing = vec![];
+    for item in book.iter() {
+        if let BookItem::Chapter(chapter) = item {
+            if let Some(chapter_path) = &chapter.path {
+                let chapter_file = src_dir.join(chapter_path);
+                let chapter_dir = chapter_file.parent().unwrap_or(src_dir);
+                let diagrams = plantuml_diagrams(&chapter.content, strict_info_string);
+                for (index, diagram) in diagrams.iter().enumerate() {
+                    if diagram.alt_text.is_some() {
+                        continue;
+                    }
+
+                    // The title can be in an included file
+                    let code = expand_includes(&diagram.code, chapter_dir, None)
+                        .unwrap_or(Cow::Borrowed(&diagram.code));
+                    if diagram_title(&code).is_none() {
+                        missing.push(format!(
+                            "  diagram {} in chapter '{}' ({:?})",
+                            index + 1,
+                            chapter.name,
+                            chapter_path
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    missing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mdbook::book::Chapter;
+    use pretty_assertions::assert_eq;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_diagrams_without_alt_text() {
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Intro",
+            String::from(
+                "```plantuml,alt=\"A and B\"\nA --|> B\n```\n\
+                 ```plantuml\ntitle A and B\nA --|> B\n```\n\
+                 ```plantuml\n@startuml a-and-b\nA --|> B\n@enduml\n```\n\
+                 ```plantuml\nA --|> B\n```\n",
+            ),
+            PathBuf::from("intro.md"),
+            vec![],
+        ));
+
+        assert_eq!(
+            vec!["  diagram 4 in chapter 'Intro' (\"intro.md\")"],
+            diagrams_without_alt_text(&book, Path::new("src"), false)
+        );
+    }
+}
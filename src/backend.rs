@@ -1,10 +1,36 @@
-use anyhow::Result;
+use anyhow::{format_err, Result};
+use error::BackendError;
 
+pub mod detection_cache;
+pub mod error;
 pub mod factory;
+pub mod ftp;
+pub mod process_guard;
 #[cfg(any(feature = "plantuml-ssl-server", feature = "plantuml-server"))]
 pub mod server;
 pub mod shell;
 
+/// What a backend supports, so the renderer can choose its strategy per backend
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// The image formats the backend can render
+    pub formats: &'static [&'static str],
+    /// Rendering many diagrams with one backend instance is cheap (e.g. a running JVM), instead
+    /// of starting PlantUML for every diagram
+    pub batching: bool,
+    /// Local `!include` files are resolved by the backend (from the working dir)
+    pub includes: bool,
+    /// Multiple backend instances can render at the same time (e.g. for prerendering)
+    pub concurrency_safe: bool,
+}
+
+impl Capabilities {
+    /// Returns true if the backend can render the image format
+    pub fn supports_format(&self, image_format: &str) -> bool {
+        self.formats.contains(&image_format)
+    }
+}
+
 pub trait Backend {
     /// Render a PlantUML string to file and return the diagram URL path to this
     /// file (as a String) for use in a link.
@@ -13,4 +39,50 @@ pub trait Backend {
     /// * `image_format` - The PlantUML image output format (see -t command line
     ///   option of PlantUML)
     fn render_from_string(&self, plantuml_code: &str, image_format: &str) -> Result<Vec<u8>>;
+
+    /// What the backend supports
+    fn capabilities(&self) -> Capabilities;
+}
+
+/// Check the image format is one of the formats supported by the backend
+/// # Arguments
+/// * `backend_name` - The backend name used in the error message
+/// * `image_format` - The requested image format
+/// * `supported_formats` - The formats supported by the backend
+pub fn check_format(
+    backend_name: &str,
+    image_format: &str,
+    supported_formats: &[&str],
+) -> Result<()> {
+    if !supported_formats.contains(&image_format) {
+        return Err(BackendError::UnsupportedFormat.tag()(format_err!(
+            "The {} backend does not support the '{}' image format (supported formats are: {}).",
+            backend_name,
+            image_format,
+            supported_formats.join(", ")
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_check_format() {
+        assert!(check_format("Foo", "svg", &["png", "svg"]).is_ok());
+
+        let error = check_format("Foo", "braille", &["png", "svg"]).unwrap_err();
+        assert_eq!(
+            "The Foo backend does not support the 'braille' image format (supported formats are: png, svg).",
+            format!("{error}")
+        );
+        assert_eq!(
+            Some(BackendError::UnsupportedFormat),
+            BackendError::of(&error)
+        );
+    }
 }
@@ -0,0 +1,246 @@
+use crate::backend::shell::{find_executable, split_shell_command};
+use crate::STATE_DIR;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+const CACHE_FILE_NAME: &str = "backend-detection.json";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+    /// Modification time (nanoseconds since the epoch) of the binaries used by the command
+    mtime: u128,
+    /// The version reported by PlantUML
+    version: String,
+}
+
+/// The detection cache file. Cache files of older versions (only the entries) are read as an
+/// empty cache, so the PlantUML command is detected again once.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct CacheFile {
+    /// The version of the PlantUML command detected last, the one the diagrams are rendered with
+    #[serde(default)]
+    last_version: Option<String>,
+    /// The working commands, keyed by the command string
+    #[serde(default)]
+    entries: HashMap<String, Entry>,
+}
+
+/// Remembers which PlantUML commands were found to be working, so the (slow)
+/// JVM startup for the version probe can be skipped on subsequent builds.
+/// Entries are keyed by the command string and invalidated when the binaries
+/// used by the command (e.g. the executable, or plantuml.jar) change.
+pub struct DetectionCache {
+    path: PathBuf,
+    file: CacheFile,
+    revalidate: bool,
+}
+
+impl DetectionCache {
+    /// Load the detection cache from the cache dir, a missing or corrupt cache
+    /// file results in an empty cache.
+    /// # Arguments
+    /// * `cache_dir` - The cache dir, the cache file is stored in its state dir
+    /// * `revalidate` - Ignore the cached entries (they are refreshed on insert)
+    pub fn load(cache_dir: &Path, revalidate: bool) -> Self {
+        let path = cache_dir.join(STATE_DIR).join(CACHE_FILE_NAME);
+        let file = fs::read(&path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+
+        Self {
+            path,
+            file,
+            revalidate,
+        }
+    }
+
+    /// Get the cached PlantUML version for the given command, None if the
+    /// command is not cached, or the command's binaries changed.
+    pub fn get(&self, cmd: &str) -> Option<&str> {
+        if self.revalidate {
+            return None;
+        }
+
+        let entry = self.file.entries.get(cmd)?;
+        if Some(entry.mtime) == command_mtime(cmd) {
+            Some(entry.version.as_str())
+        } else {
+            None
+        }
+    }
+
+    /// Remember the given command is working and save the cache. The command is
+    /// not cached if none of its binaries can be located.
+    pub fn insert(&mut self, cmd: &str, version: &str) {
+        let mtime = match command_mtime(cmd) {
+            Some(mtime) => mtime,
+            None => {
+                log::debug!(
+                    "Not caching PlantUML command '{}', its binary was not found",
+                    cmd
+                );
+                return;
+            }
+        };
+
+        self.file.entries.insert(
+            cmd.to_string(),
+            Entry {
+                mtime,
+                version: version.to_string(),
+            },
+        );
+        self.save();
+    }
+
+    /// The version of the PlantUML command detected last (also when it was not cached), None
+    /// when no command was detected yet
+    pub fn last_version(&self) -> Option<&str> {
+        self.file.last_version.as_deref()
+    }
+
+    /// Remember the version of the detected PlantUML command, the cache is saved when it changed
+    pub fn set_last_version(&mut self, version: &str) {
+        if self.last_version() != Some(version) {
+            self.file.last_version = Some(version.to_string());
+            self.save();
+        }
+    }
+
+    fn save(&self) {
+        let result = self
+            .path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| {
+                let data = serde_json::to_vec_pretty(&self.file)?;
+                fs::write(&self.path, data)
+            });
+
+        if let Err(e) = result {
+            log::warn!(
+                "Failed to save the PlantUML detection cache {:?} ({})",
+                self.path,
+                e
+            );
+        }
+    }
+}
+
+fn file_mtime(path: &Path) -> Option<u128> {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_nanos())
+}
+
+/// The most recent modification time of the binaries used by the command (the
+/// executable and any arguments that are existing files, like plantuml.jar).
+/// Returns None if none of them can be found.
+fn command_mtime(cmd: &str) -> Option<u128> {
+    let cmd_parts = split_shell_command(cmd).ok()?;
+    let (executable, args) = cmd_parts.split_first()?;
+
+    let executable = if Path::new(executable).is_file() {
+        Some(PathBuf::from(executable))
+    } else {
+        find_executable(executable)
+    };
+
+    executable
+        .into_iter()
+        .chain(args.iter().map(PathBuf::from).filter(|p| p.is_file()))
+        .filter_map(|p| file_mtime(&p))
+        .max()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_cache_roundtrip() {
+        let cache_dir = tempdir().unwrap();
+        let jar = cache_dir.path().join("plantuml.jar");
+        fs::write(&jar, "").unwrap();
+        let cmd = format!("java -jar \"{}\"", jar.display());
+
+        let mut cache = DetectionCache::load(cache_dir.path(), false);
+        assert_eq!(None, cache.get(&cmd));
+        cache.insert(&cmd, "PlantUML version 1.2.3");
+        assert_eq!(Some("PlantUML version 1.2.3"), cache.get(&cmd));
+
+        // The cache is persisted
+        let cache = DetectionCache::load(cache_dir.path(), false);
+        assert_eq!(Some("PlantUML version 1.2.3"), cache.get(&cmd));
+        assert_eq!(None, cache.get("some other command"));
+
+        // Revalidation ignores the cached entries
+        let cache = DetectionCache::load(cache_dir.path(), true);
+        assert_eq!(None, cache.get(&cmd));
+
+        // The entry is invalidated when the binary is removed
+        fs::remove_file(&jar).unwrap();
+        let cache = DetectionCache::load(cache_dir.path(), false);
+        assert_eq!(None, cache.get(&cmd));
+    }
+
+    #[test]
+    fn test_last_version() {
+        let cache_dir = tempdir().unwrap();
+        let jar = cache_dir.path().join("plantuml.jar");
+        fs::write(&jar, "").unwrap();
+        let cmd = format!("java -jar \"{}\"", jar.display());
+
+        let mut cache = DetectionCache::load(cache_dir.path(), false);
+        assert_eq!(None, cache.last_version());
+        cache.insert(&cmd, "PlantUML version 1.2.3");
+        cache.set_last_version("PlantUML version 1.2.3");
+
+        // The last version is persisted next to the entries
+        let cache = DetectionCache::load(cache_dir.path(), true);
+        assert_eq!(Some("PlantUML version 1.2.3"), cache.last_version());
+        let cache = DetectionCache::load(cache_dir.path(), false);
+        assert_eq!(Some("PlantUML version 1.2.3"), cache.get(&cmd));
+
+        // Older cache files (only the entries) are read as an empty cache
+        fs::write(
+            &cache.path,
+            serde_json::to_vec(&cache.file.entries).unwrap(),
+        )
+        .unwrap();
+        let cache = DetectionCache::load(cache_dir.path(), false);
+        assert_eq!(None, cache.last_version());
+        assert_eq!(None, cache.get(&cmd));
+    }
+
+    #[test]
+    fn test_missing_binaries_are_not_cached() {
+        let cache_dir = tempdir().unwrap();
+
+        let mut cache = DetectionCache::load(cache_dir.path(), false);
+        cache.insert(
+            "surely-this-plantuml-does-not-exist",
+            "PlantUML version 1.2.3",
+        );
+        assert_eq!(None, cache.get("surely-this-plantuml-does-not-exist"));
+        assert!(!cache.path.exists());
+    }
+
+    #[test]
+    fn test_corrupt_cache_file() {
+        let cache_dir = tempdir().unwrap();
+        let state_dir = cache_dir.path().join(STATE_DIR);
+        fs::create_dir_all(&state_dir).unwrap();
+        fs::write(state_dir.join(CACHE_FILE_NAME), "{ corrupt").unwrap();
+
+        let cache = DetectionCache::load(cache_dir.path(), false);
+        assert!(cache.file.entries.is_empty());
+        assert_eq!(None, cache.last_version());
+    }
+}
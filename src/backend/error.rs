@@ -0,0 +1,182 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// The category of a backend failure, so the renderer can decide whether rendering the diagram
+/// again makes sense. The backends tag their errors with it (see BackendError::tag).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendError {
+    /// The backend did not respond in time
+    Timeout,
+    /// The PlantUML process could not be started (e.g. PlantUML or java is not installed)
+    ProcessSpawn,
+    /// PlantUML reported an error in the diagram (at the given line, when known)
+    SyntaxError { line: Option<usize> },
+    /// The server could not be reached, or responded with an error (the HTTP status, if any)
+    Network { status: Option<u16> },
+    /// The backend cannot render the requested image format
+    UnsupportedFormat,
+    /// Reading or writing a (source or image) file failed
+    Io,
+}
+
+impl BackendError {
+    /// The category of the error, None for errors not tagged by a backend
+    pub fn of(error: &anyhow::Error) -> Option<Self> {
+        error
+            .chain()
+            .find_map(|e| e.downcast_ref::<Categorized>())
+            .map(|categorized| categorized.category)
+    }
+
+    /// The category of an I/O error of a network backend (timeouts are reported as WouldBlock on
+    /// some platforms)
+    pub fn of_network_io(error: &anyhow::Error) -> Option<Self> {
+        let io_error = error.chain().find_map(|e| e.downcast_ref::<io::Error>())?;
+        match io_error.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Some(Self::Timeout),
+            _ => Some(Self::Network { status: None }),
+        }
+    }
+
+    /// Returns true if rendering the diagram again might succeed, i.e. the (network) backend did
+    /// not respond in time. Other errors fail again, or were already retried by the backend (e.g.
+    /// the shell's file mode fallback).
+    pub fn is_retryable(self) -> bool {
+        self == Self::Timeout
+    }
+
+    /// Returns a function tagging an error with this category (for use with map_err)
+    pub fn tag(self) -> impl Fn(anyhow::Error) -> anyhow::Error {
+        move |error| {
+            if Self::of(&error).is_some() {
+                // Keep the (more specific) category of the backend's own error
+                return error;
+            }
+            anyhow::Error::new(Categorized {
+                category: self,
+                error,
+            })
+        }
+    }
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout => write!(f, "timeout"),
+            Self::ProcessSpawn => write!(f, "failed to start PlantUML"),
+            Self::SyntaxError { line: Some(line) } => write!(f, "syntax error at line {line}"),
+            Self::SyntaxError { line: None } => write!(f, "syntax error"),
+            Self::Network {
+                status: Some(status),
+            } => write!(f, "network error (HTTP status {status})"),
+            Self::Network { status: None } => write!(f, "network error"),
+            Self::UnsupportedFormat => write!(f, "unsupported image format"),
+            Self::Io => write!(f, "I/O error"),
+        }
+    }
+}
+
+/// An error tagged with its backend error category, it is displayed like the error itself
+#[derive(Debug)]
+struct Categorized {
+    category: BackendError,
+    error: anyhow::Error,
+}
+
+impl fmt::Display for Categorized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl Error for Categorized {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.error.chain().nth(1)
+    }
+}
+
+/// The line of the error PlantUML reports on stderr, 'ERROR\n<line number>\n<message>' in piped
+/// mode and 'Error line <line number> in file: <file>' in file mode
+pub fn syntax_error_line(stderr: &str) -> Option<usize> {
+    let mut lines = stderr.trim_start().lines();
+    let first_line = lines.next()?.trim();
+    if first_line == "ERROR" {
+        return lines.next()?.trim().parse().ok();
+    }
+
+    let line = first_line.strip_prefix("Error line ")?;
+    line.split_whitespace().next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{format_err, Context};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_backend_error() {
+        assert_eq!(None, BackendError::of(&format_err!("Oops")));
+
+        let error = BackendError::SyntaxError { line: Some(3) }.tag()(format_err!("Bad diagram"))
+            .context("In chapter 'foo'");
+        assert_eq!(
+            Some(BackendError::SyntaxError { line: Some(3) }),
+            BackendError::of(&error)
+        );
+        assert_eq!("In chapter 'foo': Bad diagram", format!("{error:#}"));
+
+        // The innermost category is kept
+        let error = BackendError::Io.tag()(error);
+        assert_eq!(
+            Some(BackendError::SyntaxError { line: Some(3) }),
+            BackendError::of(&error)
+        );
+    }
+
+    #[test]
+    fn test_of_network_io() {
+        let timeout = Err::<(), _>(io::Error::new(io::ErrorKind::TimedOut, "timed out"))
+            .context("Failed to read")
+            .unwrap_err();
+        assert_eq!(
+            Some(BackendError::Timeout),
+            BackendError::of_network_io(&timeout)
+        );
+
+        let refused = anyhow::Error::new(io::Error::new(io::ErrorKind::ConnectionRefused, "no"));
+        assert_eq!(
+            Some(BackendError::Network { status: None }),
+            BackendError::of_network_io(&refused)
+        );
+        assert_eq!(None, BackendError::of_network_io(&format_err!("Oops")));
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        assert!(BackendError::Timeout.is_retryable());
+        assert!(!BackendError::Network { status: None }.is_retryable());
+        assert!(!BackendError::Network { status: Some(503) }.is_retryable());
+        assert!(!BackendError::Network { status: Some(404) }.is_retryable());
+        assert!(!BackendError::SyntaxError { line: None }.is_retryable());
+        assert!(!BackendError::UnsupportedFormat.is_retryable());
+        assert!(!BackendError::ProcessSpawn.is_retryable());
+        assert!(!BackendError::Io.is_retryable());
+    }
+
+    #[test]
+    fn test_syntax_error_line() {
+        assert_eq!(Some(2), syntax_error_line("ERROR\n2\nSyntax Error?\n"));
+        assert_eq!(
+            Some(12),
+            syntax_error_line(
+                "Error line 12 in file: /tmp/src0.puml\nSome diagram description contains errors\n"
+            )
+        );
+        assert_eq!(None, syntax_error_line("ERROR\n"));
+        assert_eq!(None, syntax_error_line("Some warning\n2\n"));
+        assert_eq!(None, syntax_error_line(""));
+    }
+}
@@ -1,135 +1,377 @@
+use crate::backend::detection_cache::DetectionCache;
+use crate::backend::ftp::{parse_ftp_url, PlantUMLFtp};
 #[cfg(any(feature = "plantuml-ssl-server", feature = "plantuml-server"))]
 use crate::backend::server::PlantUMLServer;
-use crate::backend::shell::{split_shell_command, PlantUMLShell};
+use crate::backend::shell::{create_command, PlantUMLShell};
 use crate::backend::Backend;
 use crate::config::Config;
+use anyhow::{bail, ensure, Result};
 #[cfg(any(feature = "plantuml-ssl-server", feature = "plantuml-server"))]
 use reqwest::Url;
-use std::process::Command;
+use std::path::{Path, PathBuf};
 use std::str;
+#[cfg(any(feature = "plantuml-ssl-server", feature = "plantuml-server"))]
+use std::time::Duration;
+
+/// Test if given PlantUML executable is a working one, the result is cached
+/// in the detection cache.
+fn is_working_plantuml_cmd(cmd: &str, detection_cache: &mut DetectionCache) -> bool {
+    if let Some(version) = detection_cache.get(cmd).map(String::from) {
+        log::info!("Detected {} (cached)", version);
+        detection_cache.set_last_version(&version);
+        return true;
+    }
+
+    match plantuml_version(cmd) {
+        Some(version) => {
+            detection_cache.insert(cmd, &version);
+            detection_cache.set_last_version(&version);
+            true
+        }
+        None => false,
+    }
+}
 
-/// Test if given PlantUML executable is a working one
-fn is_working_plantuml_cmd(cmd: &str) -> bool {
-    let cmd_parts = match split_shell_command(cmd) {
-        Ok(cp) => cp,
+/// The version of the PlantUML the diagrams are rendered with, it is part of the cache key so
+/// the diagrams are rendered again when PlantUML is upgraded. It is the version of the PlantUML
+/// command detected last (so it is also known when PlantUML is not available). None for the
+/// PlantUML (FTP) servers, their version is unknown, and when sharing the cache across backends.
+/// # Arguments
+/// * `cfg` - The configuration options
+/// * `cache_dir` - The cache dir holding the detection cache
+pub fn tool_version(cfg: &Config, cache_dir: &Path) -> Option<String> {
+    let is_server = cfg
+        .plantuml_cmd
+        .as_deref()
+        .map_or(false, |cmd| cmd.contains("://"));
+    if cfg.share_cache_across_backends || is_server {
+        return None;
+    }
+
+    DetectionCache::load(cache_dir, false)
+        .last_version()
+        .map(String::from)
+}
+
+/// Get the PlantUML version reported by the given command, None if the command
+/// does not work.
+fn plantuml_version(cmd: &str) -> Option<String> {
+    let mut command = match create_command(cmd) {
+        Ok(command) => command,
         Err(e) => {
             log::warn!("PlantUML command {} is invalid ({}).", cmd, e);
-            return false;
+            return None;
         }
     };
 
-    log::error!("Testing PlantUML command {} ({:?})", cmd, cmd_parts);
-    let result = Command::new(&cmd_parts[0])
-        .args(&cmd_parts[1..])
-        .arg("-version")
-        .output()
-        .map(|output| {
-            match str::from_utf8(&output.stdout) {
-                Ok(stdout) => {
-                    // First line in stdout should be the version number
-                    if let Some(version) = stdout.lines().next() {
-                        log::info!("Detected {}", version);
-                        true
-                    } else {
-                        false
-                    }
-                }
-                Err(e) => {
-                    log::error!("Failed to parse '{}' stdout ({})", cmd, e);
-                    false
+    log::debug!("Testing PlantUML command {} ({:?})", cmd, command);
+    let result = command.arg("-version").output().map(|output| {
+        match str::from_utf8(&output.stdout) {
+            Ok(stdout) => {
+                // First line in stdout should be the version number
+                if let Some(version) = stdout.lines().next() {
+                    log::info!("Detected {}", version);
+                    Some(version.to_string())
+                } else {
+                    None
                 }
             }
-        });
+            Err(e) => {
+                log::debug!("Failed to parse '{}' stdout ({})", cmd, e);
+                None
+            }
+        }
+    });
 
     match result {
-        Ok(valid) => valid,
+        Ok(version) => version,
+        Err(e) => {
+            log::debug!("Test of '{}' failed ({})", cmd, e);
+            None
+        }
+    }
+}
+
+/// Test if the java command can be run
+fn is_working_java_cmd(java_cmd: &str) -> bool {
+    match create_command(java_cmd).and_then(|mut command| Ok(command.arg("-version").output()?)) {
+        Ok(output) => output.status.success(),
         Err(e) => {
-            log::error!("Test of '{}' failed ({})", cmd, e);
+            log::debug!("Test of java command '{}' failed ({})", java_cmd, e);
             false
         }
     }
 }
 
-fn create_shell_backend(cfg: &Config) -> PlantUMLShell {
-    let piped = cfg.piped;
-    if let Some(cfg_cmd) = &cfg.plantuml_cmd {
-        if is_working_plantuml_cmd(cfg_cmd) {
-            PlantUMLShell::new(cfg_cmd.to_string(), piped)
+/// Create the command for running the given plantuml.jar file using java
+/// # Arguments
+/// * `java_cmd` - The java command (including JVM options)
+/// * `jar` - Path to plantuml.jar
+fn jar_command(java_cmd: &str, jar: &Path) -> String {
+    format!("{} -jar \"{}\"", java_cmd, jar.display())
+}
+
+/// Compose the PlantUML command from the java-cmd and plantuml-jar options.
+/// Fails with a message pointing out which of the two is wrong when the
+/// composed command does not work.
+fn configured_jar_command(
+    java_cmd: &str,
+    jar: &Path,
+    detection_cache: &mut DetectionCache,
+) -> Result<String> {
+    ensure!(
+        jar.is_file(),
+        "The plantuml-jar '{}' in book.toml does not exist",
+        jar.display()
+    );
+
+    let cmd = jar_command(java_cmd, jar);
+    if is_working_plantuml_cmd(&cmd, detection_cache) {
+        return Ok(cmd);
+    }
+
+    if is_working_java_cmd(java_cmd) {
+        bail!(
+            "The plantuml-jar '{}' in book.toml is not a working PlantUML jar (tried '{}')",
+            jar.display(),
+            cmd
+        );
+    } else {
+        bail!(
+            "The java-cmd '{}' in book.toml could not be run, please check the java-cmd, \
+                or make sure java can be found on the path",
+            java_cmd
+        );
+    }
+}
+
+/// The locations where plantuml.jar is commonly installed
+/// # Arguments
+/// * `book_root` - The book's root directory
+/// * `env` - Environment variable lookup
+fn well_known_jar_locations(
+    book_root: &Path,
+    env: &dyn Fn(&str) -> Option<String>,
+) -> Vec<PathBuf> {
+    let mut locations = vec![book_root.join("plantuml.jar")];
+
+    if let Some(home) = env("HOME").or_else(|| env("USERPROFILE")) {
+        locations.push(Path::new(&home).join(".local/share/plantuml/plantuml.jar"));
+    }
+
+    // Homebrew (Apple silicon and Intel)
+    locations.push(PathBuf::from(
+        "/opt/homebrew/opt/plantuml/libexec/plantuml.jar",
+    ));
+    locations.push(PathBuf::from(
+        "/usr/local/opt/plantuml/libexec/plantuml.jar",
+    ));
+
+    // Chocolatey
+    let chocolatey =
+        env("ChocolateyInstall").unwrap_or_else(|| String::from("C:\\ProgramData\\chocolatey"));
+    locations.push(
+        Path::new(&chocolatey)
+            .join("lib")
+            .join("plantuml")
+            .join("tools")
+            .join("plantuml.jar"),
+    );
+
+    locations
+}
+
+/// The PlantUML commands to try when no plantuml-cmd is configured, in order of preference.
+/// Returns the commands to try and the well known plantuml.jar locations that do not exist.
+/// # Arguments
+/// * `book_root` - The book's root directory
+/// * `java_cmd` - The java command used for running plantuml.jar
+/// * `env` - Environment variable lookup
+fn candidate_commands(
+    book_root: &Path,
+    java_cmd: &str,
+    env: &dyn Fn(&str) -> Option<String>,
+) -> (Vec<String>, Vec<PathBuf>) {
+    let mut candidates = vec![];
+    if let Some(cmd) = env("PLANTUML").filter(|cmd| !cmd.is_empty()) {
+        candidates.push(cmd);
+    }
+    if let Some(jar) = env("PLANTUML_JAR").filter(|jar| !jar.is_empty()) {
+        candidates.push(jar_command(java_cmd, Path::new(&jar)));
+    }
+
+    candidates.push(String::from("plantuml"));
+    candidates.push(format!("{java_cmd} -jar plantuml.jar"));
+
+    let mut missing = vec![];
+    for jar in well_known_jar_locations(book_root, env) {
+        if jar.is_file() {
+            candidates.push(jar_command(java_cmd, &jar));
+        } else {
+            missing.push(jar);
+        }
+    }
+
+    (candidates, missing)
+}
+
+/// Get the working PlantUML command, the configured one (plantuml-jar, or plantuml-cmd), or the
+/// auto detected one.
+fn plantuml_command(cfg: &Config, book_root: &Path, cache_dir: &Path) -> Result<String> {
+    let mut detection_cache = DetectionCache::load(cache_dir, cfg.revalidate);
+    let java_cmd = cfg.java_cmd.as_deref().unwrap_or("java");
+    if let Some(jar) = &cfg.plantuml_jar {
+        ensure!(
+            cfg.plantuml_cmd.is_none(),
+            "Both plantuml-cmd and plantuml-jar are configured in book.toml, please remove one of them"
+        );
+        configured_jar_command(java_cmd, jar, &mut detection_cache)
+    } else if let Some(cfg_cmd) = &cfg.plantuml_cmd {
+        if is_working_plantuml_cmd(cfg_cmd, &mut detection_cache) {
+            Ok(cfg_cmd.to_string())
         } else {
-            panic!(
+            bail!(
                 "PlantUML executable '{}' was not found, please check the plantuml-cmd in book.toml, \
                     or make sure the plantuml executable can be found on the path (or by java)",
                 cfg_cmd
             );
         }
     } else {
-        let candidates = ["plantuml", "java -jar plantuml.jar"];
-        for cmd in candidates {
-            if is_working_plantuml_cmd(cmd) {
-                return PlantUMLShell::new(cmd.to_string(), piped);
+        let (candidates, missing) =
+            candidate_commands(book_root, java_cmd, &|name| std::env::var(name).ok());
+        for cmd in &candidates {
+            if is_working_plantuml_cmd(cmd, &mut detection_cache) {
+                return Ok(cmd.to_string());
             }
         }
 
-        panic!(
-            "PlantUML executable could not be auto detected, tried '{}'. either specify one in book.toml, \
+        bail!(
+            "PlantUML executable could not be auto detected, tried '{}' (plantuml.jar was not found in {}). \
+                Either specify one in book.toml, set the PLANTUML_JAR environment variable, \
                 or make sure the plantuml executable can be found on the path (or by java)",
-            candidates.join(",")
+            candidates.join("', '"),
+            missing.iter().map(|p| format!("{p:?}")).collect::<Vec<_>>().join(", ")
         );
     }
 }
 
-/// Checks if a plantuml server is configured, but the application is built without server support
-/// Panics if the configured PlantUML server address is incompatible with the build features.
-fn check_server_support(server_address: &str) {
-    if !server_address.starts_with("https:") && !server_address.starts_with("http:") {
-        return;
+fn create_shell_backend(cfg: &Config, book_root: &Path, cache_dir: &Path) -> Result<PlantUMLShell> {
+    let cmd = plantuml_command(cfg, book_root, cache_dir)?;
+    Ok(PlantUMLShell::new(cmd, cfg, cache_dir))
+}
+
+/// Create the FTP server backend for the ftp://host[:port] plantuml-cmd. A local
+/// PlantUML FTP server is started (using plantuml-jar, or the auto detected
+/// PlantUML command) when it is not running yet.
+fn create_ftp_backend(
+    cfg: &Config,
+    url: &str,
+    book_root: &Path,
+    cache_dir: &Path,
+) -> Result<PlantUMLFtp> {
+    let (host, port) = parse_ftp_url(url)?;
+    if host == "localhost" || host == "127.0.0.1" {
+        let start_cfg = Config {
+            plantuml_cmd: None,
+            ..cfg.clone()
+        };
+        PlantUMLFtp::start(
+            host,
+            port,
+            &|| plantuml_command(&start_cfg, book_root, cache_dir),
+            cache_dir,
+        )
+    } else {
+        ensure!(
+            !cfg.offline,
+            "The PlantUML FTP server '{}' cannot be used in offline mode ({})",
+            url,
+            OFFLINE_HINT
+        );
+        Ok(PlantUMLFtp::new(host, port))
     }
+}
 
-    assert!(
-        cfg!(feature = "plantuml-ssl-server") || !server_address.starts_with("https:"),
-        "The PlantUML command '{}' is configured to use a PlantUML SSL server, but the mdbook-plantuml plugin \
-        is built without SSL server support.\nPlease rebuild/reinstall the \
-        plugin with SSL server support, or configure the plantuml command line tool as \
-        backend. See the the Features section in README.md",
-        &server_address
-    );
+/// How offline mode is enabled, for the error messages
+pub const OFFLINE_HINT: &str =
+    "offline = true in book.toml, or the CI_OFFLINE environment variable is set";
 
-    assert!(
-        cfg!(feature = "plantuml-ssl-server") || cfg!(feature = "plantuml-server") || !server_address.starts_with("http:"),
-        "The PlantUML command '{}' is configured to use a PlantUML server, but the mdbook-plantuml plugin \
-        is built without server support.\nPlease rebuild/reinstall the \
-        plugin with server support, or configure the plantuml command line tool as \
-        backend. See the the Features section in README.md",
-        &server_address
-    );
+/// Why the configured PlantUML server cannot be used by this build of the plugin (the feature it
+/// needs is not enabled), None when it can be used or no server is configured
+pub fn unsupported_server(cfg: &Config) -> Option<&'static str> {
+    let server_address = cfg.plantuml_cmd.as_deref().unwrap_or("");
+    if server_address.starts_with("https:") && !cfg!(feature = "plantuml-ssl-server") {
+        Some("mdbook-plantuml is built without the plantuml-ssl-server feature")
+    } else if server_address.starts_with("http:")
+        && !cfg!(any(
+            feature = "plantuml-ssl-server",
+            feature = "plantuml-server"
+        ))
+    {
+        Some("mdbook-plantuml is built without the plantuml-server feature")
+    } else {
+        None
+    }
+}
+
+/// Checks if a plantuml server is configured, but the application is built without server support
+/// Fails if the configured PlantUML server address is incompatible with the build features.
+fn check_server_support(cfg: &Config) -> Result<()> {
+    if let Some(reason) = unsupported_server(cfg) {
+        bail!(
+            "The PlantUML command '{}' is configured to use a PlantUML server, but {}.\nPlease \
+            rebuild/reinstall the plugin with server support, or configure the plantuml command \
+            line tool as backend. See the Features section in README.md",
+            cfg.plantuml_cmd.as_deref().unwrap_or(""),
+            reason
+        );
+    }
+
+    Ok(())
 }
 
 #[cfg(not(any(feature = "plantuml-ssl-server", feature = "plantuml-server")))]
-/// Returns None, or panics, because we have no server support
+/// Returns None, or fails, because we have no server support
 /// Returns Option<PlantUMLShell>, because otherwise a dummy trait would need to be implemented as a placeholder
-fn create_server_backend(cfg: &Config) -> Option<PlantUMLShell> {
-    let server_address = cfg.plantuml_cmd.as_deref().unwrap_or("");
-    check_server_support(server_address);
+fn create_server_backend(cfg: &Config) -> Result<Option<PlantUMLShell>> {
+    check_server_support(cfg)?;
 
-    None
+    Ok(None)
 }
 
 #[cfg(any(feature = "plantuml-ssl-server", feature = "plantuml-server"))]
-fn create_server_backend(cfg: &Config) -> Option<PlantUMLServer> {
+fn create_server_backend(cfg: &Config) -> Result<Option<PlantUMLServer>> {
     let server_address = cfg.plantuml_cmd.as_deref().unwrap_or("");
     if !server_address.starts_with("https:") && !server_address.starts_with("http:") {
-        return None;
+        return Ok(None);
     }
 
     // Make sure the application was built with the appropriate features (in this case potential https support)
-    check_server_support(server_address);
+    check_server_support(cfg)?;
 
     match Url::parse(server_address) {
-        Ok(server_url) => Some(PlantUMLServer::new(server_url)),
+        Ok(server_url) => {
+            if let Some(template) = &cfg.server_url_template {
+                ensure!(
+                    template.contains("{encoded}"),
+                    "The server-url-template '{}' has no {{encoded}} placeholder for the diagram",
+                    template
+                );
+            }
+            Ok(Some(
+                PlantUMLServer::new(server_url)
+                    .with_url_template(cfg.server_url_template.clone())
+                    .with_pool(
+                        Duration::from_secs(cfg.server_pool_idle_timeout),
+                        cfg.server_pool_max_idle,
+                    ),
+            ))
+        }
         Err(e) => {
-            panic!(
+            bail!(
                 "The PlantUML command '{}' is an invalid server address ({})",
-                server_address, e
+                server_address,
+                e
             );
         }
     }
@@ -137,12 +379,204 @@ fn create_server_backend(cfg: &Config) -> Option<PlantUMLServer> {
 
 /// Create an instance of the Backend
 /// # Arguments
-/// * `img_root` - The path to the directory where to store the images
 /// * `cfg` - The configuration options
-pub fn create(cfg: &Config) -> Box<dyn Backend> {
-    if let Some(server_backend) = create_server_backend(cfg) {
-        Box::new(server_backend)
+/// * `book_root` - The book's root directory
+/// * `cache_dir` - The directory for files the backend wants to keep between builds
+///
+/// Fails when no working backend could be created (e.g. PlantUML is not installed).
+pub fn create(cfg: &Config, book_root: &Path, cache_dir: &Path) -> Result<Box<dyn Backend>> {
+    if let Some(server_address) = cfg
+        .plantuml_cmd
+        .as_deref()
+        .filter(|cmd| cmd.starts_with("https:") || cmd.starts_with("http:"))
+    {
+        ensure!(
+            !cfg.offline,
+            "The PlantUML server '{}' cannot be used in offline mode ({}), configure a local PlantUML \
+             command instead",
+            server_address,
+            OFFLINE_HINT
+        );
+    }
+
+    if let Some(url) = cfg
+        .plantuml_cmd
+        .as_deref()
+        .filter(|cmd| cmd.starts_with("ftp://"))
+    {
+        return Ok(Box::new(create_ftp_backend(
+            cfg, url, book_root, cache_dir,
+        )?));
+    }
+
+    if let Some(server_backend) = create_server_backend(cfg)? {
+        Ok(Box::new(server_backend))
     } else {
-        Box::new(create_shell_backend(cfg))
+        Ok(Box::new(create_shell_backend(cfg, book_root, cache_dir)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::collections::HashMap;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_offline() {
+        let book_root = tempdir().unwrap();
+        let cfg = Config {
+            plantuml_cmd: Some(String::from("https://www.plantuml.com/plantuml")),
+            offline: true,
+            ..Config::default()
+        };
+        let result = create(&cfg, book_root.path(), book_root.path());
+        assert!(format!("{:#}", result.err().unwrap()).contains("cannot be used in offline mode"));
+
+        let cfg = Config {
+            plantuml_cmd: Some(String::from("ftp://plantuml.example.com")),
+            ..cfg
+        };
+        let result = create(&cfg, book_root.path(), book_root.path());
+        assert!(format!("{:#}", result.err().unwrap()).contains("cannot be used in offline mode"));
+    }
+
+    #[test]
+    fn test_unsupported_server() {
+        let server = |url: &str| Config {
+            plantuml_cmd: Some(String::from(url)),
+            ..Config::default()
+        };
+        assert_eq!(
+            !cfg!(feature = "plantuml-ssl-server"),
+            unsupported_server(&server("https://www.plantuml.com/plantuml")).is_some()
+        );
+        assert_eq!(
+            !cfg!(any(
+                feature = "plantuml-ssl-server",
+                feature = "plantuml-server"
+            )),
+            unsupported_server(&server("http://localhost:8080/plantuml")).is_some()
+        );
+        assert_eq!(None, unsupported_server(&server("plantuml")));
+        assert_eq!(None, unsupported_server(&Config::default()));
+    }
+
+    #[test]
+    fn test_tool_version() {
+        let cache_dir = tempdir().unwrap();
+        let cfg = Config::default();
+        assert_eq!(None, tool_version(&cfg, cache_dir.path()));
+
+        DetectionCache::load(cache_dir.path(), false).set_last_version("PlantUML version 1.2.3");
+        assert_eq!(
+            Some(String::from("PlantUML version 1.2.3")),
+            tool_version(&cfg, cache_dir.path())
+        );
+
+        // Unknown for servers, and not used when sharing the cache across backends
+        let server = Config {
+            plantuml_cmd: Some(String::from("http://localhost:8080/plantuml")),
+            ..Config::default()
+        };
+        assert_eq!(None, tool_version(&server, cache_dir.path()));
+        let shared = Config {
+            share_cache_across_backends: true,
+            ..Config::default()
+        };
+        assert_eq!(None, tool_version(&shared, cache_dir.path()));
+    }
+
+    #[test]
+    fn test_candidate_commands() {
+        let book_root = tempdir().unwrap();
+        let no_env = |_: &str| None;
+
+        // Note that the well known install locations might exist on the test machine
+        let (candidates, missing) = candidate_commands(book_root.path(), "java", &no_env);
+        assert_eq!(vec!["plantuml", "java -jar plantuml.jar"], candidates[..2]);
+        assert_eq!(book_root.path().join("plantuml.jar"), missing[0]);
+
+        // The jar in the book root is picked up when it exists
+        fs::write(book_root.path().join("plantuml.jar"), "").unwrap();
+        let (candidates, missing) = candidate_commands(book_root.path(), "java", &no_env);
+        assert_eq!(
+            jar_command("java", &book_root.path().join("plantuml.jar")),
+            candidates[2]
+        );
+        assert!(!missing.contains(&book_root.path().join("plantuml.jar")));
+
+        // The environment variables take precedence
+        let env: HashMap<&str, &str> = [
+            ("PLANTUML", "/opt/plantuml/bin/plantuml"),
+            ("PLANTUML_JAR", "/opt/my plantuml/plantuml.jar"),
+        ]
+        .iter()
+        .cloned()
+        .collect();
+        let env_lookup = |name: &str| env.get(name).map(|v| v.to_string());
+        let (candidates, _) = candidate_commands(book_root.path(), "java", &env_lookup);
+        assert_eq!("/opt/plantuml/bin/plantuml", candidates[0]);
+        assert_eq!("java -jar \"/opt/my plantuml/plantuml.jar\"", candidates[1]);
+
+        // Using a custom JVM
+        let (candidates, _) =
+            candidate_commands(book_root.path(), "/opt/jdk/bin/java -Xmx1g", &no_env);
+        assert_eq!("/opt/jdk/bin/java -Xmx1g -jar plantuml.jar", candidates[1]);
+    }
+
+    #[test]
+    fn test_configured_jar_command() {
+        let cache_dir = tempdir().unwrap();
+        let mut detection_cache = DetectionCache::load(cache_dir.path(), false);
+
+        let result = configured_jar_command(
+            "java",
+            Path::new("surely/this/does/not/exist.jar"),
+            &mut detection_cache,
+        );
+        assert_eq!(
+            "The plantuml-jar 'surely/this/does/not/exist.jar' in book.toml does not exist",
+            format!("{}", result.unwrap_err())
+        );
+
+        let jar = cache_dir.path().join("plantuml.jar");
+        fs::write(&jar, "").unwrap();
+        let result = configured_jar_command(
+            "surely-this-java-does-not-exist",
+            &jar,
+            &mut detection_cache,
+        );
+        assert!(format!("{}", result.unwrap_err()).starts_with(
+            "The java-cmd 'surely-this-java-does-not-exist' in book.toml could not be run"
+        ));
+    }
+
+    #[test]
+    fn test_well_known_jar_locations() {
+        let env: HashMap<&str, &str> = [("HOME", "/home/foo"), ("ChocolateyInstall", "D:/choco")]
+            .iter()
+            .cloned()
+            .collect();
+        let locations = well_known_jar_locations(Path::new("book"), &|name| {
+            env.get(name).map(|v| v.to_string())
+        });
+
+        assert!(locations.contains(&Path::new("book").join("plantuml.jar")));
+        assert!(
+            locations.contains(&Path::new("/home/foo").join(".local/share/plantuml/plantuml.jar"))
+        );
+        assert!(locations.contains(&PathBuf::from(
+            "/opt/homebrew/opt/plantuml/libexec/plantuml.jar"
+        )));
+        assert!(locations.contains(
+            &Path::new("D:/choco")
+                .join("lib")
+                .join("plantuml")
+                .join("tools")
+                .join("plantuml.jar")
+        ));
     }
 }
@@ -0,0 +1,454 @@
+use crate::backend::error::BackendError;
+use crate::backend::process_guard::ProcessGuard;
+use crate::backend::shell::create_command;
+use crate::backend::{self, Backend, Capabilities};
+use anyhow::{bail, format_err, Context, Result};
+use std::cell::{Cell, RefCell};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::process::Stdio;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The port PlantUML's FTP server listens on by default (plantuml -ftp)
+const DEFAULT_PORT: u16 = 4242;
+
+/// Timeout for reading from/writing to the FTP server (rendering included)
+const IO_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long to wait for a started FTP server to accept connections
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The image formats the PlantUML FTP server can render
+const SUPPORTED_FORMATS: [&str; 6] = ["svg", "png", "txt", "utxt", "eps", "pdf"];
+
+/// Translate the image format into the PlantUML file format name (used in the
+/// SITE command) and the extension of the generated file.
+fn ftp_format(image_format: &str) -> Result<(&'static str, &'static str)> {
+    backend::check_format("PlantUML FTP server", image_format, &SUPPORTED_FORMATS)?;
+
+    Ok(match image_format {
+        "png" => ("PNG", "png"),
+        "txt" => ("ATXT", "atxt"),
+        "utxt" => ("UTXT", "utxt"),
+        "eps" => ("EPS", "eps"),
+        "pdf" => ("PDF", "pdf"),
+        _ => ("SVG", "svg"),
+    })
+}
+
+/// Parse an ftp://host[:port] URL into the host and port (defaults to 4242)
+pub fn parse_ftp_url(url: &str) -> Result<(String, u16)> {
+    let address = url
+        .strip_prefix("ftp://")
+        .ok_or_else(|| format_err!("'{}' is not an ftp:// URL", url))?
+        .trim_end_matches('/');
+
+    let (host, port) = match address.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            port.parse::<u16>()
+                .with_context(|| format!("Invalid port in the PlantUML FTP server URL '{url}'"))?,
+        ),
+        None => (address, DEFAULT_PORT),
+    };
+
+    if host.is_empty() || host.contains('/') {
+        bail!("Invalid PlantUML FTP server URL '{}'", url);
+    }
+
+    Ok((host.to_string(), port))
+}
+
+/// Parse the "227 Entering Passive Mode (h1,h2,h3,h4,p1,p2)" response message
+fn parse_pasv_response(message: &str) -> Result<(String, u16)> {
+    let start = message.find('(').map_or(0, |pos| pos + 1);
+    let end = message[start..]
+        .find(')')
+        .map_or(message.len(), |pos| start + pos);
+
+    let numbers = message[start..end]
+        .split(',')
+        .map(|n| n.trim().parse::<u8>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| format_err!("Invalid FTP passive mode response '{}'", message))?;
+    if numbers.len() != 6 {
+        bail!("Invalid FTP passive mode response '{}'", message);
+    }
+
+    let host = format!(
+        "{}.{}.{}.{}",
+        numbers[0], numbers[1], numbers[2], numbers[3]
+    );
+    let port = u16::from(numbers[4]) << 8 | u16::from(numbers[5]);
+    Ok((host, port))
+}
+
+/// A minimal (passive mode) FTP client, just enough to talk to PlantUML's FTP server
+struct FtpConnection {
+    control: BufReader<TcpStream>,
+}
+
+impl FtpConnection {
+    fn connect(host: &str, port: u16) -> Result<Self> {
+        let stream = TcpStream::connect((host, port)).with_context(|| {
+            format!("Failed to connect to the PlantUML FTP server {host}:{port}")
+        })?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+        let mut connection = Self {
+            control: BufReader::new(stream),
+        };
+        connection.expect_response(&[220])?;
+        if connection.command("USER anonymous", &[230, 331])? == 331 {
+            connection.command("PASS anonymous", &[230])?;
+        }
+        connection.command("TYPE I", &[200])?;
+
+        Ok(connection)
+    }
+
+    /// Read a (possibly multi line) response, returns the status code and the message
+    fn read_response(&mut self) -> Result<(u32, String)> {
+        let mut line = String::new();
+        self.control.read_line(&mut line)?;
+        if line.len() < 3 {
+            bail!("Unexpected FTP server response '{}'", line.trim_end());
+        }
+
+        let code = line[..3]
+            .parse::<u32>()
+            .map_err(|_| format_err!("Unexpected FTP server response '{}'", line.trim_end()))?;
+        let mut message = line[3..].trim().to_string();
+        if line[3..].starts_with('-') {
+            // Multi line response, ends with "<code> <message>"
+            let last_line_prefix = format!("{code} ");
+            loop {
+                let mut line = String::new();
+                if self.control.read_line(&mut line)? == 0 {
+                    bail!("FTP server closed the connection");
+                }
+                message.push('\n');
+                message.push_str(line.trim_end());
+                if line.starts_with(&last_line_prefix) {
+                    break;
+                }
+            }
+        }
+
+        Ok((code, message))
+    }
+
+    fn expect_response(&mut self, expected: &[u32]) -> Result<(u32, String)> {
+        let (code, message) = self.read_response()?;
+        if !expected.contains(&code) {
+            bail!("Unexpected FTP server response '{} {}'", code, message);
+        }
+
+        Ok((code, message))
+    }
+
+    /// Send a command and check the response code, returns the response code
+    fn command(&mut self, command: &str, expected: &[u32]) -> Result<u32> {
+        self.send(command)?;
+        let (code, _) = self
+            .expect_response(expected)
+            .with_context(|| format!("FTP command '{command}' failed"))?;
+        Ok(code)
+    }
+
+    fn send(&mut self, command: &str) -> Result<()> {
+        let stream = self.control.get_mut();
+        stream.write_all(command.as_bytes())?;
+        stream.write_all(b"\r\n")?;
+        Ok(())
+    }
+
+    /// Open a passive mode data connection
+    fn data_connection(&mut self) -> Result<TcpStream> {
+        self.send("PASV")?;
+        let (_, message) = self.expect_response(&[227])?;
+        let (host, port) = parse_pasv_response(&message)?;
+
+        let stream = TcpStream::connect((host.as_str(), port))
+            .with_context(|| format!("Failed to open the FTP data connection to {host}:{port}"))?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        Ok(stream)
+    }
+
+    fn store(&mut self, file_name: &str, data: &[u8]) -> Result<()> {
+        let mut data_connection = self.data_connection()?;
+        self.command(&format!("STOR {file_name}"), &[125, 150])?;
+        data_connection.write_all(data)?;
+        drop(data_connection);
+        self.expect_response(&[226, 250])?;
+        Ok(())
+    }
+
+    fn retrieve(&mut self, file_name: &str) -> Result<Vec<u8>> {
+        let mut data_connection = self.data_connection()?;
+        self.command(&format!("RETR {file_name}"), &[125, 150])?;
+        let mut data = vec![];
+        data_connection.read_to_end(&mut data)?;
+        self.expect_response(&[226, 250])?;
+        Ok(data)
+    }
+}
+
+/// Renders diagrams using PlantUML's FTP server mode (plantuml -ftp), which
+/// keeps the JVM running between diagrams. The sources are uploaded and the
+/// rendered images downloaded using FTP.
+pub struct PlantUMLFtp {
+    host: String,
+    port: u16,
+    connection: RefCell<Option<FtpConnection>>,
+    file_counter: Cell<u32>,
+    /// The FTP server started by this backend (stopped when the backend is dropped)
+    server: Option<ProcessGuard>,
+}
+
+impl PlantUMLFtp {
+    /// Attach to the FTP server at the given address
+    pub fn new(host: String, port: u16) -> Self {
+        log::info!("Selected PlantUML FTP server {}:{}", host, port);
+        Self {
+            host,
+            port,
+            connection: RefCell::new(None),
+            file_counter: Cell::new(0),
+            server: None,
+        }
+    }
+
+    /// Attach to the FTP server at the given address, start the PlantUML FTP
+    /// server when it is not running yet.
+    /// # Arguments
+    /// * `host` - The FTP server's host
+    /// * `port` - The FTP server's port
+    /// * `plantuml_cmd` - Get the PlantUML command for starting the server
+    /// * `cache_dir` - The cache dir the server's PID file is written to
+    pub fn start(
+        host: String,
+        port: u16,
+        plantuml_cmd: &dyn Fn() -> Result<String>,
+        cache_dir: &Path,
+    ) -> Result<Self> {
+        let mut backend = Self::new(host, port);
+        if let Ok(connection) = FtpConnection::connect(&backend.host, port) {
+            backend.connection.replace(Some(connection));
+            return Ok(backend);
+        }
+
+        let plantuml_cmd = plantuml_cmd()?;
+        log::info!(
+            "Starting the PlantUML FTP server '{} -ftp:{}'",
+            plantuml_cmd,
+            port
+        );
+        let server = ProcessGuard::spawn(
+            create_command(&plantuml_cmd)?
+                .arg(format!("-ftp:{port}"))
+                .stdin(Stdio::null())
+                .stdout(Stdio::null()),
+            "PlantUML FTP server",
+            cache_dir,
+        )?;
+        backend.server = Some(server);
+
+        let started = Instant::now();
+        loop {
+            match FtpConnection::connect(&backend.host, port) {
+                Ok(connection) => {
+                    backend.connection.replace(Some(connection));
+                    return Ok(backend);
+                }
+                Err(e) if started.elapsed() > STARTUP_TIMEOUT => {
+                    return Err(e.context("The started PlantUML FTP server does not respond"));
+                }
+                Err(_) => thread::sleep(Duration::from_millis(200)),
+            }
+        }
+    }
+
+    fn render(&self, plantuml_code: &str, image_format: &str) -> Result<Vec<u8>> {
+        let (ftp_format, extension) = ftp_format(image_format)?;
+
+        let mut connection = self.connection.borrow_mut();
+        if connection.is_none() {
+            *connection = Some(FtpConnection::connect(&self.host, self.port)?);
+        }
+        let ftp = connection.as_mut().unwrap();
+
+        let index = self.file_counter.get();
+        self.file_counter.set(index + 1);
+        let source_name = format!("src{index}.puml");
+        let image_name = format!("src{index}.{extension}");
+
+        ftp.command(&format!("SITE {ftp_format}"), &[200])?;
+        ftp.store(&source_name, plantuml_code.as_bytes())?;
+        let data = ftp.retrieve(&image_name)?;
+
+        // Keep the server's memory usage down, failing to delete is harmless
+        for file_name in [&source_name, &image_name].iter() {
+            if let Err(e) = ftp.command(&format!("DELE {file_name}"), &[250]) {
+                log::debug!("{:#}", e);
+            }
+        }
+
+        Ok(data)
+    }
+}
+
+impl Backend for PlantUMLFtp {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            formats: &SUPPORTED_FORMATS,
+            batching: true,
+            // The server's working dir is not the chapter's directory
+            includes: false,
+            // The uploaded file names are only unique per backend instance
+            concurrency_safe: false,
+        }
+    }
+
+    fn render_from_string(&self, plantuml_code: &str, image_format: &str) -> Result<Vec<u8>> {
+        let result = self.render(plantuml_code, image_format);
+        if result.is_err() {
+            // Reconnect for the next diagram, the connection state is unknown
+            self.connection.replace(None);
+        }
+
+        result
+            .map_err(|e| match BackendError::of_network_io(&e) {
+                Some(category) => category.tag()(e),
+                None => e,
+            })
+            .with_context(|| {
+                format!(
+                    "Failed to render the diagram using the PlantUML FTP server {}:{}",
+                    self.host, self.port
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_parse_ftp_url() {
+        assert_eq!(
+            (String::from("localhost"), 4242),
+            parse_ftp_url("ftp://localhost").unwrap()
+        );
+        assert_eq!(
+            (String::from("127.0.0.1"), 1234),
+            parse_ftp_url("ftp://127.0.0.1:1234/").unwrap()
+        );
+        assert!(parse_ftp_url("http://localhost").is_err());
+        assert!(parse_ftp_url("ftp://localhost:foo").is_err());
+        assert!(parse_ftp_url("ftp://").is_err());
+        assert!(parse_ftp_url("ftp://localhost/path").is_err());
+    }
+
+    #[test]
+    fn test_parse_pasv_response() {
+        assert_eq!(
+            (String::from("127.0.0.1"), 4 * 256 + 1),
+            parse_pasv_response("Entering Passive Mode (127,0,0,1,4,1).").unwrap()
+        );
+        assert!(parse_pasv_response("Entering Passive Mode (127,0,0,1,4)").is_err());
+        assert!(parse_pasv_response("Entering Passive Mode (foo)").is_err());
+    }
+
+    #[test]
+    fn test_ftp_format() {
+        assert_eq!(("SVG", "svg"), ftp_format("svg").unwrap());
+        assert_eq!(("ATXT", "atxt"), ftp_format("txt").unwrap());
+        assert!(ftp_format("braille").is_err());
+    }
+
+    /// Serve a single FTP session, storing the uploaded file and returning it
+    /// (prefixed with the requested format) when the image is retrieved
+    fn fake_ftp_server() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut format = String::new();
+            let mut stored = vec![];
+            let mut data_listener: Option<TcpListener> = None;
+
+            writer
+                .write_all(b"220-Welcome\r\n220 PlantUML\r\n")
+                .unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 {
+                let command = line.trim_end().to_string();
+                line.clear();
+                let response = if command == "USER anonymous" {
+                    String::from("331 Password")
+                } else if command.starts_with("PASS") {
+                    String::from("230 Ok")
+                } else if command == "TYPE I" {
+                    String::from("200 Ok")
+                } else if let Some(site) = command.strip_prefix("SITE ") {
+                    format = site.to_string();
+                    String::from("200 Ok")
+                } else if command == "PASV" {
+                    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                    let data_port = listener.local_addr().unwrap().port();
+                    data_listener = Some(listener);
+                    format!(
+                        "227 Entering Passive Mode (127,0,0,1,{},{})",
+                        data_port >> 8,
+                        data_port & 0xff
+                    )
+                } else if command.starts_with("STOR src0.puml") {
+                    writer.write_all(b"150 Ok\r\n").unwrap();
+                    let (mut data, _) = data_listener.take().unwrap().accept().unwrap();
+                    data.read_to_end(&mut stored).unwrap();
+                    String::from("226 Stored")
+                } else if command.starts_with("RETR src0.") {
+                    writer.write_all(b"150 Ok\r\n").unwrap();
+                    let (mut data, _) = data_listener.take().unwrap().accept().unwrap();
+                    data.write_all(format.as_bytes()).unwrap();
+                    data.write_all(&stored).unwrap();
+                    drop(data);
+                    String::from("226 Sent")
+                } else if command.starts_with("DELE") {
+                    String::from("250 Deleted")
+                } else {
+                    String::from("500 Unknown command")
+                };
+                writer
+                    .write_all(format!("{response}\r\n").as_bytes())
+                    .unwrap();
+            }
+        });
+
+        port
+    }
+
+    #[test]
+    fn test_render_from_string() {
+        let port = fake_ftp_server();
+        let backend = PlantUMLFtp::new(String::from("127.0.0.1"), port);
+
+        assert_eq!(
+            b"PNG@startuml\nA --|> B\n@enduml".to_vec(),
+            backend
+                .render_from_string("@startuml\nA --|> B\n@enduml", "png")
+                .unwrap()
+        );
+        assert!(backend.render_from_string("foo", "braille").is_err());
+    }
+}
@@ -0,0 +1,377 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// The directory (in the cache dir) with the PID files of the running helper processes
+const PID_DIR: &str = "daemons";
+
+/// How long to wait for a killed helper process to exit
+const EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Guards a long-lived helper process (e.g. the PlantUML FTP server). The
+/// process is killed when the guard is dropped (after a successful build, an
+/// error, or a panic). Its PID (and command line) is recorded in a PID file in
+/// the cache dir, so stragglers (e.g. after the preprocessor was killed) can be
+/// stopped using `mdbook-plantuml kill-daemons`. The process's stderr is forwarded to the
+/// log (see forward_stderr), so it neither gets lost nor blocks the process
+/// when the pipe is full.
+pub struct ProcessGuard {
+    child: Child,
+    name: String,
+    pid_file: PathBuf,
+    /// The thread forwarding the process's stderr to the log (None if it could not be started)
+    stderr_logger: Option<JoinHandle<()>>,
+}
+
+impl ProcessGuard {
+    /// Spawn the command and guard the process
+    /// # Arguments
+    /// * `command` - The command to spawn
+    /// * `name` - The process name used in log messages
+    /// * `cache_dir` - The cache dir the PID file is written to
+    pub fn spawn(command: &mut Command, name: &str, cache_dir: &Path) -> Result<Self> {
+        let mut child = command
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to start the {name}"))?;
+
+        let pid_dir = cache_dir.join(PID_DIR);
+        let pid_file = pid_dir.join(format!("{}.pid", child.id()));
+        if let Err(e) = fs::create_dir_all(&pid_dir)
+            .and_then(|_| fs::write(&pid_file, pid_file_content(child.id(), command)))
+        {
+            log::warn!("Failed to write the PID file {:?} ({})", pid_file, e);
+        }
+
+        let stderr_logger = child.stderr.take().and_then(|stderr| {
+            let prefix = name.to_string();
+            thread::Builder::new()
+                .name(format!("{name} stderr"))
+                .spawn(move || {
+                    forward_stderr(stderr, &prefix);
+                })
+                .map_err(|e| log::warn!("Failed to forward the stderr of the {} ({})", name, e))
+                .ok()
+        });
+
+        log::debug!("Started the {} (PID {})", name, child.id());
+        Ok(Self {
+            child,
+            name: name.to_string(),
+            pid_file,
+            stderr_logger,
+        })
+    }
+
+    /// Wait (a limited time) for the stderr forwarding thread to log the last lines. It ends when
+    /// the pipe is closed, which can take longer when a child of the process keeps it open.
+    fn stop_stderr_logger(&mut self) {
+        if let Some(stderr_logger) = self.stderr_logger.take() {
+            let stopped = Instant::now();
+            while !stderr_logger.is_finished() {
+                if stopped.elapsed() > EXIT_TIMEOUT {
+                    // Detach it, it ends with the preprocessor
+                    return;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+            let _ = stderr_logger.join();
+        }
+    }
+}
+
+/// The content of a PID file, the PID followed by the program and its arguments (one per line).
+/// The command line is verified before killing the process, as the PID may have been reused by
+/// another process since.
+fn pid_file_content(pid: u32, command: &Command) -> String {
+    let mut content = format!("{pid}\n");
+    for part in std::iter::once(command.get_program()).chain(command.get_args()) {
+        content.push_str(&part.to_string_lossy().replace('\n', " "));
+        content.push('\n');
+    }
+
+    content
+}
+
+/// Forward the lines of a process's stderr to the log (prefixed with the process name) until the
+/// stream is closed. Errors and exceptions are logged as warning, anything else as debug message.
+/// Returns the number of forwarded lines.
+/// # Arguments
+/// * `stderr` - The process's stderr
+/// * `name` - The process name used as prefix
+fn forward_stderr<R: Read>(stderr: R, name: &str) -> usize {
+    let mut reader = BufReader::new(stderr);
+    let mut line = Vec::new();
+    let mut forwarded = 0;
+    // Read bytes, a line that is not valid UTF-8 should not stop the forwarding
+    while reader
+        .read_until(b'\n', &mut line)
+        .map_or(false, |read| read > 0)
+    {
+        let text = String::from_utf8_lossy(&line);
+        let text = text.trim_end();
+        if !text.is_empty() {
+            log::log!(stderr_level(text), "[{}] {}", name, text);
+            forwarded += 1;
+        }
+        line.clear();
+    }
+
+    forwarded
+}
+
+/// The log level of a stderr line, java exceptions (and their stack traces) and errors are
+/// warnings
+fn stderr_level(line: &str) -> log::Level {
+    let is_error = line.contains("Exception")
+        || line.trim_start().starts_with("at ")
+        || ["ERROR", "SEVERE", "Error:"]
+            .iter()
+            .any(|prefix| line.starts_with(prefix));
+    if is_error {
+        log::Level::Warn
+    } else {
+        log::Level::Debug
+    }
+}
+
+impl Drop for ProcessGuard {
+    fn drop(&mut self) {
+        if let Ok(None) = self.child.try_wait() {
+            if let Err(e) = self.child.kill() {
+                log::warn!("Failed to stop the {} ({})", self.name, e);
+            }
+
+            // Do not hang the build on a process that refuses to die
+            let killed = Instant::now();
+            while let Ok(None) = self.child.try_wait() {
+                if killed.elapsed() > EXIT_TIMEOUT {
+                    log::warn!(
+                        "The {} (PID {}) did not stop in time",
+                        self.name,
+                        self.child.id()
+                    );
+                    return;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        }
+
+        self.stop_stderr_logger();
+        log::debug!("Stopped the {} (PID {})", self.name, self.child.id());
+        let _ = fs::remove_file(&self.pid_file);
+    }
+}
+
+/// Kill the helper processes left behind by earlier builds (as recorded in the
+/// PID files), returns the number of processes that were killed.
+/// # Arguments
+/// * `cache_dir` - The cache dir containing the PID files
+pub fn kill_daemons(cache_dir: &Path) -> Result<usize> {
+    let pid_dir = cache_dir.join(PID_DIR);
+    let entries = match fs::read_dir(&pid_dir) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(0),
+    };
+
+    let mut killed = 0;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().map_or(true, |ext| ext != "pid") {
+            continue;
+        }
+
+        let content = fs::read_to_string(&path).unwrap_or_default();
+        let mut lines = content.lines();
+        let pid = lines.next().and_then(|pid| pid.trim().parse::<u32>().ok());
+        let command_line: Vec<&str> = lines.collect();
+        if let Some(pid) = pid {
+            if !is_running_command(pid, &command_line) {
+                log::debug!(
+                    "Not killing process {}, it is not the recorded helper process (anymore)",
+                    pid
+                );
+            } else if kill_process(pid) {
+                log::info!("Killed PlantUML helper process {}", pid);
+                killed += 1;
+            }
+        }
+
+        fs::remove_file(&path).with_context(|| format!("Failed to remove PID file {path:?}"))?;
+    }
+
+    Ok(killed)
+}
+
+/// Returns true if the process with the given PID runs the recorded command line (the program and
+/// its arguments), false when that cannot be verified (e.g. a PID file without command line)
+fn is_running_command(pid: u32, command_line: &[&str]) -> bool {
+    if command_line.is_empty() {
+        return false;
+    }
+
+    // The quoting of the arguments differs per platform, so only check they are all there
+    process_command_line(pid).map_or(false, |running| {
+        command_line.iter().all(|part| running.contains(part))
+    })
+}
+
+/// The command line of the process with the given PID, None if the process does not exist (or
+/// the command line cannot be determined)
+fn process_command_line(pid: u32) -> Option<String> {
+    if cfg!(target_os = "linux") {
+        let command_line = fs::read(format!("/proc/{pid}/cmdline")).ok()?;
+        let command_line = String::from_utf8_lossy(&command_line).replace('\0', " ");
+        return Some(command_line).filter(|command_line| !command_line.trim().is_empty());
+    }
+
+    let mut command = if cfg!(target_family = "windows") {
+        let mut command = Command::new("powershell");
+        command.arg("-NoProfile").arg("-Command").arg(format!(
+            "(Get-CimInstance Win32_Process -Filter 'ProcessId={pid}').CommandLine"
+        ));
+        command
+    } else {
+        let mut command = Command::new("ps");
+        command
+            .args(["-ww", "-o", "args=", "-p"])
+            .arg(pid.to_string());
+        command
+    };
+
+    let output = command.output().ok()?;
+    let command_line = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.success() && !command_line.is_empty()).then_some(command_line)
+}
+
+/// Kill the process with the given PID, returns false if the process does not
+/// exist (anymore)
+fn kill_process(pid: u32) -> bool {
+    let mut command = if cfg!(target_family = "windows") {
+        let mut command = Command::new("taskkill");
+        command.arg("/F").arg("/PID").arg(pid.to_string());
+        command
+    } else {
+        let mut command = Command::new("kill");
+        command.arg(pid.to_string());
+        command
+    };
+
+    command
+        .output()
+        .map_or(false, |output| output.status.success())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_process_guard() {
+        let cache_dir = tempdir().unwrap();
+        let guard =
+            ProcessGuard::spawn(Command::new("sleep").arg("30"), "sleeper", cache_dir.path())
+                .unwrap();
+        let pid_file = guard.pid_file.clone();
+        assert_eq!(
+            format!("{}\nsleep\n30\n", guard.child.id()),
+            fs::read_to_string(&pid_file).unwrap()
+        );
+
+        // The process is killed (and the PID file removed) on drop
+        let started = Instant::now();
+        drop(guard);
+        assert!(started.elapsed() < EXIT_TIMEOUT);
+        assert!(!pid_file.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_process_guard_stderr() {
+        // The process does not block on a full stderr pipe
+        let cache_dir = tempdir().unwrap();
+        let mut guard = ProcessGuard::spawn(
+            Command::new("sh")
+                .arg("-c")
+                .arg("yes 'Some warning' | head -n 100000 >&2"),
+            "chatterbox",
+            cache_dir.path(),
+        )
+        .unwrap();
+        let started = Instant::now();
+        while let Ok(None) = guard.child.try_wait() {
+            assert!(started.elapsed() < EXIT_TIMEOUT);
+            thread::sleep(Duration::from_millis(50));
+        }
+        drop(guard);
+    }
+
+    #[test]
+    fn test_forward_stderr() {
+        let stderr: &[u8] = b"Starting\n\nInvalid \xff UTF-8\nlast line without newline";
+        assert_eq!(3, forward_stderr(stderr, "test"));
+        assert_eq!(0, forward_stderr(&b""[..], "test"));
+    }
+
+    #[test]
+    fn test_stderr_level() {
+        assert_eq!(
+            log::Level::Debug,
+            stderr_level("PlantUML FTP server started")
+        );
+        assert_eq!(
+            log::Level::Warn,
+            stderr_level("Exception in thread \"main\" java.lang.OutOfMemoryError")
+        );
+        assert_eq!(
+            log::Level::Warn,
+            stderr_level("\tat net.sourceforge.plantuml.Run.main(Run.java:1)")
+        );
+        assert_eq!(log::Level::Warn, stderr_level("ERROR: something failed"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_kill_daemons() {
+        let cache_dir = tempdir().unwrap();
+        assert_eq!(0, kill_daemons(cache_dir.path()).unwrap());
+
+        let mut straggler = Command::new("sleep").arg("30").spawn().unwrap();
+        let pid_dir = cache_dir.path().join(PID_DIR);
+        fs::create_dir_all(&pid_dir).unwrap();
+        fs::write(
+            pid_dir.join(format!("{}.pid", straggler.id())),
+            format!("{}\nsleep\n30\n", straggler.id()),
+        )
+        .unwrap();
+        fs::write(pid_dir.join("garbage.pid"), "not a pid").unwrap();
+
+        // The PID was reused by another process (or the command line is unknown)
+        let mut bystanders = vec![];
+        for content in ["java\n-jar\nplantuml.jar\n", ""] {
+            let bystander = Command::new("sleep").arg("31").spawn().unwrap();
+            fs::write(
+                pid_dir.join(format!("{}.pid", bystander.id())),
+                format!("{}\n{}", bystander.id(), content),
+            )
+            .unwrap();
+            bystanders.push(bystander);
+        }
+
+        assert_eq!(1, kill_daemons(cache_dir.path()).unwrap());
+        assert!(!straggler.wait().unwrap().success());
+        assert_eq!(0, fs::read_dir(&pid_dir).unwrap().count());
+        for mut bystander in bystanders {
+            assert!(bystander.try_wait().unwrap().is_none());
+            bystander.kill().unwrap();
+            let _ = bystander.wait();
+        }
+    }
+}
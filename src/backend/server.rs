@@ -1,30 +1,167 @@
-use crate::backend::Backend;
+use crate::backend::error::BackendError;
+use crate::backend::{self, Backend, Capabilities};
 use crate::base64;
-use anyhow::{bail, Result};
-use deflate::deflate_bytes;
+use anyhow::{bail, format_err, Result};
+use deflate::{deflate_bytes_conf, Compression};
+use reqwest::blocking::Client;
 use reqwest::Url;
+use std::cell::RefCell;
+use std::time::Duration;
+
+/// The server's response to a diagram request
+struct ServerResponse {
+    /// The HTTP status code
+    status: u16,
+    /// The Content-Type header (if any)
+    content_type: Option<String>,
+    body: Vec<u8>,
+}
 
 /// Helper trait for unit testing purposes (allow testing without a live server)
 trait ImageDownloader {
-    fn download_image(&self, request_url: &Url) -> Result<Vec<u8>>;
+    fn download_image(&self, request_url: &Url) -> Result<ServerResponse>;
 }
 
-struct RealImageDownloader;
+/// Downloads the images with a single client, so the (keep-alive) connections to the server are
+/// reused instead of set up per diagram
+struct RealImageDownloader {
+    /// How long idle connections are kept open (None for reqwest's default)
+    pool_idle_timeout: Option<Duration>,
+    /// The maximum number of idle connections kept open (None for no limit)
+    pool_max_idle: Option<usize>,
+    /// The client, created on first use
+    client: RefCell<Option<Client>>,
+}
+
+impl RealImageDownloader {
+    /// The (shared) client
+    fn client(&self) -> Result<Client> {
+        if let Some(client) = &*self.client.borrow() {
+            return Ok(client.clone());
+        }
+
+        let mut builder = Client::builder();
+        if let Some(idle_timeout) = self.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(idle_timeout);
+        }
+        if let Some(max_idle) = self.pool_max_idle {
+            builder = builder.pool_max_idle_per_host(max_idle);
+        }
+        let client = builder
+            .build()
+            .or_else(|e| bail!("Failed to create the PlantUML server client ({})", e))?;
+        self.client.replace(Some(client.clone()));
+        Ok(client)
+    }
+}
 
 impl ImageDownloader for RealImageDownloader {
-    /// Download the image at the given URL, return the response body as a
-    /// Vec<u8>
-    fn download_image(&self, request_url: &Url) -> Result<Vec<u8>> {
-        let mut image_buf: Vec<u8> = vec![];
-        reqwest::blocking::get(request_url.clone())
-            .and_then(|mut response| response.copy_to(&mut image_buf))
-            .or_else(|e| bail!("Failed to generate diagram ({})", e))?;
-        Ok(image_buf)
+    /// Download the image at the given URL
+    fn download_image(&self, request_url: &Url) -> Result<ServerResponse> {
+        let mut body: Vec<u8> = vec![];
+        let response = self
+            .client()?
+            .get(request_url.clone())
+            .send()
+            .and_then(|mut response| response.copy_to(&mut body).map(|_| response))
+            .map_err(|e| {
+                let category = if e.is_timeout() {
+                    BackendError::Timeout
+                } else {
+                    BackendError::Network {
+                        status: e.status().map(|status| status.as_u16()),
+                    }
+                };
+                category.tag()(format_err!("Failed to generate diagram ({})", e))
+            })?;
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+
+        Ok(ServerResponse {
+            status: response.status().as_u16(),
+            content_type,
+            body,
+        })
+    }
+}
+
+/// The media type the server responds with for the (server) image format
+fn expected_content_type(server_format: &str) -> &'static str {
+    match server_format {
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "txt" => "text/plain",
+        "eps" => "application/postscript",
+        "pdf" => "application/pdf",
+        _ => "",
+    }
+}
+
+/// Check the response is an image in the requested format, so error pages (e.g. of a misconfigured
+/// server URL) are not saved as image. PlantUML servers respond with an error status and an image
+/// of the error for invalid diagrams, so error responses with the expected content type are
+/// accepted.
+fn validate_response(server_format: &str, response: &ServerResponse) -> Result<()> {
+    // Ignore the parameters (e.g. "text/plain; charset=utf-8")
+    let content_type = response
+        .content_type
+        .as_deref()
+        .map(|content_type| content_type.split(';').next().unwrap_or_default().trim());
+    let expected = expected_content_type(server_format);
+    let content_type_ok = content_type.map_or(true, |content_type| {
+        content_type.eq_ignore_ascii_case(expected)
+    });
+    let status_ok = (200..300).contains(&response.status);
+    if content_type_ok && (status_ok || content_type.is_some()) {
+        return Ok(());
+    }
+
+    let body = String::from_utf8_lossy(&response.body);
+    let first_lines: Vec<&str> = body
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .take(5)
+        .collect();
+    let error = format_err!(
+        "The PlantUML server responded with status {} ({}) instead of a {} image, check the \
+         server URL (plantuml-cmd). The response starts with:\n{}",
+        response.status,
+        content_type.unwrap_or("no content type"),
+        server_format,
+        first_lines.join("\n")
+    );
+    Err(BackendError::Network {
+        status: Some(response.status),
+    }
+    .tag()(error))
+}
+
+/// The image formats the PlantUML server can render (see the servlets in
+/// https://github.com/plantuml/plantuml-server)
+const SUPPORTED_FORMATS: [&str; 6] = ["svg", "png", "txt", "utxt", "eps", "pdf"];
+
+/// Translate the image format into the server's endpoint name. The server's txt
+/// endpoint renders unicode text, there is no separate utxt endpoint.
+fn server_format(image_format: &str) -> Result<&str> {
+    backend::check_format("PlantUML server", image_format, &SUPPORTED_FORMATS)?;
+
+    if image_format == "utxt" {
+        Ok("txt")
+    } else {
+        Ok(image_format)
     }
 }
 
 pub struct PlantUMLServer {
     server_url: Url,
+    /// The request URL template (see Config::server_url_template), None for the standard
+    /// PlantUML server layout ({base}/{format}/{encoded})
+    url_template: Option<String>,
+    downloader: RealImageDownloader,
 }
 
 impl PlantUMLServer {
@@ -40,11 +177,55 @@ impl PlantUMLServer {
             repath
         };
 
-        Self { server_url }
+        Self {
+            server_url,
+            url_template: None,
+            downloader: RealImageDownloader {
+                pool_idle_timeout: None,
+                pool_max_idle: None,
+                client: RefCell::new(None),
+            },
+        }
+    }
+
+    /// Keep the idle connections to the server open for the given time, and at most the given
+    /// number of them (None for no limit)
+    pub fn with_pool(self, idle_timeout: Duration, max_idle: Option<usize>) -> Self {
+        Self {
+            downloader: RealImageDownloader {
+                pool_idle_timeout: Some(idle_timeout),
+                pool_max_idle: max_idle,
+                client: RefCell::new(None),
+            },
+            ..self
+        }
+    }
+
+    /// Use the URL template for the requests, for servers with another path layout than the
+    /// PlantUML server (e.g. "{base}/api/plantuml?fmt={format}&src={encoded}")
+    pub fn with_url_template(self, url_template: Option<String>) -> Self {
+        Self {
+            url_template,
+            ..self
+        }
     }
 
     /// Format the PlantUML server URL using the encoded diagram and extension
     fn url(&self, image_format: &str, encoded_diagram: &str) -> Result<Url> {
+        if let Some(template) = &self.url_template {
+            let url = template
+                .replace("{base}", self.server_url.as_str().trim_end_matches('/'))
+                .replace("{format}", image_format)
+                .replace("{encoded}", encoded_diagram);
+            return Url::parse(&url).map_err(|e| {
+                anyhow::format_err!(
+                    "Error constructing PlantUML server URL from the template '{}' ({})",
+                    template,
+                    e
+                )
+            });
+        }
+
         let path = format!("{image_format}/{encoded_diagram}");
 
         self.server_url.join(&path).map_err(|e| {
@@ -66,22 +247,35 @@ impl PlantUMLServer {
         downloader: &dyn ImageDownloader,
     ) -> Result<Vec<u8>> {
         let encoded = encode_diagram_source(plantuml_code);
-        let request_url = self.url(image_format, &encoded)?;
+        let server_format = server_format(image_format)?;
+        let request_url = self.url(server_format, &encoded)?;
 
-        downloader.download_image(&request_url)
+        let response = downloader.download_image(&request_url)?;
+        validate_response(server_format, &response)?;
+        Ok(response.body)
     }
 }
 
-/// Compress and encode the image source, return the encoed Base64-ish string
+/// Compress and encode the image source, return the encoed Base64-ish string. The best
+/// compression is used to keep the request URLs short.
 fn encode_diagram_source(plantuml_code: &str) -> String {
-    let compressed = deflate_bytes(plantuml_code.as_bytes());
+    let compressed = deflate_bytes_conf(plantuml_code.as_bytes(), Compression::Best);
     base64::encode(&compressed)
 }
 
 impl Backend for PlantUMLServer {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            formats: &SUPPORTED_FORMATS,
+            batching: false,
+            // The server cannot read the local files
+            includes: false,
+            concurrency_safe: true,
+        }
+    }
+
     fn render_from_string(&self, plantuml_code: &str, image_format: &str) -> Result<Vec<u8>> {
-        let downloader = RealImageDownloader {};
-        self.render_string(plantuml_code, image_format, &downloader)
+        self.render_string(plantuml_code, image_format, &self.downloader)
     }
 }
 
@@ -116,6 +310,29 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_url_template() {
+        let srv = PlantUMLServer::new(Url::parse("http://froboz:1234/plantuml").unwrap())
+            .with_url_template(Some(String::from("{base}/render/{format}/{encoded}")));
+        assert_eq!(
+            Url::parse("http://froboz:1234/plantuml/render/svg/SrRGrQsnKt0100==").unwrap(),
+            srv.url("svg", "SrRGrQsnKt0100==").unwrap()
+        );
+
+        let srv =
+            PlantUMLServer::new(Url::parse("http://froboz").unwrap()).with_url_template(Some(
+                String::from("{base}/api/plantuml?fmt={format}&src={encoded}"),
+            ));
+        assert_eq!(
+            Url::parse("http://froboz/api/plantuml?fmt=png&src=SrRGrQsnKt0100==").unwrap(),
+            srv.url("png", "SrRGrQsnKt0100==").unwrap()
+        );
+
+        let srv = PlantUMLServer::new(Url::parse("http://froboz").unwrap())
+            .with_url_template(Some(String::from("{format}/{encoded}")));
+        assert!(srv.url("png", "SrRGrQsnKt0100==").is_err());
+    }
+
     #[test]
     fn test_encode_diagram_source() {
         assert_eq!("SrRGrQsnKt0100==", encode_diagram_source("C --|> D"));
@@ -124,7 +341,7 @@ mod tests {
     create_mock! {
         impl ImageDownloader for ImageDownloaderMock (self) {
             expect_download_image("download_image"):
-                fn download_image(&self, request_url: &Url) -> Result<Vec<u8>>;
+                fn download_image(&self, request_url: &Url) -> Result<ServerResponse>;
         }
     }
 
@@ -139,7 +356,7 @@ mod tests {
             .with(deref(
                 Url::parse("http://froboz/svg/SrRGrQsnKt0100==").unwrap(),
             ))
-            .returning(|_| Ok(b"the rendered image".to_vec()));
+            .returning(|_| Ok(response(200, Some("image/svg+xml"), "the rendered image")));
 
         let img_data = srv
             .render_string("C --|> D", "svg", &mock_downloader)
@@ -147,4 +364,69 @@ mod tests {
 
         assert_eq!("the rendered image", String::from_utf8_lossy(&img_data));
     }
+
+    fn response(status: u16, content_type: Option<&str>, body: &str) -> ServerResponse {
+        ServerResponse {
+            status,
+            content_type: content_type.map(String::from),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_validate_response() {
+        let svg = "<svg></svg>";
+        assert!(validate_response("svg", &response(200, Some("image/svg+xml"), svg)).is_ok());
+        assert!(validate_response("svg", &response(200, None, svg)).is_ok());
+        assert!(validate_response(
+            "txt",
+            &response(200, Some("text/plain; charset=utf-8"), "A")
+        )
+        .is_ok());
+        // The image of a syntax error
+        assert!(validate_response("png", &response(400, Some("image/png"), "PNG")).is_ok());
+
+        let html =
+            "<html>\n<head><title>404 Not Found</title></head>\n\n<body>Not Found</body>\n</html>";
+        let error = validate_response("svg", &response(404, Some("text/html"), html)).unwrap_err();
+        assert_eq!(
+            "The PlantUML server responded with status 404 (text/html) instead of a svg image, \
+             check the server URL (plantuml-cmd). The response starts with:\n<html>\n\
+             <head><title>404 Not Found</title></head>\n<body>Not Found</body>\n</html>",
+            error.to_string()
+        );
+        assert_eq!(
+            Some(BackendError::Network { status: Some(404) }),
+            BackendError::of(&error)
+        );
+        assert!(validate_response("svg", &response(200, Some("text/html"), html)).is_err());
+        let error = validate_response("svg", &response(500, None, "Oops")).unwrap_err();
+        assert_eq!(
+            Some(BackendError::Network { status: Some(500) }),
+            BackendError::of(&error)
+        );
+    }
+
+    #[test]
+    fn test_server_format() {
+        assert_eq!("svg", server_format("svg").unwrap());
+        assert_eq!("png", server_format("png").unwrap());
+        assert_eq!("txt", server_format("txt").unwrap());
+        assert_eq!("txt", server_format("utxt").unwrap());
+
+        assert!(server_format("braille").is_err());
+        assert!(server_format("latex").is_err());
+    }
+
+    #[test]
+    fn test_render_string_unsupported_format() {
+        let srv = PlantUMLServer::new(Url::parse("http://froboz").unwrap());
+
+        let mut mock_downloader = ImageDownloaderMock::new();
+        mock_downloader.expect_download_image().called_never();
+
+        assert!(srv
+            .render_string("C --|> D", "braille", &mock_downloader)
+            .is_err());
+    }
 }
@@ -1,53 +1,228 @@
-use crate::backend::Backend;
+use crate::backend::error::{syntax_error_line, BackendError};
+use crate::backend::{self, Backend, Capabilities};
+use crate::config::{Config, DEFAULT_LIMIT_SIZE};
+use crate::log_context::log_field_value;
+use crate::renderer::{hash_string, truncated_size};
 use anyhow::{bail, format_err, Context, Result};
 
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
 use std::fs;
 use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
-use tempfile::tempdir;
+use std::process::{Command, Output, Stdio};
+use tempfile::TempDir;
 
-/// Split a shell command into its parts, e.g. "python D:\\foo" will become ["Python", "D:/Foo"]
+/// Split a shell command into its parts, e.g. "python D:\\foo" will become ["python", "D:\\foo"]
+/// On windows the command is split using the windows (CommandLineToArgvW) rules, elsewhere posix
+/// shell rules apply.
 pub fn split_shell_command(cmd: &str) -> Result<Vec<String>> {
-    let preprocessed: String = {
-        // Windows paths are converted to forward slash paths (shell_words and shlex both assume
-        // posix paths and treat the backslashes as escape characters), which would make C:\foo\bar
-        // become C:foobar
-        if cfg!(target_family = "windows") {
-            cmd.replace('\\', "/")
-        } else {
-            String::from(cmd)
+    if cfg!(target_family = "windows") {
+        Ok(split_windows_command(cmd))
+    } else {
+        shlex::split(cmd).ok_or_else(|| format_err!("Invalid command"))
+    }
+}
+
+/// Split a command line using the windows rules (see CommandLineToArgvW). Backslashes are only
+/// special when they precede a double quote, so paths like C:\Program Files\foo.jar survive
+/// untouched as long as they are quoted.
+///  * 2n backslashes followed by a quote become n backslashes, the quote toggles quoted mode
+///  * 2n+1 backslashes followed by a quote become n backslashes and a literal quote
+///  * Two quotes in quoted mode become a literal quote, quoted mode continues
+///  * Any other backslash is taken literally
+///  * An unclosed quote runs to the end of the command line
+fn split_windows_command(cmd: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut in_part = false;
+    let mut quoted = false;
+    let mut backslashes: usize = 0;
+
+    let mut chars = cmd.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                backslashes += 1;
+                in_part = true;
+            }
+            '"' => {
+                current.extend(std::iter::repeat('\\').take(backslashes / 2));
+                if backslashes % 2 == 1 {
+                    current.push('"');
+                } else if quoted && chars.peek() == Some(&'"') {
+                    chars.next();
+                    current.push('"');
+                } else {
+                    quoted = !quoted;
+                }
+                backslashes = 0;
+                in_part = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                current.extend(std::iter::repeat('\\').take(backslashes));
+                backslashes = 0;
+                if in_part {
+                    parts.push(std::mem::take(&mut current));
+                    in_part = false;
+                }
+            }
+            c => {
+                current.extend(std::iter::repeat('\\').take(backslashes));
+                backslashes = 0;
+                current.push(c);
+                in_part = true;
+            }
         }
+    }
+
+    current.extend(std::iter::repeat('\\').take(backslashes));
+    if in_part {
+        parts.push(current);
+    }
+
+    parts
+}
+
+/// Find an executable on the PATH, on windows the .exe, .bat and .cmd extensions are tried as well
+pub fn find_executable(name: &str) -> Option<PathBuf> {
+    let extensions: &[&str] = if cfg!(target_family = "windows") {
+        &["", ".exe", ".bat", ".cmd"]
+    } else {
+        &[""]
     };
 
-    let cmd_parts =
-        shlex::split(preprocessed.as_str()).ok_or_else(|| format_err!("Invalid command"))?;
-    Ok(cmd_parts)
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path).find_map(|dir| {
+        extensions
+            .iter()
+            .map(|ext| dir.join(format!("{name}{ext}")))
+            .find(|candidate| candidate.is_file())
+    })
 }
 
-fn create_command(plantuml_cmd: &str) -> Result<Command> {
+/// Create the command for the given (quote aware) PlantUML command line. A plantuml.jar path is
+/// run using java, and on windows .bat/.cmd wrappers on the PATH are resolved (Command only
+/// searches for .exe files).
+pub fn create_command(plantuml_cmd: &str) -> Result<Command> {
     let cmd_parts = split_shell_command(plantuml_cmd)?;
+    let (executable, args) = match cmd_parts.split_first() {
+        Some(parts) => parts,
+        None => bail!("Invalid command (empty)"),
+    };
 
-    let mut command = Command::new(&cmd_parts[0]);
-    command.args(&cmd_parts[1..]);
+    let mut command = if executable.to_lowercase().ends_with(".jar") {
+        let mut command = Command::new("java");
+        command.arg("-jar").arg(executable);
+        command
+    } else if cfg!(target_family = "windows") && !Path::new(executable).is_file() {
+        Command::new(find_executable(executable).unwrap_or_else(|| PathBuf::from(executable)))
+    } else {
+        Command::new(executable)
+    };
+    command.args(args);
 
     Ok(command)
 }
 
+/// The JVM option that keeps a sandboxed PlantUML from opening any windows
+const JAVA_HEADLESS: &str = "-Djava.awt.headless=true";
+
+/// Create the command running the PlantUML command line in the sandbox (wrapper) command. The
+/// `{temp_dir}` placeholders in the sandbox command are replaced by the given temp dir, and java is
+/// forced to run headless.
+/// # Arguments
+/// * `sandbox_cmd` - The (quote aware) sandbox command line, e.g. "firejail --quiet --net=none"
+/// * `plantuml_cmd` - The (quote aware) PlantUML command line
+/// * `temp_dir` - The only directory PlantUML is supposed to write to
+pub fn create_sandboxed_command(
+    sandbox_cmd: &str,
+    plantuml_cmd: &str,
+    temp_dir: &Path,
+) -> Result<Command> {
+    let temp_dir = temp_dir.to_string_lossy();
+    let sandbox_parts: Vec<String> = split_shell_command(sandbox_cmd)
+        .with_context(|| "Invalid sandbox-cmd")?
+        .iter()
+        .map(|part| part.replace("{temp_dir}", &temp_dir))
+        .collect();
+    let (sandbox, sandbox_args) = match sandbox_parts.split_first() {
+        Some(parts) => parts,
+        None => bail!("Invalid sandbox-cmd (empty)"),
+    };
+
+    let cmd_parts = split_shell_command(plantuml_cmd)?;
+    let (executable, args) = match cmd_parts.split_first() {
+        Some(parts) => parts,
+        None => bail!("Invalid command (empty)"),
+    };
+
+    let mut command = if cfg!(target_family = "windows") && !Path::new(sandbox.as_str()).is_file() {
+        Command::new(find_executable(sandbox).unwrap_or_else(|| PathBuf::from(sandbox)))
+    } else {
+        Command::new(sandbox)
+    };
+    command.args(sandbox_args);
+    if executable.to_lowercase().ends_with(".jar") {
+        command.arg("java").arg(JAVA_HEADLESS).arg("-jar");
+    }
+    command.arg(executable).args(args);
+
+    add_java_tool_options(&mut command, &[JAVA_HEADLESS.to_string()]);
+
+    Ok(command)
+}
+
+/// Add the JVM options to the JAVA_TOOL_OPTIONS environment variable of the command, wrapper
+/// scripts (e.g. the plantuml script of a package manager) start java themselves, so the
+/// options cannot be passed as java arguments
+fn add_java_tool_options(command: &mut Command, options: &[String]) {
+    let current = command
+        .get_envs()
+        .find(|(name, _)| *name == "JAVA_TOOL_OPTIONS")
+        .and_then(|(_, value)| value.map(|value| value.to_string_lossy().into_owned()))
+        .or_else(|| std::env::var("JAVA_TOOL_OPTIONS").ok())
+        .unwrap_or_default();
+    command.env(
+        "JAVA_TOOL_OPTIONS",
+        format!("{current} {}", options.join(" ")).trim_start(),
+    );
+}
+
+/// The image formats (-t<format>) the PlantUML command line tool can render
+/// (see https://plantuml.com/command-line). PlantUML falls back to png for
+/// unknown formats, so these are validated up front.
+const SUPPORTED_FORMATS: [&str; 15] = [
+    "svg",
+    "png",
+    "txt",
+    "utxt",
+    "eps",
+    "eps:text",
+    "pdf",
+    "vdx",
+    "xmi",
+    "scxml",
+    "html",
+    "latex",
+    "latex:nopreamble",
+    "braille",
+    "preproc",
+];
+
 struct PipedRunner;
 impl PipedRunner {
-    fn run(plantuml_cmd: &str, plantuml_src: &str, format: &str) -> Result<Vec<u8>> {
-        let mut child = create_command(plantuml_cmd)?
-            // There cannot be a space between -t and format! Otherwise PlantUML generates a PNG image
-            .arg(format!("-t{format}"))
-            .arg("-nometadata")
+    fn run(shell: &PlantUMLShell, plantuml_src: &str, format: &str) -> Result<Vec<u8>> {
+        let mut child = shell
+            .command(format)?
             .arg("-pipe")
             .arg("-pipeNoStderr")
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .spawn()
-            .with_context(|| format!("Failed to start PlantUML command '{plantuml_cmd}' "))?;
+            .with_context(|| format!("Failed to start PlantUML command '{}' ", shell.plantuml_cmd))
+            .map_err(BackendError::ProcessSpawn.tag())?;
 
         // Pipe the plantuml source
         child
@@ -55,22 +230,77 @@ impl PipedRunner {
             .take()
             .unwrap() // We can simply unwrap, because we know stdin is piped
             .write_all(plantuml_src.as_bytes())
-            .with_context(|| "Failed to pipe PlantUML code")?;
+            .with_context(|| "Failed to pipe PlantUML code")
+            .map_err(BackendError::Io.tag())?;
 
         // And wait for the result
         let output = child
             .wait_with_output()
-            .with_context(|| "Failed to get generated piped PlantUML image")?;
-        if output.status.success() {
-            Ok(output.stdout)
-        } else {
-            Err(format_err!(
+            .with_context(|| "Failed to get generated piped PlantUML image")
+            .map_err(BackendError::Io.tag())?;
+        if let Err(e) = Self::check_output(&output) {
+            let artifacts: [(&str, &[u8]); 2] =
+                [("output", &output.stdout), ("stderr.txt", &output.stderr)];
+            return Err(shell.keep_failed(e, plantuml_src, &artifacts));
+        }
+
+        Ok(output.stdout)
+    }
+
+    fn check_output(output: &Output) -> Result<()> {
+        let error_image = Self::detect_error_image(&output.stdout, &output.stderr);
+        let syntax_error = BackendError::SyntaxError {
+            line: syntax_error_line(&String::from_utf8_lossy(&output.stderr)),
+        };
+        if !output.status.success() {
+            let error = format_err!(
                 "Failed to render image in piped mode ({})\n  stdout: '{}'\n  stderr: '{}'",
                 output.status,
-                String::from_utf8(output.stdout).unwrap_or_default(),
-                String::from_utf8(output.stderr).unwrap_or_default(),
-            ))
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr),
+            );
+            return match error_image {
+                Some(_) => Err(syntax_error.tag()(error)),
+                None => Err(error),
+            };
+        }
+
+        // PlantUML can exit successfully while rendering an image containing the error instead
+        // of the diagram. Caching such an image would hide the error until the source changes.
+        if let Some(error) = error_image {
+            return Err(syntax_error.tag()(format_err!(
+                "Failed to render image in piped mode ({})",
+                error
+            )));
         }
+
+        Ok(())
+    }
+
+    /// Check whether PlantUML rendered an error image instead of the diagram.
+    /// Returns the error description when it did.
+    /// # Arguments
+    /// * `stdout` - The rendered image
+    /// * `stderr` - The PlantUML error output (normally empty due to -pipeNoStderr)
+    fn detect_error_image(stdout: &[u8], stderr: &[u8]) -> Option<String> {
+        // PlantUML reports errors as 'ERROR\n<line number>\n<message>'
+        let stderr = String::from_utf8_lossy(stderr);
+        if stderr.trim_start().starts_with("ERROR") {
+            return Some(stderr.trim().lines().collect::<Vec<_>>().join(" "));
+        }
+
+        // Text based formats (svg, txt, utxt, ...) contain the error banner in plain text
+        const ERROR_BANNER: &[u8] = b"Syntax Error?";
+        if stdout
+            .windows(ERROR_BANNER.len())
+            .any(|window| window == ERROR_BANNER)
+        {
+            return Some(String::from(
+                "PlantUML rendered an error image, check the diagram syntax",
+            ));
+        }
+
+        None
     }
 }
 
@@ -80,76 +310,477 @@ impl FileRunner {
     fn find_generated_file(generation_dir: &Path, src_file_name: &str) -> Result<PathBuf> {
         // PlantUML creates an output file based on the format, it is not always the same as `format` though (e.g. braille outputs a file
         // with extension `.braille.png`)
-        // Just see which other file is in the directory next to our source file. That's the generated one...
-        let entries = fs::read_dir(generation_dir)?;
+        // Just see which other file in the directory has the same base name as our source file. That's the generated one...
+        let stem = Path::new(src_file_name)
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let prefix = format!("{stem}.");
+        let entries = fs::read_dir(generation_dir).map_err(|e| BackendError::Io.tag()(e.into()))?;
 
         // Now find the generated file
         for path in entries.flatten() {
-            if path.file_name() != src_file_name {
+            let file_name = path.file_name();
+            if file_name != src_file_name && file_name.to_string_lossy().starts_with(&prefix) {
                 return Ok(path.path());
             }
         }
 
-        bail!("Failed to find generated PlantUML image.");
+        Err(BackendError::Io.tag()(format_err!(
+            "Failed to find generated PlantUML image."
+        )))
     }
 
-    fn run(plantuml_cmd: &str, plantuml_src: &str, format: &str) -> Result<Vec<u8>> {
-        // Generate the file in a tmpdir
-        let generation_dir = tempdir().with_context(|| "Failed to create PlantUML tempdir")?;
+    fn run(shell: &PlantUMLShell, plantuml_src: &str, format: &str) -> Result<Vec<u8>> {
+        if let Some(metadata_dir) = &shell.metadata_dir {
+            return Self::run_metadata_cached(shell, metadata_dir, plantuml_src, format);
+        }
+
+        // All diagrams are generated in the same (per build) tmpdir, so every diagram needs its own file name
+        let generation_dir = shell.generation_dir()?;
+        let src_file_name = shell.next_src_file_name();
 
         // Write the PlantUML source file
-        const SRC_FILE_NAME: &str = "src.puml";
-        let src_file = generation_dir.path().join(SRC_FILE_NAME);
+        let src_file = generation_dir.join(&src_file_name);
         fs::write(&src_file, plantuml_src)
-            .with_context(|| "Failed to write PlantUML source file")?;
+            .with_context(|| "Failed to write PlantUML source file")
+            .map_err(BackendError::Io.tag())?;
+
+        let result = Self::render(shell, &generation_dir, &src_file_name, format);
+        let generated_file = Self::find_generated_file(&generation_dir, &src_file_name).ok();
+
+        let result = result.map_err(|e| {
+            // Keep the partial output (PlantUML often writes an image containing the error)
+            let partial_output = generated_file.as_ref().and_then(|f| {
+                let file_name = f.file_name()?.to_string_lossy().to_string();
+                let (_, extension) = file_name.split_once('.')?;
+                Some((format!("output.{extension}"), fs::read(f).ok()?))
+            });
+            let artifacts: Vec<(&str, &[u8])> = partial_output
+                .iter()
+                .map(|(name, data)| (name.as_str(), data.as_slice()))
+                .collect();
+            shell.keep_failed(e, plantuml_src, &artifacts)
+        });
+
+        // Clean up, so the tmpdir does not grow with every diagram
+        let _ = fs::remove_file(&src_file);
+        if let Some(generated_file) = generated_file {
+            let _ = fs::remove_file(generated_file);
+        }
+
+        result
+    }
+
+    /// Render in the metadata cache dir, the source and image files are kept so PlantUML
+    /// (-checkmetadata) only regenerates the image when the source in its metadata changed.
+    fn run_metadata_cached(
+        shell: &PlantUMLShell,
+        metadata_dir: &Path,
+        plantuml_src: &str,
+        format: &str,
+    ) -> Result<Vec<u8>> {
+        fs::create_dir_all(metadata_dir)
+            .with_context(|| format!("Failed to create the metadata cache dir {metadata_dir:?}"))
+            .map_err(BackendError::Io.tag())?;
+        let position = log_field_value("chapter").zip(log_field_value("block"));
+        let src_file_name = metadata_src_file_name(position, plantuml_src, format);
+        let src_file = metadata_dir.join(&src_file_name);
+        if fs::read_to_string(&src_file).ok().as_deref() != Some(plantuml_src) {
+            fs::write(&src_file, plantuml_src)
+                .with_context(|| "Failed to write PlantUML source file")
+                .map_err(BackendError::Io.tag())?;
+        }
+
+        Self::render(shell, metadata_dir, &src_file_name, format)
+            .map_err(|e| shell.keep_failed(e, plantuml_src, &[]))
+    }
+
+    fn render(
+        shell: &PlantUMLShell,
+        generation_dir: &Path,
+        src_file_name: &str,
+        format: &str,
+    ) -> Result<Vec<u8>> {
+        let src_file = generation_dir.join(src_file_name);
 
         // Call PlantUML
-        create_command(plantuml_cmd)?
-            // There cannot be a space between -t and format! Otherwise PlantUML generates a PNG image
-            .arg(format!("-t{format}"))
-            .arg("-nometadata")
+        let mut command = shell.command(format)?;
+        if shell.metadata_dir.is_some() {
+            command.arg("-checkmetadata");
+        }
+        let output = command
             .arg(src_file.to_str().unwrap())
             .output()
-            .with_context(|| "Failed to render image")?;
+            .with_context(|| "Failed to render image")
+            .map_err(BackendError::ProcessSpawn.tag())?;
+        if !output.status.success() {
+            // PlantUML might still have written an (error) image, do not pick that one up
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let error = format_err!(
+                "Failed to render image ({})\n  stdout: '{}'\n  stderr: '{}'",
+                output.status,
+                String::from_utf8_lossy(&output.stdout),
+                stderr,
+            );
+            return match syntax_error_line(&stderr) {
+                Some(line) => Err(BackendError::SyntaxError { line: Some(line) }.tag()(error)),
+                None => Err(error),
+            };
+        }
+
+        let generated_file = Self::find_generated_file(generation_dir, src_file_name)?;
+        fs::read(generated_file)
+            .with_context(|| "Failed to read rendered image")
+            .map_err(BackendError::Io.tag())
+    }
+}
+
+/// The directory (in the cache dir) of the metadata cache (see metadata-cache)
+pub const METADATA_DIR: &str = "metadata";
+
+/// The position part of a metadata cache file name, `<chapter>-<block>` (the chapter with every
+/// character but letters, digits, `.` and `_` replaced, so the name has no other `-`)
+fn metadata_position(chapter: &str, block: &str) -> String {
+    let chapter: String = chapter
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{chapter}-{block}")
+}
+
+/// The source file name of a diagram in the metadata cache dir. A diagram keeps its file (so its
+/// generated image) while it is edited, the files are named after the diagram's position in the
+/// book (the chapter and block of the render context) and the format. Diagrams rendered outside
+/// of a chapter are named after their source.
+fn metadata_src_file_name(
+    position: Option<(String, String)>,
+    plantuml_src: &str,
+    format: &str,
+) -> String {
+    let format = format.replace(':', "_");
+    match position {
+        Some((chapter, block)) => {
+            format!("{}-{}.puml", metadata_position(&chapter, &block), format)
+        }
+        None => format!("{}-{}.puml", hash_string(plantuml_src), format),
+    }
+}
 
-        let generated_file = Self::find_generated_file(generation_dir.path(), SRC_FILE_NAME)?;
-        fs::read(generated_file).with_context(|| "Failed to read rendered image")
+/// Remove the sources and images of the diagrams that are no longer in the book from the
+/// metadata cache dir. The files of the diagrams rendered outside of a chapter (e.g. the
+/// extra-sources and the render subcommand) are named after their source and are kept.
+/// # Arguments
+/// * `cache_dir` - The book's cache dir
+/// * `chapters` - The book's chapters (as in the render context) and their number of diagrams
+pub fn prune_metadata_cache(cache_dir: &Path, chapters: &[(String, usize)]) {
+    let entries = match fs::read_dir(cache_dir.join(METADATA_DIR)) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    let positions: HashSet<String> = chapters
+        .iter()
+        .flat_map(|(chapter, count)| {
+            (1..=*count).map(move |block| metadata_position(chapter, &block.to_string()))
+        })
+        .collect();
+    for entry in entries.flatten() {
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        let mut parts = file_name.splitn(3, '-');
+        let position = match (parts.next(), parts.next(), parts.next()) {
+            (Some(chapter), Some(block), Some(_)) => format!("{chapter}-{block}"),
+            // Named after the source (<hash>-<format>), not after a position
+            _ => continue,
+        };
+        if !positions.contains(&position) {
+            log::debug!("Removing unused metadata cache file {:?}", entry.path());
+            let _ = fs::remove_file(entry.path());
+        }
     }
 }
 
 pub struct PlantUMLShell {
     plantuml_cmd: String,
     piped: bool,
+    charset: String,
+    /// Parent directory for the file mode tmpdir (None uses the system temp dir)
+    temp_dir: Option<PathBuf>,
+    /// The file mode tmpdir, created on first use and removed when the shell is dropped
+    generation_dir: RefCell<Option<TempDir>>,
+    /// Used to create unique source file names in the generation dir
+    src_file_counter: Cell<usize>,
+    /// Where to keep the source and output of failed renders (None when keep-failed is disabled)
+    failed_dir: Option<PathBuf>,
+    /// The wrapper command PlantUML is run in (None when not sandboxed)
+    sandbox_cmd: Option<String>,
+    /// Where the file mode sources and images are kept for PlantUML's metadata based change
+    /// detection (None when metadata-cache is disabled, the image metadata is stripped then)
+    metadata_dir: Option<PathBuf>,
+    /// Keep the metadata (the diagram source) in the images
+    metadata: bool,
+    /// The JVM options passed in JAVA_TOOL_OPTIONS (see Config::java_options)
+    java_options: Vec<String>,
+    /// Render a diagram in file mode when rendering it in piped mode fails
+    piped_fallback: bool,
+    /// The number of diagrams rendered in file mode after piped mode failed
+    piped_fallbacks: Cell<usize>,
+    /// The maximum image width and height in pixels (PLANTUML_LIMIT_SIZE)
+    limit_size: u32,
+    /// Render a clipped png image again once with a doubled limit size
+    retry_truncated: bool,
+    /// The limit size overriding the configured one while retrying a clipped image
+    limit_size_override: Cell<Option<u32>>,
 }
 
 /// Invokes PlantUML as a shell/cmd program.
 impl PlantUMLShell {
-    pub fn new(plantuml_cmd: String, piped: bool) -> Self {
+    pub fn new(plantuml_cmd: String, cfg: &Config, cache_dir: &Path) -> Self {
         log::info!(
             "Selected PlantUML shell {} (piped={})",
             &plantuml_cmd,
-            piped
+            cfg.piped
         );
         Self {
             plantuml_cmd,
-            piped,
+            piped: cfg.piped,
+            charset: cfg.charset.clone(),
+            temp_dir: cfg.temp_dir.clone(),
+            generation_dir: RefCell::new(None),
+            src_file_counter: Cell::new(0),
+            failed_dir: cfg.keep_failed.then(|| cache_dir.join("failed")),
+            sandbox_cmd: cfg.sandbox_cmd.clone(),
+            metadata_dir: (cfg.metadata_cache && !cfg.piped).then(|| cache_dir.join(METADATA_DIR)),
+            metadata: cfg.metadata_cache,
+            java_options: cfg.java_options(),
+            piped_fallback: cfg.piped_fallback,
+            piped_fallbacks: Cell::new(0),
+            limit_size: cfg.limit_size.unwrap_or(DEFAULT_LIMIT_SIZE),
+            retry_truncated: cfg.retry_truncated,
+            limit_size_override: Cell::new(None),
         }
     }
+
+    /// Keep the source and output of a failed render in the failed dir (when
+    /// enabled), returns the error extended with the location of the kept files.
+    /// # Arguments
+    /// * `error` - The render error
+    /// * `plantuml_src` - The source of the diagram that failed to render
+    /// * `artifacts` - The file names and contents of the (partial) render output
+    fn keep_failed(
+        &self,
+        error: anyhow::Error,
+        plantuml_src: &str,
+        artifacts: &[(&str, &[u8])],
+    ) -> anyhow::Error {
+        let failed_dir = match &self.failed_dir {
+            Some(dir) => dir.join(hash_string(plantuml_src)),
+            None => return error,
+        };
+
+        let keep = || -> Result<()> {
+            fs::create_dir_all(&failed_dir)?;
+            fs::write(failed_dir.join("src.puml"), plantuml_src)?;
+            for (name, data) in artifacts {
+                if !data.is_empty() {
+                    fs::write(failed_dir.join(name), data)?;
+                }
+            }
+            Ok(())
+        };
+
+        match keep() {
+            Ok(()) => {
+                let kept = format_err!(
+                    "{:#}\n  The failed render is kept in {:?}",
+                    error,
+                    failed_dir
+                );
+                match BackendError::of(&error) {
+                    Some(category) => category.tag()(kept),
+                    None => kept,
+                }
+            }
+            Err(e) => {
+                log::warn!(
+                    "Failed to keep the failed render in {:?} ({})",
+                    failed_dir,
+                    e
+                );
+                error
+            }
+        }
+    }
+
+    /// Get the directory the file mode images are generated in, it is created
+    /// when it does not exist yet.
+    fn generation_dir(&self) -> Result<PathBuf> {
+        let mut generation_dir = self.generation_dir.borrow_mut();
+        if generation_dir.is_none() {
+            let mut builder = tempfile::Builder::new();
+            builder.prefix("mdbook-plantuml");
+            let dir = if let Some(temp_dir) = &self.temp_dir {
+                fs::create_dir_all(temp_dir)
+                    .with_context(|| format!("Failed to create PlantUML temp-dir {temp_dir:?}"))?;
+                builder.tempdir_in(temp_dir)
+            } else {
+                builder.tempdir()
+            }
+            .with_context(|| "Failed to create PlantUML tempdir")?;
+
+            log::debug!("Created PlantUML tempdir {:?}", dir.path());
+            *generation_dir = Some(dir);
+        }
+
+        // Can safely unwrap, we just made sure it is there
+        Ok(generation_dir.as_ref().unwrap().path().to_path_buf())
+    }
+
+    fn next_src_file_name(&self) -> String {
+        let index = self.src_file_counter.get();
+        self.src_file_counter.set(index + 1);
+        format!("src{index}.puml")
+    }
+
+    /// Create the PlantUML command with the arguments shared by the piped and file runners
+    fn command(&self, format: &str) -> Result<Command> {
+        let mut command = match &self.sandbox_cmd {
+            Some(sandbox_cmd) => {
+                create_sandboxed_command(sandbox_cmd, &self.plantuml_cmd, &self.generation_dir()?)?
+            }
+            None => create_command(&self.plantuml_cmd)?,
+        };
+        command
+            // There cannot be a space between -t and format! Otherwise PlantUML generates a PNG image
+            .arg(format!("-t{format}"));
+        if !self.metadata {
+            command.arg("-nometadata");
+        }
+        command.arg("-charset").arg(&self.charset);
+        if !self.java_options.is_empty() {
+            add_java_tool_options(&mut command, &self.java_options);
+        }
+        if let Some(limit_size) = self.limit_size_override.get() {
+            // Added last, so it takes precedence over the configured limit size
+            add_java_tool_options(
+                &mut command,
+                &[format!("-DPLANTUML_LIMIT_SIZE={limit_size}")],
+            );
+        }
+
+        Ok(command)
+    }
 }
 
 impl Backend for PlantUMLShell {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            formats: &SUPPORTED_FORMATS,
+            batching: false,
+            includes: true,
+            concurrency_safe: true,
+        }
+    }
+
     fn render_from_string(&self, plantuml_code: &str, image_format: &str) -> Result<Vec<u8>> {
+        backend::check_format("PlantUML shell", image_format, &SUPPORTED_FORMATS)?;
+
+        let data = self.render(plantuml_code, image_format)?;
+        if !self.retry_truncated || truncated_size(&data, self.limit_size).is_none() {
+            return Ok(data);
+        }
+
+        let limit_size = self.limit_size.saturating_mul(2);
+        log::info!(
+            "The image is probably clipped at the limit size, rendering it again with a limit \
+             size of {}",
+            limit_size
+        );
+        self.limit_size_override.set(Some(limit_size));
+        let retried = self.render(plantuml_code, image_format);
+        self.limit_size_override.set(None);
+
+        // Keep the clipped image when the retry fails, the renderer warns about the clipping
+        Ok(retried.unwrap_or_else(|e| {
+            log::warn!(
+                "Failed to render the clipped image again with a limit size of {} ({:#}), keeping \
+                 the clipped image",
+                limit_size,
+                e
+            );
+            data
+        }))
+    }
+}
+
+impl PlantUMLShell {
+    /// Render the diagram in piped mode (falling back to file mode) or in file mode
+    fn render(&self, plantuml_code: &str, image_format: &str) -> Result<Vec<u8>> {
         if self.piped {
-            PipedRunner::run(&self.plantuml_cmd, plantuml_code, image_format)
+            PipedRunner::run(self, plantuml_code, image_format).or_else(|e| {
+                if !file_mode_might_succeed(&e) || !self.use_piped_fallback() {
+                    return Err(e);
+                }
+
+                log::warn!(
+                    "Failed to render the diagram in piped mode, retrying in file mode ({:#})",
+                    e
+                );
+                FileRunner::run(self, plantuml_code, image_format)
+            })
         } else {
-            FileRunner::run(&self.plantuml_cmd, plantuml_code, image_format)
+            FileRunner::run(self, plantuml_code, image_format)
         }
     }
 }
 
+/// Returns true if a diagram that failed to render in piped mode might render in file mode. An
+/// error in the diagram fails in file mode too, and a diagram that timed out would only time out
+/// again (doubling the time spent on it).
+fn file_mode_might_succeed(error: &anyhow::Error) -> bool {
+    !matches!(
+        BackendError::of(error),
+        Some(BackendError::SyntaxError { .. } | BackendError::Timeout)
+    )
+}
+
+/// The maximum number of diagrams rendered in file mode after piped mode failed, when piped mode
+/// fails this often it is probably broken (or the diagrams are), and retrying only slows the
+/// build down
+const MAX_PIPED_FALLBACKS: usize = 10;
+
+impl PlantUMLShell {
+    /// Returns true if a diagram that failed to render in piped mode should be rendered in file
+    /// mode (see MAX_PIPED_FALLBACKS), counts the fallback
+    fn use_piped_fallback(&self) -> bool {
+        if !self.piped_fallback {
+            return false;
+        }
+
+        let fallbacks = self.piped_fallbacks.get();
+        if fallbacks == MAX_PIPED_FALLBACKS {
+            log::warn!(
+                "Rendering in piped mode failed {} times, no longer retrying in file mode \
+                 (consider using piped = false)",
+                fallbacks
+            );
+        }
+        self.piped_fallbacks.set(fallbacks + 1);
+        fallbacks < MAX_PIPED_FALLBACKS
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::FontConfig;
+    use tempfile::tempdir;
 
     #[test]
     fn test_find_generated_file() {
@@ -157,6 +788,46 @@ mod tests {
 
         let found_file = FileRunner::find_generated_file(generation_dir.path(), "somefile.txt");
         assert!(found_file.is_err());
+
+        // Only the file with the same base name as the source is picked up
+        fs::write(generation_dir.path().join("src1.puml"), "").unwrap();
+        fs::write(generation_dir.path().join("src10.svg"), "").unwrap();
+        fs::write(generation_dir.path().join("src2.svg"), "").unwrap();
+        let found_file = FileRunner::find_generated_file(generation_dir.path(), "src1.puml");
+        assert!(found_file.is_err());
+
+        fs::write(generation_dir.path().join("src1.braille.png"), "").unwrap();
+        let found_file = FileRunner::find_generated_file(generation_dir.path(), "src1.puml");
+        assert_eq!(
+            generation_dir.path().join("src1.braille.png"),
+            found_file.unwrap()
+        );
+    }
+
+    #[test]
+    fn test_generation_dir() {
+        let temp_dir = tempdir().unwrap();
+        let cfg = Config {
+            temp_dir: Some(temp_dir.path().join("nested")),
+            ..Config::default()
+        };
+        let shell = PlantUMLShell::new(String::from("plantuml"), &cfg, Path::new("cache"));
+
+        // The generation dir is created in the configured temp-dir and reused
+        let generation_dir = shell.generation_dir().unwrap();
+        assert!(generation_dir.is_dir());
+        assert_eq!(
+            Some(temp_dir.path().join("nested").as_path()),
+            generation_dir.parent()
+        );
+        assert_eq!(generation_dir, shell.generation_dir().unwrap());
+
+        assert_eq!("src0.puml", shell.next_src_file_name());
+        assert_eq!("src1.puml", shell.next_src_file_name());
+
+        // And removed with the shell
+        drop(shell);
+        assert!(!generation_dir.exists());
     }
 
     #[test]
@@ -177,17 +848,17 @@ mod tests {
         assert!(split_shell_command("python \"/foo").is_err());
 
         if cfg!(target_family = "windows") {
-            // On windows backslashes are converted to forward slashes paths
+            // On windows backslashes are regular path separators
             assert_eq!(
-                vec![String::from("python"), String::from("D:/foo/bar")],
+                vec![String::from("python"), String::from("D:\\foo\\bar")],
                 split_shell_command("python D:\\foo\\bar").unwrap()
             );
 
-            // String with escaped space (escaping with backslashes is not a thing on windows)
+            // Escaping spaces with backslashes is not a thing on windows
             assert_eq!(
                 vec![
                     String::from("python"),
-                    String::from("foo/"),
+                    String::from("foo\\"),
                     String::from("bar")
                 ],
                 split_shell_command("python foo\\ bar").unwrap()
@@ -208,4 +879,482 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_create_command() {
+        assert!(create_command("").is_err());
+        assert!(create_command("plantuml \"unclosed").is_err());
+
+        let command = create_command("plantuml -v").unwrap();
+        assert_eq!("plantuml", command.get_program());
+        assert_eq!(vec!["-v"], command.get_args().collect::<Vec<_>>());
+
+        // Jar files are run using java (spaces in quoted paths are preserved)
+        let command = create_command("\"/opt/Plant UML/plantuml.JAR\" -v").unwrap();
+        assert_eq!("java", command.get_program());
+        assert_eq!(
+            vec!["-jar", "/opt/Plant UML/plantuml.JAR", "-v"],
+            command.get_args().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_create_sandboxed_command() {
+        let temp_dir = Path::new("/tmp/mdbook-plantuml");
+        assert!(create_sandboxed_command("", "plantuml", temp_dir).is_err());
+        assert!(create_sandboxed_command("firejail", "", temp_dir).is_err());
+
+        let command = create_sandboxed_command(
+            "bwrap --ro-bind / / --bind {temp_dir} {temp_dir} --",
+            "plantuml -v",
+            temp_dir,
+        )
+        .unwrap();
+        assert_eq!("bwrap", command.get_program());
+        assert_eq!(
+            vec![
+                "--ro-bind",
+                "/",
+                "/",
+                "--bind",
+                "/tmp/mdbook-plantuml",
+                "/tmp/mdbook-plantuml",
+                "--",
+                "plantuml",
+                "-v"
+            ],
+            command.get_args().collect::<Vec<_>>()
+        );
+        let java_options = command
+            .get_envs()
+            .find(|(key, _)| *key == "JAVA_TOOL_OPTIONS")
+            .and_then(|(_, value)| value)
+            .unwrap();
+        assert!(java_options
+            .to_string_lossy()
+            .ends_with("-Djava.awt.headless=true"));
+
+        // Jar files are run using a headless java
+        let command = create_sandboxed_command("firejail", "plantuml.jar -v", temp_dir).unwrap();
+        assert_eq!("firejail", command.get_program());
+        assert_eq!(
+            vec![
+                "java",
+                "-Djava.awt.headless=true",
+                "-jar",
+                "plantuml.jar",
+                "-v"
+            ],
+            command.get_args().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_split_windows_command() {
+        assert!(split_windows_command("").is_empty());
+        assert!(split_windows_command("   ").is_empty());
+
+        // Quoted paths with spaces
+        assert_eq!(
+            vec![
+                String::from("java"),
+                String::from("-jar"),
+                String::from("C:\\Program Files\\PlantUML\\plantuml.jar")
+            ],
+            split_windows_command("java -jar \"C:\\Program Files\\PlantUML\\plantuml.jar\"")
+        );
+
+        // Quoted executable path
+        assert_eq!(
+            vec![
+                String::from("C:\\Program Files\\Java\\bin\\java.exe"),
+                String::from("-jar"),
+                String::from("plantuml.jar")
+            ],
+            split_windows_command("\"C:\\Program Files\\Java\\bin\\java.exe\" -jar plantuml.jar")
+        );
+
+        // Quotes in the middle of an argument
+        assert_eq!(
+            vec![String::from("C:\\Program Files\\plantuml.jar")],
+            split_windows_command("C:\"\\Program Files\"\\plantuml.jar")
+        );
+
+        // UNC paths keep their leading backslashes
+        assert_eq!(
+            vec![
+                String::from("java"),
+                String::from("-jar"),
+                String::from("\\\\server\\share\\plantuml.jar")
+            ],
+            split_windows_command("java -jar \\\\server\\share\\plantuml.jar")
+        );
+
+        // Escaped quotes
+        assert_eq!(
+            vec![String::from("foo"), String::from("a\"b")],
+            split_windows_command("foo a\\\"b")
+        );
+        assert_eq!(
+            vec![String::from("foo"), String::from("a\\\\b c")],
+            split_windows_command("foo \"a\\\\b c\"")
+        );
+        assert_eq!(
+            vec![String::from("foo"), String::from("a\\"), String::from("b")],
+            split_windows_command("foo \"a\\\\\" b")
+        );
+
+        // Trailing backslashes are literal
+        assert_eq!(
+            vec![String::from("C:\\foo\\")],
+            split_windows_command("C:\\foo\\")
+        );
+
+        // Empty quoted argument
+        assert_eq!(
+            vec![String::from("foo"), String::new()],
+            split_windows_command("foo \"\"")
+        );
+
+        // Two quotes in quoted mode are a literal quote
+        assert_eq!(
+            vec![String::from("foo"), String::from("a \"b\" c")],
+            split_windows_command("foo \"a \"\"b\"\" c\"")
+        );
+
+        // An unclosed quoted string runs to the end of the line
+        assert_eq!(
+            vec![String::from("python"), String::from("/foo bar")],
+            split_windows_command("python \"/foo bar")
+        );
+    }
+
+    #[test]
+    fn test_command_arguments() {
+        let cfg = Config {
+            charset: String::from("ISO-8859-1"),
+            ..Config::default()
+        };
+        let shell = PlantUMLShell::new(String::from("plantuml -v"), &cfg, Path::new("cache"));
+        let command = shell.command("svg").unwrap();
+
+        assert_eq!("plantuml", command.get_program());
+        assert_eq!(
+            vec!["-v", "-tsvg", "-nometadata", "-charset", "ISO-8859-1"],
+            command.get_args().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_font_config_java_tool_options() {
+        let cfg = Config {
+            font_config: FontConfig {
+                encoding: Some(String::from("UTF-8")),
+                font_dirs: vec![],
+            },
+            ..Config::default()
+        };
+        let shell = PlantUMLShell::new(String::from("plantuml"), &cfg, Path::new("cache"));
+        let command = shell.command("svg").unwrap();
+        let java_tool_options = command
+            .get_envs()
+            .find(|(key, _)| *key == "JAVA_TOOL_OPTIONS")
+            .and_then(|(_, value)| value)
+            .unwrap();
+        assert!(java_tool_options
+            .to_string_lossy()
+            .ends_with("-Dfile.encoding=UTF-8"));
+    }
+
+    #[test]
+    fn test_use_piped_fallback() {
+        let shell = PlantUMLShell::new(
+            String::from("plantuml"),
+            &Config::default(),
+            Path::new("cache"),
+        );
+        for _ in 0..MAX_PIPED_FALLBACKS {
+            assert!(shell.use_piped_fallback());
+        }
+        assert!(!shell.use_piped_fallback());
+        assert!(!shell.use_piped_fallback());
+
+        let cfg = Config {
+            piped_fallback: false,
+            ..Config::default()
+        };
+        let shell = PlantUMLShell::new(String::from("plantuml"), &cfg, Path::new("cache"));
+        assert!(!shell.use_piped_fallback());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_piped_fallback() {
+        // A fake PlantUML failing in piped mode, and copying the source in file mode
+        let tools_dir = tempdir().unwrap();
+        let script = tools_dir.path().join("plantuml.sh");
+        fs::write(
+            &script,
+            "for arg; do case $arg in -pipe) exit 1;; *.puml) cp \"$arg\" \"${arg%.puml}.svg\";; esac; done\n",
+        )
+        .unwrap();
+        let shell = PlantUMLShell::new(
+            format!("sh {}", script.display()),
+            &Config::default(),
+            Path::new("cache"),
+        );
+        assert_eq!(
+            b"<svg>A</svg>".to_vec(),
+            shell.render_from_string("<svg>A</svg>", "svg").unwrap()
+        );
+
+        let cfg = Config {
+            piped_fallback: false,
+            ..Config::default()
+        };
+        let shell =
+            PlantUMLShell::new(format!("sh {}", script.display()), &cfg, Path::new("cache"));
+        assert!(shell.render_from_string("<svg>A</svg>", "svg").is_err());
+    }
+
+    #[test]
+    fn test_file_mode_might_succeed() {
+        assert!(file_mode_might_succeed(&format_err!("Oops")));
+        assert!(file_mode_might_succeed(&BackendError::Io.tag()(
+            format_err!("Oops")
+        )));
+        assert!(!file_mode_might_succeed(&BackendError::SyntaxError {
+            line: Some(2)
+        }
+        .tag()(format_err!(
+            "Oops"
+        ))));
+        assert!(!file_mode_might_succeed(&BackendError::Timeout.tag()(
+            format_err!("Oops")
+        )));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_piped_syntax_error() {
+        // A fake PlantUML rendering an error image in piped mode, file mode would succeed
+        let tools_dir = tempdir().unwrap();
+        let script = tools_dir.path().join("plantuml.sh");
+        fs::write(
+            &script,
+            "for arg; do case $arg in -pipe) cat > /dev/null; echo '<svg>Syntax Error?</svg>'; exit 0;; *.puml) cp \"$arg\" \"${arg%.puml}.svg\";; esac; done\n",
+        )
+        .unwrap();
+        let shell = PlantUMLShell::new(
+            format!("sh {}", script.display()),
+            &Config::default(),
+            Path::new("cache"),
+        );
+
+        // Not rendered again in file mode
+        let error = shell.render_from_string("<svg>A</svg>", "svg").unwrap_err();
+        assert_eq!(
+            Some(BackendError::SyntaxError { line: None }),
+            BackendError::of(&error)
+        );
+        assert_eq!(0, shell.piped_fallbacks.get());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_retry_truncated() {
+        // A fake PlantUML rendering a 4096 pixels wide png, or a 5000 pixels wide one when the
+        // limit size is raised
+        let tools_dir = tempdir().unwrap();
+        let script = tools_dir.path().join("plantuml.sh");
+        fs::write(
+            &script,
+            "cat > /dev/null\n\
+             printf '\\211PNG\\r\\n\\032\\n\\0\\0\\0\\rIHDR'\n\
+             case \"$JAVA_TOOL_OPTIONS\" in\n\
+             *PLANTUML_LIMIT_SIZE=8192*) printf '\\0\\0\\023\\210\\0\\0\\0\\1';;\n\
+             *) printf '\\0\\0\\020\\0\\0\\0\\0\\1';;\n\
+             esac\n",
+        )
+        .unwrap();
+        let width = |data: Vec<u8>| u32::from_be_bytes([data[16], data[17], data[18], data[19]]);
+
+        let shell = PlantUMLShell::new(
+            format!("sh {}", script.display()),
+            &Config::default(),
+            Path::new("cache"),
+        );
+        let data = shell.render_from_string("A --|> B", "png").unwrap();
+        assert_eq!(4096, width(data));
+
+        let cfg = Config {
+            retry_truncated: true,
+            ..Config::default()
+        };
+        let shell =
+            PlantUMLShell::new(format!("sh {}", script.display()), &cfg, Path::new("cache"));
+        let data = shell.render_from_string("A --|> B", "png").unwrap();
+        assert_eq!(5000, width(data));
+        assert_eq!(None, shell.limit_size_override.get());
+
+        // The clipped image is kept when rendering it again fails
+        let failing_script = tools_dir.path().join("failing-plantuml.sh");
+        fs::write(
+            &failing_script,
+            fs::read_to_string(&script)
+                .unwrap()
+                .replace("printf '\\0\\0\\023\\210\\0\\0\\0\\1'", "exit 1"),
+        )
+        .unwrap();
+        let shell = PlantUMLShell::new(
+            format!("sh {}", failing_script.display()),
+            &cfg,
+            Path::new("cache"),
+        );
+        let data = shell.render_from_string("A --|> B", "png").unwrap();
+        assert_eq!(4096, width(data));
+        assert_eq!(None, shell.limit_size_override.get());
+    }
+
+    #[test]
+    fn test_metadata_cache_arguments() {
+        let cfg = Config {
+            charset: String::from("ISO-8859-1"),
+            metadata_cache: true,
+            ..Config::default()
+        };
+        let shell = PlantUMLShell::new(String::from("plantuml"), &cfg, Path::new("cache"));
+        assert_eq!(
+            vec!["-tsvg", "-charset", "ISO-8859-1"],
+            shell.command("svg").unwrap().get_args().collect::<Vec<_>>()
+        );
+        // The metadata is only checked in file mode
+        assert_eq!(None, shell.metadata_dir);
+
+        let cfg = Config {
+            piped: false,
+            ..cfg
+        };
+        let shell = PlantUMLShell::new(String::from("plantuml"), &cfg, Path::new("cache"));
+        assert_eq!(
+            Some(Path::new("cache").join("metadata")),
+            shell.metadata_dir
+        );
+
+        assert_eq!(
+            format!("{}-latex_nopreamble.puml", hash_string("A --|> B")),
+            metadata_src_file_name(None, "A --|> B", "latex:nopreamble")
+        );
+        let position = Some((String::from("ch1/my-intro.md"), String::from("2")));
+        assert_eq!(
+            "ch1_my_intro.md-2-svg.puml",
+            metadata_src_file_name(position, "A --|> B", "svg")
+        );
+    }
+
+    #[test]
+    fn test_prune_metadata_cache() {
+        let cache_dir = tempdir().unwrap();
+        let metadata_dir = cache_dir.path().join(METADATA_DIR);
+        fs::create_dir_all(&metadata_dir).unwrap();
+        let files = [
+            "ch1_intro.md-1-svg.puml",
+            "ch1_intro.md-1-svg.svg",
+            "ch1_intro.md-2-png.puml",
+            "2fd4e1c67a2d28fced849ee1bb76e7391b93eb12-svg.puml",
+            "2fd4e1c67a2d28fced849ee1bb76e7391b93eb12-svg.svg",
+            "ch1_intro.md-3-svg.puml",
+            "removed.md-1-svg.puml",
+        ];
+        for file in files {
+            fs::write(metadata_dir.join(file), "").unwrap();
+        }
+
+        prune_metadata_cache(cache_dir.path(), &[(String::from("ch1/intro.md"), 2)]);
+        let mut kept: Vec<String> = fs::read_dir(&metadata_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        kept.sort();
+        let mut expected = files[..5].to_vec();
+        expected.sort_unstable();
+        assert_eq!(expected, kept);
+    }
+
+    #[test]
+    fn test_detect_error_image() {
+        assert_eq!(None, PipedRunner::detect_error_image(b"<svg></svg>", b""));
+        assert_eq!(
+            None,
+            PipedRunner::detect_error_image(b"<svg>Syntax</svg>", b"Some warning")
+        );
+
+        assert_eq!(
+            Some(String::from("ERROR 2 Syntax Error?")),
+            PipedRunner::detect_error_image(b"", b"ERROR\n2\nSyntax Error?\n")
+        );
+
+        assert!(
+            PipedRunner::detect_error_image(b"<svg><text>Syntax Error?</text></svg>", b"")
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_keep_failed() {
+        let cache_dir = tempdir().unwrap();
+        let error = || format_err!("Oh no");
+
+        // Disabled by default
+        let shell = PlantUMLShell::new(
+            String::from("plantuml"),
+            &Config::default(),
+            cache_dir.path(),
+        );
+        assert_eq!(
+            "Oh no",
+            format!("{}", shell.keep_failed(error(), "@startuml", &[]))
+        );
+        assert!(!cache_dir.path().join("failed").exists());
+
+        let cfg = Config {
+            keep_failed: true,
+            ..Config::default()
+        };
+        let shell = PlantUMLShell::new(String::from("plantuml"), &cfg, cache_dir.path());
+        let artifacts: [(&str, &[u8]); 2] = [("output.svg", b"<svg>"), ("stderr.txt", b"")];
+        let e = shell.keep_failed(error(), "@startuml", &artifacts);
+
+        let failed_dir = cache_dir
+            .path()
+            .join("failed")
+            .join(hash_string("@startuml"));
+        assert_eq!(
+            format!("Oh no\n  The failed render is kept in {failed_dir:?}"),
+            format!("{e}")
+        );
+        assert_eq!(
+            "@startuml",
+            fs::read_to_string(failed_dir.join("src.puml")).unwrap()
+        );
+        assert_eq!(
+            "<svg>",
+            fs::read_to_string(failed_dir.join("output.svg")).unwrap()
+        );
+        // Empty artifacts are skipped
+        assert!(!failed_dir.join("stderr.txt").exists());
+    }
+
+    #[test]
+    fn test_unsupported_format() {
+        let shell = PlantUMLShell::new(
+            String::from("plantuml"),
+            &Config::default(),
+            Path::new("cache"),
+        );
+        let result = shell.render_from_string("@startuml\n@enduml", "svgz");
+        assert!(
+            format!("{}", result.unwrap_err()).contains("does not support the 'svgz' image format")
+        );
+    }
 }
@@ -0,0 +1,229 @@
+use crate::backend::{self, Backend};
+use crate::config::Config;
+use crate::cwd_guard::CwdGuard;
+use crate::failure::{tag, FailureKind};
+use crate::includes::expand_includes;
+use crate::pipeline::plantuml_diagrams;
+use crate::CACHE_DIR;
+use crate::{config_from_book_config, resolve_config_paths};
+use anyhow::{format_err, Context, Result};
+use mdbook::book::BookItem;
+use mdbook::MDBook;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// The render times of the book's diagrams for one backend configuration
+pub struct BenchResult {
+    /// The backend configuration, e.g. "plantuml (piped)"
+    pub name: String,
+    /// The render time of every rendered diagram (for all iterations)
+    pub times: Vec<Duration>,
+    /// The number of renders that failed
+    pub failures: usize,
+}
+
+impl BenchResult {
+    /// The render time below which the given percentage of the renders finished
+    pub fn percentile(&self, percentage: usize) -> Duration {
+        let mut times = self.times.clone();
+        times.sort();
+        match times.len() {
+            0 => Duration::ZERO,
+            len => times[((len * percentage + 99) / 100).clamp(1, len) - 1],
+        }
+    }
+
+    /// The number of diagrams rendered per second
+    pub fn throughput(&self) -> f64 {
+        let total: Duration = self.times.iter().sum();
+        if total.is_zero() {
+            0.0
+        } else {
+            self.times.len() as f64 / total.as_secs_f64()
+        }
+    }
+
+    /// A one line summary of the results
+    pub fn summary(&self) -> String {
+        format!(
+            "{}: {} render(s), {:.2} diagrams/s, p50 {:.0}ms, p95 {:.0}ms, {} failure(s)",
+            self.name,
+            self.times.len(),
+            self.throughput(),
+            self.percentile(50).as_secs_f64() * 1000.0,
+            self.percentile(95).as_secs_f64() * 1000.0,
+            self.failures
+        )
+    }
+}
+
+/// A diagram to benchmark, with the mdBook includes expanded
+struct BenchDiagram {
+    code: String,
+    format: String,
+    /// The chapter's directory, so relative PlantUML includes work
+    chapter_dir: PathBuf,
+}
+
+/// Render all diagrams of the book repeatedly (bypassing the cache) with the
+/// configured backend. The piped and file modes are compared for the PlantUML
+/// shell backend.
+/// # Arguments
+/// * `book_dir` - The book's root directory (containing book.toml)
+/// * `iterations` - The number of times every diagram is rendered
+/// * `revalidate` - Re-run the PlantUML command detection
+pub fn bench(book_dir: &Path, iterations: usize, revalidate: bool) -> Result<Vec<BenchResult>> {
+    let md = MDBook::load(book_dir)
+        .map_err(|e| format_err!("{:#}", e))
+        .with_context(|| format!("Failed to load the book from {book_dir:?}"))?;
+    let book_root = dunce::canonicalize(book_dir)?;
+
+    let mut cfg = config_from_book_config(&md.config)?;
+    cfg.revalidate = revalidate;
+    resolve_config_paths(&mut cfg, &book_root);
+
+    let src_dir = book_root.join(&md.config.book.src);
+    let include_root = cfg.restrict_includes_to_book.then_some(book_root.as_path());
+    let mut diagrams = vec![];
+    for item in md.book.iter() {
+        if let BookItem::Chapter(chapter) = item {
+            if let Some(chapter_path) = &chapter.path {
+                let chapter_file = src_dir.join(chapter_path);
+                let chapter_dir = chapter_file.parent().unwrap_or(&src_dir);
+                for diagram in plantuml_diagrams(&chapter.content, cfg.strict_info_string) {
+                    diagrams.push(BenchDiagram {
+                        code: expand_includes(&diagram.code, chapter_dir, include_root)?
+                            .into_owned(),
+                        format: diagram.format,
+                        chapter_dir: chapter_dir.to_path_buf(),
+                    });
+                }
+            }
+        }
+    }
+    log::info!(
+        "Benchmarking {} PlantUML diagram(s), {} iteration(s)",
+        diagrams.len(),
+        iterations
+    );
+
+    let _cwd_guard = CwdGuard::new()?;
+    let mut results = vec![];
+    for (name, cfg) in bench_configs(&cfg) {
+        let backend = backend::factory::create(&cfg, &book_root, &book_root.join(CACHE_DIR))
+            .map_err(tag(FailureKind::BackendNotFound))?;
+        results.push(bench_backend(name, backend.as_ref(), &diagrams, iterations));
+    }
+
+    Ok(results)
+}
+
+/// The backend configurations to compare, the piped and file modes for the
+/// PlantUML shell, the configured backend otherwise.
+fn bench_configs(cfg: &Config) -> Vec<(String, Config)> {
+    match cfg.plantuml_cmd.as_deref() {
+        Some(cmd) if cmd.contains("://") => vec![(cmd.to_string(), cfg.clone())],
+        cmd => {
+            let cmd = cmd.unwrap_or("PlantUML");
+            [true, false]
+                .iter()
+                .map(|&piped| {
+                    let mode = if piped { "piped" } else { "file" };
+                    let cfg = Config {
+                        piped,
+                        ..cfg.clone()
+                    };
+                    (format!("{cmd} ({mode})"), cfg)
+                })
+                .collect()
+        }
+    }
+}
+
+fn bench_backend(
+    name: String,
+    backend: &dyn Backend,
+    diagrams: &[BenchDiagram],
+    iterations: usize,
+) -> BenchResult {
+    let mut result = BenchResult {
+        name,
+        times: vec![],
+        failures: 0,
+    };
+
+    for _ in 0..iterations {
+        for diagram in diagrams {
+            if let Err(e) = std::env::set_current_dir(&diagram.chapter_dir) {
+                log::warn!(
+                    "Failed to change working dir to {:?} ({})",
+                    diagram.chapter_dir,
+                    e
+                );
+            }
+
+            let started = Instant::now();
+            match backend.render_from_string(&diagram.code, &diagram.format) {
+                Ok(_) => result.times.push(started.elapsed()),
+                Err(e) => {
+                    log::debug!("Failed to render diagram ({:#})", e);
+                    result.failures += 1;
+                }
+            }
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn millis(times: &[u64]) -> BenchResult {
+        BenchResult {
+            name: String::from("plantuml (piped)"),
+            times: times.iter().map(|ms| Duration::from_millis(*ms)).collect(),
+            failures: 1,
+        }
+    }
+
+    #[test]
+    fn test_percentile() {
+        let result = millis(&[50, 10, 40, 30, 20]);
+        assert_eq!(Duration::from_millis(30), result.percentile(50));
+        assert_eq!(Duration::from_millis(50), result.percentile(95));
+        assert_eq!(Duration::from_millis(10), result.percentile(0));
+        assert_eq!(Duration::ZERO, millis(&[]).percentile(50));
+    }
+
+    #[test]
+    fn test_summary() {
+        assert_eq!(
+            "plantuml (piped): 4 render(s), 4.00 diagrams/s, p50 200ms, p95 400ms, 1 failure(s)",
+            millis(&[100, 200, 300, 400]).summary()
+        );
+        assert_eq!(0.0, millis(&[]).throughput());
+    }
+
+    #[test]
+    fn test_bench_configs() {
+        let names = |cfg: &Config| -> Vec<String> {
+            bench_configs(cfg)
+                .into_iter()
+                .map(|(name, cfg)| format!("{} {}", name, cfg.piped))
+                .collect()
+        };
+
+        assert_eq!(
+            vec!["PlantUML (piped) true", "PlantUML (file) false"],
+            names(&Config::default())
+        );
+        let cfg = Config {
+            plantuml_cmd: Some(String::from("https://www.plantuml.com/plantuml")),
+            ..Config::default()
+        };
+        assert_eq!(vec!["https://www.plantuml.com/plantuml true"], names(&cfg));
+    }
+}
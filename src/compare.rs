@@ -0,0 +1,354 @@
+use crate::backend;
+use crate::cwd_guard::CwdGuard;
+use crate::failure::{tag, FailureKind};
+use crate::markings::DiagramMarkings;
+use crate::pipeline::{diagram_source, plantuml_diagrams, DiagramChapter};
+use crate::renderer::text_fallback_format;
+use crate::stable_svg::stable_svg;
+use crate::CACHE_DIR;
+use crate::{config_from_book_config, resolve_config_paths};
+use anyhow::{format_err, Context, Result};
+use mdbook::book::BookItem;
+use mdbook::MDBook;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt::Write;
+use std::path::Path;
+
+/// The result of rendering a diagram with both PlantUML commands
+pub struct DiagramComparison {
+    /// The diagram, e.g. "intro.md diagram 2"
+    pub diagram: String,
+    /// The difference between both images, None when these are the same
+    pub difference: Option<String>,
+}
+
+/// Render all diagrams of the book with two PlantUML commands (e.g. two PlantUML versions,
+/// bypassing the cache) and compare the images, to validate a PlantUML upgrade.
+/// # Arguments
+/// * `book_dir` - The book's root directory (containing book.toml)
+/// * `cmd_a` - The first PlantUML command (or server URL)
+/// * `cmd_b` - The second PlantUML command (or server URL)
+/// * `revalidate` - Re-run the PlantUML command detection
+pub fn compare_versions(
+    book_dir: &Path,
+    cmd_a: &str,
+    cmd_b: &str,
+    revalidate: bool,
+) -> Result<Vec<DiagramComparison>> {
+    let md = MDBook::load(book_dir)
+        .map_err(|e| format_err!("{:#}", e))
+        .with_context(|| format!("Failed to load the book from {book_dir:?}"))?;
+    let book_root = dunce::canonicalize(book_dir)?;
+
+    let mut cfg = config_from_book_config(&md.config)?;
+    cfg.revalidate = revalidate;
+    resolve_config_paths(&mut cfg, &book_root);
+
+    let cache_dir = book_root.join(CACHE_DIR);
+    let create_backend = |cmd: &str| {
+        let mut cfg = cfg.clone();
+        cfg.plantuml_cmd = Some(cmd.to_string());
+        backend::factory::create(&cfg, &book_root, &cache_dir)
+            .with_context(|| format!("Failed to create the backend for '{cmd}'"))
+            .map_err(tag(FailureKind::BackendNotFound))
+    };
+    let backend_a = create_backend(cmd_a)?;
+    let backend_b = create_backend(cmd_b)?;
+
+    let src_dir = book_root.join(&md.config.book.src);
+    let markings = DiagramMarkings::from_config(&cfg, md.config.book.title.as_deref());
+    let _cwd_guard = CwdGuard::new()?;
+    let mut comparisons = vec![];
+    for item in md.book.iter() {
+        if let BookItem::Chapter(chapter) = item {
+            if let Some(chapter_path) = &chapter.path {
+                let chapter_file = src_dir.join(chapter_path);
+                let chapter_dir = chapter_file.parent().unwrap_or(&src_dir);
+                // Render from the chapter's directory, so relative PlantUML includes work
+                if let Err(e) = std::env::set_current_dir(chapter_dir) {
+                    log::warn!("Failed to change working dir to {:?} ({})", chapter_dir, e);
+                }
+
+                let chapter_markings = markings.for_chapter(&chapter.name);
+                let diagram_chapter = DiagramChapter {
+                    include_dir: chapter_dir,
+                    markings: &chapter_markings,
+                };
+                let diagrams = plantuml_diagrams(&chapter.content, cfg.strict_info_string);
+                for (index, diagram) in diagrams.iter().enumerate() {
+                    let code = diagram_source(&diagram_chapter, diagram, &cfg)?;
+                    let format = if cfg.text_fallback {
+                        text_fallback_format(&code, &diagram.format)
+                    } else {
+                        &diagram.format
+                    };
+
+                    let name = format!("{} diagram {}", chapter_path.display(), index + 1);
+                    log::info!("Comparing {}", name);
+                    let image_a = backend_a.render_from_string(&code, format);
+                    let image_b = backend_b.render_from_string(&code, format);
+                    let difference = match (image_a, image_b) {
+                        (Ok(a), Ok(b)) => image_difference(&a, &b),
+                        (Err(e), Ok(_)) => Some(format!("failed to render with '{cmd_a}' ({e:#})")),
+                        (Ok(_), Err(e)) => Some(format!("failed to render with '{cmd_b}' ({e:#})")),
+                        // The diagram is broken, not the upgrade
+                        (Err(_), Err(_)) => None,
+                    };
+                    comparisons.push(DiagramComparison {
+                        diagram: name,
+                        difference,
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(comparisons)
+}
+
+/// The comparison report, lists the differing diagrams and a summary
+pub fn comparison_report(comparisons: &[DiagramComparison]) -> String {
+    let mut report = String::new();
+    for comparison in comparisons {
+        if let Some(difference) = &comparison.difference {
+            let _ = writeln!(report, "{}: {}", comparison.diagram, difference);
+        }
+    }
+
+    let differing = comparisons
+        .iter()
+        .filter(|comparison| comparison.difference.is_some())
+        .count();
+    let _ = writeln!(
+        report,
+        "{} of {} diagram(s) differ",
+        differing,
+        comparisons.len()
+    );
+
+    report
+}
+
+/// The difference between the images rendered by both commands, None when these are the same.
+/// PNG images are compared by size and image data (the metadata holding e.g. the PlantUML
+/// version is ignored), SVG images by their elements (the comments, PlantUML processing
+/// instructions and ids are ignored) and text by line.
+pub fn image_difference(a: &[u8], b: &[u8]) -> Option<String> {
+    if a == b {
+        return None;
+    }
+
+    if let (Some(png_a), Some(png_b)) = (png_image(a), png_image(b)) {
+        return if png_a.size != png_b.size {
+            Some(format!(
+                "the image size differs ({}x{} vs {}x{})",
+                png_a.size.0, png_a.size.1, png_b.size.0, png_b.size.1
+            ))
+        } else if png_a.data != png_b.data {
+            Some(format!(
+                "the pixels differ (both {}x{})",
+                png_a.size.0, png_a.size.1
+            ))
+        } else {
+            None
+        };
+    }
+
+    let (text_a, text_b) = (String::from_utf8_lossy(a), String::from_utf8_lossy(b));
+    if text_a.contains("<svg") && text_b.contains("<svg") {
+        let (svg_a, svg_b) = (stable_svg(&text_a), stable_svg(&text_b));
+        if svg_a == svg_b {
+            return None;
+        }
+
+        let (elements_a, elements_b) = (svg_elements(&svg_a), svg_elements(&svg_b));
+        let mut tags: Vec<&str> = elements_a
+            .keys()
+            .chain(elements_b.keys())
+            .copied()
+            .collect();
+        tags.sort_unstable();
+        tags.dedup();
+        let differences: Vec<String> = tags
+            .into_iter()
+            .filter_map(|tag| {
+                let count_a = elements_a.get(tag).copied().unwrap_or_default();
+                let count_b = elements_b.get(tag).copied().unwrap_or_default();
+                if count_a == count_b {
+                    None
+                } else {
+                    Some(format!("<{tag}> {count_a} vs {count_b}"))
+                }
+            })
+            .collect();
+        return Some(if differences.is_empty() {
+            String::from("the element attributes or text differ")
+        } else {
+            format!("the elements differ ({})", differences.join(", "))
+        });
+    }
+
+    let differing_lines = text_a
+        .lines()
+        .zip(text_b.lines())
+        .filter(|(line_a, line_b)| line_a != line_b)
+        .count()
+        + text_a.lines().count().abs_diff(text_b.lines().count());
+    Some(format!("{differing_lines} line(s) differ"))
+}
+
+/// The size and the (compressed) image data of a PNG image
+struct PngImage<'a> {
+    size: (u32, u32),
+    /// The concatenated IDAT chunks
+    data: Vec<&'a [u8]>,
+}
+
+/// Parse the PNG chunks, None if the data is not a PNG image
+fn png_image(data: &[u8]) -> Option<PngImage<'_>> {
+    let mut chunks = data.strip_prefix(b"\x89PNG\r\n\x1a\n")?;
+    let mut image = PngImage {
+        size: (0, 0),
+        data: vec![],
+    };
+    while chunks.len() >= 12 {
+        let length = usize::try_from(u32::from_be_bytes([
+            chunks[0], chunks[1], chunks[2], chunks[3],
+        ]))
+        .ok()?;
+        let chunk_type = &chunks[4..8];
+        let chunk_data = chunks.get(8..8 + length)?;
+        match chunk_type {
+            b"IHDR" if length >= 8 => {
+                image.size = (
+                    u32::from_be_bytes([
+                        chunk_data[0],
+                        chunk_data[1],
+                        chunk_data[2],
+                        chunk_data[3],
+                    ]),
+                    u32::from_be_bytes([
+                        chunk_data[4],
+                        chunk_data[5],
+                        chunk_data[6],
+                        chunk_data[7],
+                    ]),
+                );
+            }
+            b"IDAT" => image.data.push(chunk_data),
+            _ => {}
+        }
+        // The chunk's length, type, data and CRC
+        chunks = chunks.get(12 + length..)?;
+    }
+
+    Some(image)
+}
+
+/// The number of elements per tag name in the SVG image
+fn svg_elements(svg: &str) -> BTreeMap<&str, usize> {
+    let mut elements = BTreeMap::new();
+    for (pos, _) in svg.match_indices('<') {
+        let tag = svg[pos + 1..]
+            .split(|c: char| c.is_whitespace() || c == '>' || c == '/')
+            .next()
+            .unwrap_or_default();
+        if !tag.is_empty() && !tag.starts_with(['?', '!']) {
+            *elements.entry(tag).or_default() += 1;
+        }
+    }
+
+    elements
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    /// A PNG image with the given size, text metadata and image data
+    fn png(width: u32, height: u32, text: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        let mut chunk = |chunk_type: &[u8], chunk_data: &[u8]| {
+            png.extend_from_slice(&(chunk_data.len() as u32).to_be_bytes());
+            png.extend_from_slice(chunk_type);
+            png.extend_from_slice(chunk_data);
+            png.extend_from_slice(&[0, 0, 0, 0]);
+        };
+        let mut ihdr = width.to_be_bytes().to_vec();
+        ihdr.extend_from_slice(&height.to_be_bytes());
+        ihdr.extend_from_slice(&[8, 6, 0, 0, 0]);
+        chunk(b"IHDR", &ihdr);
+        chunk(b"tEXt", text);
+        chunk(b"IDAT", data);
+        chunk(b"IEND", b"");
+        png
+    }
+
+    #[test]
+    fn test_png_difference() {
+        let image = png(100, 50, b"PlantUML 1.2022.7", b"pixels");
+        assert_eq!(None, image_difference(&image, &image));
+        // The metadata is ignored
+        assert_eq!(
+            None,
+            image_difference(&image, &png(100, 50, b"PlantUML 1.2023.1", b"pixels"))
+        );
+        assert_eq!(
+            Some(String::from("the image size differs (100x50 vs 100x60)")),
+            image_difference(&image, &png(100, 60, b"", b"pixels"))
+        );
+        assert_eq!(
+            Some(String::from("the pixels differ (both 100x50)")),
+            image_difference(&image, &png(100, 50, b"", b"other pixels"))
+        );
+    }
+
+    #[test]
+    fn test_svg_difference() {
+        let svg_a = "<svg><!--MD5=[1]--><g id=\"a1\"><rect/><text>A</text></g></svg>";
+        let svg_b = "<svg><!--MD5=[2]--><g id=\"b2\"><rect/><text>A</text></g></svg>";
+        assert_eq!(None, image_difference(svg_a.as_bytes(), svg_b.as_bytes()));
+
+        let svg_b = "<svg><g><rect/><rect/><path/><text>A</text></g></svg>";
+        assert_eq!(
+            Some(String::from(
+                "the elements differ (<path> 0 vs 1, <rect> 1 vs 2)"
+            )),
+            image_difference(svg_a.as_bytes(), svg_b.as_bytes())
+        );
+
+        let svg_b = "<svg><g><rect/><text>B</text></g></svg>";
+        assert_eq!(
+            Some(String::from("the element attributes or text differ")),
+            image_difference(svg_a.as_bytes(), svg_b.as_bytes())
+        );
+    }
+
+    #[test]
+    fn test_text_difference() {
+        assert_eq!(
+            Some(String::from("2 line(s) differ")),
+            image_difference(b"A\nB\nC\n", b"A\nX\nC\nD\n")
+        );
+    }
+
+    #[test]
+    fn test_comparison_report() {
+        let comparisons = [
+            DiagramComparison {
+                diagram: String::from("intro.md diagram 1"),
+                difference: None,
+            },
+            DiagramComparison {
+                diagram: String::from("intro.md diagram 2"),
+                difference: Some(String::from("2 line(s) differ")),
+            },
+        ];
+        assert_eq!(
+            "intro.md diagram 2: 2 line(s) differ\n1 of 2 diagram(s) differ\n",
+            comparison_report(&comparisons)
+        );
+    }
+}
@@ -1,22 +1,232 @@
+use anyhow::{bail, format_err, Result};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::path::PathBuf;
 
 /// Workaround for serde's lack of support for default = "true"
 fn bool_true() -> bool {
     true
 }
 
+const fn one() -> usize {
+    1
+}
+
+const fn ninety() -> u64 {
+    90
+}
+
+/// PlantUML's default maximum image width and height (PLANTUML_LIMIT_SIZE) in pixels
+pub const DEFAULT_LIMIT_SIZE: u32 = 4096;
+
+/// How the image links (when not using data URIs) are created
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum LinkStyle {
+    /// Relative to the chapter (e.g. ../mdbook-plantuml-img/foo.svg)
+    Relative,
+    /// Relative to the book root (e.g. /mdbook-plantuml-img/foo.svg), these also work for
+    /// nested chapters in the consolidated print.html
+    Absolute,
+}
+
+/// How the (non text) images are emitted in the chapters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum ImageMarkup {
+    /// Markdown images (e.g. ![alt](foo.svg))
+    Markdown,
+    /// Raw HTML img elements (e.g. <img src="foo.svg" alt="alt">)
+    Html,
+}
+
+impl ImageMarkup {
+    /// The image markup by name (as used in the config, and the code block's info string)
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "markdown" => Some(Self::Markdown),
+            "html" => Some(Self::Html),
+            _ => None,
+        }
+    }
+}
+
+/// How the PlantUML code blocks are handled for a renderer (see Config::renderer_strategy)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RendererStrategy {
+    /// The preprocessor does not run for the renderer
+    Unsupported,
+    /// The code blocks are kept as is (the passthrough-renderers option)
+    Passthrough,
+    /// The diagrams are rendered as png data URIs (the epub-profile option)
+    EpubProfile,
+    /// The diagrams are rendered as configured
+    Render,
+}
+
+/// What to do when no PlantUML backend is available (e.g. java or PlantUML is not installed)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum MissingBackend {
+    /// Fail the book build
+    Error,
+    /// Render a placeholder instead of the diagrams (cached images are still used)
+    Placeholder,
+    /// Leave the PlantUML code blocks untouched
+    KeepSource,
+}
+
+/// What to do when the render time budget (max-total-render-seconds) is spent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum BudgetExceeded {
+    /// Fail the book build
+    Error,
+    /// Render placeholders for the remaining uncached diagrams
+    Placeholder,
+}
+
+/// Which links in the (SVG) diagrams are checked (see check-diagram-links)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum DiagramLinkCheck {
+    /// No links are checked
+    Off,
+    /// The links to files in the book are checked
+    Internal,
+    /// The links to files in the book and the external (http/https) links are checked
+    All,
+}
+
+/// The PlantUML security profile (PLANTUML_SECURITY_PROFILE), limits what `!include`, `%load`
+/// etc. can access
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum SecurityProfile {
+    /// No restrictions
+    Unsecure,
+    /// The (pre 1.2023) legacy behavior
+    Legacy,
+    /// Only the allowlisted files and URLs can be accessed
+    Allowlist,
+    /// Only the internet (no local files) can be accessed
+    Internet,
+    /// No files and URLs can be accessed
+    Sandbox,
+}
+
+impl SecurityProfile {
+    /// The profile's name (as used by PlantUML)
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Unsecure => "UNSECURE",
+            Self::Legacy => "LEGACY",
+            Self::Allowlist => "ALLOWLIST",
+            Self::Internet => "INTERNET",
+            Self::Sandbox => "SANDBOX",
+        }
+    }
+}
+
+/// The font options of the PlantUML (java) process, see font-config
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct FontConfig {
+    /// The JVM's default encoding (-Dfile.encoding), e.g. UTF-8
+    pub encoding: Option<String>,
+    /// The directories with extra fonts (relative to the book root, or absolute), e.g. CJK or
+    /// emoji fonts
+    pub font_dirs: Vec<PathBuf>,
+}
+
+impl FontConfig {
+    /// The JVM options (-D...) for the font configuration
+    pub fn java_options(&self) -> Vec<String> {
+        let mut options = vec![];
+        if let Some(encoding) = &self.encoding {
+            options.push(java_property("file.encoding", encoding));
+        }
+        if let Ok(font_path) = std::env::join_paths(&self.font_dirs) {
+            if !self.font_dirs.is_empty() {
+                // Append, so the system fonts are still found
+                options.push(java_property(
+                    "sun.java2d.fontpath",
+                    &format!("append:{}", font_path.to_string_lossy()),
+                ));
+            }
+        }
+
+        options
+    }
+}
+
+/// A JVM system property option (-D<name>=<value>). The shell passes the options in
+/// JAVA_TOOL_OPTIONS, which the JVM splits on whitespace, so a value with whitespace is quoted.
+fn java_property(name: &str, value: &str) -> String {
+    if value.contains(char::is_whitespace) {
+        format!("\"-D{name}={value}\"")
+    } else {
+        format!("-D{name}={value}")
+    }
+}
+
+/// A curated set of options, set using `profile = "<name>"` (explicitly set options take precedence)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum Profile {
+    /// Fail on any error, and keep the failed renders for inspection
+    Ci,
+    /// Show the diagrams that cannot be rendered (or placeholders) in the book instead of failing
+    Local,
+    /// Only use cached images and placeholders, nothing is rendered when no backend is available
+    Fast,
+}
+
+impl Profile {
+    /// The options of the profile (using the book.toml keys)
+    fn options(self) -> serde_json::Value {
+        match self {
+            Self::Ci => json!({
+                "fail-on-error": true,
+                "keep-failed": true,
+                "missing-backend": "error",
+            }),
+            Self::Local => json!({
+                "fail-on-error": false,
+                "keep-failed": true,
+                "missing-backend": "placeholder",
+            }),
+            Self::Fast => json!({
+                "fail-on-error": false,
+                "missing-backend": "placeholder",
+            }),
+        }
+    }
+}
+
 /// The configuration options available with this backend.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(default, rename_all = "kebab-case")]
 pub struct Config {
     /// By default it is assumed plantuml.jar is on the path
     /// Use plantuml_cmd if it is not on the path, or if you
     /// have some additional parameters.
     pub plantuml_cmd: Option<String>,
+    /// The java command (including JVM options like -Xmx1g) used to run plantuml_jar, or the
+    /// auto detected plantuml.jar (defaults to "java")
+    pub java_cmd: Option<String>,
+    /// Path to plantuml.jar (relative to the book root, or absolute), run with java_cmd.
+    /// Cannot be combined with plantuml_cmd.
+    pub plantuml_jar: Option<PathBuf>,
     /// When the PlantUML shell is called this option enables piped mode, meaning no temporary directories
     /// and files are needed for image generation (defaults to false).
     #[serde(default = "bool_true")]
     pub piped: bool,
+    /// Render a diagram in file mode when rendering it in piped mode fails, some PlantUML builds
+    /// fail on valid diagrams in piped mode (defaults to true)
+    #[serde(default = "bool_true")]
+    pub piped_fallback: bool,
     /// PlantUML images become clickable for zoom by setting this flag to `true`.
     /// This is convenient for large diagrams which are hard to see in the book.
     /// The default value is `false`.
@@ -24,22 +234,546 @@ pub struct Config {
     /// Instead of creating inlined links to image files use data URIs (defaults to true)
     #[serde(default = "bool_true")]
     pub use_data_uris: bool,
+    /// How the images are emitted, as markdown images or as raw HTML img elements (defaults to
+    /// markdown). Can be overridden per code block (markup=html).
+    pub image_markup: ImageMarkup,
     /// Verbose logging (debug level)
     pub verbose: bool,
+    /// The charset passed to the PlantUML shell (-charset), defaults to UTF-8
+    pub charset: String,
+    /// Stop the book build when a diagram fails to render, instead of embedding the error
+    /// message in the chapter (defaults to false)
+    pub fail_on_error: bool,
+    /// Directory for the temporary files needed for rendering in (non piped) file mode
+    /// (defaults to the system temp dir)
+    pub temp_dir: Option<PathBuf>,
+    /// Keep the source and (partial) output of failed renders in
+    /// .mdbook-plantuml-cache/failed/<hash> for debugging (defaults to false)
+    pub keep_failed: bool,
+    /// Ignore the cached PlantUML command detection result (set by the --revalidate
+    /// command line flag)
+    #[serde(skip)]
+    pub revalidate: bool,
+    /// The renderers this preprocessor runs for (e.g. ["html", "epub"]), defaults to all renderers
+    pub supported_renderers: Option<Vec<String>>,
+    /// The renderers for which the PlantUML code blocks are kept as is (defaults to ["markdown"]),
+    /// those renderers do not copy the generated images.
+    pub passthrough_renderers: Vec<String>,
+    /// Automatically use data URIs and png images when rendering for the epub renderer
+    /// (defaults to true). EPUB renderers do not copy the image dir and have poor SVG support.
+    pub epub_profile: bool,
+    /// Render all (non text) images in this format, regardless of the code block's format
+    /// (set by the epub profile)
+    #[serde(skip)]
+    pub forced_image_format: Option<String>,
+    /// Render the diagrams without readable text output (e.g. gantt and mindmap diagrams) as svg
+    /// when a text format (txt, utxt) is requested, a warning is logged (defaults to true)
+    #[serde(default = "bool_true")]
+    pub text_fallback: bool,
+    /// Add the diagram's text (utxt) rendering after every braille image, as text alternative
+    /// for screen readers (defaults to false)
+    pub braille_text: bool,
+    /// How the image links are created when not using data URIs (defaults to relative)
+    pub link_style: LinkStyle,
+    /// Glob patterns (relative to the book root) of PlantUML files to render into the image dir,
+    /// so they can be referenced with plain image links (e.g. ["diagrams/**/*.puml"])
+    pub extra_sources: Vec<String>,
+    /// Command run on every newly rendered image before it is cached (e.g. "svgo --input {file}"),
+    /// supports the {file}, {format} and {hash} placeholders
+    pub post_process_cmd: Option<String>,
+    /// Add a list of figures (linking to every rendered diagram) to the book, either in place of
+    /// the {{#plantuml-list-of-figures}} placeholder, or as a new chapter at the end of the book
+    pub list_of_figures: bool,
+    /// The data code block languages ("json" and/or "yaml") that are rendered as PlantUML JSON/YAML
+    /// diagrams (add plantuml-render to the info string to render individual code blocks)
+    pub render_data: Vec<String>,
+    /// Add a glossary (mapping the participants/classes/components to the diagrams they appear in)
+    /// to the book, either in place of the {{#plantuml-glossary}} placeholder, or as a new chapter
+    pub glossary: bool,
+    /// Write a .gitignore (ignoring everything) in the generated image and cache dirs, an existing
+    /// .gitignore is left untouched
+    pub gitignore: bool,
+    /// What to do when no PlantUML backend could be created (defaults to error)
+    pub missing_backend: MissingBackend,
+    /// What to do when the configured PlantUML server cannot be used, because the plugin is built
+    /// without the (SSL) server feature (defaults to error)
+    pub unsupported_backend: MissingBackend,
+    /// Add the PlantUML source in an HTML comment after every image (defaults to false)
+    pub embed_source_comment: bool,
+    /// Only parse space separated key=value pairs after the language in the info string
+    /// (CommonMark style), the legacy comma separated syntax is ignored (defaults to false)
+    pub strict_info_string: bool,
+    /// Strip the UTF-8 BOM and convert CRLF line endings to LF before hashing and rendering the
+    /// diagrams, so the cached images are the same on all platforms (defaults to true)
+    #[serde(default = "bool_true")]
+    pub normalize_source: bool,
+    /// Forbid network access, i.e. using a PlantUML server and remote (URL) includes
+    /// (defaults to false, also enabled by the CI_OFFLINE environment variable)
+    pub offline: bool,
+    /// The environment variable holding the key the cached images are signed with (HMAC), cached
+    /// images without a valid signature are rendered again (defaults to None, no signing)
+    pub cache_signing_key_env: Option<String>,
+    /// Reject diagrams that `!include` (or mdBook `{{#include}}`) files outside the book root
+    /// (defaults to false)
+    pub restrict_includes_to_book: bool,
+    /// Wrapper command the shell backend runs PlantUML in (e.g. firejail or bwrap), `{temp_dir}`
+    /// is replaced by the PlantUML temp dir (defaults to None, no sandbox)
+    pub sandbox_cmd: Option<String>,
+    /// Diagram sources (including the mdBook includes) larger than this are not rendered
+    /// (defaults to None, no limit)
+    pub max_diagram_bytes: Option<usize>,
+    /// Diagrams beyond this number in a chapter are not rendered (defaults to None, no limit)
+    pub max_diagrams_per_chapter: Option<usize>,
+    /// The preset the options default to (defaults to None)
+    pub profile: Option<Profile>,
+    /// Log a notice when a newer mdbook-plantuml version is available, checked at most once
+    /// a day and never on CI (defaults to false)
+    pub update_notice: bool,
+    /// The maximum total time (in seconds) spent rendering diagrams (defaults to None, no limit)
+    pub max_total_render_seconds: Option<u64>,
+    /// What to do when max_total_render_seconds is exceeded (defaults to error)
+    pub render_budget_exceeded: BudgetExceeded,
+    /// Record the files outside the book's src dir included by the diagrams in an include
+    /// manifest, used by the watch-includes command to trigger `mdbook serve` rebuilds
+    /// (defaults to false)
+    pub watch_includes: bool,
+    /// Use the cached images rendered by other backends (e.g. a PlantUML server) too, by default
+    /// the backend is part of the cache key (defaults to false)
+    pub share_cache_across_backends: bool,
+    /// Keep the PlantUML metadata (the diagram source) in the images, and keep the sources and
+    /// images in the metadata cache dir in file mode (-checkmetadata) (defaults to false)
+    pub metadata_cache: bool,
+    /// Buffer the log output of a chapter and log it at once when the chapter is rendered, so the
+    /// output of chapters does not interleave (defaults to false)
+    pub group_chapter_logs: bool,
+    /// Warn about (or with fail_on_error fail on) diagrams without alt text, i.e. without
+    /// `alt=` and without a title (defaults to false)
+    pub require_alt: bool,
+    /// Check the hyperlinks in the rendered SVG diagrams (defaults to off)
+    pub check_diagram_links: DiagramLinkCheck,
+    /// The font used by every diagram (skinparam defaultFontName), e.g. a font with CJK
+    /// characters (defaults to None, PlantUML's default font)
+    pub default_font: Option<String>,
+    /// The encoding and extra font directories of the PlantUML (java) process of the shell
+    /// backend (defaults to none)
+    pub font_config: FontConfig,
+    /// The PlantUML security profile of the shell backend (defaults to None, PlantUML's default
+    /// profile)
+    pub security_profile: Option<SecurityProfile>,
+    /// The files (relative to the book root, or absolute) and URLs the diagrams can include with
+    /// the ALLOWLIST security profile (defaults to none)
+    pub include_allowlist: Vec<String>,
+    /// Glob patterns (relative to the book root) of stable included files (e.g. vendored icon
+    /// packs), these are hashed once and memoized instead of read for every diagram (defaults
+    /// to none)
+    pub hash_ignore: Vec<String>,
+    /// The maximum image width and height in pixels (PLANTUML_LIMIT_SIZE) of the shell backend,
+    /// PlantUML clips larger images (defaults to None, PlantUML's default of 4096)
+    pub limit_size: Option<u32>,
+    /// Render a clipped image again once with a doubled limit-size (defaults to false)
+    pub retry_truncated: bool,
+    /// The header added to every diagram, the {chapter}, {book} and {date} placeholders are
+    /// replaced by the chapter's name, the book's title and the build date (defaults to None)
+    pub header: Option<String>,
+    /// The footer added to every diagram, with the same placeholders as the header (defaults to
+    /// None)
+    pub footer: Option<String>,
+    /// The options added to the `@startditaa` line of every ditaa diagram, e.g.
+    /// `["--no-shadows", "scale=1.5"]` (defaults to none)
+    pub ditaa_args: Vec<String>,
+    /// Make the SVG images diff friendly, by removing the comments and renumbering the ids
+    /// (defaults to false)
+    pub stable_svg: bool,
+    /// The maximum number of concurrent renders with the PlantUML shell backend,
+    /// every render runs a PlantUML (JVM) process (defaults to 1)
+    #[serde(default = "one")]
+    pub shell_max_parallel: usize,
+    /// The maximum number of concurrent renders with a PlantUML server (defaults to 1)
+    #[serde(default = "one")]
+    pub server_max_parallel: usize,
+    /// The PlantUML server's request URL, for servers with another path layout than the
+    /// PlantUML server. The {base} (server URL), {format} and {encoded} (diagram) placeholders
+    /// are replaced, e.g. "{base}/render/{format}/{encoded}" (defaults to none, for
+    /// "{base}/{format}/{encoded}")
+    pub server_url_template: Option<String>,
+    /// How long (in seconds) idle connections to the PlantUML server are kept open for reuse
+    /// (defaults to 90)
+    #[serde(default = "ninety")]
+    pub server_pool_idle_timeout: u64,
+    /// The maximum number of idle connections to the PlantUML server kept open (defaults to no
+    /// limit)
+    pub server_pool_max_idle: Option<usize>,
+    /// The book's root directory (set when preprocessing the book)
+    #[serde(skip)]
+    pub book_root: Option<PathBuf>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             plantuml_cmd: None,
+            java_cmd: None,
+            plantuml_jar: None,
             piped: true,
+            piped_fallback: true,
             clickable_img: false,
             use_data_uris: true,
+            image_markup: ImageMarkup::Markdown,
             verbose: false,
+            charset: String::from("UTF-8"),
+            fail_on_error: false,
+            temp_dir: None,
+            keep_failed: false,
+            revalidate: false,
+            supported_renderers: None,
+            passthrough_renderers: vec![String::from("markdown")],
+            epub_profile: true,
+            forced_image_format: None,
+            text_fallback: true,
+            braille_text: false,
+            link_style: LinkStyle::Relative,
+            extra_sources: vec![],
+            post_process_cmd: None,
+            list_of_figures: false,
+            render_data: vec![],
+            glossary: false,
+            gitignore: true,
+            missing_backend: MissingBackend::Error,
+            unsupported_backend: MissingBackend::Error,
+            embed_source_comment: false,
+            strict_info_string: false,
+            normalize_source: true,
+            offline: false,
+            cache_signing_key_env: None,
+            restrict_includes_to_book: false,
+            sandbox_cmd: None,
+            max_diagram_bytes: None,
+            max_diagrams_per_chapter: None,
+            profile: None,
+            update_notice: false,
+            max_total_render_seconds: None,
+            render_budget_exceeded: BudgetExceeded::Error,
+            watch_includes: false,
+            share_cache_across_backends: false,
+            metadata_cache: false,
+            group_chapter_logs: false,
+            require_alt: false,
+            check_diagram_links: DiagramLinkCheck::Off,
+            default_font: None,
+            font_config: FontConfig::default(),
+            security_profile: None,
+            include_allowlist: vec![],
+            hash_ignore: vec![],
+            limit_size: None,
+            retry_truncated: false,
+            header: None,
+            footer: None,
+            ditaa_args: vec![],
+            stable_svg: false,
+            shell_max_parallel: 1,
+            server_max_parallel: 1,
+            server_url_template: None,
+            server_pool_idle_timeout: 90,
+            server_pool_max_idle: None,
+            book_root: None,
         }
     }
 }
 
+impl Config {
+    /// Create the configuration from the (book.toml) options, the profile's options are used for
+    /// the options that are not set explicitly.
+    /// # Arguments
+    /// * `options` - The preprocessor options (a table)
+    pub fn from_options(mut options: serde_json::Value) -> Result<Self> {
+        let profile = match options.get("profile") {
+            Some(profile) => Some(
+                serde_json::from_value::<Profile>(profile.clone())
+                    .map_err(|e| format_err!("Invalid profile {} ({})", profile, e))?,
+            ),
+            None => None,
+        };
+
+        if let (Some(profile), Some(table)) = (profile, options.as_object_mut()) {
+            if let serde_json::Value::Object(profile_options) = profile.options() {
+                for (key, value) in profile_options {
+                    table.entry(key).or_insert(value);
+                }
+            }
+        }
+
+        Ok(serde_json::from_value(options)?)
+    }
+
+    /// Returns true if the preprocessor should run for the given renderer
+    pub fn supports_renderer(&self, renderer: &str) -> bool {
+        // "not-supported" is used by mdbook's tests to check preprocessors decline renderers
+        renderer != "not-supported"
+            && self
+                .supported_renderers
+                .as_ref()
+                .map_or(true, |renderers| renderers.iter().any(|r| r == renderer))
+    }
+
+    /// How the PlantUML code blocks are handled for the given renderer
+    pub fn renderer_strategy(&self, renderer: &str) -> RendererStrategy {
+        if !self.supports_renderer(renderer) {
+            RendererStrategy::Unsupported
+        } else if self.is_passthrough_renderer(renderer) {
+            RendererStrategy::Passthrough
+        } else if renderer == "epub" && self.epub_profile {
+            RendererStrategy::EpubProfile
+        } else {
+            RendererStrategy::Render
+        }
+    }
+
+    /// Why the renderer is (or is not) supported, e.g. "epub is supported, the diagrams are
+    /// rendered as png data URIs (epub-profile)"
+    pub fn explain_renderer_strategy(&self, renderer: &str) -> String {
+        match self.renderer_strategy(renderer) {
+            RendererStrategy::Unsupported if renderer == "not-supported" => format!(
+                "{renderer} is not supported, it is used by mdBook's tests to check preprocessors \
+                 decline renderers"
+            ),
+            RendererStrategy::Unsupported => format!(
+                "{} is not supported, it is not in supported-renderers ({})",
+                renderer,
+                self.supported_renderers
+                    .as_deref()
+                    .unwrap_or_default()
+                    .join(", ")
+            ),
+            RendererStrategy::Passthrough => format!(
+                "{renderer} is supported, but the PlantUML code blocks are kept as is (it is in \
+                 passthrough-renderers)"
+            ),
+            RendererStrategy::EpubProfile => format!(
+                "{renderer} is supported, the diagrams are rendered as png data URIs (epub-profile)"
+            ),
+            RendererStrategy::Render if self.use_data_uris => {
+                format!("{renderer} is supported, the diagrams are rendered as data URIs")
+            }
+            RendererStrategy::Render => format!(
+                "{renderer} is supported, the diagrams are rendered as image files (use-data-uris \
+                 is false)"
+            ),
+        }
+    }
+
+    /// Force data URIs with png images, so the generated EPUB contains working diagrams
+    pub fn apply_epub_profile(&mut self) {
+        self.use_data_uris = true;
+        self.forced_image_format = Some(String::from("png"));
+    }
+
+    /// Returns true if the PlantUML code blocks should be kept as is for the given renderer
+    pub fn is_passthrough_renderer(&self, renderer: &str) -> bool {
+        self.passthrough_renderers.iter().any(|r| r == renderer)
+    }
+
+    /// Enable offline mode when the CI_OFFLINE environment variable is set (to anything
+    /// but an empty string, "0" or "false")
+    /// # Arguments
+    /// * `env` - Environment variable lookup
+    pub fn detect_offline(&mut self, env: &dyn Fn(&str) -> Option<String>) {
+        if let Some(value) = env("CI_OFFLINE") {
+            let value = value.trim();
+            if !value.is_empty() && value != "0" && !value.eq_ignore_ascii_case("false") {
+                self.offline = true;
+            }
+        }
+    }
+
+    /// The JVM options (-D...) of the PlantUML shell, for the font config, the limit size and the
+    /// security profile (with the include allowlist)
+    pub fn java_options(&self) -> Vec<String> {
+        let mut options = self.font_config.java_options();
+        if let Some(limit_size) = self.limit_size {
+            options.push(format!("-DPLANTUML_LIMIT_SIZE={limit_size}"));
+        }
+        if let Some(profile) = self.security_profile {
+            options.push(format!("-DPLANTUML_SECURITY_PROFILE={}", profile.name()));
+        }
+
+        let (urls, paths): (Vec<&str>, Vec<&str>) = self
+            .include_allowlist
+            .iter()
+            .map(String::as_str)
+            .partition(|entry| entry.starts_with("http://") || entry.starts_with("https://"));
+        if let Ok(allowed_paths) = std::env::join_paths(&paths) {
+            if !paths.is_empty() {
+                options.push(java_property(
+                    "plantuml.allowlist.path",
+                    &allowed_paths.to_string_lossy(),
+                ));
+            }
+        }
+        if !urls.is_empty() {
+            options.push(java_property("plantuml.allowlist.url", &urls.join(";")));
+        }
+
+        options
+    }
+
+    /// The shell options changing the rendered images that are part of the cache key, the font
+    /// config, the security profile and the include allowlist. None when none is set, so the
+    /// images cached before are still used.
+    pub fn cache_options_key(&self) -> Option<String> {
+        let mut options = vec![];
+        if let Some(encoding) = &self.font_config.encoding {
+            options.push(format!("encoding={encoding}"));
+        }
+        if !self.font_config.font_dirs.is_empty() {
+            let font_dirs: Vec<_> = self
+                .font_config
+                .font_dirs
+                .iter()
+                .map(|dir| dir.to_string_lossy())
+                .collect();
+            options.push(format!("font-dirs={}", font_dirs.join(";")));
+        }
+        if let Some(profile) = self.security_profile {
+            options.push(format!("security-profile={}", profile.name()));
+        }
+        if !self.include_allowlist.is_empty() {
+            options.push(format!(
+                "include-allowlist={}",
+                self.include_allowlist.join(";")
+            ));
+        }
+
+        (!options.is_empty()).then(|| options.join(" "))
+    }
+
+    /// The maximum number of concurrent renders for the configured backend
+    pub fn max_parallel(&self) -> usize {
+        let max_parallel = match self.plantuml_cmd.as_deref() {
+            Some(cmd) if cmd.starts_with("http://") || cmd.starts_with("https://") => {
+                self.server_max_parallel
+            }
+            _ => self.shell_max_parallel,
+        };
+
+        max_parallel.max(1)
+    }
+
+    /// The backend identity that is part of the cache key, the PlantUML server (or FTP) URL, or
+    /// "shell" for the PlantUML command line. None when sharing the cache across backends.
+    pub fn cache_backend_key(&self) -> Option<String> {
+        if self.share_cache_across_backends {
+            return None;
+        }
+
+        match self.plantuml_cmd.as_deref() {
+            Some(cmd) if cmd.contains("://") => Some(cmd.trim_end_matches('/').to_string()),
+            _ => Some(String::from("shell")),
+        }
+    }
+
+    /// The key the cached images are signed with (read from the cache_signing_key_env
+    /// environment variable), fails when the environment variable is not set.
+    /// # Arguments
+    /// * `env` - Environment variable lookup
+    pub fn cache_signing_key(
+        &self,
+        env: &dyn Fn(&str) -> Option<String>,
+    ) -> Result<Option<Vec<u8>>> {
+        match &self.cache_signing_key_env {
+            Some(name) => match env(name).filter(|key| !key.is_empty()) {
+                Some(key) => Ok(Some(key.into_bytes())),
+                None => bail!(
+                    "The environment variable '{}' (cache-signing-key-env) with the cache signing key is not set",
+                    name
+                ),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Replace the ${VAR} environment variable references in the command and path valued
+    /// options. Fails when a referenced variable is not defined.
+    /// # Arguments
+    /// * `env` - Environment variable lookup
+    pub fn interpolate_env(&mut self, env: &dyn Fn(&str) -> Option<String>) -> Result<()> {
+        if let Some(cmd) = &self.plantuml_cmd {
+            self.plantuml_cmd = Some(interpolate_env(cmd, "plantuml-cmd", env)?);
+        }
+        if let Some(cmd) = &self.java_cmd {
+            self.java_cmd = Some(interpolate_env(cmd, "java-cmd", env)?);
+        }
+        if let Some(jar) = &self.plantuml_jar {
+            self.plantuml_jar = Some(PathBuf::from(interpolate_env(
+                &jar.to_string_lossy(),
+                "plantuml-jar",
+                env,
+            )?));
+        }
+        if let Some(cmd) = &self.post_process_cmd {
+            self.post_process_cmd = Some(interpolate_env(cmd, "post-process-cmd", env)?);
+        }
+        if let Some(dir) = &self.temp_dir {
+            self.temp_dir = Some(PathBuf::from(interpolate_env(
+                &dir.to_string_lossy(),
+                "temp-dir",
+                env,
+            )?));
+        }
+        for pattern in &mut self.extra_sources {
+            *pattern = interpolate_env(pattern, "extra-sources", env)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Replace the ${VAR} environment variable references in the value, $${VAR} is
+/// an escaped reference (resulting in ${VAR}).
+/// # Arguments
+/// * `value` - The config value
+/// * `key` - The config key (for the error message)
+/// * `env` - Environment variable lookup
+fn interpolate_env(value: &str, key: &str, env: &dyn Fn(&str) -> Option<String>) -> Result<String> {
+    let mut interpolated = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let (before, reference) = rest.split_at(start);
+        let end = match reference.find('}') {
+            Some(end) => end,
+            None => bail!(
+                "Unterminated environment variable reference in {} '{}'",
+                key,
+                value
+            ),
+        };
+
+        if let Some(before) = before.strip_suffix('$') {
+            // Escaped reference
+            interpolated.push_str(before);
+            interpolated.push_str(&reference[..=end]);
+        } else {
+            let name = &reference[2..end];
+            match env(name) {
+                Some(var) => {
+                    interpolated.push_str(before);
+                    interpolated.push_str(&var);
+                }
+                None => bail!(
+                    "Environment variable '{}' used in {} '{}' is not defined",
+                    name,
+                    key,
+                    value
+                ),
+            }
+        }
+
+        rest = &reference[end + 1..];
+    }
+    interpolated.push_str(rest);
+
+    Ok(interpolated)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -49,9 +783,409 @@ mod tests {
     fn default() {
         let cfg = Config::default();
         assert_eq!(cfg.plantuml_cmd, None);
+        assert_eq!(cfg.java_cmd, None);
+        assert_eq!(cfg.plantuml_jar, None);
         assert_eq!(cfg.piped, true);
+        assert_eq!(cfg.piped_fallback, true);
         assert_eq!(cfg.clickable_img, false);
         assert_eq!(cfg.use_data_uris, true);
+        assert_eq!(cfg.image_markup, ImageMarkup::Markdown);
         assert_eq!(cfg.verbose, false);
+        assert_eq!(cfg.charset, "UTF-8");
+        assert_eq!(cfg.fail_on_error, false);
+        assert_eq!(cfg.temp_dir, None);
+        assert_eq!(cfg.keep_failed, false);
+        assert_eq!(cfg.revalidate, false);
+        assert_eq!(cfg.supported_renderers, None);
+        assert_eq!(cfg.passthrough_renderers, vec![String::from("markdown")]);
+        assert_eq!(cfg.epub_profile, true);
+        assert_eq!(cfg.forced_image_format, None);
+        assert_eq!(cfg.text_fallback, true);
+        assert_eq!(cfg.braille_text, false);
+        assert_eq!(cfg.link_style, LinkStyle::Relative);
+        assert!(cfg.extra_sources.is_empty());
+        assert_eq!(cfg.post_process_cmd, None);
+        assert_eq!(cfg.list_of_figures, false);
+        assert!(cfg.render_data.is_empty());
+        assert_eq!(cfg.glossary, false);
+        assert_eq!(cfg.gitignore, true);
+        assert_eq!(cfg.missing_backend, MissingBackend::Error);
+        assert_eq!(cfg.unsupported_backend, MissingBackend::Error);
+        assert_eq!(cfg.embed_source_comment, false);
+        assert_eq!(cfg.strict_info_string, false);
+        assert_eq!(cfg.normalize_source, true);
+        assert_eq!(cfg.offline, false);
+        assert_eq!(cfg.cache_signing_key_env, None);
+        assert_eq!(cfg.restrict_includes_to_book, false);
+        assert_eq!(cfg.sandbox_cmd, None);
+        assert_eq!(cfg.max_diagram_bytes, None);
+        assert_eq!(cfg.max_diagrams_per_chapter, None);
+        assert_eq!(cfg.profile, None);
+        assert_eq!(cfg.update_notice, false);
+        assert_eq!(cfg.max_total_render_seconds, None);
+        assert_eq!(cfg.render_budget_exceeded, BudgetExceeded::Error);
+        assert_eq!(cfg.watch_includes, false);
+        assert_eq!(cfg.share_cache_across_backends, false);
+        assert_eq!(cfg.metadata_cache, false);
+        assert_eq!(cfg.group_chapter_logs, false);
+        assert_eq!(cfg.require_alt, false);
+        assert_eq!(cfg.check_diagram_links, DiagramLinkCheck::Off);
+        assert_eq!(cfg.default_font, None);
+        assert_eq!(cfg.font_config, FontConfig::default());
+        assert_eq!(cfg.security_profile, None);
+        assert_eq!(cfg.include_allowlist, Vec::<String>::new());
+        assert_eq!(cfg.hash_ignore, Vec::<String>::new());
+        assert_eq!(cfg.limit_size, None);
+        assert_eq!(cfg.retry_truncated, false);
+        assert_eq!(cfg.header, None);
+        assert_eq!(cfg.footer, None);
+        assert_eq!(cfg.ditaa_args, Vec::<String>::new());
+        assert_eq!(cfg.stable_svg, false);
+        assert_eq!(cfg.shell_max_parallel, 1);
+        assert_eq!(cfg.server_max_parallel, 1);
+        assert_eq!(cfg.server_url_template, None);
+        assert_eq!(cfg.server_pool_idle_timeout, 90);
+        assert_eq!(cfg.server_pool_max_idle, None);
+        assert_eq!(cfg.book_root, None);
+    }
+
+    #[test]
+    fn from_options() {
+        let cfg = Config::from_options(json!({ "piped": false })).unwrap();
+        assert_eq!(cfg.piped, false);
+        assert_eq!(cfg.fail_on_error, false);
+
+        let cfg = Config::from_options(json!({ "profile": "ci" })).unwrap();
+        assert_eq!(cfg.profile, Some(Profile::Ci));
+        assert_eq!(cfg.fail_on_error, true);
+        assert_eq!(cfg.keep_failed, true);
+
+        // Explicit options take precedence over the profile
+        let cfg = Config::from_options(json!({ "profile": "ci", "keep-failed": false })).unwrap();
+        assert_eq!(cfg.fail_on_error, true);
+        assert_eq!(cfg.keep_failed, false);
+
+        let cfg = Config::from_options(json!({ "profile": "fast" })).unwrap();
+        assert_eq!(cfg.missing_backend, MissingBackend::Placeholder);
+
+        let error = Config::from_options(json!({ "profile": "slow" })).unwrap_err();
+        assert!(format!("{error}").starts_with("Invalid profile \"slow\""));
+    }
+
+    #[test]
+    fn apply_epub_profile() {
+        let mut cfg = Config {
+            use_data_uris: false,
+            ..Config::default()
+        };
+        cfg.apply_epub_profile();
+        assert_eq!(cfg.use_data_uris, true);
+        assert_eq!(cfg.forced_image_format, Some(String::from("png")));
+    }
+
+    #[test]
+    fn is_passthrough_renderer() {
+        let cfg = Config::default();
+        assert!(cfg.is_passthrough_renderer("markdown"));
+        assert!(!cfg.is_passthrough_renderer("html"));
+
+        let cfg = Config {
+            passthrough_renderers: vec![String::from("pandoc")],
+            ..Config::default()
+        };
+        assert!(cfg.is_passthrough_renderer("pandoc"));
+        assert!(!cfg.is_passthrough_renderer("markdown"));
+    }
+
+    #[test]
+    fn supports_renderer() {
+        let cfg = Config::default();
+        assert!(cfg.supports_renderer("html"));
+        assert!(cfg.supports_renderer("markdown"));
+        assert!(!cfg.supports_renderer("not-supported"));
+
+        let cfg = Config {
+            supported_renderers: Some(vec![String::from("html"), String::from("epub")]),
+            ..Config::default()
+        };
+        assert!(cfg.supports_renderer("html"));
+        assert!(cfg.supports_renderer("epub"));
+        assert!(!cfg.supports_renderer("markdown"));
+        assert!(!cfg.supports_renderer("linkcheck"));
+    }
+
+    #[test]
+    fn test_renderer_strategy() {
+        let cfg = Config {
+            supported_renderers: Some(vec![
+                String::from("html"),
+                String::from("epub"),
+                String::from("markdown"),
+            ]),
+            ..Config::default()
+        };
+        assert_eq!(RendererStrategy::Render, cfg.renderer_strategy("html"));
+        assert_eq!(RendererStrategy::EpubProfile, cfg.renderer_strategy("epub"));
+        assert_eq!(
+            RendererStrategy::Passthrough,
+            cfg.renderer_strategy("markdown")
+        );
+        assert_eq!(RendererStrategy::Unsupported, cfg.renderer_strategy("pdf"));
+        assert_eq!(
+            "pdf is not supported, it is not in supported-renderers (html, epub, markdown)",
+            cfg.explain_renderer_strategy("pdf")
+        );
+        assert_eq!(
+            "html is supported, the diagrams are rendered as data URIs",
+            cfg.explain_renderer_strategy("html")
+        );
+
+        let cfg = Config {
+            epub_profile: false,
+            use_data_uris: false,
+            ..Config::default()
+        };
+        assert_eq!(RendererStrategy::Render, cfg.renderer_strategy("epub"));
+        assert_eq!(
+            "epub is supported, the diagrams are rendered as image files (use-data-uris is false)",
+            cfg.explain_renderer_strategy("epub")
+        );
+    }
+
+    #[test]
+    fn test_detect_offline() {
+        let offline = |value: Option<&str>| {
+            let mut cfg = Config::default();
+            cfg.detect_offline(&|name| {
+                assert_eq!("CI_OFFLINE", name);
+                value.map(String::from)
+            });
+            cfg.offline
+        };
+
+        assert!(!offline(None));
+        assert!(!offline(Some("")));
+        assert!(!offline(Some("0")));
+        assert!(!offline(Some("False")));
+        assert!(offline(Some("1")));
+        assert!(offline(Some("true")));
+    }
+
+    #[test]
+    fn test_cache_signing_key() {
+        let env = |name: &str| match name {
+            "CACHE_KEY" => Some(String::from("secret")),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        };
+
+        let mut cfg = Config::default();
+        assert_eq!(None, cfg.cache_signing_key(&env).unwrap());
+
+        cfg.cache_signing_key_env = Some(String::from("CACHE_KEY"));
+        assert_eq!(
+            Some(b"secret".to_vec()),
+            cfg.cache_signing_key(&env).unwrap()
+        );
+
+        cfg.cache_signing_key_env = Some(String::from("EMPTY"));
+        assert!(cfg.cache_signing_key(&env).is_err());
+        cfg.cache_signing_key_env = Some(String::from("MISSING"));
+        assert!(cfg.cache_signing_key(&env).is_err());
+    }
+
+    #[test]
+    fn test_font_config_java_options() {
+        assert!(FontConfig::default().java_options().is_empty());
+
+        let font_config = FontConfig {
+            encoding: Some(String::from("UTF-8")),
+            font_dirs: vec![PathBuf::from("/book/fonts")],
+        };
+        assert_eq!(
+            vec![
+                String::from("-Dfile.encoding=UTF-8"),
+                String::from("-Dsun.java2d.fontpath=append:/book/fonts"),
+            ],
+            font_config.java_options()
+        );
+
+        // A font dir with spaces is quoted (the options are split on whitespace)
+        let font_config = FontConfig {
+            encoding: None,
+            font_dirs: vec![PathBuf::from("/my book/fonts")],
+        };
+        assert_eq!(
+            vec![String::from(
+                "\"-Dsun.java2d.fontpath=append:/my book/fonts\""
+            )],
+            font_config.java_options()
+        );
+    }
+
+    #[test]
+    fn test_java_options() {
+        assert!(Config::default().java_options().is_empty());
+
+        let cfg = Config::from_options(json!({
+            "limit-size": 8192,
+            "security-profile": "ALLOWLIST",
+            "include-allowlist": ["/book/common", "https://example.com/lib", "/book/more"],
+        }))
+        .unwrap();
+        assert_eq!(Some(SecurityProfile::Allowlist), cfg.security_profile);
+        let separator = if cfg!(windows) { ';' } else { ':' };
+        assert_eq!(
+            vec![
+                String::from("-DPLANTUML_LIMIT_SIZE=8192"),
+                String::from("-DPLANTUML_SECURITY_PROFILE=ALLOWLIST"),
+                format!("-Dplantuml.allowlist.path=/book/common{separator}/book/more"),
+                String::from("-Dplantuml.allowlist.url=https://example.com/lib"),
+            ],
+            cfg.java_options()
+        );
+
+        // Paths are joined with the platform's path separator, and quoted when they contain
+        // whitespace (the options are split on whitespace)
+        let cfg = Config {
+            include_allowlist: vec![String::from("/my book/common"), String::from("/book")],
+            ..Config::default()
+        };
+        assert_eq!(
+            vec![format!(
+                "\"-Dplantuml.allowlist.path=/my book/common{separator}/book\""
+            )],
+            cfg.java_options()
+        );
+    }
+
+    #[test]
+    fn test_cache_options_key() {
+        assert_eq!(None, Config::default().cache_options_key());
+
+        let cfg = Config {
+            security_profile: Some(SecurityProfile::Allowlist),
+            include_allowlist: vec![String::from("/book/common"), String::from("/book/more")],
+            ..Config::default()
+        };
+        assert_eq!(
+            Some(String::from(
+                "security-profile=ALLOWLIST include-allowlist=/book/common;/book/more"
+            )),
+            cfg.cache_options_key()
+        );
+
+        let cfg = Config {
+            font_config: FontConfig {
+                encoding: Some(String::from("UTF-8")),
+                font_dirs: vec![PathBuf::from("/book/fonts")],
+            },
+            ..Config::default()
+        };
+        assert_eq!(
+            Some(String::from("encoding=UTF-8 font-dirs=/book/fonts")),
+            cfg.cache_options_key()
+        );
+    }
+
+    #[test]
+    fn test_max_parallel() {
+        let mut cfg = Config {
+            shell_max_parallel: 2,
+            server_max_parallel: 8,
+            ..Config::default()
+        };
+        assert_eq!(2, cfg.max_parallel());
+
+        cfg.plantuml_cmd = Some(String::from("https://www.plantuml.com/plantuml"));
+        assert_eq!(8, cfg.max_parallel());
+
+        cfg.server_max_parallel = 0;
+        assert_eq!(1, cfg.max_parallel());
+    }
+
+    #[test]
+    fn test_cache_backend_key() {
+        let mut cfg = Config::default();
+        assert_eq!(Some(String::from("shell")), cfg.cache_backend_key());
+
+        cfg.plantuml_cmd = Some(String::from("java -jar plantuml.jar"));
+        assert_eq!(Some(String::from("shell")), cfg.cache_backend_key());
+
+        cfg.plantuml_cmd = Some(String::from("https://www.plantuml.com/plantuml/"));
+        assert_eq!(
+            Some(String::from("https://www.plantuml.com/plantuml")),
+            cfg.cache_backend_key()
+        );
+
+        cfg.share_cache_across_backends = true;
+        assert_eq!(None, cfg.cache_backend_key());
+    }
+
+    #[test]
+    fn test_interpolate_env() {
+        let env = |name: &str| match name {
+            "PLANTUML_JAR" => Some(String::from("/opt/plantuml.jar")),
+            "HOME" => Some(String::from("/home/foo")),
+            _ => None,
+        };
+
+        assert_eq!(
+            "java -jar /opt/plantuml.jar",
+            interpolate_env("java -jar ${PLANTUML_JAR}", "key", &env).unwrap()
+        );
+        assert_eq!(
+            "/home/foo/a/home/foo",
+            interpolate_env("${HOME}/a${HOME}", "key", &env).unwrap()
+        );
+        assert_eq!(
+            "no vars $HOME",
+            interpolate_env("no vars $HOME", "key", &env).unwrap()
+        );
+        assert_eq!(
+            "${HOME} /home/foo",
+            interpolate_env("$${HOME} ${HOME}", "key", &env).unwrap()
+        );
+
+        let error = interpolate_env("java -jar ${MISSING}", "plantuml-cmd", &env).unwrap_err();
+        assert_eq!(
+            "Environment variable 'MISSING' used in plantuml-cmd 'java -jar ${MISSING}' is not defined",
+            format!("{error}")
+        );
+        assert!(interpolate_env("${HOME", "key", &env).is_err());
+    }
+
+    #[test]
+    fn test_config_interpolate_env() {
+        let env = |name: &str| match name {
+            "DIR" => Some(String::from("/opt")),
+            _ => None,
+        };
+
+        let mut cfg = Config {
+            plantuml_cmd: Some(String::from("java -jar ${DIR}/plantuml.jar")),
+            temp_dir: Some(PathBuf::from("${DIR}/tmp")),
+            extra_sources: vec![String::from("${DIR}/*.puml")],
+            post_process_cmd: Some(String::from("${DIR}/svgo {file}")),
+            java_cmd: Some(String::from("${DIR}/java -Xmx1g")),
+            plantuml_jar: Some(PathBuf::from("${DIR}/plantuml.jar")),
+            ..Config::default()
+        };
+        cfg.interpolate_env(&env).unwrap();
+        assert_eq!(
+            cfg.plantuml_cmd,
+            Some(String::from("java -jar /opt/plantuml.jar"))
+        );
+        assert_eq!(cfg.temp_dir, Some(PathBuf::from("/opt/tmp")));
+        assert_eq!(cfg.extra_sources, vec![String::from("/opt/*.puml")]);
+        assert_eq!(cfg.post_process_cmd, Some(String::from("/opt/svgo {file}")));
+        assert_eq!(cfg.java_cmd, Some(String::from("/opt/java -Xmx1g")));
+        assert_eq!(cfg.plantuml_jar, Some(PathBuf::from("/opt/plantuml.jar")));
+
+        let mut cfg = Config {
+            plantuml_cmd: Some(String::from("${MISSING}")),
+            ..Config::default()
+        };
+        assert!(cfg.interpolate_env(&env).is_err());
     }
 }
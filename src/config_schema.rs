@@ -0,0 +1,99 @@
+use crate::config::Config;
+use schemars::schema::{InstanceType, Schema, SchemaObject};
+use serde_json::Value;
+
+/// The mdBook options of every preprocessor table (see the mdBook documentation)
+fn mdbook_properties() -> Vec<(&'static str, Schema)> {
+    let of_type = |instance_type: InstanceType| SchemaObject {
+        instance_type: Some(instance_type.into()),
+        ..SchemaObject::default()
+    };
+    let string = || Schema::Object(of_type(InstanceType::String));
+    let strings = || {
+        let mut schema = of_type(InstanceType::Array);
+        schema.array().items = Some(string().into());
+        Schema::Object(schema)
+    };
+
+    vec![
+        ("command", string()),
+        ("renderers", strings()),
+        ("before", strings()),
+        ("after", strings()),
+    ]
+}
+
+/// The JSON Schema of the `[preprocessor.plantuml]` table in book.toml, for editor autocomplete
+/// and validation. It is derived from the configuration (the doc comments are the descriptions),
+/// extended with mdBook's own preprocessor options.
+pub fn config_schema() -> Value {
+    let mut schema = schemars::schema_for!(Config);
+    schema.schema.metadata().title = Some(String::from("mdbook-plantuml"));
+    schema.schema.metadata().description = Some(String::from(
+        "The [preprocessor.plantuml] options in book.toml",
+    ));
+
+    let object = schema.schema.object();
+    for (key, property) in mdbook_properties() {
+        object.properties.insert(String::from(key), property);
+    }
+    object.additional_properties = Some(Box::new(Schema::Bool(false)));
+
+    serde_json::to_value(schema).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    fn test_config_schema() {
+        let schema = config_schema();
+        let properties = &schema["properties"];
+        assert_eq!(json!("boolean"), properties["fail-on-error"]["type"]);
+        assert_eq!(json!(false), properties["fail-on-error"]["default"]);
+        assert!(properties["fail-on-error"]["description"]
+            .as_str()
+            .unwrap()
+            .starts_with("Stop the book build"));
+        assert_eq!(
+            json!(["string", "null"]),
+            properties["plantuml-cmd"]["type"]
+        );
+        assert_eq!(json!(0.0), properties["limit-size"]["minimum"]);
+        // The allowed values of the enums (one variant per value, with its doc as description)
+        let values = |name: &str| -> Vec<Value> {
+            schema["definitions"][name]["oneOf"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|variant| variant["enum"][0].clone())
+                .collect()
+        };
+        assert_eq!(
+            vec![json!("relative"), json!("absolute")],
+            values("LinkStyle")
+        );
+        assert_eq!(
+            vec![json!("ci"), json!("local"), json!("fast")],
+            values("Profile")
+        );
+        assert_eq!(json!("relative"), properties["link-style"]["default"]);
+        assert_eq!(
+            json!({ "type": "string" }),
+            properties["extra-sources"]["items"]
+        );
+        assert_eq!(
+            json!(["string", "null"]),
+            schema["definitions"]["FontConfig"]["properties"]["encoding"]["type"]
+        );
+        assert_eq!(json!({ "type": "string" }), properties["command"]);
+        assert_eq!(json!(false), schema["additionalProperties"]);
+
+        // The options set by the preprocessor itself are not part of the schema
+        assert!(properties.get("revalidate").is_none());
+        assert!(properties.get("book-root").is_none());
+    }
+}
@@ -0,0 +1,29 @@
+use anyhow::Result;
+use std::path::PathBuf;
+
+/// Restores the current working dir when dropped, so it is also restored when rendering the
+/// chapters (which changes the working dir to the chapter's dir) bails out early
+pub struct CwdGuard {
+    org_cwd: PathBuf,
+}
+
+impl CwdGuard {
+    /// Remember the current working dir
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            org_cwd: std::env::current_dir()?,
+        })
+    }
+}
+
+impl Drop for CwdGuard {
+    fn drop(&mut self) {
+        if let Err(e) = std::env::set_current_dir(&self.org_cwd) {
+            log::error!(
+                "Failed to restore the working dir to {:?} ({}).",
+                self.org_cwd,
+                e
+            );
+        }
+    }
+}
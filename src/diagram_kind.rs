@@ -0,0 +1,146 @@
+/// The kind of PlantUML diagram, detected from the (first) @start tag of the code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagramKind {
+    /// @startuml, or no @start tag at all
+    Uml,
+    Ditaa,
+    Mindmap,
+    Gantt,
+    Wbs,
+    Json,
+    Yaml,
+    Salt,
+    /// Any other @start tag (e.g. @startregex)
+    Other,
+}
+
+impl DiagramKind {
+    /// Detect the diagram kind from the PlantUML code
+    pub fn detect(plantuml_code: &str) -> Self {
+        plantuml_code
+            .match_indices("@start")
+            .next()
+            .map_or(Self::Uml, |(pos, start)| {
+                let tag = plantuml_code[pos + start.len()..]
+                    .split(|c: char| !c.is_ascii_alphanumeric())
+                    .next()
+                    .unwrap_or_default();
+                Self::from_tag(tag)
+            })
+    }
+
+    fn from_tag(tag: &str) -> Self {
+        match tag {
+            "uml" => Self::Uml,
+            "ditaa" => Self::Ditaa,
+            "mindmap" => Self::Mindmap,
+            "gantt" => Self::Gantt,
+            "wbs" => Self::Wbs,
+            "json" => Self::Json,
+            "yaml" => Self::Yaml,
+            "salt" => Self::Salt,
+            _ => Self::Other,
+        }
+    }
+
+    /// The diagram kind's name (as used in the @start tag), for log and error messages
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Uml => "uml",
+            Self::Ditaa => "ditaa",
+            Self::Mindmap => "mindmap",
+            Self::Gantt => "gantt",
+            Self::Wbs => "wbs",
+            Self::Json => "json",
+            Self::Yaml => "yaml",
+            Self::Salt => "salt",
+            Self::Other => "PlantUML",
+        }
+    }
+
+    /// The image format used when the code block does not specify one
+    pub const fn default_format(self) -> &'static str {
+        match self.forced_format() {
+            Some(format) => format,
+            None => "svg",
+        }
+    }
+
+    /// Returns true if the diagram kind supports the styling directives (skinparam, !theme,
+    /// header and footer)
+    pub const fn supports_styling(self) -> bool {
+        matches!(self, Self::Uml | Self::Mindmap | Self::Gantt | Self::Wbs)
+    }
+
+    /// Returns false if PlantUML's text output (txt, utxt) of this kind is unreadable
+    pub const fn supports_text(self) -> bool {
+        !matches!(self, Self::Mindmap | Self::Gantt | Self::Wbs)
+    }
+
+    /// The only image format PlantUML supports for this kind (if any)
+    pub const fn forced_format(self) -> Option<&'static str> {
+        match self {
+            // ditaa only has png format support afaik
+            Self::Ditaa => Some("png"),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(DiagramKind::Uml, DiagramKind::detect(""));
+        assert_eq!(DiagramKind::Uml, DiagramKind::detect("A --|> B"));
+        assert_eq!(
+            DiagramKind::Uml,
+            DiagramKind::detect("@startuml\nA --|> B\n@enduml")
+        );
+        assert_eq!(
+            DiagramKind::Uml,
+            DiagramKind::detect("@startuml(id=foo)\n@enduml")
+        );
+        assert_eq!(DiagramKind::Ditaa, DiagramKind::detect("@startditaa"));
+        assert_eq!(
+            DiagramKind::Ditaa,
+            DiagramKind::detect("Also when not at the start of the code block @startditaa")
+        );
+        assert_eq!(
+            DiagramKind::Mindmap,
+            DiagramKind::detect("@startmindmap name\n")
+        );
+        assert_eq!(
+            DiagramKind::Gantt,
+            DiagramKind::detect("\n  @startgantt\r\n")
+        );
+        assert_eq!(DiagramKind::Wbs, DiagramKind::detect("@startwbs"));
+        assert_eq!(
+            DiagramKind::Json,
+            DiagramKind::detect("@startjson\n{}\n@endjson")
+        );
+        assert_eq!(DiagramKind::Yaml, DiagramKind::detect("@startyaml"));
+        assert_eq!(DiagramKind::Salt, DiagramKind::detect("@startsalt"));
+        assert_eq!(DiagramKind::Other, DiagramKind::detect("@startregex"));
+
+        // The first diagram determines the kind
+        assert_eq!(
+            DiagramKind::Gantt,
+            DiagramKind::detect("@startgantt\n@endgantt\n@startditaa")
+        );
+    }
+
+    #[test]
+    fn test_formats() {
+        assert_eq!("png", DiagramKind::Ditaa.default_format());
+        assert_eq!(Some("png"), DiagramKind::Ditaa.forced_format());
+        assert!(DiagramKind::Uml.supports_text());
+        assert!(!DiagramKind::Gantt.supports_text());
+        assert!(!DiagramKind::Mindmap.supports_text());
+        assert_eq!("svg", DiagramKind::Uml.default_format());
+        assert_eq!(None, DiagramKind::Mindmap.forced_format());
+    }
+}
@@ -1,7 +1,10 @@
+use crate::long_path::long_path;
 use std::collections::HashSet;
 use std::fs;
 use std::path::{Path, PathBuf};
 
+pub const GITIGNORE_FILE_NAME: &str = ".gitignore";
+
 /// Remove all files (not sub dirs and their files) that are not flagged as keep
 /// from the given directory. Used for removing stale cached image files.
 ///
@@ -40,12 +43,13 @@ impl DirCleaner {
 
     pub fn keep(&mut self, img_path: &Path) {
         log::debug!("DirCleaner - Keeping {}", img_path.to_string_lossy());
-        self.files.remove(img_path);
+        self.files.remove(&long_path(img_path.to_path_buf()));
     }
 
     fn files(img_path: &Path) -> HashSet<PathBuf> {
         let mut files = HashSet::new();
-        match std::fs::read_dir(img_path) {
+        // The paths are extended-length paths when too long on windows, both when listed and kept
+        match std::fs::read_dir(long_path(img_path.to_path_buf())) {
             Err(e) => {
                 log::error!(
                     "DirCleaner - Failed to list directory contents of {} ({}).",
@@ -57,8 +61,9 @@ impl DirCleaner {
                 for entry in entries.flatten() {
                     // Here, `entry` is a `DirEntry`.
                     if let Ok(file_type) = entry.file_type() {
-                        if file_type.is_file() {
-                            files.insert(entry.path());
+                        // Never remove the (generated or user provided) .gitignore
+                        if file_type.is_file() && entry.file_name() != GITIGNORE_FILE_NAME {
+                            files.insert(long_path(entry.path()));
                             log::debug!(
                                 "DirCleaner - Found existing file {}",
                                 entry.path().to_string_lossy()
@@ -118,6 +123,7 @@ mod tests {
         assert!(create_file(Path::new("foo.txt"), false));
         assert!(create_file(Path::new("bar.txt"), false));
         assert!(create_file(Path::new("baz.txt"), false));
+        assert!(create_file(Path::new(GITIGNORE_FILE_NAME), true));
         assert!(std::fs::create_dir(file_path(target_path, Path::new("skipped"))).is_ok());
         assert!(create_file(Path::new("skipped/skippedfile.txt"), true));
 
@@ -171,4 +177,25 @@ mod tests {
         // The directory should now be empty
         assert_eq!(expected_files, DirCleaner::files(&target_path));
     }
+
+    #[test]
+    fn handles_long_paths() {
+        // Deeper than MAX_PATH on windows
+        let dir = tempdir().unwrap();
+        let target_path = dir
+            .path()
+            .join(["some-deeply-nested-chapter-dir"; 10].join("/"));
+        fs::create_dir_all(&target_path).unwrap();
+        let kept = target_path.join(format!("{}.svg", "0123456789abcdef".repeat(4)));
+        fs::write(&kept, "").unwrap();
+        fs::write(target_path.join("obsolete.svg"), "").unwrap();
+
+        {
+            let mut cleaner = DirCleaner::new(&target_path);
+            cleaner.keep(&kept);
+        }
+
+        assert!(kept.is_file());
+        assert!(!target_path.join("obsolete.svg").exists());
+    }
 }
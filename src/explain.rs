@@ -0,0 +1,244 @@
+use crate::backend::factory::tool_version;
+use crate::failure::{tag, FailureKind};
+use crate::fingerprint::RenderFingerprint;
+use crate::include_cache::IncludeCache;
+use crate::markings::DiagramMarkings;
+use crate::pipeline::{diagram_source, plantuml_diagrams, DiagramChapter};
+use crate::renderer::{
+    image_path, is_cached, is_empty_cache_entry, normalize_source, render_format,
+    text_fallback_format,
+};
+use crate::CACHE_DIR;
+use crate::{config_from_book_config, image_dir, resolve_config_paths};
+use anyhow::{bail, format_err, Context, Result};
+use mdbook::book::BookItem;
+use mdbook::MDBook;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+/// Explain the cache lookup of the diagrams in a chapter, for debugging diagrams that are
+/// rendered on every build. Lists the computed hash, the inputs of the hash, the cache entry
+/// and whether (or why not) the cached image is reused per diagram.
+/// # Arguments
+/// * `book_dir` - The book's root directory (containing book.toml)
+/// * `chapter_file` - The chapter's markdown file
+/// * `block` - The (1 based) index of the diagram in the chapter, None for all diagrams
+pub fn explain_cache(book_dir: &Path, chapter_file: &Path, block: Option<usize>) -> Result<String> {
+    let md = MDBook::load(book_dir)
+        .map_err(|e| format_err!("{:#}", e))
+        .with_context(|| format!("Failed to load the book from {book_dir:?}"))?;
+    let book_cfg = &md.config;
+    let book_root = dunce::canonicalize(book_dir)?;
+    let mut cfg = config_from_book_config(book_cfg)?;
+    resolve_config_paths(&mut cfg, &book_root);
+    let signing_key = cfg
+        .cache_signing_key(&|name| std::env::var(name).ok())
+        .map_err(tag(FailureKind::Config))?;
+
+    let markdown = fs::read_to_string(chapter_file)
+        .with_context(|| format!("Failed to read the chapter {chapter_file:?}"))?;
+    let chapter_file = dunce::canonicalize(chapter_file)?;
+    let chapter_dir = chapter_file.parent().unwrap_or(&book_root);
+    let img_root = image_dir(&book_root, &book_cfg.book.src, &cfg);
+    let backend_key = cfg.cache_backend_key();
+    let tool_version = tool_version(&cfg, &book_root.join(CACHE_DIR));
+    let options_key = cfg.cache_options_key();
+    let chapter_name = md
+        .book
+        .iter()
+        .find_map(|item| match item {
+            BookItem::Chapter(chapter) => chapter
+                .path
+                .as_ref()
+                .filter(|path| {
+                    dunce::canonicalize(book_root.join(&book_cfg.book.src).join(path)).ok()
+                        == Some(chapter_file.clone())
+                })
+                .map(|_| chapter.name.as_str()),
+            _ => None,
+        })
+        .unwrap_or_default();
+    let markings = DiagramMarkings::from_config(&cfg, book_cfg.book.title.as_deref())
+        .for_chapter(chapter_name);
+
+    // Read only, the memoized hashes are not saved
+    let include_cache = IncludeCache::new(&book_root, &cfg.hash_ignore, None);
+
+    let chapter = DiagramChapter {
+        include_dir: chapter_dir,
+        markings: &markings,
+    };
+    let diagrams = plantuml_diagrams(&markdown, cfg.strict_info_string);
+    if let Some(block) = block {
+        if block == 0 || block > diagrams.len() {
+            bail!(
+                "The chapter {:?} has no diagram {} (it has {} diagram(s))",
+                chapter_file,
+                block,
+                diagrams.len()
+            );
+        }
+    }
+
+    let mut report = String::new();
+    for (index, diagram) in diagrams.iter().enumerate() {
+        if block.map_or(false, |block| block != index + 1) {
+            continue;
+        }
+
+        // Same as the chapter processing, the includes are expanded before hashing
+        let code = diagram_source(&chapter, diagram, &cfg)
+            .with_context(|| format!("Failed to prepare the source of diagram {}", index + 1))?;
+        let code = if cfg.normalize_source {
+            normalize_source(&code).into_owned()
+        } else {
+            code
+        };
+        let image_format = if cfg.text_fallback {
+            text_fallback_format(&code, &diagram.format)
+        } else {
+            &diagram.format
+        };
+        let image_format = render_format(cfg.forced_image_format.as_deref(), image_format);
+        let fingerprint = RenderFingerprint::new(&code)
+            .with_includes(chapter_dir, &include_cache)
+            .with_format(image_format)
+            .with_backend(backend_key.as_deref())
+            .with_tool_version(tool_version.as_deref())
+            .with_options(options_key.as_deref());
+        let hash = fingerprint.hash();
+        let cache_entry = image_path(&img_root, &hash, &code, image_format);
+
+        let _ = writeln!(report, "Diagram {} ({})", index + 1, diagram.format);
+        let _ = writeln!(report, "  hash: {hash}");
+        let _ = writeln!(report, "  inputs:");
+        for input in fingerprint.to_string().lines() {
+            let _ = writeln!(report, "    {input}");
+        }
+        let _ = writeln!(
+            report,
+            "  options: normalize-source = {}, share-cache-across-backends = {}, \
+             forced-image-format = {}, cache-signing-key-env = {}",
+            cfg.normalize_source,
+            cfg.share_cache_across_backends,
+            cfg.forced_image_format.as_deref().unwrap_or("none"),
+            cfg.cache_signing_key_env.as_deref().unwrap_or("none")
+        );
+        let _ = writeln!(report, "  cache entry: {}", cache_entry.display());
+        let _ = writeln!(
+            report,
+            "  reused: {}",
+            reuse_reason(&cache_entry, signing_key.as_deref())
+        );
+    }
+
+    if report.is_empty() {
+        report = format!("The chapter {chapter_file:?} has no PlantUML diagrams\n");
+    }
+
+    Ok(report)
+}
+
+/// Whether (or why not) the cached image is reused
+fn reuse_reason(cache_entry: &Path, signing_key: Option<&[u8]>) -> String {
+    if !cache_entry.exists() {
+        return String::from(
+            "no, the image is not cached (it was never rendered, or the source, an included file, \
+             the format or the backend changed since)",
+        );
+    }
+    if !is_cached(cache_entry, signing_key) {
+        return String::from("no, the cached image has no valid signature");
+    }
+
+    if is_empty_cache_entry(cache_entry) {
+        String::from("no, the cached image is empty")
+    } else {
+        String::from("yes")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::hash_string;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_explain_cache() {
+        let book_dir = tempdir().unwrap();
+        let src_dir = book_dir.path().join("src");
+        fs::create_dir_all(&src_dir).unwrap();
+        fs::write(
+            book_dir.path().join("book.toml"),
+            "[preprocessor.plantuml]\nshare-cache-across-backends = true\n",
+        )
+        .unwrap();
+        fs::write(
+            src_dir.join("SUMMARY.md"),
+            "# Summary\n\n- [Intro](intro.md)\n",
+        )
+        .unwrap();
+        fs::write(src_dir.join("common.puml"), "skinparam monochrome true").unwrap();
+        let chapter = src_dir.join("intro.md");
+        fs::write(
+            &chapter,
+            "```plantuml\nA --|> B\n```\n```plantuml,format=png\n!include common.puml\nB\n```\n",
+        )
+        .unwrap();
+
+        // Data URIs are used by default, so the images are cached in the cache dir
+        let img_dir = book_dir.path().join(".mdbook-plantuml-cache");
+        fs::create_dir_all(&img_dir).unwrap();
+        let hash = hash_string("A --|> B\n");
+        fs::write(img_dir.join(format!("{hash}.svg")), "<svg>A</svg>").unwrap();
+
+        let report = explain_cache(book_dir.path(), &chapter, None).unwrap();
+        assert!(report.contains(&format!("Diagram 1 (svg)\n  hash: {hash}\n")));
+        assert!(report.contains(&format!("    source {hash}\n    format svg\n")));
+        assert!(report.contains("  reused: yes\n"));
+
+        // The included file is listed, the diagram is not cached
+        let report = explain_cache(book_dir.path(), &chapter, Some(2)).unwrap();
+        assert!(!report.contains("Diagram 1"));
+        assert!(report.contains(&format!(
+            "common.puml {}\n",
+            hash_string("skinparam monochrome true")
+        )));
+        assert!(report.contains("  reused: no, the image is not cached"));
+
+        assert!(explain_cache(book_dir.path(), &chapter, Some(3)).is_err());
+
+        // The footer (with the chapter's name) is part of the hash
+        fs::write(
+            book_dir.path().join("book.toml"),
+            "[book]\ntitle = \"My book\"\n\
+             [preprocessor.plantuml]\nshare-cache-across-backends = true\n\
+             footer = \"{book} - {chapter}\"\n",
+        )
+        .unwrap();
+        let report = explain_cache(book_dir.path(), &chapter, Some(1)).unwrap();
+        assert!(report.contains(&format!(
+            "  hash: {}\n",
+            hash_string("A --|> B\nfooter My book - Intro\n")
+        )));
+    }
+
+    #[test]
+    fn test_reuse_reason() {
+        let img_dir = tempdir().unwrap();
+        let image = img_dir.path().join("foo.svg");
+        assert!(reuse_reason(&image, None).starts_with("no, the image is not cached"));
+
+        fs::write(&image, "<svg></svg>").unwrap();
+        assert_eq!(
+            "no, the cached image has no valid signature",
+            reuse_reason(&image, Some(b"secret"))
+        );
+        assert_eq!("yes", reuse_reason(&image, None));
+
+        fs::write(&image, "").unwrap();
+        assert_eq!("no, the cached image is empty", reuse_reason(&image, None));
+    }
+}
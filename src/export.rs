@@ -0,0 +1,234 @@
+use crate::backend;
+use crate::cwd_guard::CwdGuard;
+use crate::failure::{tag, FailureKind};
+use crate::includes::expand_includes;
+use crate::pipeline::{plantuml_diagrams, Diagram};
+use crate::renderer::{image_extension, validate_image_data};
+use crate::CACHE_DIR;
+use crate::{config_from_book_config, resolve_config_paths};
+use anyhow::{Context, Result};
+use mdbook::book::BookItem;
+use mdbook::MDBook;
+use std::fs;
+use std::path::Path;
+
+/// Export the PlantUML diagrams of a book as .puml files (and optionally the
+/// rendered images).
+/// The files are named after the chapter, the diagram's index in the chapter
+/// and the optional diagram id (e.g. `chapter-1-2-my-diagram.puml`).
+/// # Arguments
+/// * `book_dir` - The book's root directory (containing book.toml)
+/// * `out_dir` - The directory to write the diagrams to
+/// * `render` - Also render the diagrams (using the book's PlantUML config)
+/// * `revalidate` - Re-run the PlantUML command detection
+pub fn export_diagrams(
+    book_dir: &Path,
+    out_dir: &Path,
+    render: bool,
+    revalidate: bool,
+) -> Result<()> {
+    let md = MDBook::load(book_dir)
+        .map_err(|e| anyhow::format_err!("{:#}", e))
+        .with_context(|| format!("Failed to load the book from {book_dir:?}"))?;
+    let book_root = dunce::canonicalize(book_dir)?;
+
+    fs::create_dir_all(out_dir)
+        .with_context(|| format!("Failed to create the export dir {out_dir:?}"))?;
+    let out_dir = dunce::canonicalize(out_dir)?;
+
+    let mut cfg = config_from_book_config(&md.config)?;
+    let backend = if render {
+        cfg.revalidate = revalidate;
+        resolve_config_paths(&mut cfg, &book_root);
+        Some(
+            backend::factory::create(&cfg, &book_root, &book_root.join(CACHE_DIR))
+                .map_err(tag(FailureKind::BackendNotFound))?,
+        )
+    } else {
+        None
+    };
+
+    let cwd_guard = CwdGuard::new()?;
+    let src_dir = book_root.join(&md.config.book.src);
+    let include_root = cfg.restrict_includes_to_book.then_some(book_root.as_path());
+    let mut result = Ok(());
+    let mut count = 0;
+    for item in md.book.iter() {
+        if let BookItem::Chapter(chapter) = item {
+            if let Some(chapter_path) = &chapter.path {
+                let slug = chapter_slug(chapter_path);
+                let chapter_file = src_dir.join(chapter_path);
+                let chapter_dir = chapter_file.parent().unwrap_or(&src_dir);
+                for (index, diagram) in plantuml_diagrams(&chapter.content, cfg.strict_info_string)
+                    .iter()
+                    .enumerate()
+                {
+                    let file_stem = diagram_file_stem(&slug, index + 1, diagram);
+                    let code = match expand_includes(&diagram.code, chapter_dir, include_root) {
+                        Ok(code) => code,
+                        Err(e) => {
+                            result = Err(e.context(format!(
+                                "Failed to export diagram {} in chapter '{}' ({:?})",
+                                index + 1,
+                                chapter.name,
+                                chapter_path
+                            )));
+                            break;
+                        }
+                    };
+                    fs::write(out_dir.join(format!("{file_stem}.puml")), code.as_ref())?;
+                    count += 1;
+
+                    if let Some(backend) = &backend {
+                        // Render from the chapter dir, so relative includes work
+                        std::env::set_current_dir(chapter_dir)?;
+
+                        let extension = image_extension(&code, &diagram.format);
+                        let image_path = out_dir.join(format!("{file_stem}.{extension}"));
+                        result = backend
+                            .render_from_string(&code, &diagram.format)
+                            .and_then(|data| {
+                                validate_image_data(&data, &image_path)?;
+                                fs::write(&image_path, data)?;
+                                Ok(())
+                            })
+                            .with_context(|| {
+                                format!(
+                                    "Failed to render diagram {} in chapter '{}' ({:?})",
+                                    index + 1,
+                                    chapter.name,
+                                    chapter_path
+                                )
+                            })
+                            .map_err(tag(FailureKind::Render));
+                        if result.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        if result.is_err() {
+            break;
+        }
+    }
+
+    //Restore the current working dir
+    drop(cwd_guard);
+    result?;
+
+    log::info!("Exported {} PlantUML diagram(s) to {:?}", count, out_dir);
+    Ok(())
+}
+
+/// Turn the chapter path into a file name friendly slug (e.g. "Part 1/Intro.md"
+/// becomes "part-1-intro")
+fn chapter_slug(chapter_path: &Path) -> String {
+    slugify(&chapter_path.with_extension("").to_string_lossy())
+}
+
+fn slugify(text: &str) -> String {
+    let mut slug = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_alphanumeric() {
+            slug.extend(c.to_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+
+    slug.trim_end_matches('-').to_string()
+}
+
+/// The export file name (without extension) of a diagram
+/// # Arguments
+/// * `chapter_slug` - The slug of the chapter the diagram is in
+/// * `index` - The (1 based) index of the diagram in the chapter
+/// * `diagram` - The diagram
+fn diagram_file_stem(chapter_slug: &str, index: usize, diagram: &Diagram) -> String {
+    match diagram.id.as_deref().map(slugify) {
+        Some(id) if !id.is_empty() => format!("{chapter_slug}-{index}-{id}"),
+        _ => format!("{chapter_slug}-{index}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    fn diagram(id: Option<&str>) -> Diagram {
+        Diagram {
+            code: String::from("@startuml\nA --|> B\n@enduml\n"),
+            format: String::from("svg"),
+            id: id.map(String::from),
+            alt_text: None,
+            data_kind: None,
+        }
+    }
+
+    #[test]
+    fn test_chapter_slug() {
+        assert_eq!("intro", chapter_slug(Path::new("intro.md")));
+        assert_eq!(
+            "part-1-chapter-2",
+            chapter_slug(Path::new("Part 1/Chapter_2.md"))
+        );
+        assert_eq!("a-b", chapter_slug(Path::new("--A--/(b).md")));
+    }
+
+    #[test]
+    fn test_diagram_file_stem() {
+        assert_eq!("intro-1", diagram_file_stem("intro", 1, &diagram(None)));
+        assert_eq!(
+            "intro-2-my-diagram",
+            diagram_file_stem("intro", 2, &diagram(Some("my-diagram")))
+        );
+        // Ids cannot escape the export dir
+        assert_eq!(
+            "intro-3-etc-passwd",
+            diagram_file_stem("intro", 3, &diagram(Some("../etc/passwd")))
+        );
+        assert_eq!(
+            "intro-4",
+            diagram_file_stem("intro", 4, &diagram(Some("/")))
+        );
+    }
+
+    #[test]
+    fn test_export_diagrams() {
+        let book_dir = tempdir().unwrap();
+        let src_dir = book_dir.path().join("src");
+        fs::create_dir_all(src_dir.join("nested")).unwrap();
+        fs::write(
+            src_dir.join("SUMMARY.md"),
+            "# Summary\n\n- [Intro](intro.md)\n- [Nested](nested/chapter.md)\n",
+        )
+        .unwrap();
+        fs::write(
+            src_dir.join("intro.md"),
+            "# Intro\n```plantuml\nfoo\n```\n```puml,id=bar\nbar\n```\n",
+        )
+        .unwrap();
+        fs::write(src_dir.join("nested/chapter.md"), "```plantuml\nbaz\n```\n").unwrap();
+
+        let out_dir = book_dir.path().join("diagrams");
+        export_diagrams(book_dir.path(), &out_dir, false, false).unwrap();
+
+        assert_eq!(
+            "foo\n",
+            fs::read_to_string(out_dir.join("intro-1.puml")).unwrap()
+        );
+        assert_eq!(
+            "bar\n",
+            fs::read_to_string(out_dir.join("intro-2-bar.puml")).unwrap()
+        );
+        assert_eq!(
+            "baz\n",
+            fs::read_to_string(out_dir.join("nested-chapter-1.puml")).unwrap()
+        );
+        assert_eq!(3, fs::read_dir(&out_dir).unwrap().count());
+    }
+}
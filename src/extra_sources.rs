@@ -0,0 +1,216 @@
+use crate::renderer::Renderer;
+use crate::resolve_book_path;
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Render the PlantUML files matching the extra-sources globs into the image
+/// dir (as <file stem>.<extension>), so chapters can reference them with plain
+/// image links. Rendering stops at the first error.
+/// # Arguments
+/// * `renderer` - The renderer to use
+/// * `book_root` - The book's root directory (the globs are relative to it)
+/// * `patterns` - The glob patterns
+pub fn render_extra_sources(
+    renderer: &Renderer,
+    book_root: &Path,
+    patterns: &[String],
+) -> Result<()> {
+    let mut rendered: HashMap<PathBuf, PathBuf> = HashMap::new();
+    for source in find_sources(book_root, patterns) {
+        log::info!("Rendering PlantUML source {:?}", source);
+
+        // Change the working dir so the PlantUML `!include` directive can be used using relative includes
+        if let Some(source_dir) = source.parent() {
+            if let Err(e) = std::env::set_current_dir(source_dir) {
+                log::warn!("Failed to change working dir to {:?}, PlantUML might not be able to render includes ({}).", source_dir, e);
+            }
+        }
+
+        let image = renderer.render_file(&source)?;
+        if let Some(other) = rendered.insert(image.clone(), source.clone()) {
+            bail!(
+                "The PlantUML sources {:?} and {:?} are both rendered to {:?}, please rename one of them.",
+                other,
+                source,
+                image
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Find the files matching any of the glob patterns (relative to the root dir, or absolute)
+/// The patterns support `*` and `?` (within a path component) and `**` (any
+/// number of directories). Returns the matching files in sorted order.
+fn find_sources(root: &Path, patterns: &[String]) -> Vec<PathBuf> {
+    let mut sources = vec![];
+    for pattern in patterns {
+        let pattern_parts: Vec<&str> = pattern
+            .split(['/', '\\'])
+            .filter(|part| !part.is_empty() && *part != ".")
+            .collect();
+
+        // No need to search the directories before the first wildcard
+        let literal_len = pattern_parts
+            .iter()
+            .position(|part| part.contains(['*', '?']))
+            .unwrap_or(pattern_parts.len());
+        let mut literal_path = pattern_parts[..literal_len].join("/");
+        if pattern.starts_with(['/', '\\']) {
+            literal_path.insert(0, '/');
+        }
+        let base_dir = resolve_book_path(root, Path::new(&literal_path));
+
+        let mut files = vec![];
+        collect_files(&base_dir, &mut vec![], &mut files);
+        if literal_len == pattern_parts.len() && base_dir.is_file() {
+            files.push((base_dir.clone(), vec![]));
+        }
+
+        for (file, rel_parts) in files {
+            let rel_parts: Vec<&str> = rel_parts.iter().map(String::as_str).collect();
+            if matches_parts(&pattern_parts[literal_len..], &rel_parts) {
+                sources.push(file);
+            }
+        }
+    }
+
+    sources.sort();
+    sources.dedup();
+    sources
+}
+
+/// Recursively collect all files in dir, with their path components relative
+/// to the start dir. Hidden directories (like .git) are skipped.
+fn collect_files(dir: &Path, rel_parts: &mut Vec<String>, files: &mut Vec<(PathBuf, Vec<String>)>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let file_type = match entry.file_type() {
+            Ok(file_type) => file_type,
+            Err(_) => continue,
+        };
+
+        rel_parts.push(name.clone());
+        if file_type.is_dir() {
+            if !name.starts_with('.') {
+                collect_files(&entry.path(), rel_parts, files);
+            }
+        } else if file_type.is_file() {
+            files.push((entry.path(), rel_parts.clone()));
+        }
+        rel_parts.pop();
+    }
+}
+
+/// Match path components against glob pattern components
+pub fn matches_parts(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|skip| matches_parts(rest, &path[skip..])),
+        Some((part, rest)) => match path.split_first() {
+            Some((name, path_rest)) => {
+                let part: Vec<char> = part.chars().collect();
+                let name: Vec<char> = name.chars().collect();
+                matches_wildcards(&part, &name) && matches_parts(rest, path_rest)
+            }
+            None => false,
+        },
+    }
+}
+
+/// Match a single path component against a pattern with `*` and `?` wildcards
+fn matches_wildcards(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|skip| matches_wildcards(rest, &name[skip..])),
+        Some(('?', rest)) => !name.is_empty() && matches_wildcards(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && matches_wildcards(rest, &name[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    fn matches(pattern: &str, path: &str) -> bool {
+        let pattern: Vec<&str> = pattern.split('/').collect();
+        let path: Vec<&str> = path.split('/').collect();
+        matches_parts(&pattern, &path)
+    }
+
+    #[test]
+    fn test_matches_parts() {
+        assert!(matches("foo.puml", "foo.puml"));
+        assert!(!matches("foo.puml", "bar.puml"));
+        assert!(matches("*.puml", "foo.puml"));
+        assert!(!matches("*.puml", "foo.puml.bak"));
+        assert!(!matches("*.puml", "sub/foo.puml"));
+        assert!(matches("f?o.puml", "foo.puml"));
+        assert!(!matches("f?o.puml", "fo.puml"));
+        assert!(matches("**/*.puml", "foo.puml"));
+        assert!(matches("**/*.puml", "a/b/foo.puml"));
+        assert!(matches("a/**/foo.*", "a/foo.puml"));
+        assert!(matches("a/**/foo.*", "a/b/c/foo.puml"));
+        assert!(!matches("a/**/foo.*", "b/foo.puml"));
+    }
+
+    #[test]
+    fn test_find_sources() {
+        let root = tempdir().unwrap();
+        let diagrams = root.path().join("diagrams");
+        fs::create_dir_all(diagrams.join("sub")).unwrap();
+        fs::create_dir_all(diagrams.join(".hidden")).unwrap();
+        for file in [
+            "diagrams/a.puml",
+            "diagrams/b.txt",
+            "diagrams/sub/c.puml",
+            "diagrams/.hidden/d.puml",
+            "e.puml",
+        ]
+        .iter()
+        {
+            fs::write(root.path().join(file), "").unwrap();
+        }
+
+        assert_eq!(
+            vec![diagrams.join("a.puml"), diagrams.join("sub/c.puml")],
+            find_sources(root.path(), &[String::from("diagrams/**/*.puml")])
+        );
+        assert_eq!(
+            vec![diagrams.join("a.puml")],
+            find_sources(root.path(), &[String::from("./diagrams/*.puml")])
+        );
+
+        // Duplicates are removed, literal paths work too
+        assert_eq!(
+            vec![diagrams.join("a.puml"), root.path().join("e.puml")],
+            find_sources(
+                root.path(),
+                &[
+                    String::from("e.puml"),
+                    String::from("diagrams/a.puml"),
+                    String::from("diagrams/*.puml"),
+                ]
+            )
+        );
+
+        assert!(find_sources(root.path(), &[String::from("missing/**/*.puml")]).is_empty());
+
+        // Absolute patterns
+        let pattern = format!("{}/*.puml", diagrams.to_string_lossy());
+        assert_eq!(
+            vec![diagrams.join("a.puml")],
+            find_sources(Path::new("somewhere/else"), &[pattern])
+        );
+    }
+}
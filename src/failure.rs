@@ -0,0 +1,105 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// The class of a failure, determines the exit code of the mdbook-plantuml binary
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    /// Invalid book.toml options (or --set overrides)
+    Config,
+    /// No PlantUML backend could be created (e.g. PlantUML or java is not installed)
+    BackendNotFound,
+    /// A diagram could not be rendered (with fail-on-error enabled)
+    Render,
+    /// Reading or writing a file failed (or a network request failed)
+    Io,
+    /// The compared PlantUML versions render diagrams differently (the compare subcommand)
+    Differences,
+    /// Anything else
+    Other,
+}
+
+impl FailureKind {
+    /// Classify the error, errors not tagged with a kind are I/O errors when caused by one
+    pub fn of(error: &anyhow::Error) -> Self {
+        error
+            .chain()
+            .find_map(|e| e.downcast_ref::<Failure>())
+            .map(|failure| failure.kind)
+            .unwrap_or_else(|| {
+                if error.chain().any(|e| e.is::<io::Error>()) {
+                    Self::Io
+                } else {
+                    Self::Other
+                }
+            })
+    }
+
+    /// The exit code of the binary for this kind of failure
+    pub const fn exit_code(self) -> i32 {
+        match self {
+            Self::Other => 1,
+            Self::Config => 2,
+            Self::BackendNotFound => 3,
+            Self::Render => 4,
+            Self::Io => 5,
+            Self::Differences => 6,
+        }
+    }
+}
+
+/// An error tagged with its failure kind, it is displayed like the error itself
+#[derive(Debug)]
+struct Failure {
+    kind: FailureKind,
+    error: anyhow::Error,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl Error for Failure {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.error.chain().nth(1)
+    }
+}
+
+/// Returns a function tagging an error with the given failure kind (for use with map_err)
+pub fn tag(kind: FailureKind) -> impl Fn(anyhow::Error) -> anyhow::Error {
+    move |error| anyhow::Error::new(Failure { kind, error })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{format_err, Context};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_failure_kind() {
+        assert_eq!(FailureKind::Other, FailureKind::of(&format_err!("Oops")));
+
+        let io_error = Err::<(), _>(io::Error::new(io::ErrorKind::NotFound, "gone"))
+            .context("Failed to read")
+            .unwrap_err();
+        assert_eq!(FailureKind::Io, FailureKind::of(&io_error));
+
+        // The (outermost) tag takes precedence
+        let error = tag(FailureKind::Render)(io_error).context("In chapter 'foo'");
+        assert_eq!(FailureKind::Render, FailureKind::of(&error));
+        assert_eq!(4, FailureKind::of(&error).exit_code());
+
+        // Differing compared versions have their own exit code
+        assert_eq!(6, FailureKind::Differences.exit_code());
+    }
+
+    #[test]
+    fn test_tagged_error_message() {
+        let error = tag(FailureKind::Config)(format_err!("Invalid value").context("Bad config"));
+        assert_eq!("Bad config", format!("{error}"));
+        assert_eq!("Bad config: Invalid value", format!("{error:#}"));
+    }
+}
@@ -0,0 +1,419 @@
+use mdbook::book::{Book, BookItem, Chapter};
+use std::path::{Path, PathBuf};
+
+/// The placeholder that is replaced by the list of figures
+pub const LIST_OF_FIGURES_PLACEHOLDER: &str = "{{#plantuml-list-of-figures}}";
+
+const LIST_OF_FIGURES_TITLE: &str = "List of Figures";
+
+/// A rendered diagram in the list of figures
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Figure {
+    /// The diagram's caption (alt text, or title)
+    pub caption: String,
+    /// The id of the anchor in front of the diagram's image
+    pub anchor: String,
+    /// The diagram's id (id=... in the info string)
+    pub id: Option<String>,
+    /// The image number (None for text diagrams)
+    pub number: Option<usize>,
+    /// The participants/classes/components in the diagram
+    pub elements: Vec<String>,
+}
+
+/// Add the list of figures to the book. The list replaces the placeholder in
+/// the chapter(s) containing it, or it is appended as a new chapter when there
+/// is no such chapter.
+/// # Arguments
+/// * `book` - The processed book
+/// * `figures` - The figures and the paths of the chapters they are in
+pub fn add_list_of_figures(book: &mut Book, figures: &[(PathBuf, Figure)]) {
+    add_generated_chapter(
+        book,
+        LIST_OF_FIGURES_PLACEHOLDER,
+        LIST_OF_FIGURES_TITLE,
+        "plantuml-list-of-figures.md",
+        &|chapter_path| list_of_figures(figures, chapter_path),
+    );
+}
+
+/// Add generated content to the book. The content replaces the placeholder in
+/// the chapter(s) containing it, or it is appended as a new chapter (with the
+/// given title and file name) when there is no such chapter.
+/// # Arguments
+/// * `book` - The processed book
+/// * `placeholder` - The placeholder to replace
+/// * `title` - The title of the appended chapter
+/// * `file_name` - The file name of the appended chapter
+/// * `content` - Creates the content, with links relative to the given chapter path
+pub fn add_generated_chapter(
+    book: &mut Book,
+    placeholder: &str,
+    title: &str,
+    file_name: &str,
+    content: &dyn Fn(&Path) -> String,
+) {
+    let mut placeholder_found = false;
+    book.for_each_mut(|item: &mut BookItem| {
+        if let BookItem::Chapter(ref mut chapter) = *item {
+            if let Some(chapter_path) = &chapter.path {
+                if chapter.content.contains(placeholder) {
+                    let generated = content(chapter_path);
+                    chapter.content = chapter.content.replace(placeholder, &generated);
+                    placeholder_found = true;
+                }
+            }
+        }
+    });
+
+    if !placeholder_found {
+        let path = PathBuf::from(file_name);
+        let chapter_content = format!("# {}\n\n{}", title, content(&path));
+        book.push_item(Chapter::new(title, chapter_content, path, vec![]));
+    }
+}
+
+/// Replace the `{{#diagram my-id}}` references in all chapters with a link to
+/// the diagram with that id. The link text is the figure number when numbering
+/// figures (and the diagram is an image), the diagram's caption otherwise. Escaped references
+/// (`\{{#diagram my-id}}`) are kept (without the backslash).
+/// Returns the ids that could not be resolved (these references are kept as is).
+/// # Arguments
+/// * `book` - The processed book
+/// * `figures` - The figures and the paths of the chapters they are in
+/// * `numbered` - Use the figure numbers as link text
+pub fn resolve_diagram_references(
+    book: &mut Book,
+    figures: &[(PathBuf, Figure)],
+    numbered: bool,
+) -> Vec<String> {
+    let mut unresolved = vec![];
+    book.for_each_mut(|item: &mut BookItem| {
+        if let BookItem::Chapter(ref mut chapter) = *item {
+            if let Some(chapter_path) = &chapter.path {
+                if chapter.content.contains("{{#diagram") {
+                    chapter.content = resolve_references(
+                        &chapter.content,
+                        chapter_path,
+                        figures,
+                        numbered,
+                        &mut unresolved,
+                    );
+                }
+            }
+        }
+    });
+
+    unresolved
+}
+
+/// Find the diagrams sharing an id (or an anchor, different ids can map to the same anchor), the
+/// references to these would be ambiguous. Returns a description of both locations per
+/// duplicate, e.g. "'overview' in \"intro.md\" and \"design/overview.md\"".
+/// # Arguments
+/// * `figures` - The figures and the paths of the chapters they are in
+pub fn duplicate_diagram_ids(figures: &[(PathBuf, Figure)]) -> Vec<String> {
+    let mut duplicates = vec![];
+    for (index, (chapter_path, figure)) in figures.iter().enumerate() {
+        let id = match &figure.id {
+            Some(id) => id,
+            None => continue,
+        };
+        let first = figures[..index]
+            .iter()
+            .find(|(_, other)| other.id.is_some() && other.anchor == figure.anchor);
+        if let Some((first_chapter_path, first_figure)) = first {
+            let first_id = first_figure.id.as_deref().unwrap_or_default();
+            let ids = if first_id == id {
+                format!("'{id}'")
+            } else {
+                format!("'{first_id}' and '{id}' (anchor '{}')", figure.anchor)
+            };
+            duplicates.push(format!(
+                "{} in {:?} and {:?}",
+                ids, first_chapter_path, chapter_path
+            ));
+        }
+    }
+
+    duplicates
+}
+
+fn resolve_references(
+    content: &str,
+    chapter_path: &Path,
+    figures: &[(PathBuf, Figure)],
+    numbered: bool,
+    unresolved: &mut Vec<String>,
+) -> String {
+    const REFERENCE_START: &str = "{{#diagram ";
+
+    let mut resolved = String::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(start) = rest.find(REFERENCE_START) {
+        let (before, reference) = rest.split_at(start);
+        let end = match reference.find("}}") {
+            Some(end) => end + 2,
+            None => break,
+        };
+
+        let id = reference[REFERENCE_START.len()..end - 2].trim();
+        let target = figures
+            .iter()
+            .find(|(_, figure)| figure.id.as_deref() == Some(id));
+        if let Some(before) = before.strip_suffix('\\') {
+            resolved.push_str(before);
+            resolved.push_str(&reference[..end]);
+        } else if let Some((target_chapter, figure)) = target {
+            let text = match figure.number {
+                Some(number) if numbered => format!("Figure {number}"),
+                _ => figure.caption.clone(),
+            };
+            resolved.push_str(before);
+            resolved.push_str(&format!(
+                "[{}]({})",
+                text,
+                figure_url(target_chapter, figure, chapter_path)
+            ));
+        } else {
+            unresolved.push(id.to_string());
+            resolved.push_str(before);
+            resolved.push_str(&reference[..end]);
+        }
+
+        rest = &reference[end..];
+    }
+    resolved.push_str(rest);
+
+    resolved
+}
+
+/// Create the markdown list of figures, with links relative to the given chapter
+fn list_of_figures(figures: &[(PathBuf, Figure)], list_chapter_path: &Path) -> String {
+    let mut list = String::new();
+    for (chapter_path, figure) in figures {
+        if let Some(number) = figure.number {
+            list.push_str(&format!(
+                "{}. [{}]({})\n",
+                number,
+                figure.caption,
+                figure_url(chapter_path, figure, list_chapter_path)
+            ));
+        }
+    }
+
+    list
+}
+
+/// The url of the figure's anchor, relative to the given chapter
+pub fn figure_url(chapter_path: &Path, figure: &Figure, from_chapter_path: &Path) -> String {
+    let mut url = String::new();
+    for _ in 1..from_chapter_path.components().count() {
+        url.push_str("../");
+    }
+
+    url.push_str(
+        &chapter_path
+            .to_string_lossy()
+            .replace('\\', "/")
+            .replace(' ', "%20"),
+    );
+    url.push('#');
+    url.push_str(&figure.anchor);
+
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn figures() -> Vec<(PathBuf, Figure)> {
+        vec![
+            (
+                PathBuf::from("chapter 1.md"),
+                Figure {
+                    caption: String::from("Class diagram"),
+                    anchor: String::from("diag-class"),
+                    id: Some(String::from("class")),
+                    number: Some(1),
+                    elements: vec![],
+                },
+            ),
+            (
+                PathBuf::from("nested/chapter.md"),
+                Figure {
+                    caption: String::from("Figure 2"),
+                    anchor: String::from("plantuml-figure-2"),
+                    id: None,
+                    number: Some(2),
+                    elements: vec![],
+                },
+            ),
+            (
+                PathBuf::from("nested/chapter.md"),
+                Figure {
+                    caption: String::from("text"),
+                    anchor: String::from("diag-text"),
+                    id: Some(String::from("text")),
+                    number: None,
+                    elements: vec![],
+                },
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_list_of_figures() {
+        assert_eq!(
+            "1. [Class diagram](chapter%201.md#diag-class)\n\
+             2. [Figure 2](nested/chapter.md#plantuml-figure-2)\n",
+            list_of_figures(&figures(), Path::new("figures.md"))
+        );
+
+        // Links are relative to the chapter with the list
+        assert_eq!(
+            "1. [Class diagram](../chapter%201.md#diag-class)\n\
+             2. [Figure 2](../nested/chapter.md#plantuml-figure-2)\n",
+            list_of_figures(&figures(), Path::new("appendix/figures.md"))
+        );
+    }
+
+    #[test]
+    fn test_resolve_references() {
+        let mut unresolved = vec![];
+        assert_eq!(
+            "See [Figure 1](../chapter%201.md#diag-class) and [text](../nested/chapter.md#diag-text).",
+            resolve_references(
+                "See {{#diagram class}} and {{#diagram  text }}.",
+                Path::new("nested/chapter.md"),
+                &figures(),
+                true,
+                &mut unresolved
+            )
+        );
+        assert!(unresolved.is_empty());
+
+        // The caption is used when not numbering figures
+        assert_eq!(
+            "See [Class diagram](chapter%201.md#diag-class)",
+            resolve_references(
+                "See {{#diagram class}}",
+                Path::new("chapter.md"),
+                &figures(),
+                false,
+                &mut unresolved
+            )
+        );
+
+        // Escaped and unknown references are kept
+        assert_eq!(
+            "{{#diagram class}} {{#diagram missing}} {{#diagram",
+            resolve_references(
+                "\\{{#diagram class}} {{#diagram missing}} {{#diagram",
+                Path::new("chapter.md"),
+                &figures(),
+                true,
+                &mut unresolved
+            )
+        );
+        assert_eq!(vec![String::from("missing")], unresolved);
+    }
+
+    #[test]
+    fn test_duplicate_diagram_ids() {
+        assert!(duplicate_diagram_ids(&figures()).is_empty());
+
+        let mut figures = figures();
+        let mut duplicate = figures[0].1.clone();
+        duplicate.number = Some(3);
+        figures.push((PathBuf::from("appendix.md"), duplicate));
+        for id in ["a b", "a-b"] {
+            figures.push((
+                PathBuf::from("appendix.md"),
+                Figure {
+                    caption: String::from(id),
+                    anchor: String::from("diag-a-b"),
+                    id: Some(String::from(id)),
+                    number: None,
+                    elements: vec![],
+                },
+            ));
+        }
+        assert_eq!(
+            vec![
+                String::from("'class' in \"chapter 1.md\" and \"appendix.md\""),
+                String::from(
+                    "'a b' and 'a-b' (anchor 'diag-a-b') in \"appendix.md\" and \"appendix.md\""
+                ),
+            ],
+            duplicate_diagram_ids(&figures)
+        );
+    }
+
+    #[test]
+    fn test_resolve_diagram_references() {
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Intro",
+            String::from("See {{#diagram class}}"),
+            "intro.md",
+            vec![],
+        ));
+
+        assert!(resolve_diagram_references(&mut book, &figures(), true).is_empty());
+        match &book.sections[0] {
+            BookItem::Chapter(chapter) => {
+                assert_eq!("See [Figure 1](chapter%201.md#diag-class)", chapter.content)
+            }
+            _ => panic!("Expected a chapter"),
+        }
+    }
+
+    #[test]
+    fn test_add_list_of_figures_placeholder() {
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Figures",
+            format!("# Figures\n{LIST_OF_FIGURES_PLACEHOLDER}\n"),
+            "figures.md",
+            vec![],
+        ));
+
+        add_list_of_figures(&mut book, &figures());
+        assert_eq!(1, book.sections.len());
+        match &book.sections[0] {
+            BookItem::Chapter(chapter) => assert_eq!(
+                "# Figures\n\
+                 1. [Class diagram](chapter%201.md#diag-class)\n\
+                 2. [Figure 2](nested/chapter.md#plantuml-figure-2)\n\n",
+                chapter.content
+            ),
+            _ => panic!("Expected a chapter"),
+        }
+    }
+
+    #[test]
+    fn test_add_list_of_figures_chapter() {
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Intro",
+            String::from("# Intro\n"),
+            "intro.md",
+            vec![],
+        ));
+
+        add_list_of_figures(&mut book, &figures());
+        assert_eq!(2, book.sections.len());
+        match &book.sections[1] {
+            BookItem::Chapter(chapter) => {
+                assert_eq!("List of Figures", chapter.name);
+                assert!(chapter
+                    .content
+                    .starts_with("# List of Figures\n\n1. [Class diagram]"));
+            }
+            _ => panic!("Expected a chapter"),
+        }
+    }
+}
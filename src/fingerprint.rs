@@ -0,0 +1,312 @@
+use crate::include_cache::IncludeCache;
+use crate::includes::{plantuml_includes_with, IncludedFile};
+use crate::renderer::hash_string;
+use sha1::{Digest, Sha1};
+use std::fmt;
+use std::path::Path;
+
+/// The inputs of a diagram's cache hash (the base name of the cached image file).
+/// The hash is compatible with the images cached before the fingerprint was introduced, a
+/// diagram without includes and backend identity hashes to hash_string(plantuml_code).
+#[derive(Debug, Clone)]
+pub struct RenderFingerprint {
+    /// The (normalized) PlantUML code
+    source: String,
+    /// The local files included with `!include` (also nested ones), the stable files (see
+    /// IncludeCache) are hashed by their content hash instead of their content
+    includes: Vec<IncludedFile>,
+    /// The rendered image format, it is the cached image's extension so it is not hashed
+    format: Option<String>,
+    /// The backend identity (see Config::cache_backend_key)
+    backend: Option<String>,
+    /// The version of the PlantUML the image is rendered with (see factory::tool_version)
+    tool_version: Option<String>,
+    /// The options changing the rendered image (see Config::cache_options_key)
+    options: Option<String>,
+}
+
+impl RenderFingerprint {
+    pub fn new(plantuml_code: &str) -> Self {
+        Self {
+            source: plantuml_code.to_string(),
+            includes: vec![],
+            format: None,
+            backend: None,
+            tool_version: None,
+            options: None,
+        }
+    }
+
+    /// Add the content of the local files included with `!include`, so the diagram is rendered
+    /// again when an included file changes.
+    /// # Arguments
+    /// * `include_dir` - The directory relative includes are resolved from (the chapter's
+    ///   directory), independent of the current working directory
+    /// * `cache` - The included files read before, and the stable ones that are not read for
+    ///   every diagram
+    pub fn with_includes(self, include_dir: &Path, cache: &IncludeCache) -> Self {
+        Self {
+            includes: plantuml_includes_with(&self.source, include_dir, cache),
+            ..self
+        }
+    }
+
+    /// Set the rendered image format (see Renderer::render_format)
+    pub fn with_format(self, image_format: &str) -> Self {
+        Self {
+            format: Some(image_format.to_string()),
+            ..self
+        }
+    }
+
+    /// Set the backend identity, None to share the cached image across backends
+    pub fn with_backend(self, backend_key: Option<&str>) -> Self {
+        Self {
+            backend: backend_key.map(String::from),
+            ..self
+        }
+    }
+
+    /// Set the PlantUML version, so the diagram is rendered again when PlantUML is upgraded. None
+    /// when the version is unknown (e.g. for a PlantUML server).
+    pub fn with_tool_version(self, tool_version: Option<&str>) -> Self {
+        Self {
+            tool_version: tool_version.map(String::from),
+            ..self
+        }
+    }
+
+    /// Set the options changing the rendered image, None when none are set
+    pub fn with_options(self, options_key: Option<&str>) -> Self {
+        Self {
+            options: options_key.map(String::from),
+            ..self
+        }
+    }
+
+    /// The cache hash of the diagram
+    pub fn hash(&self) -> String {
+        let mut hasher = Sha1::new_with_prefix(&self.source);
+        for file in &self.includes {
+            hasher.update(b"\0");
+            hasher.update(&file.content);
+        }
+        let hash = base16ct::lower::encode_string(&hasher.finalize());
+
+        let hash = match &self.backend {
+            Some(backend) => hash_string(&format!("{hash}\0{backend}")),
+            None => hash,
+        };
+        let hash = match &self.tool_version {
+            Some(tool_version) => hash_string(&format!("{hash}\0tool {tool_version}")),
+            None => hash,
+        };
+        match &self.options {
+            Some(options) => hash_string(&format!("{hash}\0options {options}")),
+            None => hash,
+        }
+    }
+}
+
+/// The canonical serialization, one `<input> <value>` line per input in a fixed order. The
+/// source and the included files are listed by their hash.
+impl fmt::Display for RenderFingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "source {}", hash_string(&self.source))?;
+        for file in &self.includes {
+            let content_hash = if file.stable {
+                String::from_utf8_lossy(&file.content).into_owned()
+            } else {
+                base16ct::lower::encode_string(&Sha1::digest(&file.content))
+            };
+            writeln!(
+                f,
+                "include {} {}",
+                file.path.to_string_lossy().replace('\\', "/"),
+                content_hash
+            )?;
+        }
+        if let Some(format) = &self.format {
+            writeln!(f, "format {format}")?;
+        }
+        if let Some(backend) = &self.backend {
+            writeln!(f, "backend {backend}")?;
+        }
+        if let Some(tool_version) = &self.tool_version {
+            writeln!(f, "tool {tool_version}")?;
+        }
+        if let Some(options) = &self.options {
+            writeln!(f, "options {options}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_hash_includes() {
+        let include_dir = tempdir().unwrap();
+        fs::write(
+            include_dir.path().join("common.puml"),
+            "skinparam monochrome true",
+        )
+        .unwrap();
+
+        let hash = |code: &str, dir: &Path| {
+            RenderFingerprint::new(code)
+                .with_includes(dir, &IncludeCache::default())
+                .hash()
+        };
+        assert_eq!(
+            hash_string("A --|> B"),
+            hash("A --|> B", include_dir.path())
+        );
+
+        // The included file is part of the hash, it is resolved from the include dir (not the working dir)
+        let code = "!include common.puml\nA --|> B";
+        let included_hash = hash(code, include_dir.path());
+        assert_ne!(hash_string(code), included_hash);
+        assert_eq!(hash_string(code), hash(code, Path::new("/nonexistent")));
+
+        fs::write(
+            include_dir.path().join("common.puml"),
+            "skinparam monochrome false",
+        )
+        .unwrap();
+        assert_ne!(included_hash, hash(code, include_dir.path()));
+    }
+
+    #[test]
+    fn test_hash_backend() {
+        let fingerprint = RenderFingerprint::new("A --|> B");
+        let hash = hash_string("A --|> B");
+        assert_eq!(hash, fingerprint.clone().with_backend(None).hash());
+
+        let shell_hash = fingerprint.clone().with_backend(Some("shell")).hash();
+        assert_ne!(hash, shell_hash);
+        assert_eq!(hash_string(&format!("{hash}\0shell")), shell_hash);
+        assert_ne!(
+            shell_hash,
+            fingerprint
+                .with_backend(Some("https://www.plantuml.com/plantuml"))
+                .hash()
+        );
+
+        // The format is the image file's extension
+        assert_eq!(
+            hash,
+            RenderFingerprint::new("A --|> B").with_format("png").hash()
+        );
+    }
+
+    #[test]
+    fn test_hash_options() {
+        let fingerprint = RenderFingerprint::new("A --|> B").with_backend(Some("shell"));
+        let hash = fingerprint.hash();
+        assert_eq!(hash, fingerprint.clone().with_options(None).hash());
+
+        let options_hash = fingerprint
+            .clone()
+            .with_options(Some("security-profile=SANDBOX"))
+            .hash();
+        assert_eq!(
+            hash_string(&format!("{hash}\0options security-profile=SANDBOX")),
+            options_hash
+        );
+        assert_ne!(
+            options_hash,
+            fingerprint
+                .with_options(Some("security-profile=ALLOWLIST"))
+                .hash()
+        );
+    }
+
+    #[test]
+    fn test_hash_tool_version() {
+        let fingerprint = RenderFingerprint::new("A --|> B").with_backend(Some("shell"));
+        let hash = fingerprint.hash();
+        assert_eq!(hash, fingerprint.clone().with_tool_version(None).hash());
+
+        let version_hash = fingerprint
+            .clone()
+            .with_tool_version(Some("PlantUML version 1.2024.3"))
+            .hash();
+        assert_eq!(
+            hash_string(&format!("{hash}\0tool PlantUML version 1.2024.3")),
+            version_hash
+        );
+        assert_ne!(
+            version_hash,
+            fingerprint
+                .with_tool_version(Some("PlantUML version 1.2024.4"))
+                .hash()
+        );
+    }
+
+    #[test]
+    fn test_hash_stable_includes() {
+        let book_dir = tempdir().unwrap();
+        fs::create_dir_all(book_dir.path().join("vendor")).unwrap();
+        fs::write(
+            book_dir.path().join("vendor/icons.puml"),
+            "!include nested.puml",
+        )
+        .unwrap();
+        fs::write(book_dir.path().join("vendor/nested.puml"), "title Foo").unwrap();
+
+        let code = "!include vendor/icons.puml\nA --|> B";
+        let stable = IncludeCache::new(book_dir.path(), &[String::from("vendor/**")], None);
+        let fingerprint = RenderFingerprint::new(code).with_includes(book_dir.path(), &stable);
+
+        // The nested includes of a stable file are not followed
+        let icons_hash = hash_string("!include nested.puml");
+        assert_eq!(
+            format!(
+                "source {}\ninclude {}/vendor/icons.puml {}\n",
+                hash_string(code),
+                book_dir.path().to_string_lossy().replace('\\', "/"),
+                icons_hash
+            ),
+            fingerprint.to_string()
+        );
+        assert_eq!(
+            hash_string(&format!("{code}\0{icons_hash}")),
+            fingerprint.hash()
+        );
+    }
+
+    #[test]
+    fn test_canonical_serialization() {
+        let include_dir = tempdir().unwrap();
+        fs::write(include_dir.path().join("common.puml"), "title Foo").unwrap();
+
+        let fingerprint = RenderFingerprint::new("!include common.puml\nA --|> B")
+            .with_includes(include_dir.path(), &IncludeCache::default())
+            .with_format("svg")
+            .with_backend(Some("shell"))
+            .with_tool_version(Some("PlantUML version 1.2024.3"))
+            .with_options(Some("security-profile=SANDBOX"));
+        assert_eq!(
+            format!(
+                "source {}\ninclude {}/common.puml {}\nformat svg\nbackend shell\ntool PlantUML \
+                 version 1.2024.3\noptions security-profile=SANDBOX\n",
+                hash_string("!include common.puml\nA --|> B"),
+                include_dir.path().to_string_lossy().replace('\\', "/"),
+                hash_string("title Foo"),
+            ),
+            fingerprint.to_string()
+        );
+
+        assert_eq!(
+            format!("source {}\n", hash_string("A --|> B")),
+            RenderFingerprint::new("A --|> B").to_string()
+        );
+    }
+}
@@ -0,0 +1,115 @@
+use crate::diagram_kind::DiagramKind;
+use crate::markings::insert_after_start_tag;
+use std::borrow::Cow;
+
+/// The per chapter configuration in an HTML comment at the top of the chapter, overriding the
+/// configuration for all diagrams in the chapter, e.g.
+/// `<!-- plantuml: format=png theme=mono -->`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChapterFrontMatter {
+    /// The image format of the diagrams without a format in their info string
+    pub format: Option<String>,
+    /// The PlantUML theme (`!theme ...`) of the diagrams
+    pub theme: Option<String>,
+}
+
+impl ChapterFrontMatter {
+    /// Parse the front matter, it is the first (non blank) line of the chapter. Unknown keys are
+    /// ignored (with a warning).
+    pub fn parse(markdown: &str) -> Self {
+        let mut front_matter = Self::default();
+        let comment = markdown
+            .trim_start()
+            .strip_prefix("<!--")
+            .and_then(|comment| comment.split_once("-->"))
+            .and_then(|(comment, _)| comment.trim_start().strip_prefix("plantuml:"));
+        let comment = match comment {
+            Some(comment) => comment,
+            None => return front_matter,
+        };
+
+        for option in comment.split_whitespace() {
+            let (key, value) = option.split_once('=').unwrap_or((option, ""));
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            let value = Some(value.to_string()).filter(|v| !v.is_empty());
+            match key {
+                "format" => front_matter.format = value,
+                "theme" => front_matter.theme = value,
+                _ => log::warn!("Ignoring unknown front matter option '{}'", option),
+            }
+        }
+
+        front_matter
+    }
+
+    /// Add the theme to the diagram (after its @start tag). Diagrams that do not support themes
+    /// (e.g. ditaa) are returned as is.
+    pub fn apply<'a>(&self, plantuml_code: &'a str) -> Cow<'a, str> {
+        let theme = match &self.theme {
+            Some(theme) => theme,
+            None => return Cow::Borrowed(plantuml_code),
+        };
+        if !DiagramKind::detect(plantuml_code).supports_styling() {
+            return Cow::Borrowed(plantuml_code);
+        }
+
+        Cow::Owned(insert_after_start_tag(
+            plantuml_code,
+            &format!("!theme {theme}\n"),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            ChapterFrontMatter {
+                format: Some(String::from("png")),
+                theme: Some(String::from("mono")),
+            },
+            ChapterFrontMatter::parse("\n<!-- plantuml: format=png theme=mono -->\n# Chapter\n")
+        );
+        assert_eq!(
+            ChapterFrontMatter {
+                format: None,
+                theme: Some(String::from("cerulean-outline")),
+            },
+            ChapterFrontMatter::parse("<!--\nplantuml:\n  theme=\"cerulean-outline\" foo=bar\n-->")
+        );
+
+        // Only at the top of the chapter
+        assert_eq!(
+            ChapterFrontMatter::default(),
+            ChapterFrontMatter::parse("# Chapter\n<!-- plantuml: format=png -->\n")
+        );
+        assert_eq!(
+            ChapterFrontMatter::default(),
+            ChapterFrontMatter::parse("<!-- Some comment -->\n")
+        );
+    }
+
+    #[test]
+    fn test_apply() {
+        let front_matter = ChapterFrontMatter::parse("<!-- plantuml: theme=mono -->");
+        assert_eq!(
+            "@startuml\n!theme mono\nA --|> B\n@enduml\n",
+            front_matter.apply("@startuml\nA --|> B\n@enduml\n")
+        );
+        assert_eq!("!theme mono\nA --|> B", front_matter.apply("A --|> B"));
+
+        let ditaa = "@startditaa\n+--+\n@endditaa\n";
+        assert_eq!(ditaa, front_matter.apply(ditaa));
+        assert!(matches!(
+            ChapterFrontMatter::default().apply("A --|> B"),
+            Cow::Borrowed("A --|> B")
+        ));
+    }
+}
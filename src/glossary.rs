@@ -0,0 +1,288 @@
+use crate::figures::{add_generated_chapter, figure_url, Figure};
+use mdbook::book::Book;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+/// The placeholder that is replaced by the glossary
+pub const GLOSSARY_PLACEHOLDER: &str = "{{#plantuml-glossary}}";
+
+const GLOSSARY_TITLE: &str = "Diagram Glossary";
+
+/// The keywords declaring diagram elements (e.g. participant Alice)
+const ELEMENT_KEYWORDS: [&str; 25] = [
+    "participant",
+    "actor",
+    "boundary",
+    "control",
+    "entity",
+    "database",
+    "collections",
+    "queue",
+    "class",
+    "interface",
+    "abstract",
+    "enum",
+    "annotation",
+    "component",
+    "node",
+    "object",
+    "usecase",
+    "artifact",
+    "cloud",
+    "storage",
+    "rectangle",
+    "package",
+    "frame",
+    "folder",
+    "agent",
+];
+
+/// Add the glossary (an index mapping each diagram element to the diagrams it
+/// appears in) to the book. The glossary replaces the placeholder in the
+/// chapter(s) containing it, or it is appended as a new chapter when there is
+/// no such chapter.
+/// # Arguments
+/// * `book` - The processed book
+/// * `figures` - The figures and the paths of the chapters they are in
+pub fn add_glossary(book: &mut Book, figures: &[(PathBuf, Figure)]) {
+    add_generated_chapter(
+        book,
+        GLOSSARY_PLACEHOLDER,
+        GLOSSARY_TITLE,
+        "plantuml-glossary.md",
+        &|chapter_path| glossary(figures, chapter_path),
+    );
+}
+
+/// Create the markdown glossary, with links relative to the given chapter
+fn glossary(figures: &[(PathBuf, Figure)], glossary_chapter_path: &Path) -> String {
+    let mut elements: BTreeMap<&str, Vec<&(PathBuf, Figure)>> = BTreeMap::new();
+    for chapter_figure in figures {
+        for element in &chapter_figure.1.elements {
+            elements
+                .entry(element.as_str())
+                .or_default()
+                .push(chapter_figure);
+        }
+    }
+
+    let mut glossary = String::new();
+    for (element, element_figures) in elements {
+        let links: Vec<String> = element_figures
+            .iter()
+            .map(|(chapter_path, figure)| {
+                format!(
+                    "[{}]({})",
+                    figure.caption,
+                    figure_url(chapter_path, figure, glossary_chapter_path)
+                )
+            })
+            .collect();
+        glossary.push_str(&format!("- **{}**: {}\n", element, links.join(", ")));
+    }
+
+    glossary
+}
+
+/// Get the participants/classes/components in the PlantUML code (declared
+/// elements and the elements used in relations), in order of appearance.
+pub fn diagram_elements(plantuml_code: &str) -> Vec<String> {
+    let mut elements: Vec<String> = vec![];
+    let mut aliases: HashMap<String, String> = HashMap::new();
+
+    let add = |name: String, elements: &mut Vec<String>| {
+        if !elements.contains(&name) {
+            elements.push(name);
+        }
+    };
+
+    for line in plantuml_code.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('\'') || line.starts_with('@') {
+            continue;
+        }
+
+        if let Some((name, alias)) = declared_element(line) {
+            if let Some(alias) = alias {
+                aliases.insert(alias, name.clone());
+            }
+            add(name, &mut elements);
+        } else if let Some((left, right)) = related_elements(line) {
+            for name in [left, right].iter() {
+                let name = aliases.get(name).cloned().unwrap_or_else(|| name.clone());
+                add(name, &mut elements);
+            }
+        }
+    }
+
+    elements
+}
+
+/// Parse an element declaration (e.g. `participant "Long name" as L`), returns
+/// the element's name and alias.
+fn declared_element(line: &str) -> Option<(String, Option<String>)> {
+    let (keyword, rest) = line.split_once(char::is_whitespace)?;
+    if !ELEMENT_KEYWORDS.contains(&keyword.to_lowercase().as_str()) {
+        return None;
+    }
+
+    // abstract class Foo
+    let rest = rest.trim_start();
+    let rest = rest.strip_prefix("class ").unwrap_or(rest).trim_start();
+
+    let (name, rest) = element_name(rest)?;
+    let alias = rest
+        .trim_start()
+        .strip_prefix("as ")
+        .and_then(|rest| element_name(rest.trim_start()))
+        .map(|(alias, _)| alias);
+
+    Some((name, alias))
+}
+
+/// Parse a relation (e.g. `Alice -> Bob : hello`, or `A --|> B`), returns the
+/// elements on both sides of the arrow.
+fn related_elements(line: &str) -> Option<(String, String)> {
+    let is_arrow_char = |c: char| "-.<>|*#/\\".contains(c);
+
+    let arrow_start = line.find(['-', '.', '<'])?;
+    let (left, rest) = line.split_at(arrow_start);
+    let mut arrow_len = 0;
+    let mut in_brackets = false;
+    for c in rest.chars() {
+        match c {
+            '[' => in_brackets = true,
+            ']' => in_brackets = false,
+            _ if in_brackets || is_arrow_char(c) => {}
+            _ => break,
+        }
+        arrow_len += c.len_utf8();
+    }
+    let (arrow, right) = rest.split_at(arrow_len);
+    if !arrow.contains("--")
+        && !arrow.contains("->")
+        && !arrow.contains("<-")
+        && !arrow.contains("..")
+    {
+        return None;
+    }
+
+    let right = right.split(':').next().unwrap_or_default().trim();
+    let (left_name, left_rest) = element_name(left.trim())?;
+    let (right_name, right_rest) = element_name(right)?;
+    if left_rest.trim().is_empty() && right_rest.trim().is_empty() {
+        Some((left_name, right_name))
+    } else {
+        None
+    }
+}
+
+/// Parse an element name (quoted, or an identifier), returns the name and the
+/// remaining text.
+fn element_name(text: &str) -> Option<(String, &str)> {
+    if let Some(quoted) = text.strip_prefix('"') {
+        let end = quoted.find('"')?;
+        let name = &quoted[..end];
+        if name.is_empty() {
+            None
+        } else {
+            Some((name.to_string(), &quoted[end + 1..]))
+        }
+    } else {
+        let end = text
+            .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+            .unwrap_or(text.len());
+        let name = text[..end].trim_end_matches('.');
+        if name.is_empty() {
+            None
+        } else {
+            Some((name.to_string(), &text[name.len()..]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_diagram_elements() {
+        let code = r#"@startuml
+' A comment -> with an arrow
+participant "Long name" as L
+actor Bob
+abstract class Foo<T> {
+}
+L -> Bob : hello
+Bob --> Alice
+Foo --|> Bar
+Alice -[#red]-> "Some one"
+note left of Bob: a note
+@enduml
+"#;
+        assert_eq!(
+            vec!["Long name", "Bob", "Foo", "Alice", "Bar", "Some one"],
+            diagram_elements(code)
+        );
+    }
+
+    #[test]
+    fn test_declared_element() {
+        assert_eq!(
+            Some((String::from("Alice"), None)),
+            declared_element("participant Alice")
+        );
+        assert_eq!(
+            Some((String::from("My DB"), Some(String::from("db")))),
+            declared_element("database \"My DB\" as db #red")
+        );
+        assert_eq!(
+            Some((String::from("Foo"), None)),
+            declared_element("Class Foo {")
+        );
+        assert_eq!(None, declared_element("title Foo"));
+        assert_eq!(None, declared_element("participant"));
+    }
+
+    #[test]
+    fn test_related_elements() {
+        assert_eq!(
+            Some((String::from("A"), String::from("B"))),
+            related_elements("A->B: hi")
+        );
+        assert_eq!(
+            Some((String::from("A"), String::from("B"))),
+            related_elements("A ..> B")
+        );
+        assert_eq!(None, related_elements("skinparam foo -> bar"));
+        assert_eq!(None, related_elements("A - B"));
+        assert_eq!(None, related_elements("no arrows here"));
+    }
+
+    #[test]
+    fn test_glossary() {
+        let figure = |caption: &str, anchor: &str, elements: &[&str]| Figure {
+            caption: caption.to_string(),
+            anchor: anchor.to_string(),
+            id: None,
+            number: Some(1),
+            elements: elements.iter().map(|e| e.to_string()).collect(),
+        };
+        let figures = vec![
+            (
+                PathBuf::from("a.md"),
+                figure("Login", "plantuml-figure-1", &["Bob", "Alice"]),
+            ),
+            (
+                PathBuf::from("nested/b.md"),
+                figure("Logout", "plantuml-figure-2", &["Alice"]),
+            ),
+        ];
+
+        assert_eq!(
+            "- **Alice**: [Login](a.md#plantuml-figure-1), [Logout](nested/b.md#plantuml-figure-2)\n\
+             - **Bob**: [Login](a.md#plantuml-figure-1)\n",
+            glossary(&figures, Path::new("glossary.md"))
+        );
+    }
+}
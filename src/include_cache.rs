@@ -0,0 +1,312 @@
+use crate::extra_sources::matches_parts;
+use crate::STATE_DIR;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::time::UNIX_EPOCH;
+
+/// The file (in the cache's state dir) the content hashes of the stable includes are memoized in
+const MEMO_FILE_NAME: &str = "include-hashes.json";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct MemoEntry {
+    /// Modification time (nanoseconds since the epoch) of the file when it was hashed
+    mtime: u128,
+    /// The hash of the file's content
+    hash: String,
+}
+
+/// An included file read during this run
+struct ReadFile {
+    /// Modification time (nanoseconds since the epoch) of the file when it was read
+    mtime: u128,
+    content: Rc<[u8]>,
+}
+
+/// The included files read while rendering, memoized by modification time so an include
+/// shared by many diagrams (e.g. a common.puml with macros) is only read once per run.
+///
+/// The included files matching the hash-ignore patterns, i.e. stable (e.g. vendored)
+/// directories, are hashed once. Their content hash is memoized (in the cache's state dir)
+/// instead of reading them for every diagram on every build. The nested includes of a stable file are not
+/// followed, they are assumed to be stable as well.
+pub struct IncludeCache {
+    /// The directory the patterns are relative to (the book root)
+    root: PathBuf,
+    /// The glob patterns, split in path components
+    patterns: Vec<Vec<String>>,
+    /// Where the memoized hashes are saved (None to not save them)
+    memo_file: Option<PathBuf>,
+    memo: RefCell<HashMap<PathBuf, MemoEntry>>,
+    /// The memo changed since it was loaded
+    changed: Cell<bool>,
+    /// The content of the (not stable) included files read during this run, with their
+    /// modification time when read
+    contents: RefCell<HashMap<PathBuf, ReadFile>>,
+}
+
+impl Default for IncludeCache {
+    /// No stable includes, the included files are memoized for this run only
+    fn default() -> Self {
+        Self::new(Path::new(""), &[], None)
+    }
+}
+
+impl IncludeCache {
+    /// # Arguments
+    /// * `root` - The directory the patterns are relative to (the book root)
+    /// * `patterns` - The glob patterns (`*` and `?` within a path component, `**` any number
+    ///   of directories), e.g. "vendor/**"
+    /// * `cache_dir` - The directory the memoized hashes are loaded from and saved to, None to
+    ///   not save them (e.g. for read only tools)
+    pub fn new(root: &Path, patterns: &[String], cache_dir: Option<&Path>) -> Self {
+        let memo_file = cache_dir.map(|dir| dir.join(STATE_DIR).join(MEMO_FILE_NAME));
+        let memo = match (&memo_file, patterns.is_empty()) {
+            (Some(memo_file), false) => fs::read(memo_file)
+                .ok()
+                .and_then(|data| serde_json::from_slice(&data).ok())
+                .unwrap_or_default(),
+            _ => HashMap::new(),
+        };
+
+        Self {
+            root: root.to_path_buf(),
+            patterns: patterns
+                .iter()
+                .map(|pattern| {
+                    pattern
+                        .split(['/', '\\'])
+                        .filter(|part| !part.is_empty() && *part != ".")
+                        .map(String::from)
+                        .collect()
+                })
+                .collect(),
+            memo_file,
+            memo: RefCell::new(memo),
+            changed: Cell::new(false),
+            contents: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Returns true if the file matches one of the patterns
+    pub fn is_stable(&self, path: &Path) -> bool {
+        if self.patterns.is_empty() {
+            return false;
+        }
+
+        let path = normalize(path);
+        let rel_path = match path.strip_prefix(&self.root) {
+            Ok(rel_path) => rel_path,
+            Err(_) => return false,
+        };
+        let rel_parts: Vec<String> = rel_path
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        let rel_parts: Vec<&str> = rel_parts.iter().map(String::as_str).collect();
+        self.patterns.iter().any(|pattern| {
+            let pattern: Vec<&str> = pattern.iter().map(String::as_str).collect();
+            matches_parts(&pattern, &rel_parts)
+        })
+    }
+
+    /// The hash of the (stable) file's content, the file is only read when it was not hashed
+    /// before, or when it was modified since. None if the file cannot be read.
+    pub fn content_hash(&self, path: &Path) -> Option<String> {
+        let path = normalize(path);
+        let mtime = file_mtime(&path)?;
+        if let Some(entry) = self.memo.borrow().get(&path) {
+            if entry.mtime == mtime {
+                return Some(entry.hash.clone());
+            }
+        }
+
+        let content = fs::read(&path).ok()?;
+        let hash = base16ct::lower::encode_string(&Sha1::digest(&content));
+        self.memo.borrow_mut().insert(
+            path,
+            MemoEntry {
+                mtime,
+                hash: hash.clone(),
+            },
+        );
+        self.changed.set(true);
+        Some(hash)
+    }
+
+    /// The content of the included file, the file is only read when it was not read before
+    /// during this run, or when it was modified since. None if the file cannot be read.
+    pub fn read(&self, path: &Path) -> Option<Rc<[u8]>> {
+        let path = normalize(path);
+        let mtime = file_mtime(&path)?;
+        if let Some(file) = self.contents.borrow().get(&path) {
+            if file.mtime == mtime {
+                return Some(Rc::clone(&file.content));
+            }
+        }
+
+        let content: Rc<[u8]> = fs::read(&path).ok()?.into();
+        self.contents.borrow_mut().insert(
+            path,
+            ReadFile {
+                mtime,
+                content: Rc::clone(&content),
+            },
+        );
+        Some(content)
+    }
+
+    /// Save the memoized hashes (when changed), they are saved when dropped as well
+    pub fn save(&self) {
+        let memo_file = match &self.memo_file {
+            Some(memo_file) if self.changed.get() => memo_file,
+            _ => return,
+        };
+
+        let result = memo_file
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| {
+                let data = serde_json::to_vec_pretty(&*self.memo.borrow())?;
+                fs::write(memo_file, data)
+            });
+        match result {
+            Ok(()) => self.changed.set(false),
+            Err(e) => log::warn!("Failed to save the include hashes {:?} ({})", memo_file, e),
+        }
+    }
+}
+
+impl Drop for IncludeCache {
+    fn drop(&mut self) {
+        self.save();
+    }
+}
+
+/// Lexically normalize the path (resolve the `.` and `..` components), so the same file
+/// included through different relative paths has the same path
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    normalized.push(component);
+                }
+            }
+            component => normalized.push(component),
+        }
+    }
+
+    normalized
+}
+
+/// Modification time (nanoseconds since the epoch) of the file
+fn file_mtime(path: &Path) -> Option<u128> {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_nanos())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::hash_string;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_is_stable() {
+        let root = Path::new("/book");
+        let stable = IncludeCache::new(
+            root,
+            &[String::from("vendor/**"), String::from("./icons/*.puml")],
+            None,
+        );
+        assert!(stable.is_stable(Path::new("/book/vendor/aws/Compute.puml")));
+        assert!(stable.is_stable(Path::new("/book/src/../vendor/common.puml")));
+        assert!(stable.is_stable(Path::new("/book/icons/a.puml")));
+        assert!(!stable.is_stable(Path::new("/book/icons/sub/a.puml")));
+        assert!(!stable.is_stable(Path::new("/book/src/common.puml")));
+        assert!(!stable.is_stable(Path::new("/other/vendor/common.puml")));
+
+        assert!(!IncludeCache::default().is_stable(Path::new("/book/vendor/common.puml")));
+    }
+
+    #[test]
+    fn test_content_hash_memo() {
+        let book_dir = tempdir().unwrap();
+        let cache_dir = book_dir.path().join("cache");
+        let file = book_dir.path().join("vendor/common.puml");
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(&file, "skinparam monochrome true").unwrap();
+
+        let patterns = [String::from("vendor/**")];
+        let stable = IncludeCache::new(book_dir.path(), &patterns, Some(&cache_dir));
+        assert_eq!(
+            Some(hash_string("skinparam monochrome true")),
+            stable.content_hash(&file)
+        );
+        assert_eq!(None, stable.content_hash(&book_dir.path().join("missing")));
+        stable.save();
+
+        // The memoized hash is used while the file is not modified (the content is not read)
+        let memo_file = cache_dir.join(STATE_DIR).join(MEMO_FILE_NAME);
+        assert!(memo_file.is_file());
+        let mtime = file_mtime(&file).unwrap();
+        let memo = HashMap::from([(
+            normalize(&file),
+            MemoEntry {
+                mtime,
+                hash: String::from("memoized"),
+            },
+        )]);
+        fs::write(&memo_file, serde_json::to_vec(&memo).unwrap()).unwrap();
+        let stable = IncludeCache::new(book_dir.path(), &patterns, Some(&cache_dir));
+        assert_eq!(Some(String::from("memoized")), stable.content_hash(&file));
+
+        let memo = HashMap::from([(
+            normalize(&file),
+            MemoEntry {
+                mtime: mtime + 1,
+                hash: String::from("memoized"),
+            },
+        )]);
+        fs::write(&memo_file, serde_json::to_vec(&memo).unwrap()).unwrap();
+        let stable = IncludeCache::new(book_dir.path(), &patterns, Some(&cache_dir));
+        assert_eq!(
+            Some(hash_string("skinparam monochrome true")),
+            stable.content_hash(&file)
+        );
+    }
+
+    #[test]
+    fn test_read_memo() {
+        let include_dir = tempdir().unwrap();
+        let file = include_dir.path().join("common.puml");
+        fs::write(&file, "!procedure $foo()\n!endprocedure").unwrap();
+
+        let cache = IncludeCache::default();
+        let content = cache.read(&file).unwrap();
+        assert_eq!(b"!procedure $foo()\n!endprocedure", &*content);
+
+        // The same file through another relative path is read once
+        let other_path = include_dir.path().join("sub/../common.puml");
+        assert!(Rc::ptr_eq(&content, &cache.read(&other_path).unwrap()));
+        assert_eq!(1, cache.contents.borrow().len());
+
+        // A modified file is read again
+        cache
+            .contents
+            .borrow_mut()
+            .values_mut()
+            .for_each(|file| file.mtime += 1);
+        assert!(!Rc::ptr_eq(&content, &cache.read(&file).unwrap()));
+
+        assert!(cache.read(&include_dir.path().join("missing")).is_none());
+    }
+}
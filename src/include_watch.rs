@@ -0,0 +1,203 @@
+use crate::includes::plantuml_includes;
+use crate::{CACHE_DIR, STATE_DIR};
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// The include manifest file (in the cache's state dir)
+const MANIFEST_FILE: &str = "include-manifest";
+
+/// The time between two checks for changed include files
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The files outside the book's src dir included by the diagrams, and the chapters
+/// including them. `mdbook serve` does not watch these files, so the watch-includes
+/// command touches the including chapters when one changes.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct IncludeManifest {
+    /// The included file -> the chapter source files including it
+    includes: BTreeMap<PathBuf, BTreeSet<PathBuf>>,
+}
+
+impl IncludeManifest {
+    /// Record the files included by the diagram that are outside the src dir
+    /// # Arguments
+    /// * `plantuml_code` - The diagram's PlantUML code
+    /// * `include_dir` - The directory relative includes are resolved from (the chapter's dir)
+    /// * `chapter_file` - The chapter's source file
+    /// * `src_dir` - The book's src dir (watched by `mdbook serve`)
+    pub fn add_diagram(
+        &mut self,
+        plantuml_code: &str,
+        include_dir: &Path,
+        chapter_file: &Path,
+        src_dir: &Path,
+    ) {
+        for file in plantuml_includes(plantuml_code, include_dir) {
+            let path = dunce::canonicalize(&file.path).unwrap_or(file.path);
+            if !path.starts_with(src_dir) {
+                self.includes
+                    .entry(path)
+                    .or_default()
+                    .insert(chapter_file.to_path_buf());
+            }
+        }
+    }
+
+    /// Write the manifest to the cache's state dir, one `<include>\t<chapter>` line per chapter
+    pub fn save(&self, cache_dir: &Path) -> Result<()> {
+        let mut manifest = String::new();
+        for (include, chapters) in &self.includes {
+            for chapter in chapters {
+                manifest.push_str(&format!("{}\t{}\n", include.display(), chapter.display()));
+            }
+        }
+
+        let state_dir = cache_dir.join(STATE_DIR);
+        let manifest_file = state_dir.join(MANIFEST_FILE);
+        fs::create_dir_all(&state_dir)
+            .and_then(|_| fs::write(&manifest_file, manifest))
+            .with_context(|| format!("Failed to write the include manifest {manifest_file:?}"))
+    }
+
+    /// Read the manifest from the cache's state dir, an empty manifest when there is none
+    pub fn load(cache_dir: &Path) -> Self {
+        let mut manifest = Self::default();
+        if let Ok(content) = fs::read_to_string(cache_dir.join(STATE_DIR).join(MANIFEST_FILE)) {
+            for (include, chapter) in content.lines().filter_map(|line| line.split_once('\t')) {
+                manifest
+                    .includes
+                    .entry(PathBuf::from(include))
+                    .or_default()
+                    .insert(PathBuf::from(chapter));
+            }
+        }
+
+        manifest
+    }
+
+    /// The chapters including any of the given files
+    fn chapters_including<'a>(
+        &'a self,
+        includes: impl IntoIterator<Item = &'a PathBuf>,
+    ) -> BTreeSet<&'a PathBuf> {
+        includes
+            .into_iter()
+            .filter_map(|include| self.includes.get(include))
+            .flatten()
+            .collect()
+    }
+}
+
+/// Watch the included files in the book's include manifest (written by the preprocessor
+/// when watch-includes is enabled) and touch the including chapters when one changes, so
+/// a running `mdbook serve` rebuilds the book (rendering the changed diagrams). Runs until
+/// the process is stopped.
+/// # Arguments
+/// * `book_dir` - The book's root directory
+pub fn watch_includes(book_dir: &Path) -> Result<()> {
+    let cache_dir = dunce::canonicalize(book_dir)?.join(CACHE_DIR);
+    log::info!(
+        "Watching the files in the include manifest in {:?}",
+        cache_dir
+    );
+
+    let mut modified_times = HashMap::new();
+    loop {
+        // Reloaded every time, the manifest is updated by every build
+        let manifest = IncludeManifest::load(&cache_dir);
+        let changed = changed_files(manifest.includes.keys(), &mut modified_times);
+        for chapter in manifest.chapters_including(&changed) {
+            log::info!("An included file changed, touching {:?}", chapter);
+            // Rewrite the chapter as is, which is a change for the mdbook serve watcher
+            if let Err(e) = fs::read(chapter).and_then(|content| fs::write(chapter, content)) {
+                log::warn!("Failed to touch {:?} ({})", chapter, e);
+            }
+        }
+
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// The files whose modification time changed since the previous call, files seen for the
+/// first time are not changed.
+fn changed_files<'a>(
+    files: impl IntoIterator<Item = &'a PathBuf>,
+    modified_times: &mut HashMap<PathBuf, Option<SystemTime>>,
+) -> Vec<PathBuf> {
+    let mut changed = vec![];
+    for file in files {
+        let modified = fs::metadata(file).and_then(|m| m.modified()).ok();
+        match modified_times.insert(file.clone(), modified) {
+            Some(previous) if previous != modified => changed.push(file.clone()),
+            _ => {}
+        }
+    }
+
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_include_manifest() {
+        let book_dir = tempdir().unwrap();
+        let book_root = dunce::canonicalize(book_dir.path()).unwrap();
+        let src_dir = book_root.join("src");
+        fs::create_dir_all(&src_dir).unwrap();
+        fs::write(book_root.join("style.iuml"), "skinparam monochrome true").unwrap();
+        fs::write(src_dir.join("local.iuml"), "A --> B").unwrap();
+
+        let chapter_file = src_dir.join("chapter.md");
+        let mut manifest = IncludeManifest::default();
+        manifest.add_diagram(
+            "!include ../style.iuml\n!include local.iuml\nA --> B",
+            &src_dir,
+            &chapter_file,
+            &src_dir,
+        );
+
+        // Only the includes outside the src dir are recorded
+        let mut expected = IncludeManifest::default();
+        expected
+            .includes
+            .entry(book_root.join("style.iuml"))
+            .or_default()
+            .insert(chapter_file.clone());
+        assert_eq!(expected, manifest);
+
+        manifest.save(&book_root).unwrap();
+        assert_eq!(manifest, IncludeManifest::load(&book_root));
+        assert_eq!(IncludeManifest::default(), IncludeManifest::load(&src_dir));
+
+        let style = book_root.join("style.iuml");
+        assert_eq!(
+            vec![&chapter_file],
+            manifest
+                .chapters_including(&[style])
+                .into_iter()
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_changed_files() {
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("style.iuml");
+        fs::write(&file, "skinparam monochrome true").unwrap();
+        let files = vec![file.clone()];
+
+        let mut modified_times = HashMap::new();
+        assert!(changed_files(&files, &mut modified_times).is_empty());
+        assert!(changed_files(&files, &mut modified_times).is_empty());
+
+        fs::remove_file(&file).unwrap();
+        assert_eq!(files, changed_files(&files, &mut modified_times));
+    }
+}
@@ -0,0 +1,752 @@
+use crate::include_cache::IncludeCache;
+use anyhow::{bail, Context, Result};
+use std::borrow::Cow;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Maximum nesting level of included files including other files
+const MAX_INCLUDE_DEPTH: usize = 10;
+
+/// Expand mdBook's `{{#include path}}` directives in PlantUML code, the
+/// PlantUML code blocks are then independent of the preprocessor ordering.
+/// Like mdBook, line ranges (`path:2:10`, `path:2:`, `path::10`, `path:2`) and
+/// anchors (`path:anchor`) are supported, and escaped directives
+/// (`\{{#include path}}`) are kept (without the backslash).
+/// # Arguments
+/// * `code` - The PlantUML code
+/// * `base_dir` - The directory relative include paths are resolved from (the
+///   chapter's directory)
+/// * `root_dir` - The directory the included files must be in (the book root when
+///   restrict-includes-to-book is enabled), None to include files anywhere
+pub fn expand_includes<'a>(
+    code: &'a str,
+    base_dir: &Path,
+    root_dir: Option<&Path>,
+) -> Result<Cow<'a, str>> {
+    if !code.contains("{{") {
+        return Ok(Cow::Borrowed(code));
+    }
+
+    let root_dir = match root_dir {
+        Some(root_dir) => Some(
+            dunce::canonicalize(root_dir)
+                .with_context(|| format!("Failed to resolve the book root {root_dir:?}"))?,
+        ),
+        None => None,
+    };
+    expand(code, base_dir, root_dir.as_deref(), 0)
+}
+
+fn expand<'a>(
+    code: &'a str,
+    base_dir: &Path,
+    root_dir: Option<&Path>,
+    depth: usize,
+) -> Result<Cow<'a, str>> {
+    if !code.contains("{{") {
+        return Ok(Cow::Borrowed(code));
+    }
+
+    let mut expanded = String::with_capacity(code.len());
+    let mut rest = code;
+    while let Some(start) = rest.find("{{") {
+        let (before, directive) = rest.split_at(start);
+        let end = match directive.find("}}") {
+            Some(end) => end + 2,
+            None => break,
+        };
+
+        match include_args(&directive[..end]) {
+            Some(_) if before.ends_with('\\') => {
+                // Escaped directive, keep it as is
+                expanded.push_str(&before[..before.len() - 1]);
+                expanded.push_str(&directive[..end]);
+            }
+            Some(args) => {
+                if depth >= MAX_INCLUDE_DEPTH {
+                    bail!(
+                        "Failed to include '{}', the maximum include depth ({}) is exceeded",
+                        args,
+                        MAX_INCLUDE_DEPTH
+                    );
+                }
+
+                expanded.push_str(before);
+                expanded.push_str(&include(args, base_dir, root_dir, depth)?);
+            }
+            None => {
+                expanded.push_str(before);
+                expanded.push_str(&directive[..2]);
+                rest = &directive[2..];
+                continue;
+            }
+        }
+
+        rest = &directive[end..];
+    }
+    expanded.push_str(rest);
+
+    Ok(Cow::Owned(expanded))
+}
+
+/// Get the arguments of an include directive (`{{#include args}}`), None if
+/// this is not an include directive.
+fn include_args(directive: &str) -> Option<&str> {
+    let inner = directive.strip_prefix("{{")?.strip_suffix("}}")?.trim();
+    let args = inner.strip_prefix("#include")?;
+    if !args.starts_with(char::is_whitespace) {
+        return None;
+    }
+
+    let args = args.trim();
+    if args.is_empty() {
+        None
+    } else {
+        Some(args)
+    }
+}
+
+/// Read the (selected lines of the) included file
+fn include(args: &str, base_dir: &Path, root_dir: Option<&Path>, depth: usize) -> Result<String> {
+    let mut parts = args.splitn(3, ':');
+    let rel_path = parts.next().unwrap_or_default();
+    let first = parts.next();
+    let second = parts.next();
+
+    let path = base_dir.join(rel_path);
+    if let Some(root_dir) = root_dir {
+        let canonical = dunce::canonicalize(&path)
+            .with_context(|| format!("Failed to include '{}' ({:?})", args, path))?;
+        if !canonical.starts_with(root_dir) {
+            bail!(
+                "Failed to include '{}', {:?} is outside the book directory {:?} \
+                 (restrict-includes-to-book is enabled)",
+                args,
+                canonical,
+                root_dir
+            );
+        }
+    }
+    let content = fs::read_to_string(&path)
+        .with_context(|| format!("Failed to include '{}' ({:?})", args, path))?;
+    let selected = select_lines(&content, first, second)
+        .with_context(|| format!("Failed to include '{}' ({:?})", args, path))?;
+
+    let include_dir = path.parent().unwrap_or(base_dir);
+    Ok(expand(&selected, include_dir, root_dir, depth + 1)?.into_owned())
+}
+
+/// Select the lines given by the line range or anchor (all lines if both are None)
+fn select_lines(content: &str, first: Option<&str>, second: Option<&str>) -> Result<String> {
+    let parse_line = |value: &str| -> Result<Option<usize>> {
+        if value.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(value.parse::<usize>()?))
+        }
+    };
+
+    let (start, end) = match (first, second) {
+        (None, _) => return Ok(content.to_string()),
+        (Some(first), None) => {
+            if let Ok(line) = first.parse::<usize>() {
+                (Some(line), Some(line))
+            } else {
+                return anchored_lines(content, first);
+            }
+        }
+        (Some(first), Some(second)) => (parse_line(first)?, parse_line(second)?),
+    };
+
+    // Line numbers are 1 based and inclusive
+    let skip = start.unwrap_or(1).saturating_sub(1);
+    let take = end.map_or(usize::MAX, |end| end.saturating_sub(skip));
+    let mut selected = String::new();
+    for line in content.lines().skip(skip).take(take) {
+        selected.push_str(line);
+        selected.push('\n');
+    }
+
+    Ok(selected)
+}
+
+/// Select the lines between the `ANCHOR: name` and `ANCHOR_END: name` lines
+fn anchored_lines(content: &str, anchor: &str) -> Result<String> {
+    let start_marker = format!("ANCHOR: {anchor}");
+    let end_marker = format!("ANCHOR_END: {anchor}");
+
+    let mut selected = String::new();
+    let mut in_anchor = false;
+    for line in content.lines() {
+        if in_anchor {
+            if line.contains(&end_marker) {
+                return Ok(selected);
+            }
+            if !line.contains("ANCHOR: ") && !line.contains("ANCHOR_END: ") {
+                selected.push_str(line);
+                selected.push('\n');
+            }
+        } else if line.contains(&start_marker) {
+            in_anchor = true;
+        }
+    }
+
+    if in_anchor {
+        Ok(selected)
+    } else {
+        bail!("Anchor '{}' not found", anchor)
+    }
+}
+
+/// The PlantUML include directives (the longer ones with the same prefix first)
+const PLANTUML_INCLUDE_DIRECTIVES: [&str; 5] = [
+    "!include_many",
+    "!include_once",
+    "!includesub",
+    "!includeurl",
+    "!include",
+];
+
+/// The text of the PlantUML directives and functions loading a file (or URL)
+const PLANTUML_LOAD_MARKERS: [&str; 4] = ["!include", "!import", "!theme", "%load_json"];
+
+/// A local file included by a PlantUML `!include` directive
+#[derive(Debug, Clone)]
+pub struct IncludedFile {
+    pub path: PathBuf,
+    /// The file's content, or its (memoized) content hash for a stable file
+    pub content: Vec<u8>,
+    /// The file matches the hash-ignore patterns (see IncludeCache)
+    pub stable: bool,
+}
+
+/// Get the local files included by the PlantUML `!include` directives in the
+/// code (recursively, in include order). Standard library (`!include <...>`),
+/// URL and missing includes are skipped (PlantUML reports the missing ones).
+/// # Arguments
+/// * `code` - The PlantUML code
+/// * `base_dir` - The directory relative include paths are resolved from (the
+///   chapter's directory), nested includes are relative to the including file
+pub fn plantuml_includes(code: &str, base_dir: &Path) -> Vec<IncludedFile> {
+    plantuml_includes_with(code, base_dir, &IncludeCache::default())
+}
+
+/// Get the local files included by the PlantUML code (see plantuml_includes), the files read
+/// before are taken from the cache. The stable files are not read (their memoized content hash
+/// is used), nor are their nested includes.
+pub fn plantuml_includes_with(
+    code: &str,
+    base_dir: &Path,
+    cache: &IncludeCache,
+) -> Vec<IncludedFile> {
+    let mut included = vec![];
+    if code.contains("!include") {
+        collect_plantuml_includes(code, base_dir, cache, 0, &mut included);
+    }
+
+    included
+}
+
+fn collect_plantuml_includes(
+    code: &str,
+    base_dir: &Path,
+    cache: &IncludeCache,
+    depth: usize,
+    included: &mut Vec<IncludedFile>,
+) {
+    for rel_path in code.lines().filter_map(plantuml_include_path) {
+        let path = base_dir.join(rel_path);
+        if depth >= MAX_INCLUDE_DEPTH || included.iter().any(|file| file.path == path) {
+            continue;
+        }
+
+        if cache.is_stable(&path) {
+            if let Some(hash) = cache.content_hash(&path) {
+                included.push(IncludedFile {
+                    path,
+                    content: hash.into_bytes(),
+                    stable: true,
+                });
+            }
+        } else if let Some(content) = cache.read(&path) {
+            let nested = String::from_utf8_lossy(&content).into_owned();
+            let include_dir = path.parent().unwrap_or(base_dir).to_path_buf();
+            included.push(IncludedFile {
+                path,
+                content: content.to_vec(),
+                stable: false,
+            });
+            collect_plantuml_includes(&nested, &include_dir, cache, depth + 1, included);
+        }
+    }
+}
+
+/// Fail when a local file loaded by the PlantUML code (by the `!include` and `!import`
+/// directives, `!theme ... from` and `%load_json`, also in nested includes) is outside the root
+/// directory (e.g. `!include /etc/passwd`, or `!include ../../secret.txt`). Targets that cannot be
+/// resolved before rendering are rejected too, i.e. missing files, `file://` URLs and targets
+/// built from variables or functions (e.g. `!include $file`). Standard library (`<...>`) and
+/// remote URL targets are allowed.
+/// # Arguments
+/// * `code` - The PlantUML code
+/// * `base_dir` - The directory relative include paths are resolved from (the
+///   chapter's directory)
+/// * `root_dir` - The directory the included files must be in (the book root)
+pub fn check_plantuml_includes_within(code: &str, base_dir: &Path, root_dir: &Path) -> Result<()> {
+    if !PLANTUML_LOAD_MARKERS
+        .iter()
+        .any(|marker| code.contains(marker))
+    {
+        return Ok(());
+    }
+
+    let root_dir = dunce::canonicalize(root_dir)
+        .with_context(|| format!("Failed to resolve the book root {root_dir:?}"))?;
+    check_loads_within(code, base_dir, &root_dir, 0, &mut vec![])
+}
+
+fn check_loads_within(
+    code: &str,
+    base_dir: &Path,
+    root_dir: &Path,
+    depth: usize,
+    checked: &mut Vec<PathBuf>,
+) -> Result<()> {
+    for line in code.lines() {
+        for (directive, target) in plantuml_loads(line) {
+            let path = match resolve_within(target, base_dir, root_dir)? {
+                Some(path) => path,
+                None => continue,
+            };
+            if !PLANTUML_INCLUDE_DIRECTIVES.contains(&directive) || checked.contains(&path) {
+                continue;
+            }
+
+            if depth >= MAX_INCLUDE_DEPTH {
+                bail!(
+                    "Failed to check {:?}, the maximum include depth ({}) is exceeded",
+                    path,
+                    MAX_INCLUDE_DEPTH
+                );
+            }
+            let content =
+                fs::read(&path).with_context(|| format!("Failed to read include {path:?}"))?;
+            checked.push(path.clone());
+            let include_dir = path.parent().unwrap_or(base_dir);
+            check_loads_within(
+                &String::from_utf8_lossy(&content),
+                include_dir,
+                root_dir,
+                depth + 1,
+                checked,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolve the target of a PlantUML load directive, the (canonical) local file, or None for a
+/// standard library include or a remote URL. Fails when the target is outside the root directory
+/// or cannot be resolved.
+fn resolve_within(target: &str, base_dir: &Path, root_dir: &Path) -> Result<Option<PathBuf>> {
+    let dynamic = target.contains('$') || (target.contains('%') && target.contains('('));
+    if dynamic {
+        bail!(
+            "The PlantUML diagram loads '{}', which cannot be resolved before rendering \
+             (restrict-includes-to-book is enabled)",
+            target
+        );
+    }
+
+    if target.starts_with('<') {
+        return Ok(None);
+    }
+
+    if let Some((scheme, _)) = target.split_once("://") {
+        if !scheme.eq_ignore_ascii_case("file") {
+            return Ok(None);
+        }
+        bail!(
+            "The PlantUML diagram loads '{}', file URLs are not allowed \
+             (restrict-includes-to-book is enabled)",
+            target
+        );
+    }
+
+    let path = base_dir.join(target);
+    let path = dunce::canonicalize(&path).with_context(|| {
+        format!(
+            "The PlantUML diagram loads {path:?}, which does not exist \
+             (restrict-includes-to-book is enabled)"
+        )
+    })?;
+    if !path.starts_with(root_dir) {
+        bail!(
+            "The PlantUML diagram includes {:?}, which is outside the book directory {:?} \
+             (restrict-includes-to-book is enabled)",
+            path,
+            root_dir
+        );
+    }
+
+    Ok(Some(path))
+}
+
+/// Get the directives loading a file (or URL) in a line of PlantUML code with their (unresolved)
+/// target. For `%load_json` the target is the first argument, a string literal is unquoted.
+fn plantuml_loads(line: &str) -> Vec<(&'static str, &str)> {
+    let line = line.trim();
+    if line.starts_with('\'') {
+        return vec![];
+    }
+
+    let mut loads = vec![];
+    if let Some(include) = plantuml_include(line) {
+        loads.push(include);
+    } else if let Some(args) = line.strip_prefix("!import") {
+        if args.starts_with(char::is_whitespace) {
+            loads.push(("!import", args.trim().trim_matches('"')));
+        }
+    } else if line.starts_with("!theme") {
+        if let Some((_, dir)) = line.split_once(" from ") {
+            loads.push(("!theme", dir.trim().trim_matches('"')));
+        }
+    }
+
+    let mut rest = line;
+    while let Some(start) = rest.find("%load_json(") {
+        rest = &rest[start + "%load_json(".len()..];
+        let end = rest.find([',', ')']).unwrap_or(rest.len());
+        let arg = rest[..end].trim();
+        let target = match arg.strip_prefix('"').and_then(|arg| arg.strip_suffix('"')) {
+            Some(literal) => literal,
+            // Not a string literal (e.g. a variable), it cannot be resolved
+            None => arg,
+        };
+        loads.push(("%load_json", target));
+    }
+
+    loads
+}
+
+/// Get the URLs included by the PlantUML `!include` and `!includeurl`
+/// directives in the code and the local files it includes.
+/// # Arguments
+/// * `code` - The PlantUML code
+/// * `base_dir` - The directory relative include paths are resolved from (the
+///   chapter's directory)
+pub fn remote_plantuml_includes(code: &str, base_dir: &Path) -> Vec<String> {
+    let included = plantuml_includes(code, base_dir);
+    let included_code = included
+        .iter()
+        .map(|file| String::from_utf8_lossy(&file.content));
+
+    let mut urls = vec![];
+    for code in std::iter::once(Cow::Borrowed(code)).chain(included_code) {
+        for line in code.lines() {
+            match plantuml_include(line) {
+                Some((directive, url)) if directive == "!includeurl" || url.contains("://") => {
+                    urls.push(url.to_string());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    urls
+}
+
+/// Get the path of a PlantUML `!include` directive line, None if the line is
+/// not a local file include.
+fn plantuml_include_path(line: &str) -> Option<&str> {
+    match plantuml_include(line) {
+        Some((directive, path))
+            if directive != "!includeurl" && !path.starts_with('<') && !path.contains("://") =>
+        {
+            Some(path)
+        }
+        _ => None,
+    }
+}
+
+/// Get the directive and target (path, URL or `<stdlib>` include) of a PlantUML
+/// include directive line, without the `!1`, or `!id` suffix. None if the line
+/// is not an include directive.
+fn plantuml_include(line: &str) -> Option<(&'static str, &str)> {
+    let line = line.trim();
+    let (directive, args) = PLANTUML_INCLUDE_DIRECTIVES
+        .iter()
+        .find_map(|directive| Some((*directive, line.strip_prefix(directive)?)))?;
+    if !args.starts_with(char::is_whitespace) {
+        return None;
+    }
+
+    let args = args.trim().trim_matches('"');
+    // The suffix starts at the first `!` after the URL scheme (if any)
+    let suffix_start = args.find("://").map_or(0, |pos| pos + 3);
+    let target = match args[suffix_start..].find('!') {
+        Some(pos) => &args[..suffix_start + pos],
+        None => args,
+    };
+    let target = target.trim();
+
+    if target.is_empty() {
+        None
+    } else {
+        Some((directive, target))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_expand_includes() {
+        let dir = tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("diagrams")).unwrap();
+        fs::write(dir.path().join("diagrams/foo.puml"), "A --|> B\n").unwrap();
+        fs::write(
+            dir.path().join("diagrams/nested.puml"),
+            "{{#include foo.puml}}C --|> D\n",
+        )
+        .unwrap();
+
+        // Nothing to include
+        let code = "@startuml\nA --|> B\n@enduml\n";
+        assert!(matches!(
+            expand_includes(code, dir.path(), None).unwrap(),
+            Cow::Borrowed(_)
+        ));
+
+        assert_eq!(
+            "@startuml\nA --|> B\n\n@enduml\n",
+            expand_includes(
+                "@startuml\n{{#include diagrams/foo.puml}}\n@enduml\n",
+                dir.path(),
+                None
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            "A --|> B\n",
+            expand_includes("{{ #include  diagrams/foo.puml }}", dir.path(), None).unwrap()
+        );
+
+        // Nested includes are relative to the included file
+        assert_eq!(
+            "A --|> B\nC --|> D\n",
+            expand_includes("{{#include diagrams/nested.puml}}", dir.path(), None).unwrap()
+        );
+
+        // Escaped and other directives are kept
+        assert_eq!(
+            "{{#include diagrams/foo.puml}} {{#title foo}} {{",
+            expand_includes(
+                "\\{{#include diagrams/foo.puml}} {{#title foo}} {{",
+                dir.path(),
+                None
+            )
+            .unwrap()
+        );
+
+        assert!(expand_includes("{{#include missing.puml}}", dir.path(), None).is_err());
+    }
+
+    #[test]
+    fn test_recursive_include() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("self.puml"), "{{#include self.puml}}").unwrap();
+
+        let result = expand_includes("{{#include self.puml}}", dir.path(), None);
+        assert!(format!("{:#}", result.unwrap_err()).contains("maximum include depth"));
+    }
+
+    #[test]
+    fn test_expand_includes_within() {
+        let outside = tempdir().unwrap();
+        fs::write(outside.path().join("secret.txt"), "secret").unwrap();
+        let book_root = outside.path().join("book");
+        fs::create_dir_all(book_root.join("src")).unwrap();
+        fs::write(book_root.join("common.puml"), "A --|> B\n").unwrap();
+        fs::write(
+            book_root.join("src/nested.puml"),
+            "{{#include ../../secret.txt}}",
+        )
+        .unwrap();
+        let chapter_dir = book_root.join("src");
+
+        let expand =
+            |code: &str| expand_includes(code, &chapter_dir, Some(&book_root)).map(Cow::into_owned);
+        assert_eq!("A --|> B\n", expand("{{#include ../common.puml}}").unwrap());
+
+        // Absolute paths and traversals out of the book are rejected, also in nested includes
+        let secret = outside.path().join("secret.txt");
+        let error = format!(
+            "{:#}",
+            expand(&format!("{{{{#include {}}}}}", secret.to_string_lossy())).unwrap_err()
+        );
+        assert!(error.contains("outside the book directory"));
+        assert!(expand("{{#include ../../secret.txt}}").is_err());
+        assert!(expand("{{#include nested.puml}}").is_err());
+        assert!(expand("{{#include missing.puml}}").is_err());
+
+        // Without a root dir files outside the book can be included
+        assert_eq!(
+            "secret",
+            expand_includes("{{#include ../../secret.txt}}", &chapter_dir, None).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_plantuml_includes() {
+        let dir = tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("diagrams")).unwrap();
+        fs::write(dir.path().join("diagrams/foo.puml"), "!include bar.puml\n").unwrap();
+        fs::write(
+            dir.path().join("diagrams/bar.puml"),
+            "!include foo.puml\nA\n",
+        )
+        .unwrap();
+
+        let code =
+            "@startuml\n!include <C4/C4_Container>\n!includeurl https://example.com/a.puml\n\
+                    !include https://example.com/b.puml\n  !include_once diagrams/foo.puml!1\n\
+                    !include missing.puml\n@enduml\n";
+        let included = plantuml_includes(code, dir.path());
+        let paths: Vec<&Path> = included.iter().map(|file| file.path.as_path()).collect();
+        assert_eq!(
+            vec![
+                dir.path().join("diagrams/foo.puml"),
+                dir.path().join("diagrams/bar.puml")
+            ],
+            paths
+        );
+        assert_eq!(b"!include foo.puml\nA\n".to_vec(), included[1].content);
+
+        assert!(plantuml_includes("A --|> B", dir.path()).is_empty());
+    }
+
+    #[test]
+    fn test_plantuml_include_path() {
+        assert_eq!(Some("foo.puml"), plantuml_include_path("!include foo.puml"));
+        assert_eq!(
+            Some("a b.puml"),
+            plantuml_include_path("!include \"a b.puml\"")
+        );
+        assert_eq!(
+            Some("foo.puml"),
+            plantuml_include_path("!includesub foo.puml!SUB")
+        );
+        assert_eq!(
+            Some("foo.puml"),
+            plantuml_include_path("!include_many foo.puml")
+        );
+        assert_eq!(None, plantuml_include_path("!include"));
+        assert_eq!(None, plantuml_include_path("!includefoo.puml"));
+        assert_eq!(None, plantuml_include_path("!include <stdlib/foo>"));
+        assert_eq!(None, plantuml_include_path("' !include foo.puml"));
+        assert_eq!(None, plantuml_include_path("!includeurl foo.puml"));
+    }
+
+    #[test]
+    fn test_check_plantuml_includes_within() {
+        let outside = tempdir().unwrap();
+        fs::write(outside.path().join("secret.txt"), "secret").unwrap();
+        let book_root = outside.path().join("book");
+        fs::create_dir_all(book_root.join("src/sub")).unwrap();
+        fs::write(
+            book_root.join("common.puml"),
+            "!include src/sub/nested.puml",
+        )
+        .unwrap();
+        fs::write(book_root.join("src/sub/nested.puml"), "A --|> B").unwrap();
+        let chapter_dir = book_root.join("src");
+
+        let check = |code: &str| check_plantuml_includes_within(code, &chapter_dir, &book_root);
+        assert!(check("A --|> B").is_ok());
+        assert!(check("!include ../common.puml\n!include <C4/C4_Container>").is_ok());
+        assert!(check("!include https://example.com/a.puml\n' !include /etc/passwd").is_ok());
+        assert!(check("!import ../common.puml\n!$data = %load_json(\"sub/nested.puml\")").is_ok());
+
+        let secret = outside.path().join("secret.txt");
+        assert!(check("!include ../../secret.txt").is_err());
+        assert!(check(&format!("!include {}", secret.to_string_lossy())).is_err());
+        assert!(check("!import ../../secret.txt").is_err());
+        assert!(check("!theme spacelab from ../..").is_err());
+        assert!(check("!$data = %load_json(\"../../secret.txt\")").is_err());
+
+        // Targets that cannot be resolved before rendering are rejected
+        let error = format!("{:#}", check("!include missing.puml").unwrap_err());
+        assert!(error.contains("does not exist"));
+        assert!(check("!$f = \"../../secret.txt\"\n!include $f").is_err());
+        assert!(check("!include %dirpath()/../../secret.txt").is_err());
+        assert!(check("!$data = %load_json($f)").is_err());
+        assert!(check(&format!("!include file://{}", secret.to_string_lossy())).is_err());
+
+        // Nested includes are checked too
+        fs::write(
+            book_root.join("src/sub/nested.puml"),
+            "!include ../../../secret.txt",
+        )
+        .unwrap();
+        let error = format!("{:#}", check("!include ../common.puml").unwrap_err());
+        assert!(error.contains("outside the book directory"));
+    }
+
+    #[test]
+    fn test_remote_plantuml_includes() {
+        let dir = tempdir().unwrap();
+        fs::write(
+            dir.path().join("common.puml"),
+            "!include https://example.com/c.puml!1\n",
+        )
+        .unwrap();
+
+        let code = "!include <C4/C4_Container>\n!includeurl http://example.com/a.puml\n\
+                    !include https://example.com/b.puml\n!include common.puml\n";
+        assert_eq!(
+            vec![
+                "http://example.com/a.puml",
+                "https://example.com/b.puml",
+                "https://example.com/c.puml"
+            ],
+            remote_plantuml_includes(code, dir.path())
+        );
+        assert!(
+            remote_plantuml_includes("!include common.puml", Path::new("/nonexistent")).is_empty()
+        );
+    }
+
+    #[test]
+    fn test_select_lines() {
+        let content = "1\n2\n3\n4\n";
+        assert_eq!(content, select_lines(content, None, None).unwrap());
+        assert_eq!("2\n", select_lines(content, Some("2"), None).unwrap());
+        assert_eq!(
+            "2\n3\n",
+            select_lines(content, Some("2"), Some("3")).unwrap()
+        );
+        assert_eq!(
+            "3\n4\n",
+            select_lines(content, Some("3"), Some("")).unwrap()
+        );
+        assert_eq!(
+            "1\n2\n",
+            select_lines(content, Some(""), Some("2")).unwrap()
+        );
+        assert!(select_lines(content, Some("a"), Some("2")).is_err());
+    }
+
+    #[test]
+    fn test_anchored_lines() {
+        let content =
+            "A\n' ANCHOR: foo\nB\n' ANCHOR: bar\nC\n' ANCHOR_END: bar\n' ANCHOR_END: foo\nD\n";
+        assert_eq!("B\nC\n", select_lines(content, Some("foo"), None).unwrap());
+        assert_eq!("C\n", select_lines(content, Some("bar"), None).unwrap());
+        assert!(select_lines(content, Some("baz"), None).is_err());
+    }
+}
@@ -1,99 +1,719 @@
+mod alt_lint;
 mod backend;
 #[cfg(any(feature = "plantuml-ssl-server", feature = "plantuml-server"))]
 mod base64;
+mod bench;
+mod compare;
 mod config;
+mod config_schema;
+mod cwd_guard;
+mod diagram_kind;
 mod dir_cleaner;
+mod explain;
+mod export;
+mod extra_sources;
+mod failure;
+mod figures;
+mod fingerprint;
+mod front_matter;
+mod glossary;
+mod include_cache;
+mod include_watch;
+mod includes;
+mod link_check;
+mod log_context;
+mod long_path;
+mod markings;
+mod offline;
 mod pipeline;
+mod prerender;
+mod preview;
+mod render_source;
 mod renderer;
+mod signing;
+mod snapshot;
+mod stable_svg;
+mod update;
+mod warm;
 
-use crate::pipeline::render_plantuml_code_blocks;
+use crate::alt_lint::diagrams_without_alt_text;
+pub use crate::bench::{bench, BenchResult};
+pub use crate::compare::{compare_versions, comparison_report, DiagramComparison};
+pub use crate::config_schema::config_schema;
+pub use crate::explain::explain_cache;
+pub use crate::export::export_diagrams;
+use crate::extra_sources::render_extra_sources;
+pub use crate::failure::FailureKind;
+use crate::figures::{add_list_of_figures, duplicate_diagram_ids, resolve_diagram_references};
+use crate::glossary::add_glossary;
+use crate::include_cache::IncludeCache;
+pub use crate::include_watch::watch_includes;
+use crate::include_watch::IncludeManifest;
+use crate::link_check::{broken_link, svg_links};
+use crate::log_context::ChapterLog;
+pub use crate::log_context::{CHAPTER_DONE_TARGET, LOG_CONTEXT_PATTERN, LOG_GROUP_KEY};
+use crate::long_path::long_path;
+use crate::markings::DiagramMarkings;
+use crate::offline::check_offline;
+use crate::pipeline::{
+    diagram_source, plantuml_diagrams, render_plantuml_code_blocks, rendered_diagrams,
+    DiagramChapter,
+};
+use crate::prerender::{prerender, prerender_jobs};
+pub use crate::preview::preview;
+pub use crate::render_source::render_source;
+pub use crate::snapshot::{export_cache_snapshot, import_cache_snapshot};
+pub use crate::update::{check_update, upgrade_instructions};
+pub use crate::warm::warm_cache;
 
-use crate::config::Config;
+use crate::config::{
+    BudgetExceeded, Config, DiagramLinkCheck, LinkStyle, MissingBackend, RendererStrategy,
+};
+use crate::cwd_guard::CwdGuard;
+use crate::dir_cleaner::GITIGNORE_FILE_NAME;
+use crate::failure::tag;
 use crate::renderer::Renderer;
 use anyhow::{bail, Context, Result};
-use mdbook::book::{Book, BookItem};
+use mdbook::book::{Book, BookItem, Chapter};
 use mdbook::preprocess::PreprocessorContext;
+use std::borrow::Cow;
 use std::fs;
 
 use std::path::{Path, PathBuf};
 
-pub struct Preprocessor;
+/// The directory (relative to the book root) for cached images in data URI mode
+/// and other files that should not be monitored by the serve command.
+const CACHE_DIR: &str = ".mdbook-plantuml-cache";
+
+/// The directory (in the cache dir) for the state kept between builds (e.g. the detected PlantUML
+/// command). The cache dir is the image dir in data URI mode, its files that are not a used image
+/// are removed by the DirCleaner after every build, the files in sub dirs are left alone.
+const STATE_DIR: &str = "state";
+
+#[derive(Default)]
+pub struct Preprocessor {
+    /// Re-run the PlantUML command detection instead of using the cached result
+    pub revalidate: bool,
+    /// The `key=value` option overrides (--set) applied on top of the book.toml options
+    pub overrides: Vec<String>,
+}
 
 impl mdbook::preprocess::Preprocessor for Preprocessor {
     fn name(&self) -> &str {
         "plantuml"
     }
 
-    fn run(
+    fn run(&self, ctx: &PreprocessorContext, book: Book) -> Result<Book, mdbook::errors::Error> {
+        let mut book_cfg = ctx.config.clone();
+        apply_overrides(&mut book_cfg, &self.overrides).map_err(tag(FailureKind::Config))?;
+        self.process_book(&ctx.root, &book_cfg, &ctx.renderer, book)
+    }
+
+    fn supports_renderer(&self, renderer: &str) -> bool {
+        self.supports_config().supports_renderer(renderer)
+    }
+}
+
+impl Preprocessor {
+    /// The config for the supports command. mdbook does not pass the book config to the supports
+    /// command, but runs it from the book root (normally), so try reading the config from there.
+    fn supports_config(&self) -> Config {
+        mdbook::Config::from_disk("book.toml")
+            .ok()
+            .and_then(|mut book_cfg| {
+                apply_overrides(&mut book_cfg, &self.overrides).ok()?;
+                config_from_book_config(&book_cfg).ok()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Whether the renderer is supported, and why (or why not)
+    /// # Arguments
+    /// * `renderer` - The renderer's name (e.g. "html")
+    /// * `strict` - Only the renderers the diagrams are rendered for are supported, the
+    ///   passthrough renderers are not
+    pub fn renderer_support(&self, renderer: &str, strict: bool) -> (bool, String) {
+        let cfg = self.supports_config();
+        let supported = match cfg.renderer_strategy(renderer) {
+            RendererStrategy::Unsupported => false,
+            RendererStrategy::Passthrough => !strict,
+            RendererStrategy::EpubProfile | RendererStrategy::Render => true,
+        };
+        (supported, cfg.explain_renderer_strategy(renderer))
+    }
+
+    /// Get the preprocessor config (with the overrides applied) from the preprocessor context
+    pub fn config(&self, ctx: &PreprocessorContext) -> Result<Config> {
+        let mut book_cfg = ctx.config.clone();
+        apply_overrides(&mut book_cfg, &self.overrides)
+            .and_then(|_| config_from_book_config(&book_cfg))
+            .map_err(tag(FailureKind::Config))
+    }
+
+    /// Render the PlantUML code blocks in the book for the given renderer
+    /// # Arguments
+    /// * `root` - The book's root directory
+    /// * `book_cfg` - The book's configuration (book.toml)
+    /// * `renderer_name` - The renderer the book is preprocessed for (e.g. "html")
+    /// * `book` - The book to process
+    fn process_book(
         &self,
-        ctx: &PreprocessorContext,
+        root: &Path,
+        book_cfg: &mdbook::Config,
+        renderer_name: &str,
         mut book: Book,
-    ) -> Result<Book, mdbook::errors::Error> {
-        let cfg = plantuml_config(ctx);
-        let img_output_dir = image_output_dir(&ctx.root, &ctx.config.book.src, &cfg)?;
-        let org_cwd = std::env::current_dir()?;
+    ) -> Result<Book> {
+        let mut cfg = config_from_book_config(book_cfg)?;
+        cfg.revalidate = self.revalidate;
+        match cfg.renderer_strategy(renderer_name) {
+            RendererStrategy::Unsupported => {
+                // The supports command could not find the book config, so check again
+                log::info!(
+                    "Skipping PlantUML rendering, the '{}' renderer is not in supported-renderers",
+                    renderer_name
+                );
+                return Ok(book);
+            }
+            RendererStrategy::Passthrough => {
+                // Leave the code blocks untouched, the renderer would not copy the images anyway
+                log::info!(
+                    "Keeping the PlantUML code blocks as is for the '{}' renderer",
+                    renderer_name
+                );
+                return Ok(book);
+            }
+            RendererStrategy::EpubProfile => {
+                log::info!("Using data URIs with png images for the epub renderer");
+                cfg.apply_epub_profile();
+            }
+            RendererStrategy::Render => {}
+        }
+
+        let img_output_dir = image_output_dir(root, &book_cfg.book.src, &mut cfg)?;
+        let book_root = dunce::canonicalize(root)?;
+        resolve_config_paths(&mut cfg, &book_root);
+        if cfg.offline {
+            check_offline(
+                &book,
+                &book_root.join(&book_cfg.book.src),
+                cfg.strict_info_string,
+            )?;
+        }
+        if cfg.require_alt {
+            let missing = diagrams_without_alt_text(
+                &book,
+                &book_root.join(&book_cfg.book.src),
+                cfg.strict_info_string,
+            );
+            if !missing.is_empty() {
+                let message = format!(
+                    "The following PlantUML diagrams have no alt text (add alt=\"...\" to the \
+                     info string, or give the diagram a title):\n{}",
+                    missing.join("\n")
+                );
+                if cfg.fail_on_error {
+                    return Err(tag(FailureKind::Render)(anyhow::format_err!(message)));
+                }
+                log::warn!("{}", message);
+            }
+        }
+        let cache_dir = book_root.join(CACHE_DIR);
+        if cfg.gitignore {
+            write_gitignore(&img_output_dir);
+            write_gitignore(&cache_dir);
+        }
+        if cfg.update_notice && !cfg.offline {
+            update::notify_update(&cache_dir, &|name| std::env::var(name).ok());
+        }
+        let cwd_guard = CwdGuard::new()?;
+
+        // A server the plugin is built without support for has its own policy
+        let missing_backend = match backend::factory::unsupported_server(&cfg) {
+            Some(_) => cfg.unsupported_backend,
+            None => cfg.missing_backend,
+        };
+        let backend = match backend::factory::create(&cfg, &book_root, &cache_dir) {
+            Ok(backend) => Some(backend),
+            Err(e) => match missing_backend {
+                MissingBackend::Error => return Err(tag(FailureKind::BackendNotFound)(e)),
+                MissingBackend::Placeholder => {
+                    log::warn!("{:#}, rendering placeholders for the uncached diagrams", e);
+                    None
+                }
+                MissingBackend::KeepSource => {
+                    log::warn!("{:#}, keeping the PlantUML code blocks as is", e);
+                    return Ok(book);
+                }
+            },
+        };
+        let signing_key = cfg
+            .cache_signing_key(&|name| std::env::var(name).ok())
+            .map_err(tag(FailureKind::Config))?;
+        let renderer = Renderer::new(&cfg, backend, img_output_dir)
+            .with_signing_key(signing_key)
+            .with_tool_version(backend::factory::tool_version(&cfg, &cache_dir))
+            .with_include_cache(IncludeCache::new(
+                &book_root,
+                &cfg.hash_ignore,
+                Some(&cache_dir),
+            ));
+        let site_url = book_cfg
+            .get("output.html.site-url")
+            .and_then(|url| url.as_str());
+        let mut render_error: Option<anyhow::Error> = None;
+        if !cfg.extra_sources.is_empty() {
+            if cfg.use_data_uris {
+                log::warn!(
+                    "Skipping the extra-sources, these are not rendered when using data URIs"
+                );
+            } else if let Err(e) = render_extra_sources(&renderer, &book_root, &cfg.extra_sources) {
+                if cfg.fail_on_error {
+                    render_error = Some(e.context("Failed to render the PlantUML extra-sources"));
+                } else {
+                    log::error!("Failed to render the PlantUML extra-sources ({:#})", e);
+                }
+            }
+        }
 
-        let renderer = Renderer::new(&cfg, img_output_dir);
+        let src_dir = book_root.join(&book_cfg.book.src);
+        let link_check = match cfg.check_diagram_links {
+            // External links cannot be checked in offline mode
+            DiagramLinkCheck::All if cfg.offline => DiagramLinkCheck::Internal,
+            link_check => link_check,
+        };
+        let markings = DiagramMarkings::from_config(&cfg, book_cfg.book.title.as_deref());
+        // Render the uncached diagrams in parallel first (the render budget needs sequential renders)
+        let max_parallel = cfg.max_parallel();
+        let concurrency_safe = renderer
+            .backend_capabilities()
+            .map_or(false, |capabilities| capabilities.concurrency_safe);
+        if max_parallel > 1 && concurrency_safe && cfg.max_total_render_seconds.is_none() {
+            let jobs = prerender_jobs(&book, &src_dir, &renderer, &cfg, &markings);
+            prerender(&renderer, &jobs, max_parallel, &|| {
+                backend::factory::create(&cfg, &book_root, &cache_dir)
+            });
+        }
+
+        let chapter_ctx = ChapterContext {
+            cfg: &cfg,
+            renderer: &renderer,
+            book_root: &book_root,
+            src_dir: &src_dir,
+            markings: &markings,
+            link_check,
+            site_url,
+        };
+        let mut results = BookResults::default();
         book.for_each_mut(|item: &mut BookItem| {
+            if render_error.is_some() {
+                return;
+            }
+
             if let BookItem::Chapter(ref mut chapter) = *item {
-                if let Some(chapter_path) = &chapter.path {
-                    log::info!("Processing chapter '{}' ({:?})", chapter.name, chapter_path);
-                    let abs_chapter_dir = dunce::canonicalize(&ctx.root).unwrap().join(&ctx.config.book.src).join(chapter_path).parent().unwrap().to_path_buf();
-
-                    // Change the working dir so the PlantUML `!include` directive can be used using relative includes
-                    if let Err(e) = std::env::set_current_dir(&abs_chapter_dir) {
-                        log::warn!("Failed to change working dir to {:?}, PlantUML might not be able to render includes ({}).", &abs_chapter_dir, e);
-                    }
-                    log::debug!("Changed working dir to {:?}.", abs_chapter_dir);
-
-                    let rel_image_url = relative_img_url(chapter_path);
-                    chapter.content = render_plantuml_code_blocks(&chapter.content, &renderer, &rel_image_url);
+                if let Err(e) = process_chapter(chapter, &chapter_ctx, &mut results) {
+                    render_error = Some(e);
                 }
             }
         });
+        let BookResults {
+            include_manifest,
+            broken_links,
+            failures,
+            chapter_figures,
+            metadata_chapters,
+        } = results;
 
         //Restore the current working dir
-        std::env::set_current_dir(org_cwd)?;
+        drop(cwd_guard);
+
+        if cfg.watch_includes {
+            if let Err(e) = include_manifest.save(&cache_dir) {
+                log::warn!("{:#}", e);
+            }
+        }
+
+        if let Some(e) = render_error {
+            return Err(tag(FailureKind::Render)(e));
+        }
+        if cfg.metadata_cache {
+            backend::shell::prune_metadata_cache(&cache_dir, &metadata_chapters);
+        }
+        if !broken_links.is_empty() {
+            let message = format!(
+                "The following PlantUML diagrams have broken links:\n{}",
+                broken_links.join("\n")
+            );
+            if cfg.fail_on_error {
+                return Err(tag(FailureKind::Render)(anyhow::format_err!(message)));
+            }
+            log::warn!("{}", message);
+        }
+        if !failures.is_empty() {
+            log::warn!(
+                "{} diagram(s) failed to render: {}",
+                failures.len(),
+                failures.join(", ")
+            );
+        }
+
+        // Second pass, now all diagrams are known the references to them can be resolved
+        let mut figures = vec![];
+        let mut chapters = chapter_figures.iter().peekable();
+        for (index, figure) in renderer.take_figures().into_iter().enumerate() {
+            while chapters.peek().map_or(false, |(_, count)| *count <= index) {
+                chapters.next();
+            }
+            if let Some((chapter_path, _)) = chapters.peek() {
+                figures.push((chapter_path.clone(), figure));
+            }
+        }
+
+        // Colliding anchors would make the references (and the list of figures) ambiguous
+        let duplicates = duplicate_diagram_ids(&figures);
+        if !duplicates.is_empty() {
+            return Err(tag(FailureKind::Render)(anyhow::format_err!(
+                "Duplicate diagram id(s), use a unique id= per diagram:\n  {}",
+                duplicates.join("\n  ")
+            )));
+        }
+
+        if cfg.list_of_figures {
+            add_list_of_figures(&mut book, &figures);
+        }
+        if cfg.glossary {
+            add_glossary(&mut book, &figures);
+        }
+
+        let unresolved = resolve_diagram_references(&mut book, &figures, cfg.list_of_figures);
+        if !unresolved.is_empty() {
+            let message = format!(
+                "Unknown diagram id(s) in {{{{#diagram}}}} references: {}",
+                unresolved.join(", ")
+            );
+            if cfg.fail_on_error {
+                return Err(tag(FailureKind::Render)(anyhow::format_err!(message)));
+            }
+            log::warn!("{}", message);
+        }
 
-        // TODO: also return error state for further processing
         Ok(book)
     }
+}
 
-    fn supports_renderer(&self, renderer: &str) -> bool {
-        renderer != "not-supported"
-    }
+/// The state shared by the chapters of a book while rendering (see process_chapter)
+struct ChapterContext<'a> {
+    cfg: &'a Config,
+    renderer: &'a Renderer,
+    book_root: &'a Path,
+    src_dir: &'a Path,
+    markings: &'a DiagramMarkings,
+    link_check: DiagramLinkCheck,
+    site_url: Option<&'a str>,
 }
 
-fn image_output_dir(root: &Path, src_root: &Path, cfg: &Config) -> Result<PathBuf> {
-    let img_output_dir: PathBuf = {
-        let canonicalized_root =
-            dunce::canonicalize(root).with_context(|| "While determining image output dir")?;
-        if cfg.use_data_uris {
-            // Create the images in the book root dir (unmonitored by the serve command)
-            // This way the rendered images can be cached without causing additional
-            // rebuilds.
-            canonicalized_root.join(".mdbook-plantuml-cache")
-        } else {
-            // Create the images in the book src dir
-            canonicalized_root
-                .join(src_root)
-                .join("mdbook-plantuml-img")
+/// What is collected while rendering the chapters of a book (see process_chapter)
+#[derive(Default)]
+struct BookResults {
+    /// The included files of the diagrams, for the watch-includes option
+    include_manifest: IncludeManifest,
+    /// The broken links in the diagrams, e.g. "diagram 1 in chapter 'Intro' links to ..."
+    broken_links: Vec<String>,
+    /// The diagrams that failed to render (embedded errors), e.g. "ch1/a.md block 2"
+    failures: Vec<String>,
+    /// The chapter paths, and the number of figures rendered up to and including that chapter
+    chapter_figures: Vec<(PathBuf, usize)>,
+    /// The chapters and their number of diagrams, to prune the metadata cache
+    metadata_chapters: Vec<(String, usize)>,
+}
+
+/// Render the PlantUML code blocks in a chapter, and check the links in its diagrams
+/// # Arguments
+/// * `chapter` - The chapter to process, its content is replaced by the rendered content
+/// * `ctx` - The state shared by the book's chapters
+/// * `results` - Collects the chapter's results
+fn process_chapter(
+    chapter: &mut Chapter,
+    ctx: &ChapterContext,
+    results: &mut BookResults,
+) -> Result<()> {
+    let cfg = ctx.cfg;
+    let renderer = ctx.renderer;
+    // Draft chapters have no file, their diagrams are rendered from the book root (and linked as
+    // if the chapter is in the src dir), so these preview correctly while authoring
+    let (chapter_path, abs_chapter_dir) = match &chapter.path {
+        Some(chapter_path) => (
+            chapter_path.clone(),
+            ctx.src_dir
+                .join(chapter_path)
+                .parent()
+                .unwrap()
+                .to_path_buf(),
+        ),
+        None => (
+            draft_chapter_path(&chapter.name),
+            ctx.book_root.to_path_buf(),
+        ),
+    };
+    let chapter_path = &chapter_path;
+    let _chapter_log = ChapterLog::new(chapter_path, cfg.group_chapter_logs);
+    if chapter.path.is_some() {
+        log::info!("Processing chapter '{}' ({:?})", chapter.name, chapter_path);
+    } else {
+        log::info!(
+            "Processing draft chapter '{}' (it has no file), using the book root as working dir",
+            chapter.name
+        );
+    }
+
+    // Change the working dir so the PlantUML `!include` directive can be used using relative
+    // includes
+    if let Err(e) = std::env::set_current_dir(&abs_chapter_dir) {
+        log::warn!(
+            "Failed to change working dir to {:?}, PlantUML might not be able to render includes ({}).",
+            &abs_chapter_dir,
+            e
+        );
+    }
+    log::debug!("Changed working dir to {:?}.", abs_chapter_dir);
+
+    let chapter_markings = ctx.markings.for_chapter(&chapter.name);
+    let diagram_chapter = DiagramChapter {
+        include_dir: &abs_chapter_dir,
+        markings: &chapter_markings,
+    };
+    if cfg.watch_includes && chapter.path.is_some() {
+        let chapter_file = ctx.src_dir.join(chapter_path);
+        for diagram in plantuml_diagrams(&chapter.content, cfg.strict_info_string) {
+            let code = diagram_source(&diagram_chapter, &diagram, cfg).unwrap_or(diagram.code);
+            results.include_manifest.add_diagram(
+                &code,
+                &abs_chapter_dir,
+                &chapter_file,
+                ctx.src_dir,
+            );
+        }
+    }
+
+    let diagrams = if ctx.link_check == DiagramLinkCheck::Off {
+        vec![]
+    } else {
+        plantuml_diagrams(&chapter.content, cfg.strict_info_string)
+    };
+
+    if cfg.metadata_cache {
+        results.metadata_chapters.push((
+            chapter_path.display().to_string(),
+            rendered_diagrams(&chapter.content, cfg).len(),
+        ));
+    }
+
+    let rel_image_url = img_url(chapter_path, cfg.link_style, ctx.site_url);
+    let mut failed_diagrams = vec![];
+    let rendered = render_plantuml_code_blocks(
+        &chapter.content,
+        renderer,
+        &rel_image_url,
+        &abs_chapter_dir,
+        cfg,
+        &chapter_markings,
+        &mut failed_diagrams,
+    );
+    let render_error = match rendered {
+        Ok(content) => {
+            if let Cow::Owned(content) = content {
+                chapter.content = content;
+            }
+            results
+                .chapter_figures
+                .push((chapter_path.clone(), renderer.figure_count()));
+            results.failures.extend(
+                failed_diagrams
+                    .iter()
+                    .map(|index| format!("{} block {}", chapter_path.display(), index)),
+            );
+            None
         }
+        Err(e) => Some(e.context(format!(
+            "Failed to render PlantUML diagram in chapter '{}' ({:?})",
+            chapter.name, chapter_path
+        ))),
     };
 
+    for (index, diagram) in diagrams.iter().enumerate() {
+        // Diagrams that could not be prepared are not rendered
+        let code = match diagram_source(&diagram_chapter, diagram, cfg) {
+            Ok(code) => code,
+            Err(_) => continue,
+        };
+        let image_file = renderer.image_file(&code, &abs_chapter_dir, &diagram.format);
+        if image_file.extension().map_or(true, |ext| ext != "svg") {
+            continue;
+        }
+        let svg = fs::read_to_string(&image_file).unwrap_or_default();
+        for url in svg_links(&svg) {
+            if let Some(reason) = broken_link(&url, &abs_chapter_dir, ctx.src_dir, ctx.link_check) {
+                results.broken_links.push(format!(
+                    "  diagram {} in chapter '{}' ({:?}) links to {} ({})",
+                    index + 1,
+                    chapter.name,
+                    chapter_path,
+                    url,
+                    reason
+                ));
+            }
+        }
+    }
+
+    if let Some(e) = render_error {
+        return Err(e);
+    }
+    if cfg.render_budget_exceeded == BudgetExceeded::Error && renderer.render_budget_spent() {
+        bail!(renderer.render_budget_report());
+    }
+    Ok(())
+}
+
+/// Kill the PlantUML helper processes (e.g. FTP servers) left behind by earlier
+/// builds of the book, returns the number of killed processes.
+/// # Arguments
+/// * `book_dir` - The book's root directory
+pub fn kill_daemons(book_dir: &Path) -> Result<usize> {
+    backend::process_guard::kill_daemons(&book_dir.join(CACHE_DIR))
+}
+
+/// Resolve a (config) path relative to the book root, absolute paths are returned
+/// as is. Relative config paths cannot be resolved relative to the working dir,
+/// because it is changed to the chapter's dir while rendering.
+fn resolve_book_path(book_root: &Path, path: &Path) -> PathBuf {
+    if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        book_root.join(path)
+    }
+}
+
+/// Make the path valued config options (except the extra-sources globs, which
+/// are resolved when searching) absolute, see resolve_book_path.
+fn resolve_config_paths(cfg: &mut Config, book_root: &Path) {
+    cfg.book_root = Some(book_root.to_path_buf());
+    if let Some(temp_dir) = &cfg.temp_dir {
+        cfg.temp_dir = Some(resolve_book_path(book_root, temp_dir));
+    }
+    if let Some(jar) = &cfg.plantuml_jar {
+        cfg.plantuml_jar = Some(resolve_book_path(book_root, jar));
+    }
+    for font_dir in &mut cfg.font_config.font_dirs {
+        *font_dir = resolve_book_path(book_root, font_dir);
+    }
+    for entry in &mut cfg.include_allowlist {
+        if !entry.contains("://") {
+            *entry = resolve_book_path(book_root, Path::new(entry))
+                .to_string_lossy()
+                .into_owned();
+        }
+    }
+}
+
+/// Write a .gitignore ignoring all generated files in the given dir (creating
+/// the dir if needed). An existing .gitignore is honored (left untouched).
+fn write_gitignore(dir: &Path) {
+    let gitignore = dir.join(GITIGNORE_FILE_NAME);
+    if gitignore.exists() {
+        return;
+    }
+
+    let result = fs::create_dir_all(dir)
+        .and_then(|_| fs::write(&gitignore, "# Generated by mdbook-plantuml\n*\n"));
+    if let Err(e) = result {
+        log::warn!("Failed to write {:?} ({})", gitignore, e);
+    }
+}
+
+/// The image output/cache dir (see image_output_dir), without creating it
+/// # Arguments
+/// * `book_root` - The book's (canonicalized) root directory
+/// * `src_root` - The book's src dir
+/// * `cfg` - The configuration options
+fn image_dir(book_root: &Path, src_root: &Path, cfg: &Config) -> PathBuf {
+    if cfg.use_data_uris {
+        // Create the images in the book root dir (unmonitored by the serve command)
+        // This way the rendered images can be cached without causing additional
+        // rebuilds.
+        book_root.join(CACHE_DIR)
+    } else {
+        // Create the images in the book src dir
+        book_root.join(src_root).join("mdbook-plantuml-img")
+    }
+}
+
+/// Create the image output/cache dir. When the images cannot be written to the book's src dir
+/// (e.g. it is mounted read-only) data URIs are used instead (this is logged).
+/// # Arguments
+/// * `root` - The book's root directory
+/// * `src_root` - The book's src dir
+/// * `cfg` - The configuration options, use-data-uris is enabled on the fallback
+fn image_output_dir(root: &Path, src_root: &Path, cfg: &mut Config) -> Result<PathBuf> {
+    let canonicalized_root =
+        dunce::canonicalize(root).with_context(|| "While determining image output dir")?;
+    let img_output_dir = long_path(image_dir(&canonicalized_root, src_root, cfg));
+
     log::info!("Image output/cache dir will be {:?}", &img_output_dir);
 
     // Always create the image output dir
-    if !img_output_dir.is_dir() {
-        log::debug!("Image output/cache dir does not exists, creating...");
-        if let Err(e) = fs::create_dir_all(&img_output_dir) {
+    if let Err(e) = create_writable_dir(&img_output_dir) {
+        if cfg.use_data_uris {
             bail!("Failed to create the image output dir ({}).", e);
         }
+
+        log::warn!(
+            "The image output dir {:?} is not writable ({}), using data URIs instead",
+            img_output_dir,
+            e
+        );
+        cfg.use_data_uris = true;
+        return image_output_dir(root, src_root, cfg);
     }
 
     Ok(img_output_dir)
 }
 
+/// Create the directory (when it does not exist) and check files can be created in it
+fn create_writable_dir(dir: &Path) -> std::io::Result<()> {
+    if !dir.is_dir() {
+        log::debug!("Image output/cache dir does not exists, creating...");
+        fs::create_dir_all(dir)?;
+    }
+
+    // An unnamed temporary file (where supported), so it does not show up in the book's src dir
+    tempfile::tempfile_in(dir).map(|_| ())
+}
+
+/// The path used for a draft chapter (a chapter without file) in the log and error messages, e.g.
+/// "Intro (draft)". It has a single component, so the images are linked as if the chapter is in
+/// the src dir.
+fn draft_chapter_path(chapter_name: &str) -> PathBuf {
+    PathBuf::from(format!(
+        "{} (draft)",
+        chapter_name.replace(['/', '\\'], "-")
+    ))
+}
+
+/// Get the image URL for the given chapter
+/// # Arguments
+/// * `chapter_path` - The chapter's path relative to the book's src dir
+/// * `link_style` - Create a relative, or an absolute URL
+/// * `site_url` - The book's site URL (output.html.site-url), used as prefix
+///   for absolute URLs (defaults to "/")
+fn img_url(chapter_path: &Path, link_style: LinkStyle, site_url: Option<&str>) -> String {
+    match link_style {
+        LinkStyle::Relative => relative_img_url(chapter_path),
+        LinkStyle::Absolute => format!(
+            "{}/mdbook-plantuml-img",
+            site_url.unwrap_or("/").trim_end_matches('/')
+        ),
+    }
+}
+
 fn relative_img_url(chapter_path: &Path) -> String {
     let nesting_level = chapter_path.components().count();
     let mut rel_image_url = String::new();
@@ -105,22 +725,55 @@ fn relative_img_url(chapter_path: &Path) -> String {
     rel_image_url
 }
 
-pub fn plantuml_config(ctx: &PreprocessorContext) -> Config {
-    ctx.config
-        .get("preprocessor.plantuml")
-        .and_then(|raw| {
-            raw.clone()
-                .try_into()
-                .map_err(|e| {
-                    log::warn!(
-                        "Failed to get config from book.toml, using default configuration ({}).",
-                        e
-                    );
-                    e
-                })
-                .ok()
-        })
-        .unwrap_or_default()
+pub fn plantuml_config(ctx: &PreprocessorContext) -> Result<Config> {
+    config_from_book_config(&ctx.config)
+}
+
+/// Apply the `key=value` overrides to the preprocessor options in the book config.
+/// Values are parsed as JSON when possible (e.g. `false`, `12`, `["html"]`), otherwise
+/// they are used as string (e.g. `plantuml-cmd=java -jar plantuml.jar`).
+/// # Arguments
+/// * `book_cfg` - The book's configuration (book.toml)
+/// * `overrides` - The overrides (e.g. "use-data-uris=false")
+fn apply_overrides(book_cfg: &mut mdbook::Config, overrides: &[String]) -> Result<()> {
+    for assignment in overrides {
+        let (key, value) = match assignment.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => (key.trim(), value.trim()),
+            _ => bail!(
+                "Invalid option override '{}' (expected key=value)",
+                assignment
+            ),
+        };
+
+        let value = serde_json::from_str(value)
+            .unwrap_or_else(|_| serde_json::Value::String(value.to_string()));
+        book_cfg
+            .set(format!("preprocessor.plantuml.{key}"), value)
+            .with_context(|| format!("Failed to apply option override '{assignment}'"))?;
+    }
+
+    Ok(())
+}
+
+/// Get the preprocessor config from the book config, with the ${VAR} environment
+/// variable references resolved. Fails (tagged as a configuration failure) when the options are
+/// invalid.
+fn config_from_book_config(book_cfg: &mdbook::Config) -> Result<Config> {
+    let mut cfg = match book_cfg.get("preprocessor.plantuml") {
+        Some(raw) => raw
+            .clone()
+            .try_into()
+            .map_err(anyhow::Error::from)
+            .and_then(Config::from_options)
+            .context("Invalid [preprocessor.plantuml] options in book.toml")
+            .map_err(tag(FailureKind::Config))?,
+        None => Config::default(),
+    };
+
+    cfg.interpolate_env(&|name| std::env::var(name).ok())
+        .map_err(tag(FailureKind::Config))?;
+    cfg.detect_offline(&|name| std::env::var(name).ok());
+    Ok(cfg)
 }
 
 #[cfg(test)]
@@ -129,6 +782,107 @@ mod tests {
     use pretty_assertions::assert_eq;
     use tempfile::tempdir;
 
+    #[test]
+    fn test_resolve_book_path() {
+        let book_root = tempdir().unwrap();
+        assert_eq!(
+            book_root.path().join("tmp"),
+            resolve_book_path(book_root.path(), Path::new("tmp"))
+        );
+        assert_eq!(
+            book_root.path().join("../tmp"),
+            resolve_book_path(book_root.path(), Path::new("../tmp"))
+        );
+
+        let abs_path = book_root.path().join("some/where");
+        assert_eq!(abs_path, resolve_book_path(Path::new("book"), &abs_path));
+    }
+
+    #[test]
+    fn test_resolve_config_paths() {
+        let mut cfg = Config {
+            temp_dir: Some(PathBuf::from("tmp")),
+            plantuml_jar: Some(PathBuf::from("tools/plantuml.jar")),
+            include_allowlist: vec![
+                String::from("common"),
+                String::from("https://example.com/lib"),
+            ],
+            ..Config::default()
+        };
+        resolve_config_paths(&mut cfg, Path::new("/book"));
+        assert_eq!(
+            vec![
+                Path::new("/book")
+                    .join("common")
+                    .to_string_lossy()
+                    .into_owned(),
+                String::from("https://example.com/lib"),
+            ],
+            cfg.include_allowlist
+        );
+        assert_eq!(Some(Path::new("/book").join("tmp")), cfg.temp_dir);
+        assert_eq!(
+            Some(Path::new("/book").join("tools/plantuml.jar")),
+            cfg.plantuml_jar
+        );
+
+        let mut cfg = Config::default();
+        resolve_config_paths(&mut cfg, Path::new("/book"));
+        assert_eq!(None, cfg.temp_dir);
+    }
+
+    #[test]
+    fn test_config_from_book_config() {
+        let book_cfg: mdbook::Config = "[preprocessor.plantuml]\nprofile = \"ci\"\nkeep-failed = false\nmax-diagram-bytes = 1024\n"
+            .parse()
+            .unwrap();
+        let cfg = config_from_book_config(&book_cfg).unwrap();
+        assert_eq!(true, cfg.fail_on_error);
+        assert_eq!(false, cfg.keep_failed);
+        assert_eq!(Some(1024), cfg.max_diagram_bytes);
+    }
+
+    #[test]
+    fn test_invalid_book_config() {
+        for options in [
+            "profile = \"fats\"\nplantuml-cmd = \"plantuml\"\n",
+            "use-data-uris = \"yes\"\n",
+        ] {
+            let book_cfg: mdbook::Config = format!("[preprocessor.plantuml]\n{options}")
+                .parse()
+                .unwrap();
+            let error = config_from_book_config(&book_cfg).unwrap_err();
+            assert_eq!(FailureKind::Config, FailureKind::of(&error), "{options}");
+            assert!(format!("{error:#}").contains("Invalid [preprocessor.plantuml] options"));
+        }
+    }
+
+    #[test]
+    fn test_apply_overrides() {
+        let mut book_cfg: mdbook::Config = "[preprocessor.plantuml]\nuse-data-uris = true\n"
+            .parse()
+            .unwrap();
+        apply_overrides(
+            &mut book_cfg,
+            &[
+                String::from("use-data-uris=false"),
+                String::from("plantuml-cmd = java -jar plantuml.jar"),
+                String::from("supported-renderers=[\"html\"]"),
+                String::from("max-diagram-bytes=100"),
+            ],
+        )
+        .unwrap();
+
+        let cfg = config_from_book_config(&book_cfg).unwrap();
+        assert_eq!(false, cfg.use_data_uris);
+        assert_eq!(Some("java -jar plantuml.jar"), cfg.plantuml_cmd.as_deref());
+        assert_eq!(Some(vec![String::from("html")]), cfg.supported_renderers);
+        assert_eq!(Some(100), cfg.max_diagram_bytes);
+
+        assert!(apply_overrides(&mut book_cfg, &[String::from("use-data-uris")]).is_err());
+        assert!(apply_overrides(&mut book_cfg, &[String::from("=false")]).is_err());
+    }
+
     #[test]
     fn test_relative_img_url() {
         assert_eq!(
@@ -147,22 +901,248 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_draft_chapter_path() {
+        let path = draft_chapter_path("Design/Notes");
+        assert_eq!(Path::new("Design-Notes (draft)"), path);
+        assert_eq!("mdbook-plantuml-img", relative_img_url(&path));
+    }
+
+    #[test]
+    fn test_process_draft_chapter() {
+        use mdbook::book::Chapter;
+
+        let book_dir = tempdir().unwrap();
+        fs::create_dir_all(book_dir.path().join("src")).unwrap();
+        fs::write(book_dir.path().join("common.puml"), "A --|> B\n").unwrap();
+        let mut book_cfg = mdbook::Config::default();
+        book_cfg
+            .set("preprocessor.plantuml.plantuml-cmd", "/does/not/exist")
+            .unwrap();
+        book_cfg
+            .set("preprocessor.plantuml.missing-backend", "placeholder")
+            .unwrap();
+
+        let mut draft = Chapter::new_draft("Draft", vec![]);
+        draft.content =
+            String::from("```plantuml,alt=\"Draft diagram\"\n!include common.puml\n```\n");
+        let mut book = Book::new();
+        book.push_item(draft);
+
+        let book = Preprocessor::default()
+            .process_book(book_dir.path(), &book_cfg, "html", book)
+            .unwrap();
+        match book.iter().next() {
+            Some(BookItem::Chapter(chapter)) => assert_eq!(
+                "\n*PlantUML diagram 'Draft diagram' not rendered (PlantUML is not available)*\n\n",
+                chapter.content
+            ),
+            _ => panic!("The draft chapter is missing"),
+        }
+    }
+
+    /// A fake PlantUML command, reporting its version and rendering every diagram as the same svg
+    #[cfg(unix)]
+    fn fake_plantuml(dir: &Path) -> String {
+        let script = dir.join("plantuml.sh");
+        fs::write(
+            &script,
+            "case \"$*\" in *-version*) echo 'PlantUML version 1.2.3';; \
+             *) cat > /dev/null; echo '<svg><rect/></svg>';; esac\n",
+        )
+        .unwrap();
+        format!("sh {}", script.display())
+    }
+
+    /// The state kept between builds is not removed with the unused images in data URI mode
+    #[cfg(unix)]
+    #[test]
+    fn test_state_survives_data_uri_builds() {
+        use mdbook::book::Chapter;
+
+        let book_dir = tempdir().unwrap();
+        let src_dir = book_dir.path().join("src");
+        fs::create_dir_all(&src_dir).unwrap();
+        let mut book_cfg = mdbook::Config::default();
+        book_cfg
+            .set(
+                "preprocessor.plantuml.plantuml-cmd",
+                fake_plantuml(book_dir.path()),
+            )
+            .unwrap();
+        book_cfg
+            .set("preprocessor.plantuml.watch-includes", true)
+            .unwrap();
+        book_cfg
+            .set("preprocessor.plantuml.update-notice", true)
+            .unwrap();
+        book_cfg
+            .set("preprocessor.plantuml.hash-ignore", vec!["src/common.puml"])
+            .unwrap();
+        fs::write(src_dir.join("common.puml"), "skinparam monochrome true").unwrap();
+        let cache_dir = book_dir.path().join(CACHE_DIR);
+        let state_dir = cache_dir.join(STATE_DIR);
+
+        // Checked recently, so crates.io is not queried
+        let last_update_check = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            .to_string();
+        fs::create_dir_all(&state_dir).unwrap();
+        fs::write(state_dir.join("update-check"), &last_update_check).unwrap();
+
+        for _ in 0..2 {
+            let mut book = Book::new();
+            book.push_item(Chapter::new(
+                "Intro",
+                String::from("```plantuml\n!include common.puml\nA --|> B\n```\n"),
+                "intro.md",
+                vec![],
+            ));
+            Preprocessor::default()
+                .process_book(book_dir.path(), &book_cfg, "html", book)
+                .unwrap();
+
+            let images = fs::read_dir(&cache_dir)
+                .unwrap()
+                .flatten()
+                .filter(|entry| entry.path().extension().map_or(false, |ext| ext == "svg"))
+                .count();
+            assert_eq!(1, images);
+            assert!(state_dir.join("backend-detection.json").is_file());
+            assert!(state_dir.join("include-manifest").is_file());
+            assert!(state_dir.join("include-hashes.json").is_file());
+            assert_eq!(
+                last_update_check,
+                fs::read_to_string(state_dir.join("update-check")).unwrap()
+            );
+        }
+    }
+
+    #[cfg(not(feature = "plantuml-ssl-server"))]
+    #[test]
+    fn test_unsupported_backend_placeholder() {
+        let book_dir = tempdir().unwrap();
+        fs::create_dir_all(book_dir.path().join("src")).unwrap();
+        let mut book_cfg = mdbook::Config::default();
+        book_cfg
+            .set(
+                "preprocessor.plantuml.plantuml-cmd",
+                "https://www.plantuml.com/plantuml",
+            )
+            .unwrap();
+        book_cfg
+            .set("preprocessor.plantuml.unsupported-backend", "placeholder")
+            .unwrap();
+
+        let mut chapter = mdbook::book::Chapter::new_draft("Draft", vec![]);
+        chapter.content = String::from("```plantuml,alt=\"Diagram\"\nA --|> B\n```\n");
+        let mut book = Book::new();
+        book.push_item(chapter.clone());
+        let processed = Preprocessor::default()
+            .process_book(book_dir.path(), &book_cfg, "html", book)
+            .unwrap();
+        match processed.iter().next() {
+            Some(BookItem::Chapter(chapter)) => assert_eq!(
+                "\n*PlantUML diagram 'Diagram' not rendered (mdbook-plantuml is built without the \
+                 plantuml-ssl-server feature)*\n\n",
+                chapter.content
+            ),
+            _ => panic!("The chapter is missing"),
+        }
+
+        // The missing-backend option does not apply
+        book_cfg
+            .set("preprocessor.plantuml.unsupported-backend", "error")
+            .unwrap();
+        book_cfg
+            .set("preprocessor.plantuml.missing-backend", "placeholder")
+            .unwrap();
+        let mut book = Book::new();
+        book.push_item(chapter);
+        assert!(Preprocessor::default()
+            .process_book(book_dir.path(), &book_cfg, "html", book)
+            .is_err());
+    }
+
+    #[test]
+    fn test_img_url() {
+        assert_eq!(
+            String::from("../mdbook-plantuml-img"),
+            img_url(Path::new("chapter 1/nested 1"), LinkStyle::Relative, None)
+        );
+        // The site URL only applies to absolute URLs
+        assert_eq!(
+            String::from("../mdbook-plantuml-img"),
+            img_url(
+                Path::new("chapter 1/nested 1"),
+                LinkStyle::Relative,
+                Some("/docs/")
+            )
+        );
+
+        assert_eq!(
+            String::from("/mdbook-plantuml-img"),
+            img_url(Path::new("chapter 1"), LinkStyle::Absolute, None)
+        );
+        assert_eq!(
+            String::from("/mdbook-plantuml-img"),
+            img_url(Path::new("chapter 1/nested 1"), LinkStyle::Absolute, None)
+        );
+        assert_eq!(
+            String::from("/mdbook-plantuml-img"),
+            img_url(Path::new("chapter 1"), LinkStyle::Absolute, Some("/"))
+        );
+        assert_eq!(
+            String::from("/docs/mdbook-plantuml-img"),
+            img_url(Path::new("chapter 1"), LinkStyle::Absolute, Some("/docs/"))
+        );
+        assert_eq!(
+            String::from("https://example.com/docs/mdbook-plantuml-img"),
+            img_url(
+                Path::new("chapter 1/nested 1"),
+                LinkStyle::Absolute,
+                Some("https://example.com/docs")
+            )
+        );
+    }
+
+    #[test]
+    fn test_write_gitignore() {
+        let book_root = tempdir().unwrap();
+        let img_dir = book_root.path().join("src").join("mdbook-plantuml-img");
+
+        write_gitignore(&img_dir);
+        let contents = fs::read_to_string(img_dir.join(".gitignore")).unwrap();
+        assert!(contents.ends_with("\n*\n"));
+
+        // An existing .gitignore is not overwritten
+        fs::write(img_dir.join(".gitignore"), "*.svg\n").unwrap();
+        write_gitignore(&img_dir);
+        assert_eq!(
+            "*.svg\n",
+            fs::read_to_string(img_dir.join(".gitignore")).unwrap()
+        );
+    }
+
     #[test]
     fn test_image_output_dir_data_uri() {
         let output_dir = tempdir().unwrap();
         let book_root = output_dir.path().to_path_buf();
         let src_root = output_dir.path().join("src");
 
-        let cfg = Config {
+        let mut cfg = Config {
             plantuml_cmd: None,
             clickable_img: false,
             use_data_uris: true, // true = Create book_root/.mdbook-plantuml-cache
             verbose: false,
             piped: false,
+            ..Config::default()
         };
 
         assert_eq!(
-            image_output_dir(&book_root, &src_root, &cfg).unwrap(),
+            image_output_dir(&book_root, &src_root, &mut cfg).unwrap(),
             dunce::canonicalize(book_root.as_path().join(".mdbook-plantuml-cache")).unwrap()
         );
         assert!(book_root.as_path().join(".mdbook-plantuml-cache").exists());
@@ -175,16 +1155,17 @@ mod tests {
         let book_root = output_dir.path().to_path_buf();
         let src_root = output_dir.path().join("src");
 
-        let cfg = Config {
+        let mut cfg = Config {
             plantuml_cmd: None,
             clickable_img: false,
             use_data_uris: false, // false = Create src_root/.mdbook-plantuml-cache
             verbose: false,
             piped: false,
+            ..Config::default()
         };
 
         assert_eq!(
-            image_output_dir(&book_root, &src_root, &cfg).unwrap(),
+            image_output_dir(&book_root, &src_root, &mut cfg).unwrap(),
             src_root.as_path().join("mdbook-plantuml-img")
         );
         assert!(!book_root.as_path().join(".mdbook-plantuml-cache").exists());
@@ -197,16 +1178,38 @@ mod tests {
         let book_root = output_dir.path().to_path_buf();
         let src_root = output_dir.path().join("src");
 
-        let cfg = Config {
+        let mut cfg = Config {
             plantuml_cmd: None,
             clickable_img: false,
             use_data_uris: true, // true = Create book_root/.mdbook-plantuml-cache
             verbose: false,
             piped: false,
+            ..Config::default()
         };
 
         // Create a file with the same name as the directory, this should fail the dir creation
         fs::File::create(book_root.as_path().join(".mdbook-plantuml-cache")).unwrap();
-        assert!(image_output_dir(&book_root, &src_root, &cfg).is_err());
+        assert!(image_output_dir(&book_root, &src_root, &mut cfg).is_err());
+    }
+
+    #[test]
+    fn test_image_output_dir_fallback_to_data_uris() {
+        let output_dir = tempdir().unwrap();
+        let book_root = output_dir.path().to_path_buf();
+        let src_root = output_dir.path().join("src");
+        fs::create_dir_all(&src_root).unwrap();
+
+        let mut cfg = Config {
+            use_data_uris: false,
+            ..Config::default()
+        };
+
+        // The image dir cannot be created in the src dir, data URIs are used instead
+        fs::File::create(src_root.join("mdbook-plantuml-img")).unwrap();
+        assert_eq!(
+            image_output_dir(&book_root, &src_root, &mut cfg).unwrap(),
+            dunce::canonicalize(book_root.join(".mdbook-plantuml-cache")).unwrap()
+        );
+        assert!(cfg.use_data_uris);
     }
 }
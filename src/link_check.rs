@@ -0,0 +1,147 @@
+use crate::config::DiagramLinkCheck;
+use anyhow::Result;
+use std::path::Path;
+
+/// Get the hyperlink URLs (`[[url]]` links in the PlantUML source) from a rendered SVG image
+pub fn svg_links(svg: &str) -> Vec<String> {
+    let mut links = vec![];
+    let mut rest = svg;
+    while let Some(start) = rest.find("<a ") {
+        let tag = &rest[start..];
+        let tag = &tag[..tag.find('>').unwrap_or(tag.len())];
+        let href = [" href=\"", " xlink:href=\""].iter().find_map(|attribute| {
+            let value = &tag[tag.find(attribute)? + attribute.len()..];
+            Some(&value[..value.find('"')?])
+        });
+        if let Some(href) = href {
+            let href = href
+                .replace("&quot;", "\"")
+                .replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&amp;", "&");
+            if !links.contains(&href) {
+                links.push(href);
+            }
+        }
+
+        rest = &rest[start + tag.len()..];
+    }
+
+    links
+}
+
+/// Check the diagram link, returns the reason when the link is broken
+/// # Arguments
+/// * `url` - The link's URL
+/// * `chapter_dir` - The chapter's directory, relative links are resolved from here
+/// * `src_dir` - The book's source directory, absolute links (`/foo.html`) are resolved from here
+/// * `link_check` - Which links to check
+pub fn broken_link(
+    url: &str,
+    chapter_dir: &Path,
+    src_dir: &Path,
+    link_check: DiagramLinkCheck,
+) -> Option<String> {
+    if url.starts_with("http://") || url.starts_with("https://") {
+        return match link_check {
+            DiagramLinkCheck::All => check_external_url(url).err().map(|e| format!("{e:#}")),
+            _ => None,
+        };
+    }
+    if url.contains(':') || url.starts_with('#') || link_check == DiagramLinkCheck::Off {
+        // Other schemes (e.g. mailto:), and anchors within the page
+        return None;
+    }
+
+    let path = url.split(['#', '?']).next().unwrap_or_default();
+    if path.is_empty() {
+        return None;
+    }
+    let target = match path.strip_prefix('/') {
+        Some(path) => src_dir.join(path),
+        None => chapter_dir.join(path),
+    };
+
+    // Book pages are linked as .html, but are .md files in the src dir
+    let exists = target.exists()
+        || (target.extension().map_or(false, |ext| ext == "html")
+            && target.with_extension("md").exists())
+        || (target.is_dir() && target.join("README.md").exists());
+    (!exists).then(|| String::from("no such file in the book"))
+}
+
+#[cfg(any(feature = "plantuml-ssl-server", feature = "plantuml-server"))]
+fn check_external_url(url: &str) -> Result<()> {
+    let client = reqwest::blocking::Client::builder()
+        .user_agent(concat!("mdbook-plantuml/", env!("CARGO_PKG_VERSION")))
+        .timeout(std::time::Duration::from_secs(10))
+        .build()?;
+    client.head(url).send()?.error_for_status()?;
+    Ok(())
+}
+
+#[cfg(not(any(feature = "plantuml-ssl-server", feature = "plantuml-server")))]
+fn check_external_url(_url: &str) -> Result<()> {
+    anyhow::bail!(
+        "Checking external links requires the plantuml-server or plantuml-ssl-server feature"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_svg_links() {
+        let svg = "<svg><a href=\"intro.html\" target=\"_top\"><text>A</text></a>\
+                   <a xlink:href=\"https://example.com/?a=1&amp;b=2\" xlink:type=\"simple\"></a>\
+                   <a href=\"intro.html\"></a><g/></svg>";
+        assert_eq!(
+            vec!["intro.html", "https://example.com/?a=1&b=2"],
+            svg_links(svg)
+        );
+        assert!(svg_links("<svg><g/></svg>").is_empty());
+    }
+
+    #[test]
+    fn test_broken_link() {
+        let src_dir = tempdir().unwrap();
+        fs::create_dir_all(src_dir.path().join("ch1")).unwrap();
+        fs::write(src_dir.path().join("intro.md"), "# Intro").unwrap();
+        fs::write(src_dir.path().join("ch1/README.md"), "# Chapter 1").unwrap();
+        let chapter_dir = src_dir.path().join("ch1");
+        let broken = |url: &str| {
+            broken_link(
+                url,
+                &chapter_dir,
+                src_dir.path(),
+                DiagramLinkCheck::Internal,
+            )
+        };
+
+        assert_eq!(None, broken("../intro.html#usage"));
+        assert_eq!(None, broken("/intro.html"));
+        assert_eq!(None, broken("../ch1"));
+        assert_eq!(None, broken("#usage"));
+        assert_eq!(None, broken("mailto:someone@example.com"));
+        // External links are only checked when checking all links
+        assert_eq!(None, broken("https://example.invalid/"));
+        assert_eq!(
+            Some(String::from("no such file in the book")),
+            broken("missing.html")
+        );
+
+        assert_eq!(
+            None,
+            broken_link(
+                "missing.html",
+                &chapter_dir,
+                src_dir.path(),
+                DiagramLinkCheck::Off
+            )
+        );
+    }
+}
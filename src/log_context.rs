@@ -0,0 +1,138 @@
+use log_mdc::InsertGuard;
+use std::fmt::Display;
+use std::path::Path;
+
+/// The log4rs pattern of the render context fields of a message (e.g. " chapter=ch1.md block=2"),
+/// empty for messages logged outside of a render
+pub const LOG_CONTEXT_PATTERN: &str = "{X(chapter)}{X(block)}{X(hash)}";
+
+/// The log MDC key holding the chapter whose log output is grouped (see group-chapter-logs)
+pub const LOG_GROUP_KEY: &str = "log-group";
+
+/// The target of the message logged when all (grouped) log output of a chapter is logged
+pub const CHAPTER_DONE_TARGET: &str = "mdbook_plantuml::chapter_done";
+
+/// Add a field to the render context of the log messages of the current thread, until
+/// the returned guard is dropped.
+/// # Arguments
+/// * `key` - The field's key (one of the keys in LOG_CONTEXT_PATTERN)
+/// * `value` - The field's value
+pub fn log_field(key: &'static str, value: impl Display) -> InsertGuard {
+    log_mdc::insert_scoped(key, format!(" {key}={value}"))
+}
+
+/// The value of a render context field of the current thread (see log_field), None when the
+/// field is not set
+pub fn log_field_value(key: &str) -> Option<String> {
+    let prefix = format!(" {key}=");
+    log_mdc::get(key, |value| {
+        value
+            .and_then(|value| value.strip_prefix(&prefix))
+            .map(String::from)
+    })
+}
+
+/// The log context of a chapter, when grouping the log output the chapter's messages are
+/// logged at once when the context is dropped.
+pub struct ChapterLog {
+    chapter: String,
+    grouped: bool,
+    _chapter_field: InsertGuard,
+    _group: Option<InsertGuard>,
+}
+
+impl ChapterLog {
+    /// # Arguments
+    /// * `chapter_path` - The chapter's source (relative to the src dir)
+    /// * `grouped` - Group the chapter's log output
+    pub fn new(chapter_path: &Path, grouped: bool) -> Self {
+        let chapter = chapter_path.display().to_string();
+        Self {
+            _chapter_field: log_field("chapter", &chapter),
+            _group: grouped.then(|| log_mdc::insert_scoped(LOG_GROUP_KEY, &chapter)),
+            chapter,
+            grouped,
+        }
+    }
+}
+
+impl Drop for ChapterLog {
+    fn drop(&mut self) {
+        if self.grouped {
+            log::info!(target: CHAPTER_DONE_TARGET, "Finished chapter {:?}", self.chapter);
+        }
+    }
+}
+
+/// The log context of a diagram rendered outside of its chapter's processing (e.g. by a
+/// prerender thread), the same fields as the chapter processing adds. When grouping the log
+/// output the messages are logged with the chapter's.
+pub struct DiagramLog {
+    _chapter_field: InsertGuard,
+    _block_field: InsertGuard,
+    _hash_field: InsertGuard,
+    _group: Option<InsertGuard>,
+}
+
+impl DiagramLog {
+    /// # Arguments
+    /// * `chapter` - The chapter's source (relative to the src dir, see ChapterLog)
+    /// * `block` - The diagram's (1 based) number in the chapter
+    /// * `hash` - The diagram's cache hash
+    /// * `grouped` - Group the chapter's log output
+    pub fn new(chapter: &str, block: usize, hash: &str, grouped: bool) -> Self {
+        Self {
+            _chapter_field: log_field("chapter", chapter),
+            _block_field: log_field("block", block),
+            _hash_field: log_field("hash", &hash[..hash.len().min(12)]),
+            _group: grouped.then(|| log_mdc::insert_scoped(LOG_GROUP_KEY, chapter)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_chapter_log() {
+        let mdc_value = |key: &str| log_mdc::get(key, |value| value.map(String::from));
+        {
+            let _chapter = ChapterLog::new(Path::new("ch1/a.md"), true);
+            let _block = log_field("block", 2);
+            assert_eq!(
+                Some(String::from(" chapter=ch1/a.md")),
+                mdc_value("chapter")
+            );
+            assert_eq!(Some(String::from(" block=2")), mdc_value("block"));
+            assert_eq!(Some(String::from("2")), log_field_value("block"));
+            assert_eq!(Some(String::from("ch1/a.md")), mdc_value(LOG_GROUP_KEY));
+        }
+
+        assert_eq!(None, mdc_value("chapter"));
+        assert_eq!(None, mdc_value("block"));
+        assert_eq!(None, mdc_value(LOG_GROUP_KEY));
+        assert_eq!(None, log_field_value("block"));
+
+        let _chapter = ChapterLog::new(Path::new("ch1/a.md"), false);
+        assert_eq!(None, mdc_value(LOG_GROUP_KEY));
+    }
+
+    #[test]
+    fn test_diagram_log() {
+        {
+            let _diagram = DiagramLog::new("ch1/a.md", 3, "3f2a9c0d1e4b5a6c7d8e", true);
+            assert_eq!(Some(String::from("ch1/a.md")), log_field_value("chapter"));
+            assert_eq!(Some(String::from("3")), log_field_value("block"));
+            assert_eq!(Some(String::from("3f2a9c0d1e4b")), log_field_value("hash"));
+            assert_eq!(
+                Some(String::from("ch1/a.md")),
+                log_mdc::get(LOG_GROUP_KEY, |value| value.map(String::from))
+            );
+        }
+
+        assert_eq!(None, log_field_value("chapter"));
+        assert_eq!(None, log_field_value("hash"));
+    }
+}
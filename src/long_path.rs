@@ -0,0 +1,107 @@
+use std::path::PathBuf;
+
+/// Paths of this length (or longer) exceed MAX_PATH on windows. MAX_PATH is 260 characters
+/// including the terminating null, but directories are limited to 248 characters (room for an 8.3
+/// file name).
+const MAX_PATH_LENGTH: usize = 248;
+
+/// The prefix of an extended-length path on windows, its length is only limited by the file system
+const EXTENDED_LENGTH_PREFIX: &str = r"\\?\";
+
+/// Make the (absolute) path usable when it exceeds MAX_PATH on windows (e.g. deeply nested
+/// chapters in a deeply nested book), by turning it into an extended-length (`\\?\`) path. Other
+/// paths (and all paths elsewhere) are returned as is. The same path always gives the same result,
+/// so paths can still be compared after the conversion.
+pub fn long_path(path: PathBuf) -> PathBuf {
+    if cfg!(target_family = "windows") {
+        if let Some(extended) = path.to_str().and_then(extended_length_path) {
+            return PathBuf::from(extended);
+        }
+    }
+
+    path
+}
+
+/// The extended-length version of the windows path, None when it is short enough (or already
+/// extended). Windows does not normalize extended-length paths, so relative paths and paths with
+/// `.` or `..` components are not converted.
+fn extended_length_path(path: &str) -> Option<String> {
+    if path.len() < MAX_PATH_LENGTH || path.starts_with(EXTENDED_LENGTH_PREFIX) {
+        return None;
+    }
+
+    // Only backslashes are separators in an extended-length path
+    let path = path.replace('/', "\\");
+    if path
+        .split('\\')
+        .any(|component| component == "." || component == "..")
+    {
+        return None;
+    }
+
+    if let Some(unc_path) = path.strip_prefix(r"\\") {
+        // \\server\share\... becomes \\?\UNC\server\share\...
+        return Some(format!("{EXTENDED_LENGTH_PREFIX}UNC\\{unc_path}"));
+    }
+
+    let bytes = path.as_bytes();
+    let has_drive =
+        bytes.len() > 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' && bytes[2] == b'\\';
+    has_drive.then(|| format!("{EXTENDED_LENGTH_PREFIX}{path}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    /// A synthetic path of nested chapter dirs, longer than MAX_PATH
+    fn nested(root: &str, separator: &str) -> String {
+        let dirs = ["some-deeply-nested-chapter-dir"; 10];
+        format!("{}{}{}", root, separator, dirs.join(separator))
+    }
+
+    #[test]
+    fn test_extended_length_path() {
+        let path = nested("C:", "\\");
+        assert_eq!(Some(format!(r"\\?\{path}")), extended_length_path(&path));
+
+        // Forward slashes are replaced
+        assert_eq!(
+            Some(format!(r"\\?\{path}")),
+            extended_length_path(&nested("C:", "/"))
+        );
+
+        // UNC paths
+        let unc_path = nested(r"\\server\share", "\\");
+        assert_eq!(
+            Some(format!(r"\\?\UNC\{}", &unc_path[2..])),
+            extended_length_path(&unc_path)
+        );
+
+        // Short, extended, relative and not normalized paths are not converted
+        assert_eq!(
+            None,
+            extended_length_path(r"C:\book\src\mdbook-plantuml-img")
+        );
+        assert_eq!(None, extended_length_path(&format!(r"\\?\{path}")));
+        assert_eq!(None, extended_length_path(&nested("src", "\\")));
+        assert_eq!(None, extended_length_path(&nested(r"C:\book\..", "\\")));
+    }
+
+    #[test]
+    fn test_long_path() {
+        let path = PathBuf::from(nested("C:", "\\"));
+        if cfg!(target_family = "windows") {
+            assert_eq!(
+                PathBuf::from(format!(r"\\?\{}", path.display())),
+                long_path(path)
+            );
+        } else {
+            assert_eq!(path.clone(), long_path(path));
+        }
+
+        let path = PathBuf::from("/book/src/mdbook-plantuml-img");
+        assert_eq!(path.clone(), long_path(path));
+    }
+}
@@ -1,9 +1,15 @@
-use anyhow::Result;
-use clap::{Parser, Subcommand};
+use anyhow::{Context, Result};
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
 use mdbook::preprocess::{CmdPreprocessor, Preprocessor};
-use mdbook_plantuml::plantuml_config;
-use std::io;
+use mdbook_plantuml::FailureKind;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::Mutex;
 
 #[derive(Parser)]
 #[clap(version, author, about)]
@@ -14,6 +20,18 @@ pub struct Args {
     #[clap(short, long)]
     log: bool,
 
+    /// Re-run the PlantUML command detection
+    ///
+    /// (the detected command is cached in .mdbook-plantuml-cache).
+    #[clap(long)]
+    revalidate: bool,
+
+    /// Override a book.toml option, e.g. --set use-data-uris=false
+    ///
+    /// (can be used multiple times, values are parsed as JSON when possible).
+    #[clap(long = "set", value_name = "KEY=VALUE")]
+    overrides: Vec<String>,
+
     #[clap(subcommand)]
     command: Option<Command>,
 }
@@ -21,24 +39,427 @@ pub struct Args {
 #[derive(Subcommand)]
 pub enum Command {
     /// Check whether a renderer is supported by this preprocessor
-    Supports { renderer: String },
+    Supports {
+        renderer: String,
+
+        /// Print why the renderer is (or is not) supported on stderr
+        #[clap(long)]
+        explain: bool,
+
+        /// Do not treat the passthrough renderers (for which the code blocks are kept as is) as
+        /// supported
+        #[clap(long)]
+        strict: bool,
+    },
+    /// Export all PlantUML diagrams in the book as .puml files
+    Export {
+        /// The book's root directory
+        #[clap(default_value = ".")]
+        dir: PathBuf,
+
+        /// The directory to write the diagrams to
+        #[clap(long, default_value = "diagrams")]
+        out: PathBuf,
+
+        /// Also render the diagrams (written alongside the .puml files)
+        #[clap(long)]
+        render: bool,
+    },
+    /// Render a single diagram using the book's configured backend (e.g. for editor previews)
+    Render {
+        /// The PlantUML source file, use - to read the source from stdin
+        input: PathBuf,
+
+        /// The image format
+        #[clap(long, default_value = "svg")]
+        format: String,
+
+        /// The book's root directory (its book.toml configures the backend)
+        #[clap(long, default_value = ".")]
+        book: PathBuf,
+
+        /// The image file to write, the image is written to stdout when omitted
+        #[clap(long)]
+        out: Option<PathBuf>,
+    },
+    /// Serve an HTTP endpoint rendering the POSTed PlantUML source (for editor preview panes)
+    Preview {
+        /// The book's root directory (its book.toml configures the backend)
+        #[clap(long, default_value = ".")]
+        book: PathBuf,
+
+        /// The (localhost) port to listen on
+        #[clap(long, default_value_t = 3030)]
+        port: u16,
+    },
+    /// Touch the chapters including a changed file from the include manifest (see the
+    /// watch-includes option), so `mdbook serve` rebuilds the book
+    WatchIncludes {
+        /// The book's root directory
+        #[clap(default_value = ".")]
+        dir: PathBuf,
+    },
+    /// Render all uncached diagrams, so the next book build only hits the cache
+    Warm {
+        /// The book's root directory
+        #[clap(default_value = ".")]
+        dir: PathBuf,
+    },
+    /// Render the book's diagrams repeatedly (bypassing the cache) and report the render times
+    Bench {
+        /// The book's root directory
+        #[clap(default_value = ".")]
+        dir: PathBuf,
+
+        /// The number of times every diagram is rendered
+        #[clap(long, default_value_t = 3)]
+        iterations: usize,
+    },
+    /// Render the book's diagrams with two PlantUML commands (e.g. versions, bypassing the cache)
+    /// and report the differing images, exits with 1 when images differ
+    Compare {
+        /// The book's root directory
+        #[clap(default_value = ".")]
+        dir: PathBuf,
+
+        /// The first PlantUML command (or server URL), e.g. "java -jar old.jar"
+        #[clap(long)]
+        cmd_a: String,
+
+        /// The second PlantUML command (or server URL), e.g. "java -jar new.jar"
+        #[clap(long)]
+        cmd_b: String,
+    },
+    /// Inspect, export or import the image cache
+    #[clap(subcommand)]
+    Cache(CacheCommand),
+    /// Stop the PlantUML helper processes (e.g. FTP servers) left behind by earlier builds
+    KillDaemons {
+        /// The book's root directory
+        #[clap(default_value = ".")]
+        dir: PathBuf,
+    },
+    /// Print the JSON Schema of the [preprocessor.plantuml] options in book.toml (for editor
+    /// autocomplete and validation)
+    ConfigSchema,
+    /// Print the shell completion script for the given shell
+    Completions { shell: Shell },
+    /// Print the man page (roff)
+    Man,
+    /// Manage mdbook-plantuml itself
+    #[clap(name = "self", subcommand)]
+    Maintenance(SelfCommand),
+}
+
+#[derive(Subcommand)]
+pub enum CacheCommand {
+    /// Explain why the diagrams of a chapter are (not) taken from the cache, lists the hash, the
+    /// inputs of the hash and the cache entry per diagram
+    Explain {
+        /// The chapter's markdown file
+        chapter: PathBuf,
+
+        /// Only explain the given diagram (1 based index in the chapter)
+        #[clap(long)]
+        block: Option<usize>,
+
+        /// The book's root directory
+        #[clap(long, default_value = ".")]
+        book: PathBuf,
+    },
+    /// Export the cached images to a (tar) snapshot, e.g. to commit it or store it as CI artifact
+    Export {
+        /// The snapshot file to write
+        tarball: PathBuf,
+
+        /// The book's root directory
+        #[clap(long, default_value = ".")]
+        book: PathBuf,
+    },
+    /// Restore the cached images from a snapshot, incompatible snapshots (e.g. exported by
+    /// another mdbook-plantuml version) are rejected
+    Import {
+        /// The snapshot file to read
+        tarball: PathBuf,
+
+        /// The book's root directory
+        #[clap(long, default_value = ".")]
+        book: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum SelfCommand {
+    /// Check whether a newer version is available
+    CheckUpdate,
 }
 
 fn main() {
     let args = Args::parse();
 
-    let preprocessor = mdbook_plantuml::Preprocessor;
-    if let Some(Command::Supports { renderer }) = args.command {
-        handle_supports(&preprocessor, &renderer);
-    } else if let Err(e) = handle_preprocessing(&preprocessor, args.log) {
-        panic!("{}", e);
+    let preprocessor = mdbook_plantuml::Preprocessor {
+        revalidate: args.revalidate,
+        overrides: args.overrides,
+    };
+    match args.command {
+        Some(Command::Supports {
+            renderer,
+            explain,
+            strict,
+        }) => handle_supports(&preprocessor, &renderer, explain, strict),
+        Some(Command::Export { dir, out, render }) => {
+            handle_export(&dir, &out, render, args.revalidate, args.log)
+        }
+        Some(Command::Render {
+            input,
+            format,
+            book,
+            out,
+        }) => handle_render(
+            &input,
+            &format,
+            &book,
+            out.as_deref(),
+            args.revalidate,
+            args.log,
+        ),
+        Some(Command::Preview { book, port }) => {
+            handle_preview(&book, port, args.revalidate, args.log)
+        }
+        Some(Command::WatchIncludes { dir }) => handle_watch_includes(&dir, args.log),
+        Some(Command::Warm { dir }) => handle_warm(&dir, args.revalidate, args.log),
+        Some(Command::Bench { dir, iterations }) => {
+            handle_bench(&dir, iterations, args.revalidate, args.log)
+        }
+        Some(Command::Compare { dir, cmd_a, cmd_b }) => {
+            handle_compare(&dir, &cmd_a, &cmd_b, args.revalidate, args.log)
+        }
+        Some(Command::Cache(CacheCommand::Explain {
+            chapter,
+            block,
+            book,
+        })) => handle_cache_explain(&book, &chapter, block),
+        Some(Command::Cache(CacheCommand::Export { tarball, book })) => {
+            handle_cache_export(&book, &tarball)
+        }
+        Some(Command::Cache(CacheCommand::Import { tarball, book })) => {
+            handle_cache_import(&book, &tarball)
+        }
+        Some(Command::KillDaemons { dir }) => handle_kill_daemons(&dir),
+        Some(Command::ConfigSchema) => {
+            let schema = mdbook_plantuml::config_schema();
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&schema).unwrap_or_default()
+            );
+        }
+        Some(Command::Completions { shell }) => handle_completions(shell),
+        Some(Command::Maintenance(SelfCommand::CheckUpdate)) => handle_check_update(),
+        Some(Command::Man) => handle_man(),
+        None => {
+            if let Err(e) = handle_preprocessing(&preprocessor, args.log) {
+                exit_with_error(&e);
+            }
+        }
+    }
+}
+
+fn handle_export(
+    book_dir: &Path,
+    out_dir: &Path,
+    render: bool,
+    revalidate: bool,
+    log_to_file: bool,
+) {
+    let result = setup_logging(log_to_file, false)
+        .and_then(|_| mdbook_plantuml::export_diagrams(book_dir, out_dir, render, revalidate));
+
+    if let Err(e) = result {
+        exit_with_error(&e);
+    }
+}
+
+fn handle_render(
+    input: &Path,
+    format: &str,
+    book_dir: &Path,
+    out: Option<&Path>,
+    revalidate: bool,
+    log_to_file: bool,
+) {
+    let result = setup_logging(log_to_file, false).and_then(|_| {
+        // Resolve the paths first, the working dir is changed when rendering a file
+        let book_dir = dunce::canonicalize(book_dir)?;
+        let out = match out {
+            Some(out) => Some(std::env::current_dir()?.join(out)),
+            None => None,
+        };
+
+        let plantuml_code = if input == Path::new("-") {
+            let mut plantuml_code = String::new();
+            io::stdin().read_to_string(&mut plantuml_code)?;
+            plantuml_code
+        } else {
+            let plantuml_code =
+                fs::read_to_string(input).with_context(|| format!("Failed to read {input:?}"))?;
+            // Render from the source's directory, so relative includes work
+            if let Some(dir) = input.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                std::env::set_current_dir(dir)?;
+            }
+            plantuml_code
+        };
+
+        render_to(
+            &book_dir,
+            &plantuml_code,
+            format,
+            out.as_deref(),
+            revalidate,
+        )
+    });
+
+    if let Err(e) = result {
+        exit_with_error(&e);
     }
 }
 
-fn handle_preprocessing(pre: &dyn Preprocessor, log_to_file: bool) -> Result<()> {
+/// Render the diagram and write the image to the file (or stdout)
+fn render_to(
+    book_dir: &Path,
+    plantuml_code: &str,
+    format: &str,
+    out: Option<&Path>,
+    revalidate: bool,
+) -> Result<()> {
+    let data = mdbook_plantuml::render_source(book_dir, plantuml_code, format, revalidate)?;
+    match out {
+        Some(out) => fs::write(out, data).with_context(|| format!("Failed to write {out:?}"))?,
+        None => io::stdout().write_all(&data)?,
+    }
+
+    Ok(())
+}
+
+fn handle_preview(book_dir: &Path, port: u16, revalidate: bool, log_to_file: bool) {
+    let result = setup_logging(log_to_file, false)
+        .and_then(|_| mdbook_plantuml::preview(book_dir, port, revalidate));
+
+    if let Err(e) = result {
+        exit_with_error(&e);
+    }
+}
+
+fn handle_watch_includes(book_dir: &Path, log_to_file: bool) {
+    let result =
+        setup_logging(log_to_file, false).and_then(|_| mdbook_plantuml::watch_includes(book_dir));
+
+    if let Err(e) = result {
+        exit_with_error(&e);
+    }
+}
+
+fn handle_warm(book_dir: &Path, revalidate: bool, log_to_file: bool) {
+    let result = setup_logging(log_to_file, false)
+        .and_then(|_| mdbook_plantuml::warm_cache(book_dir, revalidate));
+
+    if let Err(e) = result {
+        exit_with_error(&e);
+    }
+}
+
+fn handle_bench(book_dir: &Path, iterations: usize, revalidate: bool, log_to_file: bool) {
+    let result = setup_logging(log_to_file, false)
+        .and_then(|_| mdbook_plantuml::bench(book_dir, iterations, revalidate));
+
+    match result {
+        Ok(results) => {
+            for result in results {
+                println!("{}", result.summary());
+            }
+        }
+        Err(e) => exit_with_error(&e),
+    }
+}
+
+fn handle_compare(book_dir: &Path, cmd_a: &str, cmd_b: &str, revalidate: bool, log_to_file: bool) {
+    let result = setup_logging(log_to_file, false)
+        .and_then(|_| mdbook_plantuml::compare_versions(book_dir, cmd_a, cmd_b, revalidate));
+
+    match result {
+        Ok(comparisons) => {
+            print!("{}", mdbook_plantuml::comparison_report(&comparisons));
+            if comparisons
+                .iter()
+                .any(|comparison| comparison.difference.is_some())
+            {
+                process::exit(FailureKind::Differences.exit_code());
+            }
+        }
+        Err(e) => exit_with_error(&e),
+    }
+}
+
+fn handle_cache_explain(book_dir: &Path, chapter: &Path, block: Option<usize>) {
+    match mdbook_plantuml::explain_cache(book_dir, chapter, block) {
+        Ok(report) => print!("{}", report),
+        Err(e) => exit_with_error(&e),
+    }
+}
+
+fn handle_cache_export(book_dir: &Path, tarball: &Path) {
+    match mdbook_plantuml::export_cache_snapshot(book_dir, tarball) {
+        Ok(exported) => println!("Exported {} cache entries to {:?}", exported, tarball),
+        Err(e) => exit_with_error(&e),
+    }
+}
+
+fn handle_cache_import(book_dir: &Path, tarball: &Path) {
+    match mdbook_plantuml::import_cache_snapshot(book_dir, tarball) {
+        Ok(imported) => println!("Imported {} cache entries from {:?}", imported, tarball),
+        Err(e) => exit_with_error(&e),
+    }
+}
+
+fn handle_kill_daemons(book_dir: &Path) {
+    match mdbook_plantuml::kill_daemons(book_dir) {
+        Ok(killed) => println!("Stopped {} PlantUML helper process(es)", killed),
+        Err(e) => exit_with_error(&e),
+    }
+}
+
+/// Report the error and exit with the exit code of its failure kind
+fn exit_with_error(error: &anyhow::Error) -> ! {
+    eprintln!("Error: {:#}", error);
+    process::exit(FailureKind::of(error).exit_code());
+}
+
+fn handle_check_update() {
+    match mdbook_plantuml::check_update() {
+        Ok(Some(version)) => println!("{}", mdbook_plantuml::upgrade_instructions(&version)),
+        Ok(None) => println!(
+            "mdbook-plantuml {} is the latest version",
+            env!("CARGO_PKG_VERSION")
+        ),
+        Err(e) => exit_with_error(&e.context("Failed to check for a newer version")),
+    }
+}
+
+fn handle_completions(shell: Shell) {
+    let mut command = Args::command();
+    let name = command.get_name().to_string();
+    clap_complete::generate(shell, &mut command, name, &mut io::stdout());
+}
+
+fn handle_man() {
+    if let Err(e) = clap_mangen::Man::new(Args::command()).render(&mut io::stdout()) {
+        exit_with_error(&anyhow::Error::new(e).context("Failed to print the man page"));
+    }
+}
+
+fn handle_preprocessing(pre: &mdbook_plantuml::Preprocessor, log_to_file: bool) -> Result<()> {
     let (ctx, book) = CmdPreprocessor::parse_input(io::stdin())?;
 
-    let config = plantuml_config(&ctx);
+    let config = pre.config(&ctx)?;
     setup_logging(log_to_file, config.verbose)?;
 
     log::debug!(
@@ -73,9 +494,20 @@ fn handle_preprocessing(pre: &dyn Preprocessor, log_to_file: bool) -> Result<()>
     Ok(())
 }
 
-fn handle_supports(pre: &dyn Preprocessor, renderer: &str) -> ! {
+fn handle_supports(
+    pre: &mdbook_plantuml::Preprocessor,
+    renderer: &str,
+    explain: bool,
+    strict: bool,
+) -> ! {
+    let (supported, reason) = pre.renderer_support(renderer, strict);
+    if explain {
+        // Stdout is reserved for mdbook
+        eprintln!("{reason}");
+    }
+
     // Signal whether the renderer is supported by exiting with 1 or 0.
-    if pre.supports_renderer(renderer) {
+    if supported {
         process::exit(0);
     } else {
         process::exit(1);
@@ -84,15 +516,19 @@ fn handle_supports(pre: &dyn Preprocessor, renderer: &str) -> ! {
 
 fn setup_logging(log_to_file: bool, verbose: bool) -> Result<()> {
     use log::LevelFilter;
-    use log4rs::append::console::{ConsoleAppender, Target};
-    use log4rs::append::file::FileAppender;
     use log4rs::filter::threshold::ThresholdFilter;
 
     use log4rs::config::{Appender, Config, Root};
     use log4rs::encode::pattern::PatternEncoder;
 
     // Whatever you do, DO NOT, log to stdout. Stdout is only for communication with mdbook
-    let log_std_err = ConsoleAppender::builder().target(Target::Stderr).build();
+    let log_std_err = GroupedAppender::new(
+        PatternEncoder::new(&format!(
+            "{{d}} {{l}} {{t}} - {{m}}{}{{n}}",
+            mdbook_plantuml::LOG_CONTEXT_PATTERN
+        )),
+        Box::new(io::stderr()),
+    );
     let mut config_builder = Config::builder().appender({
         let log_level = if verbose {
             LevelFilter::Debug
@@ -106,9 +542,17 @@ fn setup_logging(log_to_file: bool, verbose: bool) -> Result<()> {
     });
 
     if log_to_file {
-        let logfile = FileAppender::builder()
-            .encoder(Box::new(PatternEncoder::new("{l} - {m}\n")))
-            .build("output.log")?;
+        let logfile = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open("output.log")?;
+        let logfile = GroupedAppender::new(
+            PatternEncoder::new(&format!(
+                "{{l}} - {{m}}{}{{n}}",
+                mdbook_plantuml::LOG_CONTEXT_PATTERN
+            )),
+            Box::new(logfile),
+        );
         config_builder =
             config_builder.appender(Appender::builder().build("logfile", Box::new(logfile)));
     }
@@ -124,3 +568,67 @@ fn setup_logging(log_to_file: bool, verbose: bool) -> Result<()> {
 
     Ok(())
 }
+
+/// Log appender writing the log output of grouped chapters (see the group-chapter-logs option)
+/// at once when the chapter is done, so the output of chapters does not interleave.
+struct GroupedAppender {
+    encoder: log4rs::encode::pattern::PatternEncoder,
+    writer: Mutex<Box<dyn Write + Send>>,
+    /// The buffered log output per chapter
+    groups: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl GroupedAppender {
+    fn new(
+        encoder: log4rs::encode::pattern::PatternEncoder,
+        writer: Box<dyn Write + Send>,
+    ) -> Self {
+        Self {
+            encoder,
+            writer: Mutex::new(writer),
+            groups: Mutex::default(),
+        }
+    }
+
+    fn write(&self, data: &[u8]) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        writer.write_all(data)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for GroupedAppender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GroupedAppender").finish()
+    }
+}
+
+impl log4rs::append::Append for GroupedAppender {
+    fn append(&self, record: &log::Record) -> Result<()> {
+        use log4rs::encode::{writer::simple::SimpleWriter, Encode};
+
+        let mut line = vec![];
+        self.encoder.encode(&mut SimpleWriter(&mut line), record)?;
+
+        let group = log_mdc::get(mdbook_plantuml::LOG_GROUP_KEY, |group| {
+            group.map(String::from)
+        });
+        match group {
+            Some(group) => {
+                let mut groups = self.groups.lock().unwrap_or_else(|e| e.into_inner());
+                let output = groups.entry(group.clone()).or_default();
+                output.extend_from_slice(&line);
+                if record.target() == mdbook_plantuml::CHAPTER_DONE_TARGET {
+                    if let Some(output) = groups.remove(&group) {
+                        self.write(&output)?;
+                    }
+                }
+                Ok(())
+            }
+            None => self.write(&line),
+        }
+    }
+
+    fn flush(&self) {}
+}
@@ -0,0 +1,285 @@
+use crate::config::Config;
+use crate::diagram_kind::DiagramKind;
+use std::borrow::Cow;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The directives added to every diagram, the default font (the default-font option), the
+/// header and footer (the header and footer options, e.g. for compliance markings) and the ditaa
+/// options (the ditaa-args option). These are added to the code before hashing, so they are part
+/// of the cache key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiagramMarkings {
+    default_font: Option<String>,
+    header: Option<String>,
+    footer: Option<String>,
+    ditaa_args: Vec<String>,
+}
+
+impl DiagramMarkings {
+    /// No default font, header, footer and ditaa options
+    pub const fn none() -> Self {
+        Self {
+            default_font: None,
+            header: None,
+            footer: None,
+            ditaa_args: Vec::new(),
+        }
+    }
+
+    /// Get the header and footer from the config, with the {book} and {date} placeholders
+    /// resolved ({chapter} is resolved by for_chapter)
+    /// # Arguments
+    /// * `cfg` - The configuration options
+    /// * `book_title` - The book's title (if any)
+    pub fn from_config(cfg: &Config, book_title: Option<&str>) -> Self {
+        Self::with_date(cfg, book_title, &today())
+    }
+
+    fn with_date(cfg: &Config, book_title: Option<&str>, date: &str) -> Self {
+        let resolve = |text: &String| {
+            text.replace("{book}", book_title.unwrap_or_default())
+                .replace("{date}", date)
+        };
+        Self {
+            default_font: cfg.default_font.clone(),
+            header: cfg.header.as_ref().map(resolve),
+            footer: cfg.footer.as_ref().map(resolve),
+            ditaa_args: cfg.ditaa_args.clone(),
+        }
+    }
+
+    /// The header and footer with the {chapter} placeholder resolved
+    pub fn for_chapter(&self, chapter_name: &str) -> Self {
+        let resolve = |text: &String| text.replace("{chapter}", chapter_name);
+        Self {
+            default_font: self.default_font.clone(),
+            header: self.header.as_ref().map(resolve),
+            footer: self.footer.as_ref().map(resolve),
+            ditaa_args: self.ditaa_args.clone(),
+        }
+    }
+
+    /// Add the default font (after the diagram's @start tag) and the header and footer directives
+    /// (before its @end tag) to the diagram, or the options to a ditaa diagram's @startditaa
+    /// line. Diagrams that do not support these (e.g. JSON data) are returned as is.
+    pub fn apply<'a>(&self, plantuml_code: &'a str) -> Cow<'a, str> {
+        let kind = DiagramKind::detect(plantuml_code);
+        if kind == DiagramKind::Ditaa {
+            return insert_ditaa_options(plantuml_code, &self.ditaa_args);
+        }
+        if !kind.supports_styling() || *self == Self::none() {
+            return Cow::Borrowed(plantuml_code);
+        }
+
+        let plantuml_code = match &self.default_font {
+            Some(font) => Cow::Owned(insert_after_start_tag(
+                plantuml_code,
+                &format!("skinparam defaultFontName {}\n", font.trim()),
+            )),
+            None => Cow::Borrowed(plantuml_code),
+        };
+        if self.header.is_none() && self.footer.is_none() {
+            return plantuml_code;
+        }
+
+        let mut directives = String::new();
+        for (directive, text) in [("header", &self.header), ("footer", &self.footer)] {
+            if let Some(text) = text {
+                // Multiple lines are separated by \n in a single line directive
+                let text = text.trim().replace('\n', "\\n");
+                directives.push_str(&format!("{directive} {text}\n"));
+            }
+        }
+
+        let end_tag = plantuml_code
+            .match_indices("@end")
+            .map(|(pos, _)| pos)
+            .find(|pos| *pos == 0 || plantuml_code.as_bytes()[pos - 1] == b'\n');
+        let code = match end_tag {
+            Some(pos) => format!(
+                "{}{}{}",
+                &plantuml_code[..pos],
+                directives,
+                &plantuml_code[pos..]
+            ),
+            None if plantuml_code.is_empty() || plantuml_code.ends_with('\n') => {
+                format!("{plantuml_code}{directives}")
+            }
+            None => format!("{plantuml_code}\n{directives}"),
+        };
+
+        Cow::Owned(code)
+    }
+}
+
+/// Insert the (newline terminated) directive after the @start tag line (or at the start when
+/// there is no @start tag), so the diagram's own directives take precedence
+pub fn insert_after_start_tag(plantuml_code: &str, directive: &str) -> String {
+    let start_tag = plantuml_code
+        .match_indices("@start")
+        .map(|(pos, _)| pos)
+        .find(|pos| *pos == 0 || plantuml_code.as_bytes()[pos - 1] == b'\n');
+    match start_tag {
+        Some(pos) => {
+            let line_end = plantuml_code[pos..]
+                .find('\n')
+                .map_or(plantuml_code.len(), |end| pos + end + 1);
+            let (start, rest) = plantuml_code.split_at(line_end);
+            if start.ends_with('\n') {
+                format!("{start}{directive}{rest}")
+            } else {
+                format!("{start}\n{directive}{rest}")
+            }
+        }
+        None => format!("{directive}{plantuml_code}"),
+    }
+}
+
+/// Add the options to the `@startditaa` line of a ditaa diagram (e.g.
+/// `@startditaa(--no-shadows, scale=1.5)`). The options already on the line follow the added
+/// ones, so these take precedence. Other diagrams are returned as is.
+pub fn insert_ditaa_options<'a, S: AsRef<str>>(
+    plantuml_code: &'a str,
+    options: &[S],
+) -> Cow<'a, str> {
+    const START_TAG: &str = "@startditaa";
+    let start_tag = plantuml_code
+        .match_indices(START_TAG)
+        .map(|(pos, _)| pos)
+        .find(|pos| *pos == 0 || plantuml_code.as_bytes()[pos - 1] == b'\n');
+    let (start, rest) = match start_tag {
+        Some(pos) if !options.is_empty() => plantuml_code.split_at(pos + START_TAG.len()),
+        _ => return Cow::Borrowed(plantuml_code),
+    };
+
+    let options: Vec<&str> = options.iter().map(AsRef::as_ref).collect();
+    let options = options.join(", ");
+    let code = match rest.strip_prefix('(') {
+        Some(rest) if rest.trim_start().starts_with(')') => format!("{start}({options}{rest}"),
+        Some(rest) => format!("{start}({options}, {rest}"),
+        None => format!("{start}({options}){rest}"),
+    };
+
+    Cow::Owned(code)
+}
+
+/// Today's (UTC) date in YYYY-MM-DD format
+fn today() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    date_from_days((secs / 86_400) as i64)
+}
+
+/// The date (YYYY-MM-DD) of the number of days since 1970-01-01 (proleptic Gregorian calendar)
+fn date_from_days(days: i64) -> String {
+    // See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_apply() {
+        let cfg = Config {
+            header: Some(String::from("{book}")),
+            footer: Some(String::from("CONFIDENTIAL - {chapter} ({date})")),
+            ..Config::default()
+        };
+        let markings =
+            DiagramMarkings::with_date(&cfg, Some("My book"), "2026-10-15").for_chapter("Intro");
+        assert_eq!(
+            "@startuml\nA --|> B\nheader My book\nfooter CONFIDENTIAL - Intro (2026-10-15)\n@enduml\n",
+            markings.apply("@startuml\nA --|> B\n@enduml\n")
+        );
+        assert_eq!(
+            "A --|> B\nheader My book\nfooter CONFIDENTIAL - Intro (2026-10-15)\n",
+            markings.apply("A --|> B")
+        );
+
+        // Unsupported diagram kinds are left as is
+        let ditaa = "@startditaa\n+--+\n@endditaa\n";
+        assert!(matches!(markings.apply(ditaa), Cow::Borrowed(code) if code == ditaa));
+
+        let markings = DiagramMarkings::none();
+        assert!(matches!(
+            markings.apply("A --|> B"),
+            Cow::Borrowed("A --|> B")
+        ));
+    }
+
+    #[test]
+    fn test_apply_default_font() {
+        let cfg = Config {
+            default_font: Some(String::from("Noto Sans CJK JP")),
+            footer: Some(String::from("Footer")),
+            ..Config::default()
+        };
+        let markings = DiagramMarkings::from_config(&cfg, None);
+        assert_eq!(
+            "@startuml\nskinparam defaultFontName Noto Sans CJK JP\nA --|> B\nfooter Footer\n@enduml\n",
+            markings.apply("@startuml\nA --|> B\n@enduml\n")
+        );
+        assert_eq!(
+            "skinparam defaultFontName Noto Sans CJK JP\nA --|> B\nfooter Footer\n",
+            markings.apply("A --|> B\n")
+        );
+    }
+
+    #[test]
+    fn test_insert_ditaa_options() {
+        let options = ["--no-shadows", "scale=1.5"];
+        assert_eq!(
+            "@startditaa(--no-shadows, scale=1.5)\n+--+\n@endditaa\n",
+            insert_ditaa_options("@startditaa\n+--+\n@endditaa\n", &options)
+        );
+        assert_eq!(
+            "@startditaa(--no-shadows, scale=1.5, scale=2) foo\n",
+            insert_ditaa_options("@startditaa(scale=2) foo\n", &options)
+        );
+        assert_eq!(
+            "@startditaa(--no-shadows, scale=1.5)\n",
+            insert_ditaa_options("@startditaa()\n", &options)
+        );
+
+        let uml = "@startuml\nA --|> B\n@enduml\n";
+        assert!(matches!(insert_ditaa_options(uml, &options), Cow::Borrowed(code) if code == uml));
+        let ditaa = "@startditaa\n+--+\n@endditaa\n";
+        assert!(matches!(
+            insert_ditaa_options::<&str>(ditaa, &[]),
+            Cow::Borrowed(code) if code == ditaa
+        ));
+
+        // The ditaa-args option
+        let cfg = Config {
+            ditaa_args: vec![String::from("-E")],
+            footer: Some(String::from("Footer")),
+            ..Config::default()
+        };
+        assert_eq!(
+            "@startditaa(-E)\n+--+\n@endditaa\n",
+            DiagramMarkings::from_config(&cfg, None).apply(ditaa)
+        );
+    }
+
+    #[test]
+    fn test_date_from_days() {
+        assert_eq!("1970-01-01", date_from_days(0));
+        assert_eq!("2000-02-29", date_from_days(11_016));
+        assert_eq!("2026-10-15", date_from_days(20_741));
+    }
+}
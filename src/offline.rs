@@ -0,0 +1,86 @@
+use crate::backend::factory::OFFLINE_HINT;
+use crate::includes::{expand_includes, remote_plantuml_includes};
+use crate::pipeline::plantuml_diagrams;
+use anyhow::{bail, Result};
+use mdbook::book::{Book, BookItem};
+use std::borrow::Cow;
+use std::path::Path;
+
+/// Fail when diagrams in the book include remote (URL) files, these require
+/// network access, which is not allowed in offline mode. The error lists all
+/// these diagrams.
+/// # Arguments
+/// * `book` - The book to check
+/// * `src_dir` - The book's source directory
+/// * `strict_info_string` - Parse the info strings in strict-info-string mode
+pub fn check_offline(book: &Book, src_dir: &Path, strict_info_string: bool) -> Result<()> {
+    let mut online_diagrams = vec![];
+    for item in book.iter() {
+        if let BookItem::Chapter(chapter) = item {
+            if let Some(chapter_path) = &chapter.path {
+                let chapter_file = src_dir.join(chapter_path);
+                let chapter_dir = chapter_file.parent().unwrap_or(src_dir);
+                let diagrams = plantuml_diagrams(&chapter.content, strict_info_string);
+                for (index, diagram) in diagrams.iter().enumerate() {
+                    let code = expand_includes(&diagram.code, chapter_dir, None)
+                        .unwrap_or(Cow::Borrowed(&diagram.code));
+                    for url in remote_plantuml_includes(&code, chapter_dir) {
+                        online_diagrams.push(format!(
+                            "  diagram {} in chapter '{}' ({:?}) includes {}",
+                            index + 1,
+                            chapter.name,
+                            chapter_path,
+                            url
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    if !online_diagrams.is_empty() {
+        bail!(
+            "The following PlantUML diagrams require network access, which is not allowed in \
+             offline mode ({}):\n{}",
+            OFFLINE_HINT,
+            online_diagrams.join("\n")
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mdbook::book::Chapter;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_check_offline() {
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Local",
+            String::from("```plantuml\n!include <C4/C4_Container>\nA --|> B\n```\n"),
+            PathBuf::from("local.md"),
+            vec![],
+        ));
+        assert!(check_offline(&book, Path::new("src"), false).is_ok());
+
+        book.push_item(Chapter::new(
+            "Remote",
+            String::from(
+                "```plantuml\nA\n```\n```plantuml\n!include https://example.com/a.puml\n```\n",
+            ),
+            PathBuf::from("remote.md"),
+            vec![],
+        ));
+        let error = format!(
+            "{:#}",
+            check_offline(&book, Path::new("src"), false).unwrap_err()
+        );
+        assert!(error.contains(
+            "diagram 2 in chapter 'Remote' (\"remote.md\") includes https://example.com/a.puml"
+        ));
+    }
+}
@@ -1,13 +1,40 @@
-use crate::renderer::RendererTrait;
+use crate::config::{Config, ImageMarkup};
+use crate::diagram_kind::DiagramKind;
+use crate::front_matter::ChapterFrontMatter;
+use crate::includes::{check_plantuml_includes_within, expand_includes};
+use crate::log_context::log_field;
+use crate::markings::{insert_ditaa_options, DiagramMarkings};
+use crate::renderer::{failure_placeholder, BlockOptions, RendererTrait};
+use anyhow::{ensure, Result};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::path::Path;
 use std::string::String;
 
-pub fn render_plantuml_code_blocks(
-    markdown: &str,
+/// Render the PlantUML code blocks in the markdown, the markdown is borrowed as
+/// is when there is nothing to render. The (1 based) indices of the diagrams that
+/// failed to render (the error is embedded instead) are added to failed_diagrams.
+/// The header and footer markings (see DiagramMarkings) are added to every diagram.
+pub fn render_plantuml_code_blocks<'a>(
+    markdown: &'a str,
     renderer: &impl RendererTrait,
     rel_image_url: &str,
-) -> String {
-    let processor = CodeProcessor::new(markdown);
-    processor.process(renderer, rel_image_url)
+    include_dir: &Path,
+    cfg: &Config,
+    markings: &DiagramMarkings,
+    failed_diagrams: &mut Vec<usize>,
+) -> Result<Cow<'a, str>> {
+    // Cheap scan first, most chapters do not contain any PlantUML
+    if cfg.render_data.is_empty() && !markdown.contains("plantuml") && !markdown.contains("puml") {
+        return Ok(Cow::Borrowed(markdown));
+    }
+
+    let processor = CodeProcessor::new(markdown)
+        .strict_info_string(cfg.strict_info_string)
+        .markings(markings.clone());
+    let processed = processor.process(renderer, rel_image_url, include_dir, cfg)?;
+    failed_diagrams.extend(processor.failed_diagrams.take());
+    Ok(processed)
 }
 
 /// Find the first byte not equal to the expected byte
@@ -79,6 +106,17 @@ fn find_next_code_fence(
         }
     };
 
+    // CommonMark spec. A backtick opening fence's info string cannot contain
+    // backticks and a closing fence cannot have an info string.
+    let is_valid_rest_of_line = |c, e| {
+        let rest = &bytes[e..next_line(bytes, e).min(bytes.len())];
+        if min_length.is_some() {
+            rest.iter().all(|&r| r == b' ' || r == b'\r' || r == b'\n')
+        } else {
+            c != b'`' || !rest.contains(&b'`')
+        }
+    };
+
     while pos < bytes.len() {
         let line_start = pos;
         pos = find_first_inequal(bytes, b' ', pos);
@@ -89,7 +127,8 @@ fn find_next_code_fence(
         const MAX_FENCE_INDENT: usize = 3; // CommonMark spec allows at most 3 spaces before a fence
         if (pos - line_start) <= MAX_FENCE_INDENT && is_fence_char(bytes[pos]) {
             let first_non_fence = find_first_inequal(bytes, bytes[pos], pos);
-            if is_fence(pos, first_non_fence) {
+            if is_fence(pos, first_non_fence) && is_valid_rest_of_line(bytes[pos], first_non_fence)
+            {
                 return Some((pos, first_non_fence));
             }
 
@@ -111,9 +150,17 @@ fn find_next_code_fence(
 fn info_string(bytes: &[u8], fence_end: usize) -> Option<&str> {
     let info_start = find_first_inequal(bytes, b' ', fence_end);
     if info_start < bytes.len() {
+        // Spaces are allowed in quoted values (e.g. alt="My diagram")
         let mut pos = info_start;
-        while pos < bytes.len() && bytes[pos] != b'\n' && bytes[pos] != b' ' && bytes[pos] != b'\r'
+        let mut in_quotes = false;
+        while pos < bytes.len()
+            && bytes[pos] != b'\n'
+            && bytes[pos] != b'\r'
+            && (in_quotes || bytes[pos] != b' ')
         {
+            if bytes[pos] == b'"' {
+                in_quotes = !in_quotes;
+            }
             pos += 1;
         }
 
@@ -127,11 +174,29 @@ fn info_string(bytes: &[u8], fence_end: usize) -> Option<&str> {
     None
 }
 
+/// Gets the code block's complete info string (the rest of the fence line, without
+/// the surrounding whitespace), or None if it is empty.
+/// # Arguments
+/// * `bytes` - The bytes array to parse
+/// * `fence_end` - The start offset for the search
+fn full_info_string(bytes: &[u8], fence_end: usize) -> Option<&str> {
+    let line_end = next_line(bytes, fence_end).min(bytes.len());
+    std::str::from_utf8(&bytes[fence_end..line_end])
+        .ok()
+        .map(str::trim)
+        .filter(|info| !info.is_empty())
+}
+
 struct CodeBlock<'a> {
     /// The code block's code slice (stripped from fences and info string)
     code: &'a str,
     /// The code block's info string (if any)
     info_string: Option<&'a str>,
+    /// The info string is the language followed by space separated key=value
+    /// pairs (strict-info-string), instead of comma separated
+    strict_info_string: bool,
+    /// The number of spaces the opening fence is indented with
+    indent: usize,
     /// Byte offset of first character of opening fence
     start_pos: usize,
     /// Byte offset of newline after closing fence
@@ -139,37 +204,368 @@ struct CodeBlock<'a> {
 }
 
 impl<'a> CodeBlock<'a> {
-    /// Returns true if this code block is plantuml (i.e. starts with plantuml or puml)
+    /// The code with the opening fence's indentation removed from every line
+    /// (CommonMark spec), borrowed when the fence is not indented.
+    fn code(&self) -> Cow<'a, str> {
+        if self.indent == 0 {
+            return Cow::Borrowed(self.code);
+        }
+
+        let mut code = String::with_capacity(self.code.len());
+        for line in self.code.split_inclusive('\n') {
+            let spaces = line.bytes().take(self.indent).take_while(|&c| c == b' ');
+            code.push_str(&line[spaces.count()..]);
+        }
+
+        Cow::Owned(code)
+    }
+
+    /// Returns true if this code block is plantuml (i.e. starts with plantuml or puml), and
+    /// should be rendered (i.e. render=false is not specified). Other languages like
+    /// plantuml-source are kept as is.
     fn is_plantuml(&self) -> bool {
-        let language = self.info_string.and_then(|info| info.split(',').next());
-        language == Some("plantuml") || language == Some("puml")
+        let language = self.info_parts().first().copied();
+        (language == Some("plantuml") || language == Some("puml"))
+            && self.info_value("render") != Some("false")
+    }
+
+    /// Returns true if this is a PlantUML code block in the legacy comma separated
+    /// info string syntax (e.g. plantuml,format=png) in strict-info-string mode
+    fn uses_legacy_info_string(&self) -> bool {
+        self.strict_info_string
+            && self.info_parts().first().map_or(false, |language| {
+                language.starts_with("plantuml,") || language.starts_with("puml,")
+            })
     }
 
-    fn format(&self) -> String {
-        if self.code.contains("@startditaa") {
-            String::from("png")
+    /// The info string parts, the language is the first part
+    fn info_parts(&self) -> Vec<&'a str> {
+        let info_string = self.info_string.unwrap_or("");
+        if self.strict_info_string {
+            split_info_string_on(info_string, ' ')
+                .into_iter()
+                .filter(|part| !part.is_empty())
+                .collect()
         } else {
-            let parts = self.info_string.unwrap_or("").split(',');
-            for part in parts {
-                let eq_char = part.find('=').unwrap_or(part.len());
+            split_info_string(info_string)
+        }
+    }
 
-                if part[0..eq_char] == *"format" && part.len() > eq_char + 1 {
-                    return String::from(&part[eq_char + 1..part.len()]);
-                }
+    /// Get the (non empty) value of a key=value pair in the info string (e.g.
+    /// format=png), None if the key cannot be found. Quoted values (e.g.
+    /// alt="A, B") are returned without the quotes.
+    fn info_value(&self, key: &str) -> Option<&'a str> {
+        for part in self.info_parts() {
+            let eq_char = part.find('=').unwrap_or(part.len());
+
+            if part[0..eq_char] == *key && part.len() > eq_char + 1 {
+                let value = &part[eq_char + 1..part.len()];
+                let value = value
+                    .strip_prefix('"')
+                    .and_then(|v| v.strip_suffix('"'))
+                    .unwrap_or(value);
+                return Some(value).filter(|v| !v.is_empty());
             }
+        }
+
+        None
+    }
+
+    /// The image's alt text (alt=...) from the info string
+    fn alt_text(&self) -> Option<&'a str> {
+        self.info_value("alt")
+    }
+
+    /// The image format, from the info string (format=...), the chapter's front matter, or the
+    /// diagram kind's default format
+    fn format(&self, chapter_format: Option<&str>) -> String {
+        let kind = DiagramKind::detect(self.code);
+        let format = match kind.forced_format() {
+            Some(forced_format) => forced_format,
+            None => self
+                .info_value("format")
+                .or(chapter_format)
+                .unwrap_or(kind.default_format()),
+        };
+
+        String::from(format)
+    }
 
-            String::from("svg")
+    /// The diagram id (id=...) from the info string
+    fn id(&self) -> Option<&'a str> {
+        self.info_value("id")
+    }
+
+    /// The image markup (markup=html, or markup=markdown) from the info string, unknown values
+    /// are ignored (with a warning)
+    fn markup(&self) -> Option<ImageMarkup> {
+        let name = self.info_value("markup")?;
+        let markup = ImageMarkup::from_name(name);
+        if markup.is_none() {
+            log::warn!(
+                "Unknown image markup '{}' (use markup=markdown, or markup=html), using the configured image-markup",
+                name
+            );
+        }
+        markup
+    }
+
+    /// The space separated ditaa options (ditaa-opts="...") from the info string
+    fn ditaa_options(&self) -> Vec<&'a str> {
+        self.info_value("ditaa-opts")
+            .map_or_else(Vec::new, |options| options.split_whitespace().collect())
+    }
+
+    /// The PlantUML data diagram kind ("json", or "yaml") if this is a JSON or
+    /// YAML code block that should be rendered as diagram. Which is the case when
+    /// the info string contains plantuml-render, or when the language is in the
+    /// render_data config (unless plantuml-render=false is specified).
+    fn render_data_kind(&self, render_data: &[String]) -> Option<&'static str> {
+        let parts = self.info_parts();
+        let kind = match parts.first().copied() {
+            Some("json") => "json",
+            Some("yaml" | "yml") => "yaml",
+            _ => return None,
+        };
+
+        let render = match self.info_value("plantuml-render") {
+            Some(value) => value != "false",
+            None => parts.contains(&"plantuml-render") || render_data.iter().any(|d| d == kind),
+        };
+
+        if render {
+            Some(kind)
+        } else {
+            None
         }
     }
 }
 
+/// Check the max-diagram-bytes and max-diagrams-per-chapter limits for a diagram
+/// # Arguments
+/// * `code` - The diagram source (with the includes expanded)
+/// * `index` - The (1 based) index of the diagram in the chapter
+/// * `cfg` - The configuration options
+fn check_limits(code: &str, index: usize, cfg: &Config) -> Result<()> {
+    if let Some(max_bytes) = cfg.max_diagram_bytes {
+        ensure!(
+            code.len() <= max_bytes,
+            "Diagram {} is not rendered, its source ({} bytes) exceeds max-diagram-bytes ({} bytes)",
+            index,
+            code.len(),
+            max_bytes
+        );
+    }
+
+    if let Some(max_diagrams) = cfg.max_diagrams_per_chapter {
+        ensure!(
+            index <= max_diagrams,
+            "Diagram {} is not rendered, the chapter exceeds max-diagrams-per-chapter ({})",
+            index,
+            max_diagrams
+        );
+    }
+
+    Ok(())
+}
+
+/// Append the text with every (non empty) line indented with the given number of
+/// spaces
+fn push_indented(out: &mut String, text: &str, indent: usize) {
+    if indent == 0 {
+        out.push_str(text);
+        return;
+    }
+
+    for line in text.split_inclusive('\n') {
+        if line != "\n" && line != "\r\n" {
+            out.extend(std::iter::repeat(' ').take(indent));
+        }
+        out.push_str(line);
+    }
+}
+
+/// Wrap JSON or YAML data in the PlantUML start/end markers for the data kind
+fn wrap_data(data: &str, kind: &str) -> String {
+    format!("@start{kind}\n{}\n@end{kind}\n", data.trim_end())
+}
+
+/// Split the info string on the commas outside quotes
+fn split_info_string(info_string: &str) -> Vec<&str> {
+    split_info_string_on(info_string, ',')
+}
+
+/// Split the info string on the separators outside quotes
+fn split_info_string_on(info_string: &str, separator: char) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut in_quotes = false;
+    let mut start = 0;
+    for (pos, c) in info_string.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            c if c == separator && !in_quotes => {
+                parts.push(&info_string[start..pos]);
+                start = pos + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&info_string[start..]);
+
+    parts
+}
+
+/// A PlantUML code block's diagram source and settings
+pub struct Diagram {
+    /// The PlantUML code (with the chapter's theme and the ditaa options added)
+    pub code: String,
+    /// The image format to render
+    pub format: String,
+    /// The diagram's id (id=...) from the code block's info string (if any)
+    pub id: Option<String>,
+    /// The image's alt text (alt=...) from the code block's info string (if any)
+    pub alt_text: Option<String>,
+    /// The PlantUML data diagram kind ("json", or "yaml") of a rendered JSON or YAML code block,
+    /// its code is the data (see CodeBlock::render_data_kind)
+    pub data_kind: Option<&'static str>,
+}
+
+/// The chapter a diagram is in, what the diagram's source depends on besides its code block
+pub struct DiagramChapter<'a> {
+    /// The directory mdBook `{{#include}}` directives are resolved from (the chapter's directory)
+    pub include_dir: &'a Path,
+    /// The header and footer added to the chapter's diagrams
+    pub markings: &'a DiagramMarkings,
+}
+
+/// The source of a diagram as it is rendered (and hashed): the code block's source with the
+/// mdBook includes expanded, the data wrapped in a PlantUML data diagram and the markings added.
+/// The chapter processing and everything that has to match its images (e.g. the cache lookups)
+/// use this, so their hashes do not drift apart. Fails when the includes cannot be expanded, or
+/// the diagram includes files outside the book while restrict-includes-to-book is enabled.
+/// # Arguments
+/// * `chapter` - The chapter the diagram is in
+/// * `block` - The diagram's code block (see plantuml_diagrams)
+/// * `cfg` - The configuration options
+pub fn diagram_source(chapter: &DiagramChapter, block: &Diagram, cfg: &Config) -> Result<String> {
+    let book_root = cfg.book_root.as_deref().unwrap_or(chapter.include_dir);
+    let include_root = cfg.restrict_includes_to_book.then_some(book_root);
+    let code = expand_includes(&block.code, chapter.include_dir, include_root)?;
+    let code = match block.data_kind {
+        Some(kind) => Cow::Owned(wrap_data(&code, kind)),
+        None => code,
+    };
+    let code = chapter.markings.apply(&code).into_owned();
+    if cfg.restrict_includes_to_book {
+        check_plantuml_includes_within(&code, chapter.include_dir, book_root)?;
+    }
+
+    Ok(code)
+}
+
+/// Get all PlantUML diagrams in the markdown (in document order)
+/// # Arguments
+/// * `markdown` - The markdown to search
+/// * `strict_info_string` - Parse the info strings in strict-info-string mode
+pub fn plantuml_diagrams(markdown: &str, strict_info_string: bool) -> Vec<Diagram> {
+    let processor = CodeProcessor::new(markdown).strict_info_string(strict_info_string);
+    let mut diagrams = vec![];
+
+    let mut start_pos: usize = 0;
+    while let Some(code_block) = processor.next_code_block(start_pos) {
+        if code_block.is_plantuml() {
+            diagrams.push(processor.diagram(&code_block, None));
+        }
+
+        if code_block.end_pos <= start_pos {
+            break;
+        }
+        start_pos = code_block.end_pos;
+    }
+
+    diagrams
+}
+
+/// The diagrams the chapter processing renders (the PlantUML code blocks, and the data code
+/// blocks rendered with render-data), with their (1 based) block number in the chapter
+pub fn rendered_diagrams(markdown: &str, cfg: &Config) -> Vec<(usize, Diagram)> {
+    let processor = CodeProcessor::new(markdown).strict_info_string(cfg.strict_info_string);
+    let mut diagrams = vec![];
+
+    let mut start_pos: usize = 0;
+    while let Some(code_block) = processor.next_code_block(start_pos) {
+        let data_kind = code_block.render_data_kind(&cfg.render_data);
+        if code_block.is_plantuml() || data_kind.is_some() {
+            let block = diagrams.len() + 1;
+            diagrams.push((block, processor.diagram(&code_block, data_kind)));
+        }
+
+        if code_block.end_pos <= start_pos {
+            break;
+        }
+        start_pos = code_block.end_pos;
+    }
+
+    diagrams
+}
+
 struct CodeProcessor<'a> {
     markdown: &'a str,
+    strict_info_string: bool,
+    /// The chapter's configuration overrides
+    front_matter: ChapterFrontMatter,
+    /// The header and footer added to every diagram
+    markings: DiagramMarkings,
+    /// The (1 based) indices of the diagrams with an embedded render error
+    failed_diagrams: RefCell<Vec<usize>>,
 }
 
 impl<'a> CodeProcessor<'a> {
-    pub const fn new(markdown: &str) -> CodeProcessor {
-        CodeProcessor { markdown }
+    pub fn new(markdown: &str) -> CodeProcessor<'_> {
+        CodeProcessor {
+            markdown,
+            strict_info_string: false,
+            front_matter: ChapterFrontMatter::parse(markdown),
+            markings: DiagramMarkings::none(),
+            failed_diagrams: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// The code block's diagram, its source with the chapter's theme and the code block's ditaa
+    /// options added (the data of a data code block is taken as is), and its image format from
+    /// the info string or the chapter's front matter
+    fn diagram(&self, code_block: &CodeBlock, data_kind: Option<&'static str>) -> Diagram {
+        let block_code = code_block.code();
+        let code = match data_kind {
+            Some(_) => block_code.into_owned(),
+            None => {
+                let options = code_block.ditaa_options();
+                let code = self.front_matter.apply(&block_code);
+                insert_ditaa_options(&code, &options).into_owned()
+            }
+        };
+
+        Diagram {
+            code,
+            format: code_block.format(self.front_matter.format.as_deref()),
+            id: code_block.id().map(String::from),
+            alt_text: code_block.alt_text().map(String::from),
+            data_kind,
+        }
+    }
+
+    /// Add the header and footer markings to every diagram
+    pub fn markings(self, markings: DiagramMarkings) -> Self {
+        CodeProcessor { markings, ..self }
+    }
+
+    /// Parse the info strings as the language followed by space separated
+    /// key=value pairs, instead of the (legacy) comma separated syntax
+    pub fn strict_info_string(self, strict_info_string: bool) -> Self {
+        CodeProcessor {
+            strict_info_string,
+            ..self
+        }
     }
 
     /// Returns the byte offsets of the (optional) end fence and code end
@@ -196,17 +592,33 @@ impl<'a> CodeProcessor<'a> {
 
     /// Get next code block in document, starting at byte offset start_pos
     /// Returns None if no more code blocks are found.
-    fn next_code_block(&self, start_pos: usize) -> Option<CodeBlock> {
+    fn next_code_block(&self, start_pos: usize) -> Option<CodeBlock<'a>> {
         let bytes = self.markdown.as_bytes();
         if let Some((s, e)) = find_next_code_fence(bytes, start_pos, None, None) {
-            let info_string = info_string(bytes, e);
-            let code_start = next_line(bytes, e);
-            let fence_end = find_next_code_fence(bytes, e, Some(e - s), Some(bytes[s]));
-            let (code_end, end_pos) = Self::end_positions(bytes, fence_end);
+            let info_string = if self.strict_info_string {
+                full_info_string(bytes, e)
+            } else {
+                info_string(bytes, e)
+            };
+            let code_start = next_line(bytes, e).min(bytes.len());
+            // The closing fence cannot be on the opening fence's line (e.g. ~~~ a ~~~)
+            let fence_end = find_next_code_fence(bytes, code_start, Some(e - s), Some(bytes[s]));
+            let (mut code_end, end_pos) = Self::end_positions(bytes, fence_end);
+            // Strip the closing fence's indentation from the code
+            while fence_end.is_some() && code_end > code_start && bytes[code_end - 1] == b' ' {
+                code_end -= 1;
+            }
+
+            let mut line_start = s;
+            while line_start > start_pos && bytes[line_start - 1] == b' ' {
+                line_start -= 1;
+            }
 
             Some(CodeBlock {
                 code: &self.markdown[code_start..code_end],
                 info_string,
+                strict_info_string: self.strict_info_string,
+                indent: s - line_start,
                 start_pos: s,
                 end_pos,
             })
@@ -217,49 +629,106 @@ impl<'a> CodeProcessor<'a> {
 
     /// Processes all code blocks in the document (self.markdown)
     /// Replaces every "plantuml" code block with the renderer output.
-    /// Returns the processed markdown.
+    /// Returns the processed markdown (borrowed when no code block was rendered).
     /// # Arguments
     /// * `renderer` - The renderer to use for the "plantuml" code blocks
     /// * `rel_image_url` - The url of the image relative to the book output
     ///   dir.
-    pub fn process(&self, renderer: &impl RendererTrait, rel_image_url: &str) -> String {
-        let mut processed = String::new();
-        processed.reserve(self.markdown.len());
+    /// * `include_dir` - The directory mdBook `{{#include}}` directives in the
+    ///   code blocks are resolved from (the chapter's directory)
+    /// * `cfg` - The configuration options
+    pub fn process(
+        &self,
+        renderer: &impl RendererTrait,
+        rel_image_url: &str,
+        include_dir: &Path,
+        cfg: &Config,
+    ) -> Result<Cow<'a, str>> {
+        // Allocated when the first code block is rendered
+        let mut processed: Option<String> = None;
+        // The markdown up to here is copied in processed
+        let mut copied_pos: usize = 0;
+
+        // The number of diagrams in the chapter so far
+        let mut diagram_count: usize = 0;
 
         let bytes = self.markdown.as_bytes();
         let mut start_pos: usize = 0;
         while start_pos < bytes.len() {
             if let Some(code_block) = self.next_code_block(start_pos) {
-                if code_block.is_plantuml() {
-                    processed.push_str(&self.markdown[start_pos..code_block.start_pos]);
-                    let format = code_block.format();
+                if code_block.uses_legacy_info_string() {
+                    log::warn!(
+                        "The code block '{}' uses the comma separated info string syntax, it is not \
+                         rendered in strict-info-string mode (use spaces to separate the options)",
+                        code_block.info_string.unwrap_or_default()
+                    );
+                }
 
-                    let rendered = renderer.render(code_block.code, rel_image_url, format);
+                let data_kind = code_block.render_data_kind(&cfg.render_data);
+                if code_block.is_plantuml() || data_kind.is_some() {
+                    let processed =
+                        processed.get_or_insert_with(|| String::with_capacity(self.markdown.len()));
+                    // The replacement is indented like the opening fence
+                    let line_start = code_block.start_pos - code_block.indent;
+                    processed.push_str(&self.markdown[copied_pos..line_start]);
+                    copied_pos = code_block.end_pos;
+                    diagram_count += 1;
+                    let _block_field = log_field("block", diagram_count);
+
+                    // Expand the includes first, so the included content is part of the cache hash
+                    let diagram = self.diagram(&code_block, data_kind);
+                    let chapter = DiagramChapter {
+                        include_dir,
+                        markings: &self.markings,
+                    };
+                    let rendered = diagram_source(&chapter, &diagram, cfg).and_then(|code| {
+                        check_limits(&code, diagram_count, cfg)?;
+                        let options = BlockOptions {
+                            image_format: &diagram.format,
+                            alt_text: code_block.alt_text(),
+                            id: code_block.id(),
+                            markup: code_block.markup(),
+                        };
+                        renderer.render(&code, include_dir, rel_image_url, options)
+                    });
                     match rendered {
-                        Ok(data) => processed.push_str(data.as_str()),
+                        Ok(data) => push_indented(processed, &data, code_block.indent),
                         Err(e) => {
-                            processed.push_str(format!("{e}").as_str());
+                            if cfg.fail_on_error {
+                                return Err(e);
+                            }
+
+                            // Backend failures are shown as a placeholder naming the failure
+                            let shown =
+                                failure_placeholder(&diagram.code, code_block.alt_text(), &e)
+                                    .unwrap_or_else(|| format!("{e}"));
+                            push_indented(processed, &shown, code_block.indent);
                             log::error!("{}", e);
+                            self.failed_diagrams.borrow_mut().push(diagram_count);
                         }
                     }
-                } else {
-                    processed.push_str(&self.markdown[start_pos..code_block.end_pos]);
                 }
                 start_pos = code_block.end_pos;
             } else {
-                processed.push_str(&self.markdown[start_pos..]);
                 start_pos = bytes.len();
             }
         }
 
-        processed
+        Ok(match processed {
+            Some(mut processed) => {
+                processed.push_str(&self.markdown[copied_pos..]);
+                Cow::Owned(processed)
+            }
+            None => Cow::Borrowed(self.markdown),
+        })
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use anyhow::Result;
+    use crate::backend::error::BackendError;
+    use anyhow::{bail, format_err, Result};
     use pretty_assertions::assert_eq;
     use std::cell::RefCell;
 
@@ -272,14 +741,69 @@ mod test {
         fn render(
             &self,
             code_block: &str,
+            _include_dir: &Path,
             _rel_image_url: &str,
-            _image_format: String,
+            _options: BlockOptions,
         ) -> Result<String> {
             self.code_block.replace(code_block.to_string());
             Ok(String::from("rendered"))
         }
     }
 
+    struct FailingRenderer;
+
+    impl RendererTrait for FailingRenderer {
+        fn render(
+            &self,
+            _code_block: &str,
+            _include_dir: &Path,
+            _rel_image_url: &str,
+            _options: BlockOptions,
+        ) -> Result<String> {
+            bail!("Oh no")
+        }
+    }
+
+    #[test]
+    fn test_unchanged_markdown_is_borrowed() {
+        let renderer = FailingRenderer;
+        let render = |markdown| {
+            render_plantuml_code_blocks(
+                markdown,
+                &renderer,
+                "",
+                Path::new("."),
+                &Config::default(),
+                &DiagramMarkings::none(),
+                &mut vec![],
+            )
+            .unwrap()
+        };
+
+        assert!(matches!(
+            render("# No diagrams"),
+            Cow::Borrowed("# No diagrams")
+        ));
+        let markdown = "Some plantuml prose\n```rust\nfn main() {}\n```\n";
+        assert!(matches!(render(markdown), Cow::Borrowed(m) if m == markdown));
+
+        let renderer = FakeRenderer {
+            code_block: RefCell::new(String::new()),
+        };
+        let processed = render_plantuml_code_blocks(
+            "A\n```plantuml\nB\n```\nC",
+            &renderer,
+            "",
+            Path::new("."),
+            &Config::default(),
+            &DiagramMarkings::none(),
+            &mut vec![],
+        )
+        .unwrap();
+        assert!(matches!(processed, Cow::Owned(_)));
+        assert_eq!("A\nrendered\nC", processed);
+    }
+
     #[test]
     fn test_find_next_code_fence() {
         macro_rules! assert_find_next_code_fence {
@@ -341,10 +865,92 @@ mod test {
         assert_find_next_code_fence!(Some((8, 11)), b"```\n~~~\n```", 3, Some(3), Some(b'`'));
 
         // Rest
-        assert_find_next_code_fence!(Some((0, 3)), b"``` ```", 0, None, None);
+        // A backtick fence's info string cannot contain backticks (CommonMark spec)
+        assert_find_next_code_fence!(None, b"``` ```", 0, None, None);
+        assert_find_next_code_fence!(Some((0, 3)), b"~~~ ```", 0, None, None);
+        // A closing fence cannot have an info string (CommonMark spec)
+        assert_find_next_code_fence!(None, b"```\n``` a", 4, Some(3), Some(b'`'));
+        assert_find_next_code_fence!(Some((4, 7)), b"```\n```  \r\n", 4, Some(3), Some(b'`'));
         assert_find_next_code_fence!(None, b"``~~~", 0, None, None);
     }
 
+    #[test]
+    fn test_commonmark_fenced_code_blocks() {
+        // The (info string, code) of all code blocks in the markdown
+        fn code_blocks(markdown: &str) -> Vec<(Option<&str>, String)> {
+            let processor = CodeProcessor::new(markdown);
+            let mut blocks = vec![];
+            let mut start_pos = 0;
+            while let Some(code_block) = processor.next_code_block(start_pos) {
+                blocks.push((code_block.info_string, code_block.code().into_owned()));
+                if code_block.end_pos <= start_pos {
+                    break;
+                }
+                start_pos = code_block.end_pos;
+            }
+
+            blocks
+        }
+
+        let code = |code: &str| vec![(None, String::from(code))];
+        let info_code = |info, code: &str| vec![(Some(info), String::from(code))];
+
+        // Fenced code block examples from the CommonMark spec (0.30), the
+        // block quote and setext heading examples are left out.
+        assert_eq!(code("<\n >\n"), code_blocks("```\n<\n >\n```\n")); // 119
+        assert_eq!(code("<\n >\n"), code_blocks("~~~\n<\n >\n~~~\n")); // 120
+        assert!(code_blocks("``\nfoo\n``\n").is_empty()); // 121
+        assert_eq!(code("aaa\n~~~\n"), code_blocks("```\naaa\n~~~\n```\n")); // 122
+        assert_eq!(code("aaa\n```\n"), code_blocks("~~~\naaa\n```\n~~~\n")); // 123
+        assert_eq!(code("aaa\n```\n"), code_blocks("````\naaa\n```\n``````\n")); // 124
+        assert_eq!(code("aaa\n~~~\n"), code_blocks("~~~~\naaa\n~~~\n~~~~\n")); // 125
+        assert_eq!(code(""), code_blocks("```")); // 126
+        assert_eq!(code("\n```\naaa\n"), code_blocks("`````\n\n```\naaa\n")); // 127
+        assert_eq!(code("\n  \n"), code_blocks("```\n\n  \n```\n")); // 129
+        assert_eq!(code(""), code_blocks("```\n```\n")); // 130
+        assert_eq!(code("aaa\naaa\n"), code_blocks(" ```\n aaa\naaa\n```\n")); // 131
+        assert_eq!(
+            code("aaa\naaa\naaa\n"),
+            code_blocks("  ```\naaa\n  aaa\naaa\n  ```\n")
+        ); // 132
+        assert_eq!(
+            code("aaa\n aaa\naaa\n"),
+            code_blocks("   ```\n   aaa\n    aaa\n  aaa\n   ```\n")
+        ); // 133
+        assert!(code_blocks("    ```\n    aaa\n    ```\n").is_empty()); // 134
+        assert_eq!(code("aaa\n"), code_blocks("```\naaa\n  ```\n")); // 135
+        assert_eq!(code("aaa\n"), code_blocks("   ```\naaa\n  ```\n")); // 136
+        assert_eq!(code("aaa\n    ```\n"), code_blocks("```\naaa\n    ```\n")); // 137
+        assert!(code_blocks("``` ```\naaa\n").is_empty()); // 138
+        assert_eq!(code("aaa\n~~~ ~~\n"), code_blocks("~~~~~~\naaa\n~~~ ~~\n")); // 139
+        assert_eq!(code("bar\n"), code_blocks("foo\n```\nbar\n```\nbaz\n")); // 140
+        assert_eq!(
+            code("bar\n"),
+            code_blocks("foo\n---\n~~~\nbar\n~~~\n# baz\n")
+        ); // 141
+        assert_eq!(
+            info_code("ruby", "def foo(x)\n  return 3\nend\n"),
+            code_blocks("```ruby\ndef foo(x)\n  return 3\nend\n```\n")
+        ); // 142
+        assert_eq!(
+            info_code("ruby", "def foo(x)\n  return 3\nend\n"),
+            code_blocks("~~~~    ruby startline=3 $%@#$\ndef foo(x)\n  return 3\nend\n~~~~~~~\n")
+        ); // 143
+        assert_eq!(info_code(";", ""), code_blocks("````;\n````\n")); // 144
+        assert!(code_blocks("``` aa ```\nfoo\n").is_empty()); // 145
+        assert_eq!(
+            info_code("aa", "foo\n"),
+            code_blocks("~~~ aa ``` ~~~\nfoo\n~~~\n")
+        ); // 146
+        assert_eq!(code("``` aaa\n"), code_blocks("```\n``` aaa\n```\n")); // 147
+
+        // Backtick code (e.g. creole in salt diagrams) inside a tilde fenced PlantUML block
+        assert_eq!(
+            info_code("plantuml", "@startsalt\n```\n{ ``code`` }\n```\n@endsalt\n"),
+            code_blocks("~~~plantuml\n@startsalt\n```\n{ ``code`` }\n```\n@endsalt\n~~~\n")
+        );
+    }
+
     #[test]
     fn test_info_string() {
         #![allow(clippy::string_lit_as_bytes)]
@@ -375,6 +981,10 @@ mod test {
         assert_info_string!("  foobar baz \n", 0, Some((2, 8)));
 
         assert_info_string!("some```foobar", 7, Some((7, 13)));
+
+        // Quoted values can contain spaces
+        assert_info_string!("foo,alt=\"a b\" baz", 0, Some((0, 13)));
+        assert_info_string!("foo,alt=\"a b\nbaz", 0, Some((0, 12)));
     }
 
     #[test]
@@ -385,7 +995,14 @@ mod test {
                 let renderer = FakeRenderer {
                     code_block: RefCell::new(String::new()),
                 };
-                let result = processor.process(&renderer, &String::default());
+                let result = processor
+                    .process(
+                        &renderer,
+                        &String::default(),
+                        Path::new("."),
+                        &Config::default(),
+                    )
+                    .unwrap();
                 assert_eq!($expected_code_block, *renderer.code_block.borrow());
                 assert_eq!($rendered_output, result);
             }};
@@ -436,6 +1053,611 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_process_indented_code_block() {
+        struct MultiLineRenderer;
+
+        impl RendererTrait for MultiLineRenderer {
+            fn render(
+                &self,
+                _code_block: &str,
+                _include_dir: &Path,
+                _rel_image_url: &str,
+                _options: BlockOptions,
+            ) -> Result<String> {
+                Ok(String::from("<a id=\"x\"></a>\n\n![](img.svg)\n"))
+            }
+        }
+
+        let processor = CodeProcessor::new("1. Item\n\n   ```plantuml\n   A -> B\n   ```\n2. Next");
+        let result = processor
+            .process(
+                &MultiLineRenderer,
+                &String::default(),
+                Path::new("."),
+                &Config::default(),
+            )
+            .unwrap();
+        assert_eq!(
+            "1. Item\n\n   <a id=\"x\"></a>\n\n   ![](img.svg)\n\n2. Next",
+            result
+        );
+
+        let mut out = String::new();
+        push_indented(&mut out, "a\r\n\r\nb", 2);
+        assert_eq!("  a\r\n\r\n  b", out);
+    }
+
+    #[test]
+    fn test_process_restricted_includes() {
+        let renderer = FakeRenderer {
+            code_block: RefCell::new(String::new()),
+        };
+        let book_root = tempfile::tempdir().unwrap();
+        let cfg = Config {
+            restrict_includes_to_book: true,
+            book_root: Some(book_root.path().join("book")),
+            ..Config::default()
+        };
+        std::fs::create_dir_all(book_root.path().join("book/src")).unwrap();
+        std::fs::write(book_root.path().join("secret.txt"), "secret").unwrap();
+
+        let markdown = "```plantuml\n!include ../../secret.txt\n```\n";
+        let result = CodeProcessor::new(markdown).process(
+            &renderer,
+            "",
+            &book_root.path().join("book/src"),
+            &cfg,
+        );
+        assert!(format!("{}", result.unwrap()).contains("outside the book directory"));
+        assert_eq!("", *renderer.code_block.borrow());
+    }
+
+    #[test]
+    fn test_process_limits() {
+        let renderer = FakeRenderer {
+            code_block: RefCell::new(String::new()),
+        };
+        let markdown = "```plantuml\nA\n```\n```plantuml\nB -> C\n```\n```plantuml\nD\n```\n";
+        let processor = CodeProcessor::new(markdown);
+
+        let cfg = Config {
+            max_diagram_bytes: Some(2),
+            ..Config::default()
+        };
+        let result = processor
+            .process(&renderer, "", Path::new("."), &cfg)
+            .unwrap();
+        assert_eq!(
+            "rendered\nDiagram 2 is not rendered, its source (7 bytes) exceeds max-diagram-bytes \
+             (2 bytes)\nrendered",
+            result
+        );
+
+        let cfg = Config {
+            max_diagrams_per_chapter: Some(2),
+            fail_on_error: true,
+            ..Config::default()
+        };
+        let result = processor.process(&renderer, "", Path::new("."), &cfg);
+        assert_eq!(
+            "Diagram 3 is not rendered, the chapter exceeds max-diagrams-per-chapter (2)",
+            format!("{}", result.unwrap_err())
+        );
+        assert_eq!("B -> C\n", *renderer.code_block.borrow());
+    }
+
+    #[test]
+    fn test_process_render_failure() {
+        let markdown = "abc\n```plantuml\nfoo\n```\ndef";
+        let processor = CodeProcessor::new(markdown);
+
+        // The error is embedded in the markdown
+        assert_eq!(
+            "abc\nOh no\ndef",
+            processor
+                .process(
+                    &FailingRenderer,
+                    &String::default(),
+                    Path::new("."),
+                    &Config::default()
+                )
+                .unwrap()
+        );
+
+        assert_eq!(vec![1], *processor.failed_diagrams.borrow());
+
+        // Or returned when failing on errors
+        let result = processor.process(
+            &FailingRenderer,
+            &String::default(),
+            Path::new("."),
+            &Config {
+                fail_on_error: true,
+                ..Config::default()
+            },
+        );
+        assert_eq!("Oh no", format!("{}", result.err().unwrap()));
+
+        // Backend failures are shown as a placeholder naming the failure
+        struct SyntaxErrorRenderer;
+        impl RendererTrait for SyntaxErrorRenderer {
+            fn render(
+                &self,
+                _code_block: &str,
+                _include_dir: &Path,
+                _rel_image_url: &str,
+                _options: BlockOptions,
+            ) -> Result<String> {
+                Err(BackendError::SyntaxError { line: Some(1) }.tag()(
+                    format_err!("Oh no"),
+                ))
+            }
+        }
+
+        let processor = CodeProcessor::new("abc\n```plantuml,alt=\"My diagram\"\nfoo\n```\ndef");
+        assert_eq!(
+            "abc\n\n*PlantUML diagram 'My diagram' not rendered (syntax error at line 1)*\n\n\ndef",
+            processor
+                .process(
+                    &SyntaxErrorRenderer,
+                    &String::default(),
+                    Path::new("."),
+                    &Config::default()
+                )
+                .unwrap()
+        );
+        assert_eq!(vec![1], *processor.failed_diagrams.borrow());
+    }
+
+    #[test]
+    fn test_process_includes() {
+        let include_dir = tempfile::tempdir().unwrap();
+        std::fs::write(include_dir.path().join("foo.puml"), "A --|> B\n").unwrap();
+
+        let renderer = FakeRenderer {
+            code_block: RefCell::new(String::new()),
+        };
+        let processor = CodeProcessor::new("```plantuml\n{{#include foo.puml}}\n```\n");
+        let result = processor
+            .process(
+                &renderer,
+                &String::default(),
+                include_dir.path(),
+                &Config::default(),
+            )
+            .unwrap();
+        assert_eq!("A --|> B\n\n", *renderer.code_block.borrow());
+        assert_eq!("rendered", result);
+
+        // A missing include is reported like a rendering error
+        let processor = CodeProcessor::new("```plantuml\n{{#include bar.puml}}\n```\n");
+        let result = processor.process(
+            &renderer,
+            &String::default(),
+            include_dir.path(),
+            &Config {
+                fail_on_error: true,
+                ..Config::default()
+            },
+        );
+        assert!(format!("{:#}", result.unwrap_err()).contains("Failed to include 'bar.puml'"));
+    }
+
+    #[test]
+    fn test_plantuml_diagrams() {
+        let diagrams = plantuml_diagrams(
+            "abc\n```plantuml\nfoo\n```\ndef\n```c++\nint main;\n```\n```puml,format=png,id=bar\nbar\n```\n",
+            false,
+        );
+
+        assert_eq!(2, diagrams.len());
+        assert_eq!("foo\n", diagrams[0].code);
+        assert_eq!("svg", diagrams[0].format);
+        assert_eq!(None, diagrams[0].id);
+        assert_eq!("bar\n", diagrams[1].code);
+        assert_eq!("png", diagrams[1].format);
+        assert_eq!(Some(String::from("bar")), diagrams[1].id);
+
+        assert!(plantuml_diagrams("", false).is_empty());
+        assert!(plantuml_diagrams("no code blocks", false).is_empty());
+        // Unclosed code block
+        assert_eq!(1, plantuml_diagrams("```plantuml\nfoo", false).len());
+    }
+
+    #[test]
+    fn test_rendered_diagrams() {
+        let markdown =
+            "```plantuml\nfoo\n```\n```json\n{}\n```\n```c++\nint main;\n```\n```puml\nbar\n```\n";
+        let blocks = |cfg: &Config| -> Vec<(usize, String)> {
+            rendered_diagrams(markdown, cfg)
+                .into_iter()
+                .map(|(block, diagram)| (block, diagram.code))
+                .collect()
+        };
+        assert_eq!(
+            vec![(1, String::from("foo\n")), (2, String::from("bar\n"))],
+            blocks(&Config::default())
+        );
+
+        // The data diagrams are numbered too
+        let cfg = Config {
+            render_data: vec![String::from("json")],
+            ..Config::default()
+        };
+        assert_eq!(
+            vec![
+                (1, String::from("foo\n")),
+                (2, String::from("{}\n")),
+                (3, String::from("bar\n"))
+            ],
+            blocks(&cfg)
+        );
+        assert_eq!(
+            Some("json"),
+            rendered_diagrams(markdown, &cfg)[1].1.data_kind
+        );
+    }
+
+    #[test]
+    fn test_diagram_source() {
+        let include_dir = tempfile::tempdir().unwrap();
+        std::fs::write(include_dir.path().join("foo.puml"), "A --|> B\n").unwrap();
+        let renderer = FakeRenderer {
+            code_block: RefCell::new(String::new()),
+        };
+        let cfg = Config {
+            footer: Some(String::from("CONFIDENTIAL")),
+            ..Config::default()
+        };
+        let markings = DiagramMarkings::from_config(&cfg, None);
+
+        // The source is the same as the one rendered by the chapter processing
+        let markdown = "<!-- plantuml: theme=mono format=png -->\n\
+                        ```plantuml\n@startuml\n{{#include foo.puml}}\n@enduml\n```\n";
+        CodeProcessor::new(markdown)
+            .markings(markings.clone())
+            .process(&renderer, "", include_dir.path(), &cfg)
+            .unwrap();
+        let chapter = DiagramChapter {
+            include_dir: include_dir.path(),
+            markings: &markings,
+        };
+        let diagram = &plantuml_diagrams(markdown, false)[0];
+        assert_eq!(
+            *renderer.code_block.borrow(),
+            diagram_source(&chapter, diagram, &cfg).unwrap()
+        );
+        assert_eq!(
+            "@startuml\n!theme mono\nA --|> B\n\nfooter CONFIDENTIAL\n@enduml\n",
+            *renderer.code_block.borrow()
+        );
+        assert_eq!("png", diagram.format);
+
+        // The includes are checked too
+        let diagram = Diagram {
+            code: String::from("!include ../../secret.txt\n"),
+            format: String::from("svg"),
+            id: None,
+            alt_text: None,
+            data_kind: None,
+        };
+        let cfg = Config {
+            restrict_includes_to_book: true,
+            ..Config::default()
+        };
+        assert!(diagram_source(&chapter, &diagram, &cfg).is_err());
+    }
+
+    #[test]
+    fn test_strict_info_string() {
+        let renderer = FakeRenderer {
+            code_block: RefCell::new(String::new()),
+        };
+        let cfg = Config {
+            strict_info_string: true,
+            ..Config::default()
+        };
+        let process = |markdown| {
+            render_plantuml_code_blocks(
+                markdown,
+                &renderer,
+                "",
+                Path::new("."),
+                &cfg,
+                &DiagramMarkings::none(),
+                &mut vec![],
+            )
+            .unwrap()
+            .into_owned()
+        };
+
+        assert_eq!("rendered", process("```plantuml\nfoo\n```"));
+        assert_eq!("rendered", process("```plantuml format=png\nfoo\n```"));
+        assert_eq!(
+            "```plantuml render=false\nfoo\n```",
+            process("```plantuml render=false\nfoo\n```")
+        );
+        // The legacy comma syntax is not rendered
+        assert_eq!(
+            "```plantuml,format=png\nfoo\n```",
+            process("```plantuml,format=png\nfoo\n```")
+        );
+
+        let diagrams = plantuml_diagrams(
+            "```puml  format=png alt=\"A, B\" id=bar \nbar\n```\n```puml,id=foo\nfoo\n```",
+            true,
+        );
+        assert_eq!(1, diagrams.len());
+        assert_eq!("png", diagrams[0].format);
+        assert_eq!(Some(String::from("bar")), diagrams[0].id);
+
+        let code_block = CodeBlock {
+            code: "Foo",
+            info_string: Some("plantuml alt=\"A diagram, with a comma\" id=a"),
+            strict_info_string: true,
+            indent: 0,
+            start_pos: 0,
+            end_pos: 0,
+        };
+        assert_eq!(Some("A diagram, with a comma"), code_block.alt_text());
+        assert!(!code_block.uses_legacy_info_string());
+    }
+
+    #[test]
+    fn test_codeblock_id() {
+        let code_block = CodeBlock {
+            code: "Foo",
+            info_string: Some("plantuml,format=svg,id=my-diagram"),
+            strict_info_string: false,
+            indent: 0,
+            start_pos: 0,
+            end_pos: 0,
+        };
+        assert_eq!(Some("my-diagram"), code_block.id());
+
+        let code_block = CodeBlock {
+            code: "Foo",
+            info_string: Some("plantuml,id="),
+            strict_info_string: false,
+            indent: 0,
+            start_pos: 0,
+            end_pos: 0,
+        };
+        assert_eq!(None, code_block.id());
+    }
+
+    #[test]
+    fn test_codeblock_alt_text() {
+        let code_block = CodeBlock {
+            code: "Foo",
+            info_string: Some("plantuml,alt=\"My diagram, with a comma\",format=png"),
+            strict_info_string: false,
+            indent: 0,
+            start_pos: 0,
+            end_pos: 0,
+        };
+        assert_eq!(Some("My diagram, with a comma"), code_block.alt_text());
+        assert_eq!("png", code_block.format(None));
+
+        let code_block = CodeBlock {
+            code: "Foo",
+            info_string: Some("plantuml,alt=Diagram"),
+            strict_info_string: false,
+            indent: 0,
+            start_pos: 0,
+            end_pos: 0,
+        };
+        assert_eq!(Some("Diagram"), code_block.alt_text());
+
+        let code_block = CodeBlock {
+            code: "Foo",
+            info_string: Some("plantuml,alt=\"\""),
+            strict_info_string: false,
+            indent: 0,
+            start_pos: 0,
+            end_pos: 0,
+        };
+        assert_eq!(None, code_block.alt_text());
+    }
+
+    #[test]
+    fn test_process_markings() {
+        let renderer = FakeRenderer {
+            code_block: RefCell::new(String::new()),
+        };
+        let cfg = Config {
+            footer: Some(String::from("CONFIDENTIAL - {chapter}")),
+            ..Config::default()
+        };
+
+        let processor = CodeProcessor::new("```plantuml\n@startuml\nA --|> B\n@enduml\n```\n")
+            .markings(DiagramMarkings::from_config(&cfg, None).for_chapter("Intro"));
+        let result = processor
+            .process(&renderer, &String::default(), Path::new("."), &cfg)
+            .unwrap();
+        assert_eq!("rendered", result);
+        assert_eq!(
+            "@startuml\nA --|> B\nfooter CONFIDENTIAL - Intro\n@enduml\n",
+            *renderer.code_block.borrow()
+        );
+    }
+
+    #[test]
+    fn test_process_front_matter() {
+        let renderer = FakeRenderer {
+            code_block: RefCell::new(String::new()),
+        };
+        let markdown = "<!-- plantuml: theme=mono format=png -->\n\
+                        ```plantuml\n@startuml\nA --|> B\n@enduml\n```\n\
+                        ```plantuml,format=svg\nA\n```\n";
+        let processor = CodeProcessor::new(markdown);
+        let result = processor
+            .process(
+                &renderer,
+                &String::default(),
+                Path::new("."),
+                &Config::default(),
+            )
+            .unwrap();
+        assert_eq!(
+            "<!-- plantuml: theme=mono format=png -->\nrendered\nrendered",
+            result
+        );
+        assert_eq!("!theme mono\nA\n", *renderer.code_block.borrow());
+
+        let diagrams = plantuml_diagrams(markdown, false);
+        assert_eq!(
+            "@startuml\n!theme mono\nA --|> B\n@enduml\n",
+            diagrams[0].code
+        );
+        assert_eq!("png", diagrams[0].format);
+        assert_eq!("svg", diagrams[1].format);
+    }
+
+    #[test]
+    fn test_process_ditaa_options() {
+        let renderer = FakeRenderer {
+            code_block: RefCell::new(String::new()),
+        };
+        let markdown =
+            "```plantuml,ditaa-opts=\"--no-shadows scale=2\"\n@startditaa\n+--+\n@endditaa\n```\n";
+        let processor = CodeProcessor::new(markdown).markings(DiagramMarkings::from_config(
+            &Config {
+                ditaa_args: vec![String::from("-E")],
+                ..Config::default()
+            },
+            None,
+        ));
+        processor
+            .process(
+                &renderer,
+                &String::default(),
+                Path::new("."),
+                &Config::default(),
+            )
+            .unwrap();
+        assert_eq!(
+            "@startditaa(-E, --no-shadows, scale=2)\n+--+\n@endditaa\n",
+            *renderer.code_block.borrow()
+        );
+
+        let diagrams = plantuml_diagrams(markdown, false);
+        assert_eq!(
+            "@startditaa(--no-shadows, scale=2)\n+--+\n@endditaa\n",
+            diagrams[0].code
+        );
+    }
+
+    #[test]
+    fn test_process_markup() {
+        struct MarkupRenderer;
+
+        impl RendererTrait for MarkupRenderer {
+            fn render(
+                &self,
+                _code_block: &str,
+                _include_dir: &Path,
+                _rel_image_url: &str,
+                options: BlockOptions,
+            ) -> Result<String> {
+                Ok(format!("{:?}", options.markup))
+            }
+        }
+
+        let process = |markdown| {
+            CodeProcessor::new(markdown)
+                .process(
+                    &MarkupRenderer,
+                    &String::default(),
+                    Path::new("."),
+                    &Config::default(),
+                )
+                .unwrap()
+                .into_owned()
+        };
+        assert_eq!("None", process("```plantuml\nA\n```"));
+        assert_eq!("Some(Html)", process("```plantuml,markup=html\nA\n```"));
+        assert_eq!(
+            "Some(Markdown)",
+            process("```plantuml,markup=markdown\nA\n```")
+        );
+        assert_eq!("None", process("```plantuml,markup=rtf\nA\n```"));
+    }
+
+    #[test]
+    fn test_process_render_data() {
+        let renderer = FakeRenderer {
+            code_block: RefCell::new(String::new()),
+        };
+
+        // Data blocks are kept by default
+        let markdown = "```json\n{\"a\": 1}\n```\n";
+        let processor = CodeProcessor::new(markdown);
+        let result = processor
+            .process(
+                &renderer,
+                &String::default(),
+                Path::new("."),
+                &Config::default(),
+            )
+            .unwrap();
+        assert_eq!(markdown, result);
+
+        let processor = CodeProcessor::new("```json,plantuml-render\n{\"a\": 1}\n```\n");
+        let result = processor
+            .process(
+                &renderer,
+                &String::default(),
+                Path::new("."),
+                &Config::default(),
+            )
+            .unwrap();
+        assert_eq!("rendered", result);
+        assert_eq!(
+            "@startjson\n{\"a\": 1}\n@endjson\n",
+            *renderer.code_block.borrow()
+        );
+
+        let cfg = Config {
+            render_data: vec![String::from("yaml")],
+            ..Config::default()
+        };
+        let processor = CodeProcessor::new("```yml\na: 1\n```\n");
+        let result = processor
+            .process(&renderer, &String::default(), Path::new("."), &cfg)
+            .unwrap();
+        assert_eq!("rendered", result);
+        assert_eq!(
+            "@startyaml\na: 1\n@endyaml\n",
+            *renderer.code_block.borrow()
+        );
+    }
+
+    #[test]
+    fn test_codeblock_render_data_kind() {
+        let render_data = vec![String::from("json")];
+        let kind = |info_string| {
+            let code_block = CodeBlock {
+                code: "Foo",
+                info_string: Some(info_string),
+                strict_info_string: false,
+                indent: 0,
+                start_pos: 0,
+                end_pos: 0,
+            };
+            code_block.render_data_kind(&render_data)
+        };
+
+        assert_eq!(Some("json"), kind("json"));
+        assert_eq!(None, kind("json,plantuml-render=false"));
+        assert_eq!(Some("yaml"), kind("yaml,plantuml-render"));
+        assert_eq!(Some("yaml"), kind("yml,plantuml-render=true"));
+        assert_eq!(None, kind("yaml"));
+        assert_eq!(None, kind("plantuml"));
+        assert_eq!(None, kind("c++,plantuml-render"));
+    }
+
     #[test]
     fn test_codeblock_plantuml_detection() {
         macro_rules! is_plantuml_code_block {
@@ -443,6 +1665,8 @@ mod test {
                 let code_block = CodeBlock {
                     code: "Foo",
                     info_string: Some($info_str),
+                    strict_info_string: false,
+                    indent: 0,
                     start_pos: 0,
                     end_pos: 0,
                 };
@@ -452,6 +1676,12 @@ mod test {
         }
         assert!(is_plantuml_code_block!("plantuml"));
         assert!(is_plantuml_code_block!("plantuml,format=svg"));
+        assert!(is_plantuml_code_block!("puml,render=true"));
+
+        // Shown as source
+        assert!(!is_plantuml_code_block!("plantuml,render=false"));
+        assert!(!is_plantuml_code_block!("puml,format=png,render=false"));
+        assert!(!is_plantuml_code_block!("plantuml-source"));
 
         assert!(!is_plantuml_code_block!(",plantuml")); // Bogus info string
         assert!(!is_plantuml_code_block!("plantUML")); // Case sensitive
@@ -468,11 +1698,13 @@ mod test {
                 let code_block = CodeBlock {
                     code: $code,
                     info_string: Some($info_str),
+                    strict_info_string: false,
+                    indent: 0,
                     start_pos: 0,
                     end_pos: 0,
                 };
 
-                code_block.format()
+                code_block.format(None)
             }};
         }
 
@@ -483,6 +1715,17 @@ mod test {
         assert_eq!("jpg", get_format!("plantuml,bruh=123,format=jpg,bruh=123"));
         assert_eq!("png", get_format!("plantuml", "@startditaa"));
 
+        // The chapter's format is used when the info string has none
+        let code_block = CodeBlock {
+            code: "foo",
+            info_string: Some("plantuml"),
+            strict_info_string: false,
+            indent: 0,
+            start_pos: 0,
+            end_pos: 0,
+        };
+        assert_eq!("png", code_block.format(Some("png")));
+
         // Error/edge cases
         assert_eq!("svg", get_format!("plantuml,format="));
         assert_eq!("svg", get_format!("plantuml,format"));
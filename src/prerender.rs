@@ -0,0 +1,411 @@
+use crate::backend::Backend;
+use crate::config::Config;
+use crate::log_context::DiagramLog;
+use crate::markings::DiagramMarkings;
+use crate::pipeline::{diagram_source, rendered_diagrams, DiagramChapter};
+use crate::renderer::Renderer;
+use anyhow::Result;
+use mdbook::book::{Book, BookItem};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+
+/// An uncached diagram to render ahead of the (sequential) chapter processing
+pub struct PrerenderJob {
+    /// The code to render (see Renderer::render_input)
+    code: String,
+    /// The image format to render (see Renderer::render_input)
+    format: String,
+    /// The cache file of the image (see Renderer::image_file)
+    image_file: PathBuf,
+    /// The chapter's source (relative to the src dir) and the diagram's block number in it, for
+    /// the log context of the render
+    chapter: String,
+    block: usize,
+    /// Group the log output with the chapter's (see Config::group_chapter_logs)
+    group_logs: bool,
+}
+
+impl PrerenderJob {
+    /// The log context of the render, like the chapter processing's
+    fn log_context(&self) -> DiagramLog {
+        let file_name = self
+            .image_file
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let hash = file_name.split('.').next().unwrap_or_default();
+        DiagramLog::new(&self.chapter, self.block, hash, self.group_logs)
+    }
+}
+
+/// Get the uncached diagrams of the book that can be rendered in parallel. Diagrams with
+/// PlantUML `!include`s are rendered in the chapter processing when the backend resolves these
+/// from the working dir (which is changed per chapter). Diagrams in a format the backend does
+/// not support are left to the chapter processing as well (which reports the error).
+/// # Arguments
+/// * `book` - The book
+/// * `src_dir` - The book's source directory
+/// * `renderer` - The renderer, determines the image files
+/// * `cfg` - The configuration options
+/// * `markings` - The header and footer added to the diagrams
+pub fn prerender_jobs(
+    book: &Book,
+    src_dir: &Path,
+    renderer: &Renderer,
+    cfg: &Config,
+    markings: &DiagramMarkings,
+) -> Vec<PrerenderJob> {
+    let capabilities = match renderer.backend_capabilities() {
+        Some(capabilities) => capabilities,
+        None => return vec![],
+    };
+
+    let mut jobs: Vec<PrerenderJob> = vec![];
+    for item in book.iter() {
+        if let BookItem::Chapter(chapter) = item {
+            if let Some(chapter_path) = &chapter.path {
+                let chapter_file = src_dir.join(chapter_path);
+                let chapter_dir = chapter_file.parent().unwrap_or(src_dir);
+                let chapter_markings = markings.for_chapter(&chapter.name);
+                let diagram_chapter = DiagramChapter {
+                    include_dir: chapter_dir,
+                    markings: &chapter_markings,
+                };
+                let diagrams = rendered_diagrams(&chapter.content, cfg);
+                let max_diagrams = cfg.max_diagrams_per_chapter.unwrap_or(usize::MAX);
+                for (block, diagram) in diagrams.iter().take(max_diagrams) {
+                    let source = match diagram_source(&diagram_chapter, diagram, cfg) {
+                        Ok(source) => source,
+                        Err(_) => continue,
+                    };
+                    if cfg
+                        .max_diagram_bytes
+                        .map_or(false, |max| source.len() > max)
+                    {
+                        continue;
+                    }
+                    // Rendered like the chapter processing renders it, so it has the same hash
+                    let (code, format) = renderer.render_input(&source, &diagram.format);
+                    if (capabilities.includes && code.contains("!include"))
+                        || !capabilities.supports_format(format)
+                    {
+                        continue;
+                    }
+
+                    let image_file = renderer.image_file(&source, chapter_dir, &diagram.format);
+                    if !image_file.exists() && jobs.iter().all(|job| job.image_file != image_file) {
+                        jobs.push(PrerenderJob {
+                            code: code.into_owned(),
+                            format: format.to_string(),
+                            image_file,
+                            chapter: chapter_path.display().to_string(),
+                            block: *block,
+                            group_logs: cfg.group_chapter_logs,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    jobs
+}
+
+/// Render the diagrams in parallel and save them to the renderer's cache, so the chapter
+/// processing finds them cached. Diagrams that fail to render are left to the chapter
+/// processing (which reports the error).
+/// # Arguments
+/// * `renderer` - The renderer the images are saved by
+/// * `jobs` - The diagrams to render
+/// * `max_parallel` - The maximum number of concurrent renders
+/// * `create_backend` - Creates the backend for a render thread
+pub fn prerender(
+    renderer: &Renderer,
+    jobs: &[PrerenderJob],
+    max_parallel: usize,
+    create_backend: &(dyn Fn() -> Result<Box<dyn Backend>> + Sync),
+) {
+    if jobs.is_empty() {
+        return;
+    }
+
+    let threads = max_parallel.min(jobs.len());
+    log::info!(
+        "Rendering {} uncached diagram(s) in {} thread(s)",
+        jobs.len(),
+        threads
+    );
+
+    let next_job = AtomicUsize::new(0);
+    std::thread::scope(|scope| {
+        let (sender, receiver) = mpsc::channel();
+        for _ in 0..threads {
+            let sender = sender.clone();
+            let next_job = &next_job;
+            scope.spawn(move || {
+                let backend = match create_backend() {
+                    Ok(backend) => backend,
+                    Err(e) => {
+                        log::warn!("Failed to create a render thread backend ({:#})", e);
+                        return;
+                    }
+                };
+                loop {
+                    let index = next_job.fetch_add(1, Ordering::SeqCst);
+                    let job = match jobs.get(index) {
+                        Some(job) => job,
+                        None => break,
+                    };
+                    let _log_context = job.log_context();
+                    let data = backend.render_from_string(&job.code, &job.format);
+                    if sender.send((job, data)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        // The renderer is not thread safe, so the images are saved by this thread
+        for (job, data) in receiver {
+            let _log_context = job.log_context();
+            let saved = data
+                .and_then(|data| renderer.save_prerendered(&job.image_file, &job.format, &data));
+            if let Err(e) = saved {
+                log::debug!(
+                    "Failed to render {:?} in parallel, rendering it with its chapter ({:#})",
+                    job.image_file,
+                    e
+                );
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Capabilities;
+    use crate::log_context::log_field_value;
+    use mdbook::book::Chapter;
+    use pretty_assertions::assert_eq;
+    use std::fs;
+    use tempfile::tempdir;
+
+    struct EchoBackend {
+        /// Resolves the local includes (like the PlantUML shell)
+        includes: bool,
+    }
+
+    impl Backend for EchoBackend {
+        fn render_from_string(&self, plantuml_code: &str, _image_format: &str) -> Result<Vec<u8>> {
+            if plantuml_code.contains("fail") {
+                anyhow::bail!("Oh no");
+            }
+            Ok(format!("<svg>{plantuml_code}</svg>").into_bytes())
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities {
+                formats: &["svg"],
+                batching: false,
+                includes: self.includes,
+                concurrency_safe: true,
+            }
+        }
+    }
+
+    #[test]
+    fn test_prerender() {
+        let img_dir = tempdir().unwrap();
+        let cfg = Config::default();
+        let renderer = Renderer::new(
+            &cfg,
+            Some(Box::new(EchoBackend { includes: true })),
+            img_dir.path().into(),
+        );
+
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Intro",
+            String::from(
+                "```plantuml\nA --|> B\n```\n```plantuml\nB --|> C\n```\n\
+                 ```plantuml\nA --|> B\n```\n```plantuml\n!include foo.iuml\n```\n\
+                 ```plantuml\nfail\n```\n",
+            ),
+            PathBuf::from("intro.md"),
+            vec![],
+        ));
+
+        // Duplicates and diagrams with PlantUML includes are skipped
+        let jobs = prerender_jobs(
+            &book,
+            Path::new("src"),
+            &renderer,
+            &cfg,
+            &DiagramMarkings::none(),
+        );
+        assert_eq!(
+            vec!["A --|> B\n", "B --|> C\n", "fail\n"],
+            jobs.iter().map(|job| job.code.as_str()).collect::<Vec<_>>()
+        );
+
+        prerender(&renderer, &jobs, 2, &|| {
+            Ok(Box::new(EchoBackend { includes: true }))
+        });
+        assert_eq!(
+            "<svg>A --|> B\n</svg>",
+            fs::read_to_string(&jobs[0].image_file).unwrap()
+        );
+        assert_eq!(
+            "<svg>B --|> C\n</svg>",
+            fs::read_to_string(&jobs[1].image_file).unwrap()
+        );
+        assert!(!jobs[2].image_file.exists());
+
+        // Cached diagrams are not rendered again
+        assert_eq!(
+            1,
+            prerender_jobs(
+                &book,
+                Path::new("src"),
+                &renderer,
+                &cfg,
+                &DiagramMarkings::none(),
+            )
+            .len()
+        );
+    }
+
+    /// Renders the log context of the render thread
+    struct LogContextBackend;
+
+    impl Backend for LogContextBackend {
+        fn render_from_string(&self, _plantuml_code: &str, _image_format: &str) -> Result<Vec<u8>> {
+            let field = |key| log_field_value(key).unwrap_or_default();
+            Ok(format!("<svg>{} {}</svg>", field("chapter"), field("block")).into_bytes())
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            EchoBackend { includes: true }.capabilities()
+        }
+    }
+
+    #[test]
+    fn test_prerender_log_context() {
+        let img_dir = tempdir().unwrap();
+        let cfg = Config::default();
+        let renderer = Renderer::new(
+            &cfg,
+            Some(Box::new(EchoBackend { includes: true })),
+            img_dir.path().into(),
+        );
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Intro",
+            String::from("```plantuml\nA --|> B\n```\n```plantuml\nB --|> C\n```\n"),
+            PathBuf::from("ch1/intro.md"),
+            vec![],
+        ));
+
+        // The render threads log with the diagram's chapter and block
+        let jobs = prerender_jobs(
+            &book,
+            Path::new("src"),
+            &renderer,
+            &cfg,
+            &DiagramMarkings::none(),
+        );
+        prerender(&renderer, &jobs, 2, &|| Ok(Box::new(LogContextBackend)));
+        assert_eq!(
+            "<svg>ch1/intro.md 2</svg>",
+            fs::read_to_string(&jobs[1].image_file).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_prerender_jobs_format() {
+        let img_dir = tempdir().unwrap();
+        let cfg = Config::default();
+        let renderer = Renderer::new(
+            &cfg,
+            Some(Box::new(EchoBackend { includes: true })),
+            img_dir.path().into(),
+        );
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Intro",
+            String::from(
+                "```plantuml,format=txt\r\n@startgantt\r\n[Task] lasts 1 day\r\n@endgantt\r\n```\r\n",
+            ),
+            PathBuf::from("intro.md"),
+            vec![],
+        ));
+
+        // Gantt diagrams have no text output, these are rendered as svg (from the normalized
+        // source) like the chapter processing does
+        let jobs = prerender_jobs(
+            &book,
+            Path::new("src"),
+            &renderer,
+            &cfg,
+            &DiagramMarkings::none(),
+        );
+        assert_eq!(1, jobs.len());
+        assert_eq!("svg", jobs[0].format);
+        assert_eq!("@startgantt\n[Task] lasts 1 day\n@endgantt\n", jobs[0].code);
+        assert_eq!(
+            renderer.image_file(&jobs[0].code, Path::new("src"), "txt"),
+            jobs[0].image_file
+        );
+        assert_eq!(
+            Some("svg"),
+            jobs[0].image_file.extension().and_then(|ext| ext.to_str())
+        );
+    }
+
+    #[test]
+    fn test_prerender_jobs_capabilities() {
+        let img_dir = tempdir().unwrap();
+        let cfg = Config::default();
+        let mut book = Book::new();
+        book.push_item(Chapter::new(
+            "Intro",
+            String::from(
+                "```plantuml\n!include foo.iuml\n```\n```plantuml,format=png\nA --|> B\n```\n",
+            ),
+            PathBuf::from("intro.md"),
+            vec![],
+        ));
+
+        // The includes do not depend on the working dir, the png format is not supported
+        let renderer = Renderer::new(
+            &cfg,
+            Some(Box::new(EchoBackend { includes: false })),
+            img_dir.path().into(),
+        );
+        let jobs = prerender_jobs(
+            &book,
+            Path::new("src"),
+            &renderer,
+            &cfg,
+            &DiagramMarkings::none(),
+        );
+        assert_eq!(
+            vec!["!include foo.iuml\n"],
+            jobs.iter().map(|job| job.code.as_str()).collect::<Vec<_>>()
+        );
+
+        // Nothing to prerender without a backend
+        let renderer = Renderer::new(&cfg, None, img_dir.path().into());
+        assert!(prerender_jobs(
+            &book,
+            Path::new("src"),
+            &renderer,
+            &cfg,
+            &DiagramMarkings::none()
+        )
+        .is_empty());
+    }
+}
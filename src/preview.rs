@@ -0,0 +1,318 @@
+use crate::render_source::SourceRenderer;
+use crate::renderer::image_extension;
+use anyhow::{bail, format_err, Context, Result};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::path::Path;
+use std::time::Duration;
+
+/// The maximum size of a request body (the PlantUML source)
+const MAX_BODY_BYTES: usize = 10 * 1024 * 1024;
+
+/// The maximum size of the request line and headers
+const MAX_HEADER_BYTES: usize = 16 * 1024;
+
+/// The time a client gets to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A parsed HTTP request
+#[derive(Debug, PartialEq, Eq)]
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    /// The Host header, if any
+    host: Option<String>,
+    /// The Origin header (sent by browsers for cross-origin requests), if any
+    origin: Option<String>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    /// Get the value of a query parameter
+    fn query_param(&self, name: &str) -> Option<&str> {
+        self.query
+            .split('&')
+            .filter_map(|param| param.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Returns true if the request is addressed to the preview server on localhost and, when it
+    /// comes from a browser, by a page served from there. This keeps other web pages (e.g. using
+    /// DNS rebinding) from rendering diagrams, and reading their includes.
+    fn is_local(&self, port: u16) -> bool {
+        let local_hosts = [format!("localhost:{port}"), format!("127.0.0.1:{port}")];
+        let is_local_host = |host: &str| {
+            local_hosts
+                .iter()
+                .any(|local_host| host.eq_ignore_ascii_case(local_host))
+        };
+
+        let host_ok = self.host.as_deref().map_or(false, is_local_host);
+        let origin_ok = match &self.origin {
+            Some(origin) => origin.strip_prefix("http://").map_or(false, is_local_host),
+            None => true,
+        };
+        host_ok && origin_ok
+    }
+}
+
+/// An HTTP response
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn text(status: &'static str, text: &str) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: text.as_bytes().to_vec(),
+        }
+    }
+}
+
+/// Serve the preview endpoint on localhost until the process is stopped. Rendering
+/// a diagram is done by POSTing its source to /render (optionally with a format
+/// query parameter, e.g. /render?format=png), the response is the rendered image.
+/// # Arguments
+/// * `book_dir` - The book's root directory (its book.toml configures the backend)
+/// * `port` - The port to listen on
+/// * `revalidate` - Re-run the PlantUML command detection
+pub fn preview(book_dir: &Path, port: u16, revalidate: bool) -> Result<()> {
+    let renderer = SourceRenderer::new(book_dir, revalidate)?.with_cache(book_dir)?;
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+        .with_context(|| format!("Failed to listen on port {port}"))?;
+    log::info!(
+        "Serving PlantUML previews on http://{}/render",
+        listener.local_addr()?
+    );
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if let Err(e) = handle_connection(&renderer, stream) {
+                    log::warn!("Failed to handle the preview request ({:#})", e);
+                }
+            }
+            Err(e) => log::warn!("Failed to accept the preview connection ({})", e),
+        }
+    }
+
+    Ok(())
+}
+
+fn handle_connection(renderer: &SourceRenderer, mut stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let port = stream.local_addr()?.port();
+    let response = match read_request(&mut BufReader::new(&stream)) {
+        Ok(request) if !request.is_local(port) => Response::text(
+            "403 Forbidden",
+            "Only requests to (and from pages on) the local preview server are served",
+        ),
+        Ok(request) => respond(renderer, &request),
+        Err(e) => Response::text("400 Bad Request", &format!("{e:#}")),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    )?;
+    stream.write_all(&response.body)?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn respond(renderer: &SourceRenderer, request: &Request) -> Response {
+    if request.path != "/render" {
+        return Response::text("404 Not Found", "Unknown path, POST the diagram to /render");
+    }
+    if request.method != "POST" {
+        return Response::text("405 Method Not Allowed", "POST the diagram to /render");
+    }
+
+    let plantuml_code = match std::str::from_utf8(&request.body) {
+        Ok(plantuml_code) => plantuml_code,
+        Err(_) => return Response::text("400 Bad Request", "The diagram is not valid UTF-8"),
+    };
+    let image_format = request.query_param("format").unwrap_or("svg");
+    match renderer.render(plantuml_code, image_format) {
+        Ok(data) => Response {
+            status: "200 OK",
+            content_type: content_type(image_extension(plantuml_code, image_format)),
+            body: data,
+        },
+        Err(e) => Response::text("500 Internal Server Error", &format!("{e:#}")),
+    }
+}
+
+/// Read the request line, headers and body (sized by the Content-Length header)
+fn read_request(reader: &mut impl BufRead) -> Result<Request> {
+    let mut header_bytes_left = MAX_HEADER_BYTES;
+    let request_line = read_header_line(reader, &mut header_bytes_left)?;
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target),
+        _ => bail!("Invalid request line {:?}", request_line.trim_end()),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let mut content_length = 0;
+    let mut host = None;
+    let mut origin = None;
+    loop {
+        let header = read_header_line(reader, &mut header_bytes_left)?;
+        if header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            let name = name.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| format_err!("Invalid Content-Length {:?}", value.trim()))?;
+            } else if name.eq_ignore_ascii_case("host") {
+                host = Some(value.trim().to_string());
+            } else if name.eq_ignore_ascii_case("origin") {
+                origin = Some(value.trim().to_string());
+            }
+        }
+    }
+    if content_length > MAX_BODY_BYTES {
+        bail!(
+            "The request body ({} bytes) exceeds the maximum of {} bytes",
+            content_length,
+            MAX_BODY_BYTES
+        );
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    Ok(Request {
+        method,
+        path: path.to_string(),
+        query: query.to_string(),
+        host,
+        origin,
+        body,
+    })
+}
+
+/// Read a line of the request head, fails when the head exceeds MAX_HEADER_BYTES (the number of
+/// bytes left is decremented)
+fn read_header_line(reader: &mut impl BufRead, bytes_left: &mut usize) -> Result<String> {
+    let mut line = String::new();
+    let read = reader
+        .by_ref()
+        .take(*bytes_left as u64)
+        .read_line(&mut line)?;
+    *bytes_left -= read;
+    if *bytes_left == 0 && !line.ends_with('\n') {
+        bail!(
+            "The request headers exceed the maximum of {} bytes",
+            MAX_HEADER_BYTES
+        );
+    }
+
+    Ok(line)
+}
+
+/// The content type of an image with the given extension
+fn content_type(extension: &str) -> &'static str {
+    match extension {
+        "svg" => "image/svg+xml",
+        "png" | "braille.png" => "image/png",
+        "atxt" | "utxt" | "txt" => "text/plain; charset=utf-8",
+        "eps" => "application/postscript",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_read_request() {
+        let mut data = Cursor::new(
+            "POST /render?format=png HTTP/1.1\r\nHost: localhost\r\ncontent-length: 8\r\n\r\nA --|> B",
+        );
+        let request = read_request(&mut data).unwrap();
+        assert_eq!(
+            Request {
+                method: String::from("POST"),
+                path: String::from("/render"),
+                query: String::from("format=png"),
+                host: Some(String::from("localhost")),
+                origin: None,
+                body: b"A --|> B".to_vec(),
+            },
+            request
+        );
+        assert_eq!(Some("png"), request.query_param("format"));
+        assert_eq!(None, request.query_param("theme"));
+
+        // Without a body
+        let request = read_request(&mut Cursor::new("GET /render HTTP/1.1\r\n\r\n")).unwrap();
+        assert_eq!("", request.query);
+        assert!(request.body.is_empty());
+
+        assert!(read_request(&mut Cursor::new("\r\n")).is_err());
+        assert!(read_request(&mut Cursor::new(
+            "POST /render HTTP/1.1\r\nContent-Length: many\r\n\r\n"
+        ))
+        .is_err());
+        // The body is shorter than the Content-Length
+        assert!(read_request(&mut Cursor::new(
+            "POST /render HTTP/1.1\r\nContent-Length: 10\r\n\r\nA"
+        ))
+        .is_err());
+        // The headers are too large
+        let large_header = format!("X-Large: {}\r\n", "A".repeat(MAX_HEADER_BYTES));
+        let error = read_request(&mut Cursor::new(format!(
+            "POST /render HTTP/1.1\r\n{large_header}\r\n"
+        )))
+        .unwrap_err();
+        assert!(format!("{error}").contains("exceed the maximum"));
+    }
+
+    #[test]
+    fn test_is_local() {
+        let request = |host: Option<&str>, origin: Option<&str>| Request {
+            method: String::from("POST"),
+            path: String::from("/render"),
+            query: String::new(),
+            host: host.map(String::from),
+            origin: origin.map(String::from),
+            body: vec![],
+        };
+        assert!(request(Some("localhost:8080"), None).is_local(8080));
+        assert!(request(Some("127.0.0.1:8080"), Some("http://localhost:8080")).is_local(8080));
+        assert!(!request(None, None).is_local(8080));
+        assert!(!request(Some("localhost:9090"), None).is_local(8080));
+        // DNS rebinding, the host name resolves to 127.0.0.1
+        assert!(!request(Some("evil.example.com:8080"), None).is_local(8080));
+        // Cross-origin requests from other pages
+        assert!(!request(Some("localhost:8080"), Some("https://evil.example.com")).is_local(8080));
+        assert!(!request(Some("localhost:8080"), Some("null")).is_local(8080));
+    }
+
+    #[test]
+    fn test_content_type() {
+        assert_eq!("image/svg+xml", content_type("svg"));
+        assert_eq!("image/png", content_type("braille.png"));
+        assert_eq!("text/plain; charset=utf-8", content_type("atxt"));
+        assert_eq!("application/octet-stream", content_type("vdx"));
+    }
+}
@@ -0,0 +1,278 @@
+use crate::backend::factory::tool_version;
+use crate::backend::{self, Backend};
+use crate::config::Config;
+use crate::failure::{tag, FailureKind};
+use crate::fingerprint::RenderFingerprint;
+use crate::include_cache::IncludeCache;
+use crate::markings::DiagramMarkings;
+use crate::pipeline::{diagram_source, Diagram, DiagramChapter};
+use crate::renderer::{
+    image_extension, image_path, is_cached, normalize_source, prepare_image_data, sign_cached_image,
+};
+use crate::CACHE_DIR;
+use crate::{config_from_book_config, resolve_config_paths};
+use anyhow::{ensure, format_err, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Renders single PlantUML diagrams using the backend configured for the book
+/// (e.g. for editor previews).
+pub struct SourceRenderer {
+    cfg: Config,
+    backend: Box<dyn Backend>,
+    /// The default font, header, footer and ditaa options added to the diagrams (the {chapter}
+    /// placeholder is empty)
+    markings: DiagramMarkings,
+    /// The cache dir for the rendered images, None to always render
+    cache_dir: Option<PathBuf>,
+    signing_key: Option<Vec<u8>>,
+}
+
+impl SourceRenderer {
+    /// # Arguments
+    /// * `book_dir` - The book's root directory, the default configuration is used
+    ///   when it does not contain a book.toml
+    /// * `revalidate` - Re-run the PlantUML command detection
+    pub fn new(book_dir: &Path, revalidate: bool) -> Result<Self> {
+        let book_toml = book_dir.join("book.toml");
+        let (mut cfg, book_title) = if book_toml.is_file() {
+            mdbook::Config::from_disk(&book_toml)
+                .map_err(|e| format_err!("{:#}", e))
+                .and_then(|book_cfg| {
+                    let cfg = config_from_book_config(&book_cfg)?;
+                    Ok((cfg, book_cfg.book.title))
+                })
+                .map_err(tag(FailureKind::Config))?
+        } else {
+            (Config::default(), None)
+        };
+        cfg.revalidate = revalidate;
+        let book_root = dunce::canonicalize(book_dir)?;
+        resolve_config_paths(&mut cfg, &book_root);
+
+        let backend = backend::factory::create(&cfg, &book_root, &book_root.join(CACHE_DIR))
+            .map_err(tag(FailureKind::BackendNotFound))?;
+        let markings = DiagramMarkings::from_config(&cfg, book_title.as_deref()).for_chapter("");
+
+        Ok(Self {
+            cfg,
+            backend,
+            markings,
+            cache_dir: None,
+            signing_key: None,
+        })
+    }
+
+    /// Cache the rendered images in the book's cache dir (.mdbook-plantuml-cache),
+    /// signed when a cache signing key is configured.
+    /// # Arguments
+    /// * `book_dir` - The book's root directory
+    pub fn with_cache(self, book_dir: &Path) -> Result<Self> {
+        let cache_dir = dunce::canonicalize(book_dir)?.join(CACHE_DIR);
+        fs::create_dir_all(&cache_dir)
+            .with_context(|| format!("Failed to create the cache dir {cache_dir:?}"))?;
+        let signing_key = self
+            .cfg
+            .cache_signing_key(&|name| std::env::var(name).ok())
+            .map_err(tag(FailureKind::Config))?;
+
+        Ok(Self {
+            cache_dir: Some(cache_dir),
+            signing_key,
+            ..self
+        })
+    }
+
+    /// Render the diagram, returns the image data
+    /// # Arguments
+    /// * `plantuml_code` - The PlantUML source
+    /// * `image_format` - The image format (e.g. "svg")
+    pub fn render(&self, plantuml_code: &str, image_format: &str) -> Result<Vec<u8>> {
+        // Prepared like a code block in a chapter in the working dir
+        let include_dir = std::env::current_dir()?;
+        let diagram = Diagram {
+            code: plantuml_code.to_string(),
+            format: image_format.to_string(),
+            id: None,
+            alt_text: None,
+            data_kind: None,
+        };
+        let chapter = DiagramChapter {
+            include_dir: &include_dir,
+            markings: &self.markings,
+        };
+        let plantuml_code = diagram_source(&chapter, &diagram, &self.cfg)?;
+        let plantuml_code = if self.cfg.normalize_source {
+            normalize_source(&plantuml_code)
+        } else {
+            plantuml_code.as_str().into()
+        };
+        if let Some(max_bytes) = self.cfg.max_diagram_bytes {
+            ensure!(
+                plantuml_code.len() <= max_bytes,
+                "The diagram source ({} bytes) exceeds max-diagram-bytes ({} bytes)",
+                plantuml_code.len(),
+                max_bytes
+            );
+        }
+
+        let cached_image = match &self.cache_dir {
+            Some(cache_dir) => {
+                let include_cache = IncludeCache::new(
+                    self.cfg.book_root.as_deref().unwrap_or(cache_dir),
+                    &self.cfg.hash_ignore,
+                    None,
+                );
+                let hash = RenderFingerprint::new(&plantuml_code)
+                    .with_includes(&include_dir, &include_cache)
+                    .with_format(image_format)
+                    .with_backend(self.cfg.cache_backend_key().as_deref())
+                    .with_tool_version(tool_version(&self.cfg, cache_dir).as_deref())
+                    .with_options(self.cfg.cache_options_key().as_deref())
+                    .hash();
+                let cached_image = image_path(cache_dir, &hash, &plantuml_code, image_format);
+                if is_cached(&cached_image, self.signing_key.as_deref()) {
+                    log::debug!("Using cached image {:?}", cached_image);
+                    return fs::read(&cached_image).with_context(|| {
+                        format!("Failed to read the cached image {cached_image:?}")
+                    });
+                }
+                Some(cached_image)
+            }
+            None => None,
+        };
+
+        // Saved (and served) like the images of the chapter processing
+        let image_file = cached_image.clone().unwrap_or_else(|| {
+            let extension = image_extension(&plantuml_code, image_format);
+            PathBuf::from(format!("diagram.{extension}"))
+        });
+        let data = self
+            .backend
+            .render_from_string(&plantuml_code, image_format)
+            .and_then(|data| {
+                prepare_image_data(&data, &image_file, self.cfg.stable_svg)
+                    .map(|data| data.into_owned())
+            })
+            .map_err(tag(FailureKind::Render))?;
+
+        if let Some(cached_image) = cached_image {
+            fs::write(&cached_image, &data)
+                .with_context(|| format!("Failed to save the image to {cached_image:?}"))?;
+            if let Some(signing_key) = &self.signing_key {
+                sign_cached_image(&cached_image, signing_key)?;
+            }
+        }
+
+        Ok(data)
+    }
+}
+
+/// Render a single PlantUML diagram using the backend configured for the book
+/// (e.g. for editor previews). Returns the image data.
+/// # Arguments
+/// * `book_dir` - The book's root directory, the default configuration is used
+///   when it does not contain a book.toml
+/// * `plantuml_code` - The PlantUML source
+/// * `image_format` - The image format (e.g. "svg")
+/// * `revalidate` - Re-run the PlantUML command detection
+pub fn render_source(
+    book_dir: &Path,
+    plantuml_code: &str,
+    image_format: &str,
+    revalidate: bool,
+) -> Result<Vec<u8>> {
+    SourceRenderer::new(book_dir, revalidate)?.render(plantuml_code, image_format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn book_with_options(options: &str) -> tempfile::TempDir {
+        let book_dir = tempdir().unwrap();
+        fs::write(
+            book_dir.path().join("book.toml"),
+            format!("[book]\ntitle = \"Test\"\n\n[preprocessor.plantuml]\n{options}\n"),
+        )
+        .unwrap();
+        book_dir
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_render_source() {
+        // A fake PlantUML, echoing the (piped) source
+        let tools_dir = tempdir().unwrap();
+        let script = tools_dir.path().join("plantuml.sh");
+        fs::write(
+            &script,
+            "if [ \"$1\" = \"-version\" ]; then echo 'PlantUML version 1.0'; else cat; fi\n",
+        )
+        .unwrap();
+        let book_dir = book_with_options(&format!("plantuml-cmd = \"sh {}\"", script.display()));
+
+        assert_eq!(
+            b"<svg>A</svg>\n".to_vec(),
+            render_source(book_dir.path(), "\u{feff}<svg>A</svg>\r\n", "svg", true).unwrap()
+        );
+
+        // The output is validated
+        let error = render_source(book_dir.path(), "A --|> B", "svg", true).unwrap_err();
+        assert_eq!(FailureKind::Render, FailureKind::of(&error));
+
+        // The markings are added and the image is made stable like in the chapter processing
+        let marked_book_dir = book_with_options(&format!(
+            "plantuml-cmd = \"sh {}\"\nfooter = \"CONFIDENTIAL\"\nstable-svg = true",
+            script.display()
+        ));
+        let data = render_source(marked_book_dir.path(), "<svg><!--x--></svg>", "svg", true);
+        let data = String::from_utf8(data.unwrap()).unwrap();
+        assert!(data.contains("footer CONFIDENTIAL"));
+        assert!(!data.contains("<!--x-->"));
+
+        // Cached renders are served from the cache, even when PlantUML breaks
+        let renderer = SourceRenderer::new(book_dir.path(), true)
+            .and_then(|renderer| renderer.with_cache(book_dir.path()))
+            .unwrap();
+        assert_eq!(
+            b"<svg>B</svg>".to_vec(),
+            renderer.render("<svg>B</svg>", "svg").unwrap()
+        );
+        fs::write(&script, "exit 1\n").unwrap();
+        assert_eq!(
+            b"<svg>B</svg>".to_vec(),
+            renderer.render("<svg>B</svg>", "svg").unwrap()
+        );
+        assert!(renderer.render("<svg>C</svg>", "svg").is_err());
+    }
+
+    #[cfg(any(feature = "plantuml-ssl-server", feature = "plantuml-server"))]
+    #[test]
+    fn test_render_source_restricted_includes() {
+        // The includes are checked before the (unreachable) server is used
+        let book_dir = book_with_options(
+            "restrict-includes-to-book = true\nplantuml-cmd = \"http://localhost:1/plantuml\"",
+        );
+        let error =
+            render_source(book_dir.path(), "!include /etc/passwd", "svg", false).unwrap_err();
+        assert!(format!("{error}").contains("restrict-includes-to-book is enabled"));
+    }
+
+    #[cfg(any(feature = "plantuml-ssl-server", feature = "plantuml-server"))]
+    #[test]
+    fn test_render_source_limits() {
+        // The limit is checked before the (unreachable) server is used
+        let book_dir = book_with_options(
+            "max-diagram-bytes = 4\nplantuml-cmd = \"http://localhost:1/plantuml\"",
+        );
+        let error = render_source(book_dir.path(), "A --|> B", "svg", false).unwrap_err();
+        assert_eq!(
+            "The diagram source (8 bytes) exceeds max-diagram-bytes (4 bytes)",
+            format!("{error}")
+        );
+    }
+}
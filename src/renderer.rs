@@ -1,11 +1,28 @@
-use crate::backend::{self, Backend};
-use crate::config::Config;
+use crate::backend::error::BackendError;
+use crate::backend::factory::unsupported_server;
+use crate::backend::shell::split_shell_command;
+use crate::backend::{Backend, Capabilities};
+use crate::config::{BudgetExceeded, Config, ImageMarkup, DEFAULT_LIMIT_SIZE};
+use crate::diagram_kind::DiagramKind;
 use crate::dir_cleaner::DirCleaner;
-use anyhow::{Context, Result};
-use base64::encode;
+use crate::figures::Figure;
+use crate::fingerprint::RenderFingerprint;
+use crate::glossary::diagram_elements;
+use crate::include_cache::IncludeCache;
+use crate::log_context::log_field;
+use crate::long_path::long_path;
+use crate::signing::{sign, verify, SIGNATURE_EXTENSION};
+use crate::stable_svg::stable_svg;
+use anyhow::{bail, Context, Result};
+use base64::engine::DEFAULT_ENGINE;
+use base64::write::EncoderStringWriter;
 use sha1::{Digest, Sha1};
+use std::borrow::Cow;
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fs;
+use std::process::Command;
+use std::time::{Duration, Instant};
 
 use std::path::{Path, PathBuf};
 
@@ -13,353 +30,2664 @@ pub trait RendererTrait {
     fn render(
         &self,
         plantuml_code: &str,
+        include_dir: &Path,
         rel_img_url: &str,
-        image_format: String,
+        options: BlockOptions,
     ) -> Result<String>;
 }
 
+/// The options of a diagram's code block (from its info string)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BlockOptions<'a> {
+    /// The image format
+    pub image_format: &'a str,
+    /// The image's alt text, when None the diagram's title is used
+    pub alt_text: Option<&'a str>,
+    /// The diagram's id, used for the diagram's anchor
+    pub id: Option<&'a str>,
+    /// How the image is emitted, when None the image-markup option is used
+    pub markup: Option<ImageMarkup>,
+}
+
 /// Create the image names with the appropriate extension and path
-/// The base name of the file is a SHA1 of the code block to avoid collisions
-/// with existing and as a bonus prevent duplicate files.
-pub fn image_filename(img_root: &Path, plantuml_code: &str, image_format: &str) -> PathBuf {
+/// The base name of the file is the diagram's hash (see RenderFingerprint) to avoid
+/// collisions with existing and as a bonus prevent duplicate files.
+pub(crate) fn image_path(
+    img_root: &Path,
+    hash: &str,
+    plantuml_code: &str,
+    image_format: &str,
+) -> PathBuf {
+    let mut output_file = img_root.join(hash);
+    output_file.set_extension(image_extension(plantuml_code, image_format));
+
+    long_path(output_file)
+}
+
+/// The file extension of the image PlantUML generates for the given code and format
+pub fn image_extension<'a>(plantuml_code: &str, image_format: &'a str) -> &'a str {
     // See https://plantuml.com/command-line "Types of output files" for additional info
-    let extension = {
-        if plantuml_code.contains("@startditaa") {
-            // ditaa only has png format support afaik
-            "png"
-        } else if image_format.is_empty() {
-            "svg"
-        } else if image_format == "txt" {
-            // -ttxt outputs an .atxt file
-            "atxt"
-        } else if image_format == "braille" {
-            // -tbraille outputs a .braille.png file
-            "braille.png"
-        } else {
-            image_format
+    if let Some(forced_format) = DiagramKind::detect(plantuml_code).forced_format() {
+        forced_format
+    } else if image_format.is_empty() {
+        "svg"
+    } else if image_format == "txt" {
+        // -ttxt outputs an .atxt file
+        "atxt"
+    } else if image_format == "braille" {
+        // -tbraille outputs a .braille.png file
+        "braille.png"
+    } else {
+        image_format
+    }
+}
+
+/// Guess the image format from the image data's header, only the formats that
+/// are validated are detected.
+fn detect_image_format(data: &[u8]) -> Option<&'static str> {
+    const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+    let data = data.strip_prefix(UTF8_BOM).unwrap_or(data);
+    let first_non_whitespace = data
+        .iter()
+        .position(|c| !c.is_ascii_whitespace())
+        .unwrap_or(data.len());
+    let text = &data[first_non_whitespace..];
+
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("png")
+    } else if text.starts_with(b"<?xml") || text.starts_with(b"<svg") {
+        Some("svg")
+    } else if data.starts_with(b"%!PS") {
+        Some("eps")
+    } else if data.starts_with(b"%PDF") {
+        Some("pdf")
+    } else {
+        None
+    }
+}
+
+/// The signature file of a signed cached image (the image path with .sig appended)
+fn signature_file(image_path: &Path) -> PathBuf {
+    let mut signature_file = image_path.as_os_str().to_owned();
+    signature_file.push(".");
+    signature_file.push(SIGNATURE_EXTENSION);
+    PathBuf::from(signature_file)
+}
+
+/// Returns true if the cached image file is empty (e.g. written by an older version, or truncated
+/// by a full disk), only its size is checked
+pub(crate) fn is_empty_cache_entry(output_file: &Path) -> bool {
+    fs::metadata(output_file).map_or(false, |metadata| metadata.len() == 0)
+}
+
+/// Returns true if the image file is cached, when a signing key is given the image
+/// is only used when its signature is valid.
+pub(crate) fn is_cached(output_file: &Path, signing_key: Option<&[u8]>) -> bool {
+    if !output_file.exists() {
+        return false;
+    }
+
+    let signing_key = match signing_key {
+        Some(signing_key) => signing_key,
+        None => return true,
+    };
+
+    let signed = match (
+        fs::read(output_file),
+        fs::read_to_string(signature_file(output_file)),
+    ) {
+        (Ok(data), Ok(signature)) => {
+            verify(signing_key, &[file_name(output_file), &data], &signature)
+        }
+        _ => false,
+    };
+    if !signed {
+        log::warn!(
+            "The cached image {:?} has no valid signature, ignoring it",
+            output_file
+        );
+    }
+
+    signed
+}
+
+/// Write the signature file for the cached image
+pub(crate) fn sign_cached_image(output_file: &Path, signing_key: &[u8]) -> Result<()> {
+    let data = fs::read(output_file)?;
+    let signature = sign(signing_key, &[file_name(output_file), &data]);
+    fs::write(signature_file(output_file), signature)?;
+    Ok(())
+}
+
+/// The file name bytes (signed with the image data, so signed images cannot be swapped)
+fn file_name(path: &Path) -> &[u8] {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default()
+        .as_bytes()
+}
+
+/// An HTML comment block with the PlantUML source, the "-->" arrows are escaped
+/// so they do not end the comment.
+fn source_comment(plantuml_code: &str) -> String {
+    let source = plantuml_code.trim_end().replace("-->", "--&gt;");
+    format!("\n<!-- PlantUML source\n{source}\n-->\n\n")
+}
+
+/// A backtick code fence that is wider than any backtick run in the content, so
+/// backticks in the (ASCII art) content cannot close the fence prematurely.
+fn code_fence(content: &str) -> String {
+    let longest_run = content.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    "`".repeat(std::cmp::max(3, longest_run + 1))
+}
+
+/// Strip the BOM and convert the CRLF line endings to LF
+pub fn normalize_source(plantuml_code: &str) -> Cow<'_, str> {
+    let code = plantuml_code
+        .strip_prefix('\u{feff}')
+        .unwrap_or(plantuml_code);
+    if code.contains('\r') {
+        Cow::Owned(code.replace("\r\n", "\n"))
+    } else {
+        Cow::Borrowed(code)
+    }
+}
+
+/// Check the rendered image data matches the image file's format before it is
+/// cached, so a corrupt image is not served from the cache forever.
+/// Formats without a recognizable header (e.g. txt) are not validated.
+pub fn validate_image_data(data: &[u8], image_path: &Path) -> Result<()> {
+    let expected = match image_path.extension().and_then(|e| e.to_str()) {
+        Some(ext @ ("svg" | "png" | "eps" | "pdf")) => ext,
+        _ => return Ok(()),
+    };
+
+    let detected = detect_image_format(data);
+    if detected == Some(expected) {
+        return Ok(());
+    }
+
+    let preview_len = data.len().min(32);
+    let preview = String::from_utf8_lossy(&data[..preview_len]);
+    match detected {
+        Some(detected) => bail!(
+            "PlantUML rendered a {} image, but a {} image was requested.",
+            detected,
+            expected
+        ),
+        None if data.is_empty() => bail!(
+            "PlantUML rendered an empty image, but a {} image was requested.",
+            expected
+        ),
+        None => bail!(
+            "PlantUML output is not a valid {} image (starts with {:?}).",
+            expected,
+            preview
+        ),
+    }
+}
+
+/// The rendered image as it is cached, validated (see validate_image_data and suspicious_image)
+/// and, with stable-svg, SVG images made diff friendly (see stable_svg). The images are only
+/// validated here, so suspicious images are never cached and a cache hit does not read the image.
+/// # Arguments
+/// * `data` - The rendered image
+/// * `image_path` - The image's (cache) file, its extension is the image format
+/// * `make_stable` - Apply stable-svg
+pub fn prepare_image_data<'a>(
+    data: &'a [u8],
+    image_path: &Path,
+    make_stable: bool,
+) -> Result<Cow<'a, [u8]>> {
+    validate_image_data(data, image_path)?;
+    if let Some(reason) = suspicious_image(data, image_path) {
+        bail!("PlantUML rendered a suspicious image ({})", reason);
+    }
+
+    Ok(match image_path.extension() {
+        Some(extension) if make_stable && extension == "svg" => {
+            Cow::Owned(stable_svg(&String::from_utf8_lossy(data)).into_bytes())
+        }
+        _ => Cow::Borrowed(data),
+    })
+}
+
+/// Check the rendered image for signs of a blank or truncated image (e.g. when a server
+/// truncates the response), valid images (see validate_image_data) can still be suspicious.
+/// Returns the reason when the image looks suspicious.
+pub fn suspicious_image(data: &[u8], image_path: &Path) -> Option<&'static str> {
+    match image_path.extension().and_then(|e| e.to_str()) {
+        Some("svg") => {
+            let svg = String::from_utf8_lossy(data);
+            let start = svg.find("<svg")?;
+            let tag_end = start + svg[start..].find('>')?;
+            let start_tag = &svg[start..tag_end];
+            let content_end = svg.rfind("</svg>").unwrap_or(svg.len());
+            if start_tag.ends_with('/')
+                || svg[tag_end + 1..content_end.max(tag_end + 1)]
+                    .trim()
+                    .is_empty()
+            {
+                Some("the SVG image has no content")
+            } else if is_one_pixel_svg(start_tag) {
+                Some("the image is 1x1 pixels")
+            } else {
+                None
+            }
+        }
+        Some("png") => {
+            const IEND_CHUNK: &[u8] = b"IEND\xAE\x42\x60\x82";
+            if !data.ends_with(IEND_CHUNK) {
+                Some("the PNG image is truncated")
+            } else if data.len() >= 24 && data[16..24] == [0, 0, 0, 1, 0, 0, 0, 1] {
+                // The IHDR chunk (the first chunk) holds the width and height
+                Some("the image is 1x1 pixels")
+            } else {
+                None
+            }
         }
+        _ => None,
+    }
+}
+
+/// Check whether PlantUML clipped the image at the limit size (PLANTUML_LIMIT_SIZE), i.e. the
+/// PNG image is as wide or as high as the limit. Returns the image's size when it is clipped.
+/// # Arguments
+/// * `data` - The rendered image
+/// * `limit_size` - The maximum image width and height in pixels
+pub fn truncated_size(data: &[u8], limit_size: u32) -> Option<(u32, u32)> {
+    if !data.starts_with(b"\x89PNG") || data.len() < 24 {
+        return None;
+    }
+
+    // The IHDR chunk (the first chunk) holds the width and height
+    let width = u32::from_be_bytes([data[16], data[17], data[18], data[19]]);
+    let height = u32::from_be_bytes([data[20], data[21], data[22], data[23]]);
+    if width >= limit_size || height >= limit_size {
+        Some((width, height))
+    } else {
+        None
+    }
+}
+
+/// Returns true if the SVG start tag has a 1x1 pixel size
+fn is_one_pixel_svg(start_tag: &str) -> bool {
+    let is_one_pixel = |attribute: &str| {
+        ["\"1\"", "\"1px\"", "'1'", "'1px'"]
+            .iter()
+            .any(|value| start_tag.contains(&format!(" {attribute}={value}")))
     };
-    let mut output_file = img_root.join(hash_string(plantuml_code));
-    output_file.set_extension(extension);
 
-    output_file
+    is_one_pixel("width") && is_one_pixel("height")
+}
+
+/// Save the image data to a temporary file, run the post processing command on
+/// it and move the result to the output file. This way only successfully post
+/// processed images are cached.
+fn save_post_processed(
+    cmd: &str,
+    data: &[u8],
+    output_file: &Path,
+    image_format: &str,
+    hash: &str,
+) -> Result<()> {
+    // Keep the extension, post processing tools tend to look at it
+    let suffix = output_file
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    let tmp_file = tempfile::Builder::new()
+        .prefix(".post-process-")
+        .suffix(&suffix)
+        .tempfile_in(output_file.parent().unwrap_or_else(|| Path::new(".")))?
+        .into_temp_path();
+    fs::write(&tmp_file, data)?;
+
+    post_process(cmd, &tmp_file, image_format, hash)?;
+
+    tmp_file.persist(output_file).with_context(|| {
+        format!(
+            "Failed to save PlantUML diagram to {}.",
+            output_file.to_string_lossy()
+        )
+    })?;
+    Ok(())
+}
+
+/// Run the post processing command on the given image file
+/// # Arguments
+/// * `cmd` - The command, the {file}, {format} and {hash} placeholders are
+///   replaced by the image file path, the image format and the code's hash.
+/// * `file` - The image file
+/// * `image_format` - The image format
+/// * `hash` - The hash of the PlantUML code
+fn post_process(cmd: &str, file: &Path, image_format: &str, hash: &str) -> Result<()> {
+    let file = file.to_string_lossy();
+    let cmd_parts: Vec<String> = split_shell_command(cmd)
+        .with_context(|| format!("Invalid post processing command '{cmd}'"))?
+        .iter()
+        .map(|part| {
+            part.replace("{file}", &file)
+                .replace("{format}", image_format)
+                .replace("{hash}", hash)
+        })
+        .collect();
+    if cmd_parts.is_empty() {
+        bail!("The post processing command is empty");
+    }
+
+    log::debug!("Post processing {} ({:?})", file, cmd_parts);
+    let output = Command::new(&cmd_parts[0])
+        .args(&cmd_parts[1..])
+        .output()
+        .with_context(|| format!("Failed to run post processing command '{cmd}'"))?;
+    if !output.status.success() {
+        bail!(
+            "Post processing command '{}' failed ({}): {}",
+            cmd,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
+
+/// Get the diagram's title from the PlantUML code, this is the (single line)
+/// `title` text, or the diagram name (`@startuml name`) when there is no title.
+pub fn diagram_title(plantuml_code: &str) -> Option<String> {
+    let mut name = None;
+    for line in plantuml_code.lines().map(str::trim) {
+        if let Some(title) = strip_keyword(line, "title") {
+            if !title.is_empty() {
+                return Some(title.to_string());
+            }
+        } else if line.starts_with("@start") && name.is_none() {
+            // @startuml name, or @startuml(id=name)
+            let diagram_name = line
+                .split_once(|c: char| c.is_whitespace() || c == '(')
+                .map(|(_, name)| name.trim_start_matches("id=").trim_end_matches(')').trim());
+            name = diagram_name.filter(|n| !n.is_empty()).map(String::from);
+        }
+    }
+
+    name
 }
 
-fn hash_string(code: &str) -> String {
+/// Strip the (case insensitive) keyword (followed by whitespace) from the line
+fn strip_keyword<'a>(line: &'a str, keyword: &str) -> Option<&'a str> {
+    let prefix = line.get(..keyword.len())?;
+    let rest = &line[keyword.len()..];
+    if prefix.eq_ignore_ascii_case(keyword) && rest.starts_with(char::is_whitespace) {
+        Some(rest.trim())
+    } else {
+        None
+    }
+}
+
+/// Make the diagram id safe for use as (part of) an HTML id
+fn anchor_id(id: &str) -> String {
+    id.chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+/// The markdown shown instead of a diagram that failed to render with a backend error, naming
+/// the error's category (e.g. a syntax error at line 3). None for errors without a category,
+/// their message is shown instead.
+pub fn failure_placeholder(
+    plantuml_code: &str,
+    alt_text: Option<&str>,
+    error: &anyhow::Error,
+) -> Option<String> {
+    let category = BackendError::of(error)?;
+    let alt_text = alt_text
+        .map(String::from)
+        .or_else(|| diagram_title(plantuml_code))
+        .unwrap_or_default();
+    Some(Renderer::create_placeholder(
+        &escape_alt_text(&alt_text),
+        &category.to_string(),
+    ))
+}
+
+/// Escape the alt text for use in a markdown image link
+fn escape_alt_text(alt_text: &str) -> String {
+    let mut escaped = String::with_capacity(alt_text.len());
+    for c in alt_text.chars() {
+        match c {
+            '\\' | '[' | ']' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\r' | '\n' => escaped.push(' '),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+pub fn hash_string(code: &str) -> String {
     let hash = Sha1::new_with_prefix(code).finalize();
     base16ct::lower::encode_string(&hash)
 }
 
+/// The title of braille images (shown as tooltip), so these are recognizable in the page
+const BRAILLE_TITLE: &str = "Braille rendering";
+
+/// The media type of the image file, e.g. image/svg+xml
+fn media_type(image_path: &Path) -> &'static str {
+    let file_name = image_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    let extension = file_name.rsplit('.').next().unwrap_or_default();
+    match extension {
+        // braille images (.braille.png) are png images too
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "svg" => "image/svg+xml",
+        "atxt" | "utxt" | "txt" => "text/plain",
+        "eps" => "application/postscript",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+/// The image format to render, the forced image format (if any) applies to all formats except
+/// the text formats, which are inlined as text
+pub fn render_format<'a>(forced_image_format: Option<&'a str>, image_format: &'a str) -> &'a str {
+    match forced_image_format {
+        Some(forced) if image_format != "txt" && image_format != "utxt" => forced,
+        _ => image_format,
+    }
+}
+
+/// The image format to render the diagram in, the text formats of diagram kinds without readable
+/// text output (see DiagramKind::supports_text) are replaced by svg
+pub fn text_fallback_format<'a>(plantuml_code: &str, image_format: &'a str) -> &'a str {
+    let is_text = image_format == "txt" || image_format == "utxt";
+    if is_text && !DiagramKind::detect(plantuml_code).supports_text() {
+        "svg"
+    } else {
+        image_format
+    }
+}
+
+/// Emits the markup of a rendered (non text) image in the chapter, see Config::image_markup
+pub trait OutputWriter {
+    /// The image's markup, followed by an empty line
+    /// # Arguments
+    /// * `src` - The image's url (a link to the image file, or a data URI)
+    /// * `alt_text` - The image's alt text (not escaped)
+    /// * `title` - The image's title (shown as tooltip), if any
+    fn image(&self, src: &str, alt_text: &str, title: Option<&str>) -> String;
+}
+
+/// Markdown images (for links to the image files and data URIs)
+pub struct MarkdownWriter {
+    /// Wrap the image in a link to the image (file)
+    pub clickable: bool,
+}
+
+impl OutputWriter for MarkdownWriter {
+    fn image(&self, src: &str, alt_text: &str, title: Option<&str>) -> String {
+        let image = markdown_image(src, alt_text, title);
+        if self.clickable {
+            // Note that both Edge and Firefox do not allow clicking on data URI links
+            // So this probably won't work for data URIs. Kept in here regardless for consistency
+            format!("[{image}]({src})\n\n")
+        } else {
+            format!("{image}\n\n")
+        }
+    }
+}
+
+/// Raw HTML img elements (for links to the image files and data URIs), for the features
+/// markdown images do not support (e.g. classes and lazy loading)
+pub struct HtmlWriter {
+    /// Wrap the image in a link to the image (file)
+    pub clickable: bool,
+}
+
+impl OutputWriter for HtmlWriter {
+    fn image(&self, src: &str, alt_text: &str, title: Option<&str>) -> String {
+        let src = escape_html_attribute(src);
+        let mut image = format!(
+            "<img src=\"{}\" alt=\"{}\"",
+            src,
+            escape_html_attribute(alt_text)
+        );
+        if let Some(title) = title {
+            image.push_str(&format!(" title=\"{}\"", escape_html_attribute(title)));
+        }
+        image.push('>');
+
+        if self.clickable {
+            format!("<a href=\"{src}\">{image}</a>\n\n")
+        } else {
+            format!("{image}\n\n")
+        }
+    }
+}
+
+/// The url of the image file
+/// # Arguments
+/// * `rel_img_url` - The url of the image dir relative to the chapter
+/// * `image_path` - The image file
+fn image_url(rel_img_url: &str, image_path: &Path) -> String {
+    format!(
+        "{}/{}",
+        rel_img_url,
+        image_path.file_name().unwrap().to_str().unwrap()
+    )
+}
+
+/// The markdown image, without trailing newlines
+fn markdown_image(src: &str, alt_text: &str, title: Option<&str>) -> String {
+    let alt_text = escape_alt_text(alt_text);
+    match title {
+        Some(title) => format!("![{alt_text}]({src} \"{title}\")"),
+        None => format!("![{alt_text}]({src})"),
+    }
+}
+
+/// Escape the text for use in a (double quoted) HTML attribute value
+fn escape_html_attribute(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '\r' | '\n' => escaped.push(' '),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// The image source of a diagram, memoized so repeated diagrams are looked up,
+/// read and encoded only once per build
+#[derive(Clone)]
+enum RenderedImage {
+    /// No backend available (or the render budget is spent) and the image is not cached,
+    /// holds the reason
+    Placeholder(&'static str),
+    /// Inlined text diagram markdown
+    Text(String),
+    DataUri(String),
+    /// The cached image file
+    File(PathBuf),
+}
+
 pub struct Renderer {
-    backend: Box<dyn Backend>,
+    /// None when no backend is available (see the missing-backend option)
+    backend: Option<Box<dyn Backend>>,
     cleaner: RefCell<DirCleaner>,
     img_root: PathBuf,
     clickable_img: bool,
     use_data_uris: bool,
+    /// How the images are emitted, unless specified per code block
+    image_markup: ImageMarkup,
+    forced_image_format: Option<String>,
+    /// Render the text formats of diagrams without readable text output as svg
+    text_fallback: bool,
+    /// Add the text rendering after braille images
+    braille_text: bool,
+    post_process_cmd: Option<String>,
+    /// Add an anchor to every image (for the list of figures, or the glossary)
+    anchor_images: bool,
+    /// Add the PlantUML source in an HTML comment after every image
+    embed_source_comment: bool,
+    /// Strip the BOM and use LF line endings before hashing and rendering the code
+    normalize_source: bool,
+    /// Make the saved SVG images diff friendly (see stable_svg)
+    stable_svg: bool,
+    /// The included files read during this run, and the stable ones hashed by their memoized
+    /// content hash
+    include_cache: IncludeCache,
+    /// The maximum image width and height in pixels, used to detect clipped images
+    limit_size: u32,
+    /// The backend renders clipped images again with a doubled limit size (see
+    /// Config::retry_truncated)
+    retry_truncated: bool,
+    /// The key the cached images are signed with (if any)
+    signing_key: Option<Vec<u8>>,
+    /// The backend identity added to the diagram hashes, so images rendered by another backend
+    /// are not used (None to share the cached images across backends)
+    backend_key: Option<String>,
+    /// The PlantUML version added to the diagram hashes (see factory::tool_version)
+    tool_version: Option<String>,
+    /// The options changing the rendered images added to the diagram hashes (see
+    /// Config::cache_options_key)
+    options_key: Option<String>,
+    /// The maximum total time spent rendering diagrams (None for no limit)
+    render_budget: Option<Duration>,
+    /// Render placeholders instead of failing once the render budget is spent
+    budget_placeholders: bool,
+    /// Why the diagrams are not rendered when no backend is available (shown in the placeholders)
+    unavailable_reason: &'static str,
+    /// The time it took to render each diagram (title or image file name)
+    render_times: RefCell<Vec<(String, Duration)>>,
+    /// The rendered diagrams with an anchor (id, or figure number)
+    figures: RefCell<Vec<Figure>>,
+    /// The image source per (code hash, image format)
+    rendered: RefCell<HashMap<(String, String), RenderedImage>>,
 }
 
 impl Renderer {
-    pub fn new(cfg: &Config, img_root: PathBuf) -> Self {
+    /// Create the renderer, without backend only the cached images can be used (placeholders are
+    /// rendered for the other diagrams)
+    pub fn new(cfg: &Config, backend: Option<Box<dyn Backend>>, img_root: PathBuf) -> Self {
         let renderer = Self {
-            backend: backend::factory::create(cfg),
+            backend,
             cleaner: RefCell::new(DirCleaner::new(img_root.as_path())),
             img_root,
             clickable_img: cfg.clickable_img,
             use_data_uris: cfg.use_data_uris,
+            image_markup: cfg.image_markup,
+            forced_image_format: cfg.forced_image_format.clone(),
+            text_fallback: cfg.text_fallback,
+            braille_text: cfg.braille_text,
+            post_process_cmd: cfg.post_process_cmd.clone(),
+            anchor_images: cfg.list_of_figures || cfg.glossary,
+            embed_source_comment: cfg.embed_source_comment,
+            normalize_source: cfg.normalize_source,
+            stable_svg: cfg.stable_svg,
+            include_cache: IncludeCache::default(),
+            limit_size: cfg.limit_size.unwrap_or(DEFAULT_LIMIT_SIZE),
+            retry_truncated: cfg.retry_truncated,
+            signing_key: None,
+            backend_key: cfg.cache_backend_key(),
+            tool_version: None,
+            options_key: cfg.cache_options_key(),
+            render_budget: cfg.max_total_render_seconds.map(Duration::from_secs),
+            budget_placeholders: cfg.render_budget_exceeded == BudgetExceeded::Placeholder,
+            unavailable_reason: unsupported_server(cfg).unwrap_or("PlantUML is not available"),
+            render_times: RefCell::default(),
+            figures: RefCell::new(vec![]),
+            rendered: RefCell::new(HashMap::new()),
+        };
+
+        renderer
+    }
+
+    /// Sign the cached images with the key, cached images without a (valid)
+    /// signature are rendered again.
+    pub fn with_signing_key(self, signing_key: Option<Vec<u8>>) -> Self {
+        Self {
+            signing_key,
+            ..self
+        }
+    }
+
+    /// Add the PlantUML version to the diagram hashes, so the diagrams are rendered again when
+    /// PlantUML is upgraded
+    pub fn with_tool_version(self, tool_version: Option<String>) -> Self {
+        Self {
+            tool_version,
+            ..self
+        }
+    }
+
+    /// Read the included files shared by diagrams once, and hash the stable ones by their
+    /// memoized content hash (see IncludeCache)
+    pub fn with_include_cache(self, include_cache: IncludeCache) -> Self {
+        Self {
+            include_cache,
+            ..self
+        }
+    }
+
+    fn create_datauri(image_path: &Path) -> Result<String> {
+        // https://developer.mozilla.org/en-US/docs/Web/HTTP/Basics_of_HTTP/Data_URIs#syntax
+
+        let media_type = media_type(image_path);
+        let mut image_file = fs::File::open(image_path)
+            .with_context(|| format!("Could not open image file {image_path:?}"))?;
+
+        // Stream encode the image into a buffer of the final size, so large images are neither
+        // fully read into memory, nor copied around after encoding
+        let image_size = image_file.metadata().map_or(0, |m| m.len() as usize);
+        let prefix = format!("data:{media_type};base64,");
+        let mut uri = String::with_capacity(prefix.len() + (image_size + 2) / 3 * 4);
+        uri.push_str(&prefix);
+
+        let mut encoder = EncoderStringWriter::from_consumer(&mut uri, &DEFAULT_ENGINE);
+        std::io::copy(&mut image_file, &mut encoder)
+            .with_context(|| format!("Could not read image file {image_path:?}"))?;
+        encoder.into_inner();
+
+        Ok(uri)
+    }
+
+    /// The markdown shown instead of a diagram that is not rendered (e.g. because no backend is
+    /// available)
+    fn create_placeholder(alt_text: &str, reason: &str) -> String {
+        if alt_text.is_empty() {
+            format!("\n*PlantUML diagram not rendered ({reason})*\n\n")
+        } else {
+            format!("\n*PlantUML diagram '{alt_text}' not rendered ({reason})*\n\n")
+        }
+    }
+
+    /// Returns true if rendering the diagrams took longer than the render budget
+    pub fn render_budget_spent(&self) -> bool {
+        self.render_budget
+            .map_or(false, |budget| self.total_render_time() > budget)
+    }
+
+    fn total_render_time(&self) -> Duration {
+        self.render_times
+            .borrow()
+            .iter()
+            .map(|(_, time)| *time)
+            .sum()
+    }
+
+    /// The render budget error message, listing the diagrams that took longest to render
+    pub fn render_budget_report(&self) -> String {
+        let mut render_times = self.render_times.borrow().clone();
+        render_times.sort_by_key(|(_, time)| std::cmp::Reverse(*time));
+        let slowest: Vec<String> = render_times
+            .iter()
+            .take(5)
+            .map(|(name, time)| format!("'{}' ({:.1}s)", name, time.as_secs_f64()))
+            .collect();
+
+        format!(
+            "Rendering the PlantUML diagrams took {:.1}s, exceeding max-total-render-seconds ({}s). \
+             The slowest diagrams: {}",
+            self.total_render_time().as_secs_f64(),
+            self.render_budget.unwrap_or_default().as_secs(),
+            slowest.join(", ")
+        )
+    }
+
+    fn create_inline_txt_image(image_path: &Path) -> Result<String> {
+        log::debug!("Creating inline image from {:?}", image_path);
+        let raw_source = fs::read(image_path)
+            .with_context(|| format!("Could not read text image file {image_path:?}"))?;
+        let txt = Self::decode_txt_image(raw_source, image_path);
+        let fence = code_fence(&txt);
+
+        Ok(format!("\n{fence}txt\n{txt}{fence}\n"))
+    }
+
+    /// Decode a rendered text image. PlantUML writes txt (atxt) images in the
+    /// configured charset, so anything that is not valid UTF-8 is decoded as
+    /// Latin-1 (which maps every byte to a character) instead of failing.
+    fn decode_txt_image(raw_source: Vec<u8>, image_path: &Path) -> String {
+        match String::from_utf8(raw_source) {
+            Ok(txt) => txt,
+            Err(e) => {
+                log::warn!(
+                    "The text image {:?} is not valid UTF-8, decoding it as Latin-1",
+                    image_path
+                );
+                e.into_bytes().iter().map(|&b| char::from(b)).collect()
+            }
+        }
+    }
+
+    /// The image format requested for the diagram, the text formats of diagrams without readable
+    /// text output are replaced by svg (see Config::text_fallback)
+    fn diagram_format<'a>(&self, plantuml_code: &str, image_format: &'a str) -> &'a str {
+        if self.text_fallback {
+            text_fallback_format(plantuml_code, image_format)
+        } else {
+            image_format
+        }
+    }
+
+    /// The image format to render, applies the forced image format
+    pub fn render_format<'a>(&'a self, image_format: &'a str) -> &'a str {
+        render_format(self.forced_image_format.as_deref(), image_format)
+    }
+
+    /// The diagram as the backend renders it, the (normalized) code and the image format to
+    /// render (with the text fallback and the forced image format applied)
+    /// # Arguments
+    /// * `plantuml_code` - The PlantUML code
+    /// * `image_format` - The requested image format
+    pub fn render_input<'a>(
+        &'a self,
+        plantuml_code: &'a str,
+        image_format: &'a str,
+    ) -> (Cow<'a, str>, &'a str) {
+        let plantuml_code = self.normalized(plantuml_code);
+        let image_format = self.diagram_format(&plantuml_code, image_format);
+        (plantuml_code, self.render_format(image_format))
+    }
+
+    /// Get the image for the given code, it is rendered when not cached yet.
+    /// Returns the path to the image file.
+    fn cached_image(&self, plantuml_code: &str, hash: &str, image_format: &str) -> Result<PathBuf> {
+        let image_format = self.render_format(image_format);
+
+        let _hash_field = log_field("hash", &hash[..hash.len().min(12)]);
+
+        // When operating in data-uri mode the images are written to in .mdbook-plantuml, otherwise
+        // they are written to src/mdbook-plantuml-images (cannot write to the book output dir, because
+        // mdbook deletes the files in there after preprocessing)
+        let output_file = image_path(&self.img_root, hash, plantuml_code, image_format);
+        if !self.is_cached(&output_file) || self.is_empty_cache_entry(&output_file) {
+            // File is not cached, render the image
+            log::debug!(
+                "Rendering {} diagram {:?}",
+                DiagramKind::detect(plantuml_code).name(),
+                output_file
+            );
+            let started = Instant::now();
+            let render = || match &self.backend {
+                Some(backend) => backend.render_from_string(plantuml_code, image_format),
+                None => bail!("Cannot render the PlantUML diagram, no PlantUML backend available"),
+            };
+            let data = match render() {
+                Ok(data) => match suspicious_image(&data, &output_file) {
+                    Some(reason) => {
+                        // A suspicious retry is not cached (see prepare_image_data)
+                        log::warn!(
+                            "PlantUML rendered a suspicious image for {:?} ({}), retrying",
+                            output_file,
+                            reason
+                        );
+                        render()
+                    }
+                    None => Ok(data),
+                },
+                Err(e) => match BackendError::of(&e).filter(|category| category.is_retryable()) {
+                    Some(category) => {
+                        log::warn!(
+                            "Failed to render {:?} ({}), retrying ({:#})",
+                            output_file,
+                            category,
+                            e
+                        );
+                        render()
+                    }
+                    None => Err(e),
+                },
+            };
+            let name = diagram_title(plantuml_code).unwrap_or_else(|| {
+                output_file
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_string()
+            });
+            self.render_times
+                .borrow_mut()
+                .push((name, started.elapsed()));
+            self.save_image(&data?, &output_file, image_format, hash)?;
+        }
+
+        // Let the dir cleaner know this file should be kept
+        self.cleaner.borrow_mut().keep(&output_file);
+        if self.signing_key.is_some() {
+            self.cleaner
+                .borrow_mut()
+                .keep(&signature_file(&output_file));
+        }
+
+        Ok(output_file)
+    }
+
+    /// The width and height of the image when it is probably clipped at the limit size. An image
+    /// larger than the limit size was rendered again with a doubled limit size (retry-truncated),
+    /// it is only clipped when it reaches that raised limit.
+    fn clipped_size(&self, data: &[u8]) -> Option<(u32, u32)> {
+        let (width, height) = truncated_size(data, self.limit_size)?;
+        if self.retry_truncated && width.max(height) > self.limit_size {
+            return truncated_size(data, self.limit_size.saturating_mul(2));
+        }
+
+        Some((width, height))
+    }
+
+    /// Validate, (post process) and save the rendered image to the cache
+    fn save_image(
+        &self,
+        data: &[u8],
+        output_file: &Path,
+        image_format: &str,
+        hash: &str,
+    ) -> Result<()> {
+        let data = &*prepare_image_data(data, output_file, self.stable_svg)?;
+        if let Some((width, height)) = self.clipped_size(data) {
+            log::warn!(
+                "The {}x{} pixels image {:?} is probably clipped at the limit size, increase the \
+                 limit-size option (or use svg)",
+                width,
+                height,
+                output_file
+            );
+        }
+        // Save the file even if we inline images
+        if let Some(post_process_cmd) = &self.post_process_cmd {
+            save_post_processed(post_process_cmd, data, output_file, image_format, hash)?;
+        } else {
+            std::fs::write(output_file, data).with_context(|| {
+                format!(
+                    "Failed to save PlantUML diagram to {}.",
+                    output_file.to_string_lossy()
+                )
+            })?;
+        }
+
+        if let Some(signing_key) = &self.signing_key {
+            sign_cached_image(output_file, signing_key)?;
+        }
+
+        Ok(())
+    }
+
+    /// Save an image rendered ahead of the chapter processing (see prerender) to the cache
+    /// # Arguments
+    /// * `output_file` - The image file (see image_file)
+    /// * `image_format` - The rendered image format (see render_format)
+    /// * `data` - The rendered image
+    pub fn save_prerendered(
+        &self,
+        output_file: &Path,
+        image_format: &str,
+        data: &[u8],
+    ) -> Result<()> {
+        // The image file is named after the hash
+        let file_name = file_name(output_file);
+        let hash =
+            String::from_utf8_lossy(file_name.split(|c| *c == b'.').next().unwrap_or_default());
+        self.save_image(data, output_file, image_format, &hash)
+    }
+
+    /// What the backend supports, None when no backend is available
+    pub fn backend_capabilities(&self) -> Option<Capabilities> {
+        self.backend.as_ref().map(|backend| backend.capabilities())
+    }
+
+    /// Returns true if the image file is cached, when signing the cached images
+    /// the image is only used when its signature is valid.
+    fn is_cached(&self, output_file: &Path) -> bool {
+        is_cached(output_file, self.signing_key.as_deref())
+    }
+
+    /// Returns true if the cached image is empty and can be rendered again (the images are
+    /// validated before they are cached, so the cached image is not read)
+    fn is_empty_cache_entry(&self, output_file: &Path) -> bool {
+        if self.backend.is_none() || !is_empty_cache_entry(output_file) {
+            return false;
+        }
+
+        log::warn!(
+            "The cached image {:?} is empty, rendering it again",
+            output_file
+        );
+        true
+    }
+
+    /// Render the PlantUML code and create the markdown for the image
+    /// # Arguments
+    /// * `plantuml_code` - The PlantUML code
+    /// * `include_dir` - The directory the `!include` paths are resolved from (the chapter's
+    ///   directory)
+    /// * `rel_img_url` - The url of the image dir relative to the chapter
+    /// * `options` - The options of the diagram's code block (image format, alt text, id and
+    ///   markup)
+    pub fn render(
+        &self,
+        plantuml_code: &str,
+        include_dir: &Path,
+        rel_img_url: &str,
+        options: BlockOptions,
+    ) -> Result<String> {
+        let BlockOptions {
+            image_format,
+            alt_text,
+            id,
+            markup,
+        } = options;
+        let markup = markup.unwrap_or(self.image_markup);
+        let plantuml_code = &*self.normalized(plantuml_code);
+        let requested_format = image_format;
+        let image_format = self.diagram_format(plantuml_code, requested_format);
+        if image_format != requested_format {
+            log::warn!(
+                "PlantUML has no readable {} output for {} diagrams, rendering it as {} instead",
+                requested_format,
+                DiagramKind::detect(plantuml_code).name(),
+                image_format
+            );
+        }
+        let raw_alt_text = alt_text
+            .map(String::from)
+            .or_else(|| diagram_title(plantuml_code))
+            .unwrap_or_default();
+        let alt_text = escape_alt_text(&raw_alt_text);
+
+        let rendered = self.rendered_image(plantuml_code, include_dir, image_format)?;
+        let is_braille = self.render_format(image_format) == "braille";
+        let title = is_braille.then_some(BRAILLE_TITLE);
+
+        // Text diagrams (and placeholders) are not numbered as figures
+        let image = match rendered {
+            RenderedImage::Placeholder(reason) => {
+                let placeholder = Self::create_placeholder(&alt_text, reason);
+                self.add_anchor(plantuml_code, placeholder, alt_text, id, false)
+            }
+            RenderedImage::Text(txt) => self.add_anchor(plantuml_code, txt, alt_text, id, false),
+            RenderedImage::DataUri(uri) => {
+                let image = self.output_writer(markup).image(&uri, &raw_alt_text, title);
+                self.add_anchor(plantuml_code, image, alt_text, id, true)
+            }
+            RenderedImage::File(output_file) => {
+                let image = self.output_writer(markup).image(
+                    &image_url(rel_img_url, &output_file),
+                    &raw_alt_text,
+                    title,
+                );
+                self.add_anchor(plantuml_code, image, alt_text, id, true)
+            }
+        };
+        let image = if is_braille && self.braille_text {
+            image + &self.braille_text_alternative(plantuml_code, include_dir)?
+        } else {
+            image
+        };
+
+        if self.embed_source_comment {
+            Ok(image + &source_comment(plantuml_code))
+        } else {
+            Ok(image)
+        }
+    }
+
+    /// The writer emitting the image markup
+    fn output_writer(&self, markup: ImageMarkup) -> Box<dyn OutputWriter> {
+        let clickable = self.clickable_img;
+        match markup {
+            ImageMarkup::Html => Box::new(HtmlWriter { clickable }),
+            ImageMarkup::Markdown => Box::new(MarkdownWriter { clickable }),
+        }
+    }
+
+    /// Get the (memoized) image source for the code, renders the image when it is not cached
+    fn rendered_image(
+        &self,
+        plantuml_code: &str,
+        include_dir: &Path,
+        image_format: &str,
+    ) -> Result<RenderedImage> {
+        let hash = self
+            .fingerprint(plantuml_code, include_dir, image_format)
+            .hash();
+        let key = (hash, image_format.to_string());
+        if let Some(rendered) = self.rendered.borrow().get(&key) {
+            return Ok(rendered.clone());
+        }
+
+        let rendered = self.render_image(plantuml_code, &key.0, image_format)?;
+        self.rendered.borrow_mut().insert(key, rendered.clone());
+        Ok(rendered)
+    }
+
+    /// The inlined text (utxt) rendering of a braille diagram, empty for diagrams without
+    /// readable text output (or when the text image is not available)
+    fn braille_text_alternative(&self, plantuml_code: &str, include_dir: &Path) -> Result<String> {
+        if !DiagramKind::detect(plantuml_code).supports_text() {
+            return Ok(String::new());
+        }
+
+        match self.rendered_image(plantuml_code, include_dir, "utxt")? {
+            RenderedImage::Text(txt) => Ok(txt),
+            _ => Ok(String::new()),
+        }
+    }
+
+    /// Get the image source for the code, renders the image when it is not cached
+    fn render_image(
+        &self,
+        plantuml_code: &str,
+        hash: &str,
+        image_format: &str,
+    ) -> Result<RenderedImage> {
+        if self.backend.is_none() || self.render_budget_spent() {
+            let image_format = self.render_format(image_format);
+            if !self.is_cached(&image_path(
+                &self.img_root,
+                hash,
+                plantuml_code,
+                image_format,
+            )) {
+                if self.backend.is_none() {
+                    return Ok(RenderedImage::Placeholder(self.unavailable_reason));
+                }
+                if self.budget_placeholders {
+                    return Ok(RenderedImage::Placeholder("render time budget exceeded"));
+                }
+                bail!("{}", self.render_budget_report());
+            }
+        }
+
+        let output_file = self.cached_image(plantuml_code, hash, image_format)?;
+
+        let extension = output_file.extension().unwrap_or_default();
+        if extension == "atxt" || extension == "utxt" {
+            Ok(RenderedImage::Text(Self::create_inline_txt_image(
+                &output_file,
+            )?))
+        } else if self.use_data_uris {
+            Ok(RenderedImage::DataUri(Self::create_datauri(&output_file)?))
+        } else {
+            Ok(RenderedImage::File(output_file))
+        }
+    }
+
+    /// Prefix the image with an anchor when it has an id, or when it is a
+    /// numbered figure (when anchoring all images). Diagrams with an anchor are
+    /// recorded as figure.
+    fn add_anchor(
+        &self,
+        plantuml_code: &str,
+        image: String,
+        alt_text: String,
+        id: Option<&str>,
+        is_image: bool,
+    ) -> String {
+        let number = if is_image && self.anchor_images {
+            let figures = self.figures.borrow();
+            Some(figures.iter().filter(|f| f.number.is_some()).count() + 1)
+        } else {
+            None
+        };
+
+        let anchor = match (id, number) {
+            (Some(id), _) => format!("diag-{}", anchor_id(id)),
+            (None, Some(number)) => format!("plantuml-figure-{number}"),
+            (None, None) => return image,
+        };
+
+        let caption = if !alt_text.is_empty() {
+            alt_text
+        } else if let Some(number) = number {
+            format!("Figure {number}")
+        } else {
+            String::from(id.unwrap_or_default())
+        };
+
+        let anchored_image = format!("<a id=\"{anchor}\"></a>{image}");
+        self.figures.borrow_mut().push(Figure {
+            caption,
+            anchor,
+            id: id.map(String::from),
+            number,
+            elements: diagram_elements(plantuml_code),
+        });
+        anchored_image
+    }
+
+    /// The number of figures (diagrams with an anchor) rendered so far
+    pub fn figure_count(&self) -> usize {
+        self.figures.borrow().len()
+    }
+
+    /// Take the rendered figures
+    pub fn take_figures(&self) -> Vec<Figure> {
+        self.figures.take()
+    }
+
+    /// The (cached) image file of the diagram, the file does not exist when the diagram is not
+    /// rendered (yet)
+    /// # Arguments
+    /// * `plantuml_code` - The PlantUML code
+    /// * `include_dir` - The directory the `!include` paths are resolved from
+    /// * `image_format` - The image format
+    pub fn image_file(
+        &self,
+        plantuml_code: &str,
+        include_dir: &Path,
+        image_format: &str,
+    ) -> PathBuf {
+        let (plantuml_code, image_format) = self.render_input(plantuml_code, image_format);
+        let hash = self
+            .fingerprint(&plantuml_code, include_dir, image_format)
+            .hash();
+        image_path(&self.img_root, &hash, &plantuml_code, image_format)
+    }
+
+    /// The inputs of the diagram's cache hash
+    fn fingerprint(
+        &self,
+        plantuml_code: &str,
+        include_dir: &Path,
+        image_format: &str,
+    ) -> RenderFingerprint {
+        RenderFingerprint::new(plantuml_code)
+            .with_includes(include_dir, &self.include_cache)
+            .with_format(self.render_format(image_format))
+            .with_backend(self.backend_key.as_deref())
+            .with_tool_version(self.tool_version.as_deref())
+            .with_options(self.options_key.as_deref())
+    }
+
+    /// The code with the BOM stripped and LF line endings (when normalizing the
+    /// source), so the same diagram has the same hash on every platform.
+    fn normalized<'a>(&self, plantuml_code: &'a str) -> Cow<'a, str> {
+        if self.normalize_source {
+            normalize_source(plantuml_code)
+        } else {
+            Cow::Borrowed(plantuml_code)
+        }
+    }
+
+    /// Render a PlantUML source file to an image named after the source file
+    /// (e.g. diagrams/foo.puml becomes <img_root>/foo.svg), so it can be
+    /// referenced by a plain image link.
+    /// Returns the path to the image file.
+    pub fn render_file(&self, source: &Path) -> Result<PathBuf> {
+        let plantuml_code = fs::read_to_string(source)
+            .with_context(|| format!("Failed to read PlantUML source {source:?}"))?;
+        let plantuml_code = self.normalized(&plantuml_code);
+        let image_format = DiagramKind::detect(&plantuml_code).default_format();
+
+        let include_dir = source.parent().unwrap_or_else(|| Path::new("."));
+        let hash = self
+            .fingerprint(&plantuml_code, include_dir, image_format)
+            .hash();
+        let cached_file = self.cached_image(&plantuml_code, &hash, image_format)?;
+        let mut output_file = self.img_root.join(source.file_stem().unwrap_or_default());
+        output_file.set_extension(cached_file.extension().unwrap_or_default());
+
+        // Only touch the named image when it changed (prevents rebuild loops when serving)
+        let data = fs::read(&cached_file)?;
+        if fs::read(&output_file).ok().as_ref() != Some(&data) {
+            fs::write(&output_file, data).with_context(|| {
+                format!(
+                    "Failed to save PlantUML diagram to {}.",
+                    output_file.to_string_lossy()
+                )
+            })?;
+        }
+
+        self.cleaner.borrow_mut().keep(&output_file);
+
+        Ok(output_file)
+    }
+}
+
+impl RendererTrait for Renderer {
+    fn render(
+        &self,
+        plantuml_code: &str,
+        include_dir: &Path,
+        rel_img_url: &str,
+        options: BlockOptions,
+    ) -> Result<String> {
+        Self::render(self, plantuml_code, include_dir, rel_img_url, options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{bail, format_err, Result};
+    use pretty_assertions::assert_eq;
+    use std::cell::Cell;
+    use std::fs::File;
+    use std::io::Write;
+    use std::rc::Rc;
+    use tempfile::tempdir;
+
+    /// The image file of a diagram without includes
+    fn image_filename(img_root: &Path, plantuml_code: &str, image_format: &str) -> PathBuf {
+        image_path(
+            img_root,
+            &hash_string(plantuml_code),
+            plantuml_code,
+            image_format,
+        )
+    }
+
+    /// The options of a code block with only the image format set
+    fn block_options(image_format: &str) -> BlockOptions<'_> {
+        BlockOptions {
+            image_format,
+            ..BlockOptions::default()
+        }
+    }
+
+    /// A renderer with the default options, tests override the options they need
+    fn test_renderer(dir: &Path, backend: Option<Box<dyn Backend>>) -> Renderer {
+        Renderer {
+            backend,
+            cleaner: RefCell::new(DirCleaner::new(dir)),
+            img_root: dir.to_path_buf(),
+            clickable_img: false,
+            use_data_uris: false,
+            image_markup: ImageMarkup::Markdown,
+            forced_image_format: None,
+            text_fallback: true,
+            braille_text: false,
+            post_process_cmd: None,
+            anchor_images: false,
+            embed_source_comment: false,
+            normalize_source: false,
+            stable_svg: false,
+            include_cache: IncludeCache::default(),
+            limit_size: DEFAULT_LIMIT_SIZE,
+            retry_truncated: false,
+            signing_key: None,
+            backend_key: None,
+            tool_version: None,
+            options_key: None,
+            render_budget: None,
+            budget_placeholders: false,
+            unavailable_reason: "PlantUML is not available",
+            render_times: RefCell::default(),
+            figures: RefCell::new(vec![]),
+            rendered: RefCell::default(),
+        }
+    }
+
+    #[test]
+    fn test_code_fence() {
+        assert_eq!("```", code_fence(""));
+        assert_eq!("```", code_fence("no backticks"));
+        assert_eq!("```", code_fence("`a` ``b``"));
+        assert_eq!("````", code_fence("```\nfoo\n```"));
+        assert_eq!("``````", code_fence("a `````b"));
+    }
+
+    #[test]
+    fn test_create_inline_txt_image() {
+        let temp_directory = tempdir().unwrap();
+        let txt_path = temp_directory.path().join("file.atxt");
+
+        fs::write(&txt_path, "┌─┐\n└─┘\n").unwrap();
+        assert_eq!(
+            "\n```txt\n┌─┐\n└─┘\n```\n",
+            Renderer::create_inline_txt_image(&txt_path).unwrap()
+        );
+
+        // Latin-1 encoded output (e.g. charset = "ISO-8859-1")
+        fs::write(&txt_path, b"caf\xe9 \xb1\n").unwrap();
+        assert_eq!(
+            "\n```txt\ncafé ±\n```\n",
+            Renderer::create_inline_txt_image(&txt_path).unwrap()
+        );
+
+        let error = Renderer::create_inline_txt_image(&temp_directory.path().join("missing.atxt"))
+            .unwrap_err();
+        assert!(format!("{error}").starts_with("Could not read text image file"));
+    }
+
+    #[test]
+    fn test_image_path() {
+        assert_eq!(
+            PathBuf::from("/froboz/abc.atxt"),
+            image_path(Path::new("/froboz"), "abc", "A --|> B", "txt")
+        );
+
+        // Deeper than MAX_PATH on windows, the image is linked by its file name
+        let img_root = Path::new("C:\\").join(["some-deeply-nested-chapter-dir"; 10].join("\\"));
+        let output_file = image_path(&img_root, "abc", "A --|> B", "svg");
+        assert_eq!(
+            cfg!(target_family = "windows"),
+            output_file.to_string_lossy().starts_with(r"\\?\")
+        );
+        assert_eq!("/abc.svg", image_url("", &output_file));
+    }
+
+    #[test]
+    fn test_image_url() {
+        assert_eq!(
+            "foo/bar/baz.svg",
+            image_url("foo/bar", Path::new("/froboz/baz.svg"))
+        );
+        assert_eq!("/baz.svg", image_url("", Path::new("baz.svg")));
+        assert_eq!("/baz.svg", image_url("", Path::new("foo/baz.svg")));
+    }
+
+    #[test]
+    fn test_output_writers() {
+        let writer = MarkdownWriter { clickable: false };
+        assert_eq!(
+            "![](foo/baz.svg)\n\n",
+            writer.image("foo/baz.svg", "", None)
+        );
+        assert_eq!(
+            "![My \\[diagram\\]](foo/baz.svg \"Tip\")\n\n",
+            writer.image("foo/baz.svg", "My [diagram]", Some("Tip"))
+        );
+        let writer = MarkdownWriter { clickable: true };
+        assert_eq!(
+            "[![My diagram](foo/baz.svg)](foo/baz.svg)\n\n",
+            writer.image("foo/baz.svg", "My diagram", None)
+        );
+
+        let writer = MarkdownWriter { clickable: false };
+        assert_eq!(
+            "![A](data:image/svg+xml;base64,PHN2Zz4=)\n\n",
+            writer.image("data:image/svg+xml;base64,PHN2Zz4=", "A", None)
+        );
+
+        let writer = HtmlWriter { clickable: false };
+        assert_eq!(
+            "<img src=\"foo/baz.svg\" alt=\"A &quot;quoted&quot; &lt;b&gt;\">\n\n",
+            writer.image("foo/baz.svg", "A \"quoted\" <b>", None)
+        );
+        let writer = HtmlWriter { clickable: true };
+        assert_eq!(
+            "<a href=\"foo/baz.svg?a=1&amp;b=2\"><img src=\"foo/baz.svg?a=1&amp;b=2\" alt=\"\" \
+             title=\"Braille rendering\"></a>\n\n",
+            writer.image("foo/baz.svg?a=1&b=2", "", Some(BRAILLE_TITLE))
+        );
+    }
+
+    #[test]
+    fn test_diagram_title() {
+        assert_eq!(None, diagram_title("@startuml\nA --|> B\n@enduml\n"));
+        assert_eq!(
+            Some(String::from("My diagram")),
+            diagram_title("@startuml\n  Title  My diagram \nA --|> B\n@enduml\n")
+        );
+        assert_eq!(
+            Some(String::from("my-name")),
+            diagram_title("@startuml my-name\nA --|> B\n@enduml\n")
+        );
+        assert_eq!(
+            Some(String::from("my-id")),
+            diagram_title("@startuml(id=my-id)\nA --|> B\n@enduml\n")
+        );
+        // The title takes precedence over the name
+        assert_eq!(
+            Some(String::from("My diagram")),
+            diagram_title("@startuml my-name\ntitle My diagram\n@enduml\n")
+        );
+        // Multi line titles are not supported
+        assert_eq!(None, diagram_title("title\n  My diagram\nend title\n"));
+        assert_eq!(None, diagram_title("titles are great\n"));
+    }
+
+    #[test]
+    fn test_escape_alt_text() {
+        assert_eq!("foo bar", escape_alt_text("foo\nbar"));
+        assert_eq!("\\[foo\\] \\\\", escape_alt_text("[foo] \\"));
+    }
+
+    #[test]
+    fn test_rendering_alt_text() {
+        let output_dir = tempdir().unwrap();
+        let renderer = test_renderer(
+            output_dir.path(),
+            Some(Box::new(BackendMock { is_ok: true })),
+        );
+
+        let plantuml_code = "title My [diagram]\nA --|> B";
+        let code_hash = hash_string(plantuml_code);
+        assert_eq!(
+            format!("![My \\[diagram\\]](rel/url/{code_hash}.svg)\n\n"),
+            renderer
+                .render(
+                    plantuml_code,
+                    Path::new("."),
+                    "rel/url",
+                    block_options("svg")
+                )
+                .unwrap()
+        );
+
+        // An explicit alt text takes precedence
+        assert_eq!(
+            format!("![Explicit](rel/url/{code_hash}.svg)\n\n"),
+            renderer
+                .render(
+                    plantuml_code,
+                    Path::new("."),
+                    "rel/url",
+                    BlockOptions {
+                        alt_text: Some("Explicit"),
+                        ..block_options("svg")
+                    }
+                )
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_create_datauri_large_image() {
+        let temp_directory = tempdir().unwrap();
+        let data: Vec<u8> = (0..100_003).map(|i| (i * 7 % 256) as u8).collect();
+        let png_path = temp_directory.path().join("large.png");
+        fs::write(&png_path, &data).unwrap();
+
+        let uri = Renderer::create_datauri(&png_path).unwrap();
+        assert_eq!(
+            format!("data:image/png;base64,{}", base64::encode(&data)),
+            uri
+        );
+        assert_eq!(uri.len(), uri.capacity());
+    }
+
+    #[test]
+    fn test_create_datauri() {
+        let temp_directory = tempdir().unwrap();
+        let content = "test content";
+
+        let svg_path = temp_directory.path().join("file.svg");
+        let mut svg_file = File::create(&svg_path).unwrap();
+        writeln!(svg_file, "{content}").unwrap();
+        drop(svg_file); // Close and flush content to file
+        assert_eq!(
+            String::from("data:image/svg+xml;base64,dGVzdCBjb250ZW50Cg=="),
+            Renderer::create_datauri(&svg_path).unwrap()
+        );
+
+        let png_path = temp_directory.path().join("file.png");
+        let mut png_file = File::create(&png_path).unwrap();
+        writeln!(png_file, "{content}").unwrap();
+        drop(png_file); // Close and flush content to file
+        assert_eq!(
+            String::from("data:image/png;base64,dGVzdCBjb250ZW50Cg=="),
+            Renderer::create_datauri(&png_path).unwrap()
+        );
+
+        let txt_path = temp_directory.path().join("file.txt");
+        let mut txt_file = File::create(&txt_path).unwrap();
+        writeln!(txt_file, "{content}").unwrap();
+        drop(txt_file); // Close and flush content to file
+        assert_eq!(
+            String::from("data:text/plain;base64,dGVzdCBjb250ZW50Cg=="),
+            Renderer::create_datauri(&txt_path).unwrap()
+        );
+
+        let braille_path = temp_directory.path().join("file.braille.png");
+        fs::write(&braille_path, format!("{content}\n")).unwrap();
+        assert_eq!(
+            String::from("data:image/png;base64,dGVzdCBjb250ZW50Cg=="),
+            Renderer::create_datauri(&braille_path).unwrap()
+        );
+
+        let unknown_path = temp_directory.path().join("file.vdx");
+        fs::write(&unknown_path, format!("{content}\n")).unwrap();
+        assert_eq!(
+            String::from("data:application/octet-stream;base64,dGVzdCBjb250ZW50Cg=="),
+            Renderer::create_datauri(&unknown_path).unwrap()
+        );
+
+        let jpeg_path = temp_directory.path().join("file.jpeg");
+        let mut jpeg_file = File::create(&jpeg_path).unwrap();
+        writeln!(jpeg_file, "{content}").unwrap();
+        drop(jpeg_file); // Close and flush content to file
+        assert_eq!(
+            String::from("data:image/jpeg;base64,dGVzdCBjb250ZW50Cg=="),
+            Renderer::create_datauri(&jpeg_path).unwrap()
+        );
+    }
+
+    struct BackendMock {
+        is_ok: bool,
+    }
+
+    impl Backend for BackendMock {
+        fn render_from_string(&self, plantuml_code: &str, image_format: &str) -> Result<Vec<u8>> {
+            if self.is_ok {
+                // Prefix the image header (and suffix the PNG end chunk) so the image data
+                // passes validation
+                let header: &[u8] = match image_format {
+                    "svg" => b"<svg>",
+                    "png" | "braille" => b"\x89PNG\r\n\x1a\n",
+                    _ => b"",
+                };
+                let mut data = header.to_vec();
+                data.extend_from_slice(format!("{plantuml_code}\n{image_format}").as_bytes());
+                if header.starts_with(b"\x89PNG") {
+                    data.extend_from_slice(b"IEND\xAE\x42\x60\x82");
+                }
+                return Ok(data);
+            }
+            bail!("Oh no");
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities {
+                formats: &["svg", "png", "txt", "eps"],
+                batching: false,
+                includes: true,
+                concurrency_safe: true,
+            }
+        }
+    }
+
+    #[test]
+    fn test_rendering_md_link() {
+        let output_dir = tempdir().unwrap();
+        let renderer = test_renderer(
+            output_dir.path(),
+            Some(Box::new(BackendMock { is_ok: true })),
+        );
+
+        let plantuml_code = "some puml code";
+        let code_hash = hash_string(plantuml_code);
+
+        assert_eq!(
+            format!("![](rel/url/{code_hash}.svg)\n\n"),
+            renderer
+                .render(
+                    plantuml_code,
+                    Path::new("."),
+                    "rel/url",
+                    block_options("svg")
+                )
+                .unwrap()
+        );
+
+        // png extension
+        assert_eq!(
+            format!("![](rel/url/{code_hash}.png)\n\n"),
+            renderer
+                .render(
+                    plantuml_code,
+                    Path::new("."),
+                    "rel/url",
+                    block_options("png")
+                )
+                .unwrap()
+        );
+
+        // HTML markup
+        assert_eq!(
+            format!("<img src=\"rel/url/{code_hash}.svg\" alt=\"\">\n\n"),
+            renderer
+                .render(
+                    plantuml_code,
+                    Path::new("."),
+                    "rel/url",
+                    BlockOptions {
+                        markup: Some(ImageMarkup::Html),
+                        ..block_options("svg")
+                    }
+                )
+                .unwrap()
+        );
+
+        // txt extension
+        assert_eq!(
+            format!("\n```txt\n{plantuml_code}\ntxt```\n"), /* image format is appended by
+                                                             * fake backend */
+            renderer
+                .render(
+                    plantuml_code,
+                    Path::new("."),
+                    "rel/url",
+                    block_options("txt")
+                )
+                .unwrap()
+        );
+
+        // utxt extension
+        assert_eq!(
+            format!("\n```txt\n{plantuml_code}\ntxt```\n"), /* image format is appended by
+                                                             * fake backend */
+            renderer
+                .render(
+                    plantuml_code,
+                    Path::new("."),
+                    "rel/url",
+                    block_options("txt")
+                )
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_rendering_datauri() {
+        let output_dir = tempdir().unwrap();
+        let renderer = Renderer {
+            use_data_uris: true,
+            ..test_renderer(
+                output_dir.path(),
+                Some(Box::new(BackendMock { is_ok: true })),
+            )
+        };
+
+        let plantuml_code = "some puml code";
+
+        // svg extension
+        assert_eq!(
+            format!(
+                "![]({})\n\n",
+                "data:image/svg+xml;base64,PHN2Zz5zb21lIHB1bWwgY29kZQpzdmc="
+            ),
+            renderer
+                .render(
+                    plantuml_code,
+                    Path::new("."),
+                    "rel/url",
+                    block_options("svg")
+                )
+                .unwrap()
+        );
+
+        // png extension
+        assert_eq!(
+            format!(
+                "![]({})\n\n",
+                "data:image/png;base64,iVBORw0KGgpzb21lIHB1bWwgY29kZQpwbmdJRU5ErkJggg=="
+            ),
+            renderer
+                .render(
+                    plantuml_code,
+                    Path::new("."),
+                    "rel/url",
+                    block_options("png")
+                )
+                .unwrap()
+        );
+
+        // txt extension
+        assert_eq!(
+            String::from("\n```txt\nsome puml code\ntxt```\n"),
+            renderer
+                .render(
+                    plantuml_code,
+                    Path::new("."),
+                    "rel/url",
+                    block_options("txt")
+                )
+                .unwrap()
+        );
+
+        // utxt extension
+        assert_eq!(
+            String::from("\n```txt\nsome puml code\ntxt```\n"),
+            renderer
+                .render(
+                    plantuml_code,
+                    Path::new("."),
+                    "rel/url",
+                    block_options("txt")
+                )
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_rendering_braille() {
+        let output_dir = tempdir().unwrap();
+        let create_renderer = |use_data_uris: bool, clickable_img: bool| Renderer {
+            clickable_img,
+            use_data_uris,
+            braille_text: true,
+            ..test_renderer(
+                output_dir.path(),
+                Some(Box::new(BackendMock { is_ok: true })),
+            )
+        };
+
+        let plantuml_code = "some puml code";
+        let text_alternative = "\n```txt\nsome puml code\nutxt```\n";
+        let render = |renderer: &Renderer| {
+            renderer
+                .render(
+                    plantuml_code,
+                    Path::new("."),
+                    "rel/url",
+                    block_options("braille"),
+                )
+                .unwrap()
+        };
+
+        // Image file
+        let renderer = create_renderer(false, false);
+        let image_file = renderer.image_file(plantuml_code, Path::new("."), "braille");
+        let file_name = image_file.file_name().unwrap().to_str().unwrap();
+        assert!(file_name.ends_with(".braille.png"));
+        assert_eq!(
+            format!("![](rel/url/{file_name} \"Braille rendering\")\n\n{text_alternative}"),
+            render(&renderer)
+        );
+
+        // Clickable image file
+        let renderer = create_renderer(false, true);
+        assert_eq!(
+            format!(
+                "[![](rel/url/{file_name} \"Braille rendering\")](rel/url/{file_name})\n\n\
+                 {text_alternative}"
+            ),
+            render(&renderer)
+        );
+
+        // Data URI, with the png media type
+        let renderer = create_renderer(true, false);
+        let uri = format!(
+            "data:image/png;base64,{}",
+            base64::encode(b"\x89PNG\r\n\x1a\nsome puml code\nbrailleIEND\xAE\x42\x60\x82")
+        );
+        assert_eq!(
+            format!("![]({uri} \"Braille rendering\")\n\n{text_alternative}"),
+            render(&renderer)
+        );
+
+        // Without text alternative
+        let renderer = Renderer {
+            braille_text: false,
+            ..create_renderer(true, false)
+        };
+        assert_eq!(
+            format!("![]({uri} \"Braille rendering\")\n\n"),
+            render(&renderer)
+        );
+    }
+
+    #[test]
+    fn test_rendering_forced_image_format() {
+        let output_dir = tempdir().unwrap();
+        let renderer = Renderer {
+            use_data_uris: true,
+            forced_image_format: Some(String::from("png")),
+            ..test_renderer(
+                output_dir.path(),
+                Some(Box::new(BackendMock { is_ok: true })),
+            )
+        };
+
+        let plantuml_code = "some puml code";
+
+        // svg is rendered as png
+        assert_eq!(
+            format!(
+                "![]({})\n\n",
+                "data:image/png;base64,iVBORw0KGgpzb21lIHB1bWwgY29kZQpwbmdJRU5ErkJggg=="
+            ),
+            renderer
+                .render(
+                    plantuml_code,
+                    Path::new("."),
+                    "rel/url",
+                    block_options("svg")
+                )
+                .unwrap()
+        );
+
+        // But text formats are kept
+        assert_eq!(
+            String::from("\n```txt\nsome puml code\ntxt```\n"),
+            renderer
+                .render(
+                    plantuml_code,
+                    Path::new("."),
+                    "rel/url",
+                    block_options("txt")
+                )
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_rendering_text_fallback() {
+        let output_dir = tempdir().unwrap();
+        let cfg = Config {
+            use_data_uris: false,
+            image_markup: ImageMarkup::Markdown,
+            ..Config::default()
+        };
+        let gantt = "@startgantt\n[Task] requires 1 day\n@endgantt\n";
+
+        // Gantt diagrams are rendered as svg instead of unreadable text
+        let renderer = Renderer::new(
+            &cfg,
+            Some(Box::new(BackendMock { is_ok: true })),
+            output_dir.path().to_path_buf(),
+        );
+        let rendered = renderer
+            .render(gantt, Path::new("."), "rel/url", block_options("txt"))
+            .unwrap();
+        assert!(rendered.contains(".svg)"), "{}", rendered);
+        // The prerendered image is the same image
+        let image_file = renderer.image_file(gantt, Path::new("."), "txt");
+        assert!(image_file.exists());
+        assert_eq!(
+            Some("svg"),
+            image_file.extension().and_then(|ext| ext.to_str())
+        );
+
+        let renderer = Renderer::new(
+            &Config {
+                text_fallback: false,
+                braille_text: false,
+                ..cfg
+            },
+            Some(Box::new(BackendMock { is_ok: true })),
+            output_dir.path().to_path_buf(),
+        );
+        assert!(renderer
+            .render(gantt, Path::new("."), "rel/url", block_options("txt"))
+            .unwrap()
+            .starts_with("\n```txt\n"));
+    }
+
+    #[test]
+    fn test_rendering_failure() {
+        let output_dir = tempdir().unwrap();
+        let renderer = test_renderer(
+            output_dir.path(),
+            Some(Box::new(BackendMock { is_ok: false })),
+        );
+
+        let result = renderer.render("", Path::new("."), "rel/url", block_options("svg"));
+        let error_str = format!("{}", result.err().unwrap());
+        assert_eq!("Oh no", error_str);
+    }
+
+    /// Fails the first render with the given error category
+    struct FlakyBackendMock {
+        category: BackendError,
+        calls: Rc<Cell<usize>>,
+    }
+
+    impl Backend for FlakyBackendMock {
+        fn render_from_string(&self, plantuml_code: &str, _image_format: &str) -> Result<Vec<u8>> {
+            self.calls.set(self.calls.get() + 1);
+            if self.calls.get() == 1 {
+                return Err(self.category.tag()(format_err!("Flaky")));
+            }
+            Ok(format!("<svg>{plantuml_code}</svg>").into_bytes())
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            BackendMock { is_ok: true }.capabilities()
+        }
+    }
+
+    #[test]
+    fn test_rendering_retryable_failure() {
+        for (category, retried) in [
+            (BackendError::Timeout, true),
+            (BackendError::Network { status: Some(502) }, false),
+            (BackendError::SyntaxError { line: Some(1) }, false),
+        ] {
+            let output_dir = tempdir().unwrap();
+            let calls = Rc::new(Cell::new(0));
+            let renderer = test_renderer(
+                output_dir.path(),
+                Some(Box::new(FlakyBackendMock {
+                    category,
+                    calls: calls.clone(),
+                })),
+            );
+
+            let result = renderer.render("A", Path::new("."), "rel/url", block_options("svg"));
+            assert_eq!(retried, result.is_ok(), "{category}");
+            assert_eq!(if retried { 2 } else { 1 }, calls.get());
+        }
+    }
+
+    #[test]
+    fn test_rendering_invalid_format() {
+        let output_dir = tempdir().unwrap();
+        let renderer = test_renderer(
+            output_dir.path(),
+            Some(Box::new(BackendMock { is_ok: true })),
+        );
+
+        // The mock backend does not add an image header for eps
+        let result = renderer.render(
+            "some puml code",
+            Path::new("."),
+            "rel/url",
+            block_options("eps"),
+        );
+        assert!(result.is_err());
+
+        // And the invalid image is not cached
+        let output_file = image_filename(output_dir.path(), "some puml code", "eps");
+        assert!(!output_file.exists());
+    }
+
+    #[test]
+    fn test_render_file() {
+        let output_dir = tempdir().unwrap();
+        let source_dir = tempdir().unwrap();
+        let renderer = test_renderer(
+            output_dir.path(),
+            Some(Box::new(BackendMock { is_ok: true })),
+        );
+
+        let source = source_dir.path().join("my diagram.puml");
+        fs::write(&source, "some puml code").unwrap();
+        let output_file = renderer.render_file(&source).unwrap();
+        assert_eq!(output_dir.path().join("my diagram.svg"), output_file);
+        assert_eq!(
+            "<svg>some puml code\nsvg",
+            fs::read_to_string(&output_file).unwrap()
+        );
+        // The hashed image is cached as usual
+        assert!(image_filename(output_dir.path(), "some puml code", "svg").exists());
+
+        // Ditaa diagrams are rendered as png
+        let source = source_dir.path().join("ditaa.puml");
+        fs::write(&source, "@startditaa").unwrap();
+        assert_eq!(
+            output_dir.path().join("ditaa.png"),
+            renderer.render_file(&source).unwrap()
+        );
+
+        assert!(renderer
+            .render_file(&source_dir.path().join("missing.puml"))
+            .is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_rendering_post_process_cmd() {
+        let output_dir = tempdir().unwrap();
+        let renderer = Renderer {
+            post_process_cmd: Some(String::from(
+                "sh -c 'echo \" {format} {hash}\" >> \"$0\"' {file}",
+            )),
+            ..test_renderer(
+                output_dir.path(),
+                Some(Box::new(BackendMock { is_ok: true })),
+            )
         };
 
+        let plantuml_code = "some puml code";
+        let code_hash = hash_string(plantuml_code);
         renderer
-    }
-
-    fn create_md_link(rel_img_url: &str, image_path: &Path, clickable: bool) -> String {
-        let img_url = format!(
-            "{}/{}",
-            rel_img_url,
-            image_path.file_name().unwrap().to_str().unwrap()
+            .render(
+                plantuml_code,
+                Path::new("."),
+                "rel/url",
+                block_options("svg"),
+            )
+            .unwrap();
+        let output_file = image_filename(output_dir.path(), plantuml_code, "svg");
+        assert_eq!(
+            format!("<svg>some puml code\nsvg svg {code_hash}\n"),
+            fs::read_to_string(&output_file).unwrap()
         );
-        if clickable {
-            format!("[![]({img_url})]({img_url})\n\n")
-        } else {
-            format!("![]({img_url})\n\n")
-        }
-    }
+        // No temporary files are left behind
+        assert_eq!(1, fs::read_dir(output_dir.path()).unwrap().count());
 
-    fn create_datauri(image_path: &Path) -> Result<String> {
-        // https://developer.mozilla.org/en-US/docs/Web/HTTP/Basics_of_HTTP/Data_URIs#syntax
-
-        let media_type = match image_path
-            .extension()
-            .map(|s| s.to_str())
-            .unwrap_or(Some(""))
-        {
-            Some("jpg" | "jpeg") => "image/jpeg",
-            Some("png") => "image/png",
-            Some("svg") => "image/svg+xml",
-            Some("atxt" | "utxt" | "txt") => "text/plain",
-            _ => "",
+        // Failed post processing is reported, and the image is not cached
+        let renderer = Renderer {
+            post_process_cmd: Some(String::from("sh -c 'echo bad image >&2; exit 1'")),
+            ..renderer
         };
-
-        let image_data = fs::read(image_path)
-            .with_context(|| format!("Could not open image file {image_path:?}"))?;
-        let encoded_value = encode(image_data);
-        Ok(format!("data:{media_type};base64,{encoded_value}"))
+        let result = renderer.render(
+            "other code",
+            Path::new("."),
+            "rel/url",
+            block_options("svg"),
+        );
+        assert!(format!("{:#}", result.unwrap_err()).contains("bad image"));
+        assert!(!image_filename(output_dir.path(), "other code", "svg").exists());
+        assert_eq!(1, fs::read_dir(output_dir.path()).unwrap().count());
     }
 
-    fn create_image_datauri_element(image_path: &Path, clickable: bool) -> Result<String> {
-        let uri = Self::create_datauri(image_path)?;
-        if clickable {
-            // Note that both Edge and Firefox do not allow clicking on data URI links
-            // So this probably won't work. Kept in here regardless for consistency
-            Ok(format!("[![]({uri})]({uri})\n\n"))
-        } else {
-            Ok(format!("![]({uri})\n\n"))
-        }
-    }
+    #[test]
+    fn test_rendering_figures() {
+        let output_dir = tempdir().unwrap();
+        let renderer = Renderer {
+            anchor_images: true,
+            ..test_renderer(
+                output_dir.path(),
+                Some(Box::new(BackendMock { is_ok: true })),
+            )
+        };
 
-    fn create_inline_txt_image(image_path: &Path) -> Result<String> {
-        log::debug!("Creating inline image from {:?}", image_path);
-        let raw_source = fs::read(image_path).unwrap();
-        let txt = String::from_utf8(raw_source)?;
+        let code_hash = hash_string("title A\nA --|> B");
+        assert_eq!(
+            format!("<a id=\"plantuml-figure-1\"></a>![A](rel/url/{code_hash}.svg)\n\n"),
+            renderer
+                .render(
+                    "title A\nA --|> B",
+                    Path::new("."),
+                    "rel/url",
+                    block_options("svg")
+                )
+                .unwrap()
+        );
+        renderer
+            .render("B --|> C", Path::new("."), "rel/url", block_options("svg"))
+            .unwrap();
+        // Text diagrams are not images, so these are not listed
+        renderer
+            .render("C --|> D", Path::new("."), "rel/url", block_options("txt"))
+            .unwrap();
+        assert_eq!(2, renderer.figure_count());
 
-        Ok(format!("\n```txt\n{txt}```\n"))
+        assert_eq!(
+            vec![
+                Figure {
+                    caption: String::from("A"),
+                    anchor: String::from("plantuml-figure-1"),
+                    id: None,
+                    number: Some(1),
+                    elements: vec![String::from("A"), String::from("B")],
+                },
+                Figure {
+                    caption: String::from("Figure 2"),
+                    anchor: String::from("plantuml-figure-2"),
+                    id: None,
+                    number: Some(2),
+                    elements: vec![String::from("B"), String::from("C")],
+                },
+            ],
+            renderer.take_figures()
+        );
     }
 
-    pub fn render(
-        &self,
-        plantuml_code: &str,
-        rel_img_url: &str,
-        image_format: &str,
-    ) -> Result<String> {
-        // When operating in data-uri mode the images are written to in .mdbook-plantuml, otherwise
-        // they are written to src/mdbook-plantuml-images (cannot write to the book output dir, because
-        // mdbook deletes the files in there after preprocessing)
-        let output_file = image_filename(&self.img_root, plantuml_code, image_format);
-        if !output_file.exists() {
-            // File is not cached, render the image
-            let data = self
-                .backend
-                .render_from_string(plantuml_code, image_format)?;
+    #[test]
+    fn test_rendering_budget() {
+        let output_dir = tempdir().unwrap();
+        let mut renderer = Renderer {
+            render_budget: Some(Duration::ZERO),
+            ..test_renderer(
+                output_dir.path(),
+                Some(Box::new(BackendMock { is_ok: true })),
+            )
+        };
 
-            // Save the file even if we inline images
-            std::fs::write(&output_file, data).with_context(|| {
-                format!(
-                    "Failed to save PlantUML diagram to {}.",
-                    output_file.to_string_lossy()
-                )
-            })?;
-        }
+        let render = |renderer: &Renderer, code: &str| {
+            renderer.render(code, Path::new("."), "rel/url", block_options("svg"))
+        };
+        assert!(!renderer.render_budget_spent());
+        assert!(render(&renderer, "title Slow\nA --|> B").is_ok());
+        assert!(renderer.render_budget_spent());
 
-        // Let the dir cleaner know this file should be kept
-        self.cleaner.borrow_mut().keep(&output_file);
+        // Cached images are still used
+        assert!(render(&renderer, "title Slow\nA --|> B").is_ok());
 
-        let extension = output_file.extension().unwrap_or_default();
-        if extension == "atxt" || extension == "utxt" {
-            Self::create_inline_txt_image(&output_file)
-        } else if self.use_data_uris {
-            Self::create_image_datauri_element(&output_file, self.clickable_img)
-        } else {
-            Ok(Self::create_md_link(
-                rel_img_url,
-                &output_file,
-                self.clickable_img,
-            ))
-        }
-    }
-}
+        let error = render(&renderer, "B --|> C").unwrap_err();
+        assert!(format!("{error}").contains("exceeding max-total-render-seconds (0s)"));
+        assert!(format!("{error}").contains("The slowest diagrams: 'Slow' ("));
 
-impl RendererTrait for Renderer {
-    fn render(
-        &self,
-        plantuml_code: &str,
-        rel_img_url: &str,
-        image_format: String,
-    ) -> Result<String> {
-        Self::render(self, plantuml_code, rel_img_url, &image_format)
+        renderer.budget_placeholders = true;
+        assert_eq!(
+            "\n*PlantUML diagram not rendered (render time budget exceeded)*\n\n",
+            render(&renderer, "C --|> D").unwrap()
+        );
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use anyhow::{bail, Result};
-    use pretty_assertions::assert_eq;
-    use std::fs::File;
-    use std::io::Write;
-    use tempfile::tempdir;
 
     #[test]
-    fn test_create_md_link() {
+    fn test_rendering_without_backend() {
+        let output_dir = tempdir().unwrap();
+        let renderer = test_renderer(output_dir.path(), None);
+
         assert_eq!(
-            String::from("![](foo/bar/baz.svg)\n\n"),
-            Renderer::create_md_link("foo/bar", Path::new("/froboz/baz.svg"), false)
+            "\n*PlantUML diagram not rendered (PlantUML is not available)*\n\n",
+            renderer
+                .render("A --|> B", Path::new("."), "rel/url", block_options("svg"))
+                .unwrap()
         );
-
         assert_eq!(
-            "![](/baz.svg)\n\n",
-            Renderer::create_md_link("", Path::new("baz.svg"), false)
+            "\n*PlantUML diagram 'Foo' not rendered (PlantUML is not available)*\n\n",
+            renderer
+                .render(
+                    "A --|> B",
+                    Path::new("."),
+                    "rel/url",
+                    BlockOptions {
+                        alt_text: Some("Foo"),
+                        ..block_options("svg")
+                    }
+                )
+                .unwrap()
         );
 
+        // Cached images are still used
+        let cached_file = image_filename(output_dir.path(), "B --|> C", "svg");
+        fs::write(&cached_file, "<svg/>").unwrap();
         assert_eq!(
-            String::from("![](/baz.svg)\n\n"),
-            Renderer::create_md_link("", Path::new("foo/baz.svg"), false)
+            format!(
+                "![](rel/url/{})\n\n",
+                cached_file.file_name().unwrap().to_str().unwrap()
+            ),
+            renderer
+                .render("B --|> C", Path::new("."), "rel/url", block_options("svg"))
+                .unwrap()
         );
+
+        // Extra sources cannot be rendered
+        let source = output_dir.path().join("foo.puml");
+        fs::write(&source, "C --|> D").unwrap();
+        assert!(renderer.render_file(&source).is_err());
     }
 
     #[test]
-    fn test_create_datauri() {
-        let temp_directory = tempdir().unwrap();
-        let content = "test content";
+    fn test_rendering_moved_chapter() {
+        let output_dir = tempdir().unwrap();
+        let renderer =
+            |is_ok| test_renderer(output_dir.path(), Some(Box::new(BackendMock { is_ok })));
 
-        let svg_path = temp_directory.path().join("file.svg");
-        let mut svg_file = File::create(&svg_path).unwrap();
-        writeln!(svg_file, "{content}").unwrap();
-        drop(svg_file); // Close and flush content to file
+        let code_hash = hash_string("A --|> B");
         assert_eq!(
-            String::from("data:image/svg+xml;base64,dGVzdCBjb250ZW50Cg=="),
-            Renderer::create_datauri(&svg_path).unwrap()
+            format!("![](img/{code_hash}.svg)\n\n"),
+            renderer(true)
+                .render("A --|> B", Path::new("."), "img", block_options("svg"))
+                .unwrap()
         );
 
-        let png_path = temp_directory.path().join("file.png");
-        let mut png_file = File::create(&png_path).unwrap();
-        writeln!(png_file, "{content}").unwrap();
-        drop(png_file); // Close and flush content to file
+        // The image is named after the diagram (not the chapter), so the cached image is
+        // used after the chapter is moved (the failing backend is not used)
         assert_eq!(
-            String::from("data:image/png;base64,dGVzdCBjb250ZW50Cg=="),
-            Renderer::create_datauri(&png_path).unwrap()
+            format!("![](../../img/{code_hash}.svg)\n\n"),
+            renderer(false)
+                .render(
+                    "A --|> B",
+                    Path::new("."),
+                    "../../img",
+                    block_options("svg")
+                )
+                .unwrap()
         );
+    }
 
-        let txt_path = temp_directory.path().join("file.txt");
-        let mut txt_file = File::create(&txt_path).unwrap();
-        writeln!(txt_file, "{content}").unwrap();
-        drop(txt_file); // Close and flush content to file
+    #[test]
+    fn test_rendering_is_memoized() {
+        let output_dir = tempdir().unwrap();
+        let renderer = Renderer {
+            use_data_uris: true,
+            ..test_renderer(
+                output_dir.path(),
+                Some(Box::new(BackendMock { is_ok: true })),
+            )
+        };
+
+        let first = renderer
+            .render("A --|> B", Path::new("."), "rel/url", block_options("svg"))
+            .unwrap();
+
+        // The image file is not read again for repeated diagrams
+        fs::write(
+            image_filename(output_dir.path(), "A --|> B", "svg"),
+            "<svg/>",
+        )
+        .unwrap();
         assert_eq!(
-            String::from("data:text/plain;base64,dGVzdCBjb250ZW50Cg=="),
-            Renderer::create_datauri(&txt_path).unwrap()
+            first,
+            renderer
+                .render(
+                    "A --|> B",
+                    Path::new("."),
+                    "other/url",
+                    block_options("svg")
+                )
+                .unwrap()
         );
 
-        let jpeg_path = temp_directory.path().join("file.jpeg");
-        let mut jpeg_file = File::create(&jpeg_path).unwrap();
-        writeln!(jpeg_file, "{content}").unwrap();
-        drop(jpeg_file); // Close and flush content to file
+        // But the alt text is still applied per diagram
         assert_eq!(
-            String::from("data:image/jpeg;base64,dGVzdCBjb250ZW50Cg=="),
-            Renderer::create_datauri(&jpeg_path).unwrap()
+            first.replacen("![]", "![Foo]", 1),
+            renderer
+                .render(
+                    "A --|> B",
+                    Path::new("."),
+                    "rel/url",
+                    BlockOptions {
+                        alt_text: Some("Foo"),
+                        ..block_options("svg")
+                    }
+                )
+                .unwrap()
         );
     }
 
-    struct BackendMock {
-        is_ok: bool,
+    #[test]
+    fn test_rendering_source_comment() {
+        let output_dir = tempdir().unwrap();
+        let renderer = Renderer {
+            embed_source_comment: true,
+            ..test_renderer(
+                output_dir.path(),
+                Some(Box::new(BackendMock { is_ok: true })),
+            )
+        };
+
+        let plantuml_code = "A --> B\n";
+        assert_eq!(
+            format!(
+                "![](rel/url/{}.svg)\n\n\n<!-- PlantUML source\nA --&gt; B\n-->\n\n",
+                hash_string(plantuml_code)
+            ),
+            renderer
+                .render(
+                    plantuml_code,
+                    Path::new("."),
+                    "rel/url",
+                    block_options("svg")
+                )
+                .unwrap()
+        );
     }
 
-    impl Backend for BackendMock {
-        fn render_from_string(&self, plantuml_code: &str, image_format: &str) -> Result<Vec<u8>> {
-            if self.is_ok {
-                return Ok(Vec::from(
-                    format!("{plantuml_code}\n{image_format}").as_bytes(),
-                ));
-            }
-            bail!("Oh no");
+    #[test]
+    fn test_rendering_normalized_source() {
+        let output_dir = tempdir().unwrap();
+        let renderer = Renderer {
+            normalize_source: true,
+            ..test_renderer(
+                output_dir.path(),
+                Some(Box::new(BackendMock { is_ok: true })),
+            )
+        };
+
+        let image = format!("![](rel/url/{}.svg)\n\n", hash_string("A --|> B\nB\n"));
+        for code in [
+            "A --|> B\nB\n",
+            "A --|> B\r\nB\r\n",
+            "\u{feff}A --|> B\r\nB\n",
+        ]
+        .iter()
+        {
+            assert_eq!(
+                image,
+                renderer
+                    .render(code, Path::new("."), "rel/url", block_options("svg"))
+                    .unwrap()
+            );
         }
+
+        let renderer = Renderer {
+            normalize_source: false,
+            ..renderer
+        };
+        assert_eq!("A --|> B\r\n", renderer.normalized("A --|> B\r\n").as_ref());
     }
 
     #[test]
-    fn test_rendering_md_link() {
+    fn test_rendering_signed_cache() {
         let output_dir = tempdir().unwrap();
         let renderer = Renderer {
-            backend: Box::new(BackendMock { is_ok: true }),
-            cleaner: RefCell::new(DirCleaner::new(output_dir.path())),
-            img_root: output_dir.path().to_path_buf(),
-            clickable_img: false,
-            use_data_uris: false,
+            signing_key: Some(b"secret".to_vec()),
+            ..test_renderer(
+                output_dir.path(),
+                Some(Box::new(BackendMock { is_ok: true })),
+            )
         };
 
-        let plantuml_code = "some puml code";
-        let code_hash = hash_string(plantuml_code);
+        let output_file = image_filename(output_dir.path(), "A --|> B", "svg");
+        let image = renderer
+            .cached_image("A --|> B", &hash_string("A --|> B"), "svg")
+            .unwrap();
+        assert_eq!(output_file, image);
+        assert!(signature_file(&output_file).exists());
+        assert!(renderer.is_cached(&output_file));
 
+        // A poisoned cache entry is rendered again
+        fs::write(&output_file, "<svg><script>alert(1)</script></svg>").unwrap();
+        assert!(!renderer.is_cached(&output_file));
+        renderer
+            .cached_image("A --|> B", &hash_string("A --|> B"), "svg")
+            .unwrap();
         assert_eq!(
-            format!("![](rel/url/{code_hash}.svg)\n\n"),
-            renderer.render(plantuml_code, "rel/url", "svg").unwrap()
+            "<svg>A --|> B\nsvg",
+            fs::read_to_string(&output_file).unwrap()
         );
 
-        // png extension
+        // As is an unsigned one
+        fs::remove_file(signature_file(&output_file)).unwrap();
+        assert!(!renderer.is_cached(&output_file));
+        let renderer = renderer.with_signing_key(Some(b"other".to_vec()));
+        renderer
+            .cached_image("A --|> B", &hash_string("A --|> B"), "svg")
+            .unwrap();
+        assert!(renderer.is_cached(&output_file));
+    }
+
+    #[test]
+    fn test_rendering_id_anchors() {
+        let output_dir = tempdir().unwrap();
+        let renderer = test_renderer(
+            output_dir.path(),
+            Some(Box::new(BackendMock { is_ok: true })),
+        );
+
+        // No anchor without id (when not numbering figures)
+        let code_hash = hash_string("A --|> B");
         assert_eq!(
-            format!("![](rel/url/{code_hash}.png)\n\n"),
-            renderer.render(plantuml_code, "rel/url", "png").unwrap()
+            format!("![](rel/url/{code_hash}.svg)\n\n"),
+            renderer
+                .render("A --|> B", Path::new("."), "rel/url", block_options("svg"))
+                .unwrap()
+        );
+        assert_eq!(
+            format!("<a id=\"diag-my-id\"></a>![](rel/url/{code_hash}.svg)\n\n"),
+            renderer
+                .render(
+                    "A --|> B",
+                    Path::new("."),
+                    "rel/url",
+                    BlockOptions {
+                        id: Some("my-id"),
+                        ..block_options("svg")
+                    }
+                )
+                .unwrap()
+        );
+        assert_eq!(
+            "<a id=\"diag-a--b-\"></a>\n```txt\nfoo\ntxt```\n",
+            renderer
+                .render(
+                    "foo",
+                    Path::new("."),
+                    "rel/url",
+                    BlockOptions {
+                        alt_text: Some("Text"),
+                        id: Some("a\"<b>"),
+                        ..block_options("txt")
+                    }
+                )
+                .unwrap()
         );
 
-        // txt extension
         assert_eq!(
-            format!("\n```txt\n{plantuml_code}\ntxt```\n"), /* image format is appended by
-                                                             * fake backend */
-            renderer.render(plantuml_code, "rel/url", "txt").unwrap()
+            vec![
+                Figure {
+                    caption: String::from("my-id"),
+                    anchor: String::from("diag-my-id"),
+                    id: Some(String::from("my-id")),
+                    number: None,
+                    elements: vec![String::from("A"), String::from("B")],
+                },
+                Figure {
+                    caption: String::from("Text"),
+                    anchor: String::from("diag-a--b-"),
+                    id: Some(String::from("a\"<b>")),
+                    number: None,
+                    elements: vec![],
+                },
+            ],
+            renderer.take_figures()
         );
+    }
+
+    #[test]
+    fn test_validate_image_data() {
+        let png_magic = b"\x89PNG\r\n\x1a\nfoo";
+
+        assert!(validate_image_data(b"<?xml version=\"1.0\"?><svg/>", Path::new("a.svg")).is_ok());
+        assert!(validate_image_data(b"<svg></svg>", Path::new("a.svg")).is_ok());
+        assert!(validate_image_data(b"\xEF\xBB\xBF\n  <svg></svg>", Path::new("a.svg")).is_ok());
+        assert!(validate_image_data(png_magic, Path::new("a.png")).is_ok());
+        assert!(validate_image_data(png_magic, Path::new("a.braille.png")).is_ok());
+        assert!(validate_image_data(b"%!PS-Adobe-3.0", Path::new("a.eps")).is_ok());
+        assert!(validate_image_data(b"%PDF-1.4", Path::new("a.pdf")).is_ok());
+
+        // Formats without a known header are not validated
+        assert!(validate_image_data(b"anything", Path::new("a.atxt")).is_ok());
+        assert!(validate_image_data(b"anything", Path::new("a.utxt")).is_ok());
 
-        // utxt extension
         assert_eq!(
-            format!("\n```txt\n{plantuml_code}\ntxt```\n"), /* image format is appended by
-                                                             * fake backend */
-            renderer.render(plantuml_code, "rel/url", "txt").unwrap()
+            "PlantUML rendered a png image, but a svg image was requested.",
+            format!(
+                "{}",
+                validate_image_data(png_magic, Path::new("a.svg")).unwrap_err()
+            )
+        );
+        assert_eq!(
+            "PlantUML rendered an empty image, but a png image was requested.",
+            format!(
+                "{}",
+                validate_image_data(b"", Path::new("a.png")).unwrap_err()
+            )
+        );
+        assert_eq!(
+            "PlantUML output is not a valid svg image (starts with \"<html>\").",
+            format!(
+                "{}",
+                validate_image_data(b"<html>", Path::new("a.svg")).unwrap_err()
+            )
         );
     }
 
     #[test]
-    fn test_rendering_datauri() {
-        let output_dir = tempdir().unwrap();
-        let renderer = Renderer {
-            backend: Box::new(BackendMock { is_ok: true }),
-            cleaner: RefCell::new(DirCleaner::new(output_dir.path())),
-            img_root: output_dir.path().to_path_buf(),
-            clickable_img: false,
-            use_data_uris: true,
+    fn test_truncated_size() {
+        let png = |width: u32, height: u32| {
+            let mut data = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec();
+            data.extend_from_slice(&width.to_be_bytes());
+            data.extend_from_slice(&height.to_be_bytes());
+            data
         };
+        assert_eq!(None, truncated_size(&png(4095, 100), 4096));
+        assert_eq!(Some((4096, 100)), truncated_size(&png(4096, 100), 4096));
+        assert_eq!(Some((100, 8192)), truncated_size(&png(100, 8192), 4096));
+        assert_eq!(None, truncated_size(&png(4096, 100), 8192));
 
-        let plantuml_code = "some puml code";
+        // Only png images are checked
+        assert_eq!(None, truncated_size(b"<svg width=\"4096px\"></svg>", 4096));
+        assert_eq!(None, truncated_size(b"\x89PNG", 4096));
+    }
 
-        // svg extension
+    #[test]
+    fn test_clipped_size() {
+        let png = |width: u32| {
+            let mut data = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec();
+            data.extend_from_slice(&width.to_be_bytes());
+            data.extend_from_slice(&100u32.to_be_bytes());
+            data
+        };
+        let output_dir = tempdir().unwrap();
+        let renderer = |retry_truncated| {
+            let cfg = Config {
+                limit_size: Some(4096),
+                retry_truncated,
+                ..Config::default()
+            };
+            Renderer::new(&cfg, None, output_dir.path().to_path_buf())
+        };
+
+        let renderer_without_retry = renderer(false);
+        assert_eq!(None, renderer_without_retry.clipped_size(&png(4095)));
         assert_eq!(
-            format!(
-                "![]({})\n\n",
-                "data:image/svg+xml;base64,c29tZSBwdW1sIGNvZGUKc3Zn"
-            ),
-            renderer.render(plantuml_code, "rel/url", "svg").unwrap()
+            Some((4096, 100)),
+            renderer_without_retry.clipped_size(&png(4096))
+        );
+        assert_eq!(
+            Some((5000, 100)),
+            renderer_without_retry.clipped_size(&png(5000))
         );
 
-        // png extension
+        // Larger images were rendered again, they are only clipped at the raised limit size
+        let renderer_with_retry = renderer(true);
         assert_eq!(
-            format!(
-                "![]({})\n\n",
-                "data:image/png;base64,c29tZSBwdW1sIGNvZGUKcG5n"
-            ),
-            renderer.render(plantuml_code, "rel/url", "png").unwrap()
+            Some((4096, 100)),
+            renderer_with_retry.clipped_size(&png(4096))
+        );
+        assert_eq!(None, renderer_with_retry.clipped_size(&png(5000)));
+        assert_eq!(
+            Some((8192, 100)),
+            renderer_with_retry.clipped_size(&png(8192))
         );
+    }
 
-        // txt extension
+    #[test]
+    fn test_suspicious_image() {
+        let svg = Path::new("a.svg");
+        assert_eq!(None, suspicious_image(b"<svg><g>A</g></svg>", svg));
         assert_eq!(
-            String::from("\n```txt\nsome puml code\ntxt```\n"),
-            renderer.render(plantuml_code, "rel/url", "txt").unwrap()
+            None,
+            suspicious_image(b"<?xml?><svg width=\"10px\" height=\"1px\"><g/></svg>", svg)
+        );
+        assert_eq!(
+            Some("the SVG image has no content"),
+            suspicious_image(b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>", svg)
+        );
+        assert_eq!(
+            Some("the SVG image has no content"),
+            suspicious_image(b"<svg>\n  </svg>", svg)
+        );
+        assert_eq!(
+            Some("the image is 1x1 pixels"),
+            suspicious_image(b"<svg width=\"1px\" height=\"1px\"><g/></svg>", svg)
         );
 
-        // utxt extension
+        let png = |width: u8, height: u8, end: &[u8]| -> Vec<u8> {
+            let mut data = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+            data.extend_from_slice(&[0, 0, 0, width, 0, 0, 0, height]);
+            data.extend_from_slice(end);
+            data
+        };
+        let iend = b"\0\0\0\0IEND\xAE\x42\x60\x82";
         assert_eq!(
-            String::from("\n```txt\nsome puml code\ntxt```\n"),
-            renderer.render(plantuml_code, "rel/url", "txt").unwrap()
+            None,
+            suspicious_image(&png(20, 10, iend), Path::new("a.png"))
+        );
+        assert_eq!(
+            Some("the image is 1x1 pixels"),
+            suspicious_image(&png(1, 1, iend), Path::new("a.png"))
+        );
+        assert_eq!(
+            Some("the PNG image is truncated"),
+            suspicious_image(&png(20, 10, b"IDAT"), Path::new("a.png"))
         );
+
+        // Other formats are not checked
+        assert_eq!(None, suspicious_image(b"", Path::new("a.atxt")));
     }
 
     #[test]
-    fn test_rendering_failure() {
+    fn test_prepare_suspicious_image_data() {
+        // Suspicious images are not cached
+        let error = prepare_image_data(b"<svg/>", Path::new("a.svg"), false).unwrap_err();
+        assert!(error.to_string().contains("the SVG image has no content"));
+        assert!(prepare_image_data(b"<svg><g/></svg>", Path::new("a.svg"), false).is_ok());
+    }
+
+    #[test]
+    fn test_rendering_empty_cache_entry() {
         let output_dir = tempdir().unwrap();
-        let renderer = Renderer {
-            backend: Box::new(BackendMock { is_ok: false }),
-            cleaner: RefCell::new(DirCleaner::new(output_dir.path())),
-            img_root: output_dir.path().to_path_buf(),
-            clickable_img: false,
-            use_data_uris: false,
-        };
+        let renderer = test_renderer(
+            output_dir.path(),
+            Some(Box::new(BackendMock { is_ok: true })),
+        );
 
-        let result = renderer.render("", "rel/url", "svg");
-        let error_str = format!("{}", result.err().unwrap());
-        assert_eq!("Oh no", error_str);
+        // The empty cached image is rendered again
+        let cached_file = image_filename(output_dir.path(), "A --|> B", "svg");
+        fs::write(&cached_file, "").unwrap();
+        renderer
+            .render("A --|> B", Path::new("."), "rel/url", block_options("svg"))
+            .unwrap();
+        assert_eq!(
+            "<svg>A --|> B\nsvg",
+            fs::read_to_string(&cached_file).unwrap()
+        );
+
+        // Without a backend the empty image cannot be rendered again, so it is used
+        fs::write(&cached_file, "").unwrap();
+        let renderer = test_renderer(output_dir.path(), None);
+        assert!(!renderer.is_empty_cache_entry(&cached_file));
     }
 
     #[test]
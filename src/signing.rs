@@ -0,0 +1,80 @@
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+type HmacSha1 = Hmac<Sha1>;
+
+/// The signature file (`<image>.sig`) extension of a signed cache entry
+pub const SIGNATURE_EXTENSION: &str = "sig";
+
+/// The HMAC-SHA1 (RFC 2104) of the parts, every part is prefixed with its length (a big endian
+/// u64), so data cannot be moved from one part to the next without changing the signature
+fn mac(key: &[u8], parts: &[&[u8]]) -> HmacSha1 {
+    // We can simply unwrap, because HMAC accepts keys of any length
+    let mut mac = HmacSha1::new_from_slice(key).unwrap();
+    for part in parts {
+        mac.update(&(part.len() as u64).to_be_bytes());
+        mac.update(part);
+    }
+    mac
+}
+
+/// Sign the data with HMAC-SHA1, returns the hex encoded signature
+/// # Arguments
+/// * `key` - The secret key
+/// * `parts` - The data to sign
+pub fn sign(key: &[u8], parts: &[&[u8]]) -> String {
+    base16ct::lower::encode_string(&mac(key, parts).finalize().into_bytes())
+}
+
+/// Returns true if the (hex encoded) signature matches the data (compared in constant time)
+pub fn verify(key: &[u8], parts: &[&[u8]], signature: &str) -> bool {
+    match base16ct::lower::decode_vec(signature.trim()) {
+        Ok(signature) => mac(key, parts).verify_slice(&signature).is_ok(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_sign() {
+        // RFC 2202 test case 2, with the length prefix of the (single) part
+        let mut data = 28u64.to_be_bytes().to_vec();
+        data.extend_from_slice(b"what do ya want for nothing?");
+        let mut mac = HmacSha1::new_from_slice(b"Jefe").unwrap();
+        mac.update(&data);
+        assert_eq!(
+            base16ct::lower::encode_string(&mac.finalize().into_bytes()),
+            sign(b"Jefe", &[b"what do ya want for nothing?"])
+        );
+        assert_eq!(
+            40,
+            sign(&[0xaa; 80], &[b"Larger than block size key"]).len()
+        );
+
+        // The parts cannot be shifted into each other
+        assert_ne!(sign(b"key", &[b"a\0b"]), sign(b"key", &[b"a", b"b"]));
+        assert_ne!(sign(b"key", &[b"ab"]), sign(b"key", &[b"a", b"b"]));
+        assert_ne!(sign(b"key", &[b"a", b"bc"]), sign(b"key", &[b"ab", b"c"]));
+        assert_ne!(sign(b"key", &[b""]), sign(b"key", &[]));
+    }
+
+    #[test]
+    fn test_verify() {
+        let signature = sign(b"key", &[b"name", b"data"]);
+        assert!(verify(b"key", &[b"name", b"data"], &signature));
+        assert!(verify(
+            b"key",
+            &[b"name", b"data"],
+            &format!("{signature}\n")
+        ));
+        assert!(!verify(b"other key", &[b"name", b"data"], &signature));
+        assert!(!verify(b"key", &[b"name", b"poisoned"], &signature));
+        assert!(!verify(b"key", &[b"name", b"data"], ""));
+        assert!(!verify(b"key", &[b"name", b"data"], "not hex"));
+        assert!(!verify(b"key", &[b"name", b"data"], &signature[..38]));
+    }
+}
@@ -0,0 +1,412 @@
+use crate::{config_from_book_config, image_dir, resolve_config_paths};
+use anyhow::{bail, ensure, format_err, Context, Result};
+use mdbook::MDBook;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// The name of the manifest entry (the first entry) of a cache snapshot
+const MANIFEST_NAME: &str = "mdbook-plantuml-snapshot.json";
+
+/// The version of the snapshot layout, incremented when it changes incompatibly
+const SNAPSHOT_FORMAT: u32 = 1;
+
+/// The algorithm of the cache hashes (the cached image file names)
+const HASH_ALGORITHM: &str = "sha1";
+
+/// Describes the cache a snapshot was exported from, a snapshot is only imported when the
+/// cache entries are usable by this build (same hash scheme and backend identity)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct SnapshotManifest {
+    format: u32,
+    hash_algorithm: String,
+    /// The mdbook-plantuml version that exported the snapshot
+    tool_version: String,
+    /// The backend identity of the cache hashes (see Config::cache_backend_key)
+    backend: Option<String>,
+}
+
+impl SnapshotManifest {
+    fn new(backend: Option<String>) -> Self {
+        Self {
+            format: SNAPSHOT_FORMAT,
+            hash_algorithm: String::from(HASH_ALGORITHM),
+            tool_version: String::from(env!("CARGO_PKG_VERSION")),
+            backend,
+        }
+    }
+
+    /// Check whether the snapshot's cache entries can be used by this build, the hash scheme
+    /// may change between minor versions, so the major and minor version must match
+    fn check_compatible(&self, backend: Option<&str>) -> Result<()> {
+        ensure!(
+            self.format == SNAPSHOT_FORMAT,
+            "Unsupported snapshot format {} (expected {})",
+            self.format,
+            SNAPSHOT_FORMAT
+        );
+        ensure!(
+            self.hash_algorithm == HASH_ALGORITHM,
+            "The snapshot uses the {} hash algorithm (expected {})",
+            self.hash_algorithm,
+            HASH_ALGORITHM
+        );
+
+        let minor_version = |version: &str| -> Vec<String> {
+            version.splitn(3, '.').take(2).map(String::from).collect()
+        };
+        ensure!(
+            minor_version(&self.tool_version) == minor_version(env!("CARGO_PKG_VERSION")),
+            "The snapshot was exported by mdbook-plantuml {} (this is {})",
+            self.tool_version,
+            env!("CARGO_PKG_VERSION")
+        );
+        ensure!(
+            self.backend.as_deref() == backend,
+            "The snapshot was exported for backend '{}' (the book uses '{}', see \
+             share-cache-across-backends)",
+            self.backend.as_deref().unwrap_or("any"),
+            backend.unwrap_or("any")
+        );
+
+        Ok(())
+    }
+}
+
+/// The book's image cache dir and backend identity
+fn book_cache(book_dir: &Path) -> Result<(PathBuf, Option<String>)> {
+    let md = MDBook::load(book_dir)
+        .map_err(|e| format_err!("{:#}", e))
+        .with_context(|| format!("Failed to load the book from {book_dir:?}"))?;
+    let book_root = dunce::canonicalize(book_dir)?;
+    let mut cfg = config_from_book_config(&md.config)?;
+    resolve_config_paths(&mut cfg, &book_root);
+
+    Ok((
+        image_dir(&book_root, &md.config.book.src, &cfg),
+        cfg.cache_backend_key(),
+    ))
+}
+
+/// Returns true if the file name is a cache entry, i.e. a cached image (or its signature)
+/// named after the diagram's hash
+fn is_cache_entry(file_name: &str) -> bool {
+    match file_name.split_once('.') {
+        Some((hash, extension)) => {
+            hash.len() == 40
+                && hash.bytes().all(|c| c.is_ascii_hexdigit())
+                && !extension.is_empty()
+                && extension
+                    .bytes()
+                    .all(|c| c.is_ascii_alphanumeric() || c == b'.')
+        }
+        None => false,
+    }
+}
+
+/// Export the book's cached images to a (tar) snapshot, so the cache can be committed or
+/// stored as CI artifact and restored with import_cache_snapshot. Returns the number of
+/// exported cache entries.
+/// # Arguments
+/// * `book_dir` - The book's root directory (containing book.toml)
+/// * `tarball` - The snapshot file to write
+pub fn export_cache_snapshot(book_dir: &Path, tarball: &Path) -> Result<usize> {
+    let (cache_dir, backend) = book_cache(book_dir)?;
+
+    let mut names: Vec<String> = match fs::read_dir(&cache_dir) {
+        Ok(entries) => entries
+            .flatten()
+            .filter(|entry| entry.path().is_file())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .filter(|name| is_cache_entry(name))
+            .collect(),
+        Err(_) => vec![],
+    };
+    // Sorted, so the same cache results in the same snapshot
+    names.sort();
+
+    let write_snapshot = || -> Result<()> {
+        let mut archive = tar::Builder::new(BufWriter::new(File::create(tarball)?));
+        let manifest = serde_json::to_vec_pretty(&SnapshotManifest::new(backend))?;
+        append_tar_entry(
+            &mut archive,
+            MANIFEST_NAME,
+            manifest.len() as u64,
+            &manifest[..],
+        )?;
+        for name in &names {
+            let file = File::open(cache_dir.join(name))
+                .with_context(|| format!("Failed to read the cache entry {name}"))?;
+            append_tar_entry(&mut archive, name, file.metadata()?.len(), file)?;
+        }
+        archive.into_inner()?.flush()?;
+        Ok(())
+    };
+    write_snapshot().with_context(|| format!("Failed to write the cache snapshot {tarball:?}"))?;
+
+    Ok(names.len())
+}
+
+/// Restore the book's cached images from a snapshot created by export_cache_snapshot. The
+/// snapshot is rejected when its cache entries cannot be used by this build (see
+/// SnapshotManifest). Returns the number of imported cache entries, entries that are cached
+/// already are kept.
+/// # Arguments
+/// * `book_dir` - The book's root directory (containing book.toml)
+/// * `tarball` - The snapshot file to read
+pub fn import_cache_snapshot(book_dir: &Path, tarball: &Path) -> Result<usize> {
+    let (cache_dir, backend) = book_cache(book_dir)?;
+
+    let file = File::open(tarball)
+        .with_context(|| format!("Failed to read the cache snapshot {tarball:?}"))?;
+    let mut archive = tar::Archive::new(BufReader::new(file));
+    let invalid = || format!("Invalid cache snapshot {tarball:?}");
+    let mut entries = archive.entries().with_context(invalid)?;
+
+    let manifest: SnapshotManifest = match entries.next() {
+        Some(entry) => {
+            let entry = entry.with_context(invalid)?;
+            if entry.path().with_context(invalid)?.as_ref() != Path::new(MANIFEST_NAME) {
+                bail!("The cache snapshot {:?} has no manifest", tarball);
+            }
+            serde_json::from_reader(entry)
+                .with_context(|| format!("Invalid cache snapshot manifest in {tarball:?}"))?
+        }
+        None => bail!("The cache snapshot {:?} has no manifest", tarball),
+    };
+    manifest
+        .check_compatible(backend.as_deref())
+        .with_context(|| format!("Incompatible cache snapshot {tarball:?}"))?;
+
+    fs::create_dir_all(&cache_dir)
+        .with_context(|| format!("Failed to create the cache dir {cache_dir:?}"))?;
+    let mut imported = 0;
+    for entry in entries {
+        let mut entry = entry.with_context(invalid)?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        // Only plain cache entry names, so the snapshot cannot write outside the cache dir
+        let name = entry
+            .path()
+            .with_context(invalid)?
+            .to_string_lossy()
+            .into_owned();
+        if !is_cache_entry(&name) {
+            bail!(
+                "The cache snapshot {:?} contains an unexpected entry '{}'",
+                tarball,
+                name
+            );
+        }
+
+        let path = cache_dir.join(&name);
+        if !path.exists() {
+            // Written to a temporary file first, so a truncated snapshot leaves no partial image
+            let mut tmp_file = tempfile::NamedTempFile::new_in(&cache_dir)?;
+            let size = io::copy(&mut entry, &mut tmp_file).with_context(invalid)?;
+            ensure!(
+                size == entry.size(),
+                "The cache snapshot {:?} is truncated (entry '{}')",
+                tarball,
+                name
+            );
+            tmp_file
+                .persist(&path)
+                .with_context(|| format!("Failed to write {path:?}"))?;
+            imported += 1;
+        }
+    }
+
+    Ok(imported)
+}
+
+/// Append a regular file to the (ustar) archive. The modification time is left zero, so
+/// exporting the same cache twice results in the same archive.
+fn append_tar_entry<W: Write>(
+    archive: &mut tar::Builder<W>,
+    name: &str,
+    size: u64,
+    data: impl Read,
+) -> Result<()> {
+    let mut header = tar::Header::new_ustar();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_mode(0o644);
+    header.set_size(size);
+    archive
+        .append_data(&mut header, name, data)
+        .with_context(|| format!("Failed to add '{name}' to the snapshot"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::hash_string;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    fn create_book(book_toml: &str) -> tempfile::TempDir {
+        let book_dir = tempdir().unwrap();
+        let src_dir = book_dir.path().join("src");
+        fs::create_dir_all(&src_dir).unwrap();
+        fs::write(book_dir.path().join("book.toml"), book_toml).unwrap();
+        fs::write(
+            src_dir.join("SUMMARY.md"),
+            "# Summary\n\n- [Intro](intro.md)\n",
+        )
+        .unwrap();
+        fs::write(src_dir.join("intro.md"), "# Intro\n").unwrap();
+        book_dir
+    }
+
+    #[test]
+    fn test_append_tar_entry() {
+        let mut archive = tar::Builder::new(Vec::new());
+        append_tar_entry(&mut archive, "a.txt", 1, &b"A"[..]).unwrap();
+        append_tar_entry(&mut archive, "empty", 0, &b""[..]).unwrap();
+        append_tar_entry(&mut archive, "b.bin", 600, &[1u8; 600][..]).unwrap();
+        let archive = archive.into_inner().unwrap();
+
+        let mut reader = tar::Archive::new(&archive[..]);
+        let entries: Vec<(String, Vec<u8>, u64)> = reader
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let mut data = vec![];
+                entry.read_to_end(&mut data).unwrap();
+                let name = entry.path().unwrap().to_string_lossy().into_owned();
+                (name, data, entry.header().mtime().unwrap())
+            })
+            .collect();
+        // No modification times, so the same cache results in the same archive
+        assert_eq!(
+            vec![
+                (String::from("a.txt"), b"A".to_vec(), 0),
+                (String::from("empty"), vec![], 0),
+                (String::from("b.bin"), vec![1u8; 600], 0),
+            ],
+            entries
+        );
+    }
+
+    #[test]
+    fn test_is_cache_entry() {
+        let hash = hash_string("A --|> B");
+        assert!(is_cache_entry(&format!("{hash}.svg")));
+        assert!(is_cache_entry(&format!("{hash}.braille.png")));
+        assert!(is_cache_entry(&format!("{hash}.svg.sig")));
+
+        assert!(!is_cache_entry(&hash));
+        assert!(!is_cache_entry("backend-detection.json"));
+        assert!(!is_cache_entry(".gitignore"));
+        assert!(!is_cache_entry(&format!("{hash}./../foo")));
+    }
+
+    #[test]
+    fn test_export_import() {
+        let book_dir = create_book("[preprocessor.plantuml]\n");
+        let cache_dir = book_dir.path().join(".mdbook-plantuml-cache");
+        fs::create_dir_all(&cache_dir).unwrap();
+        let image = format!("{}.svg", hash_string("A --|> B"));
+        fs::write(cache_dir.join(&image), "<svg>A</svg>").unwrap();
+        fs::write(cache_dir.join("backend-detection.json"), "{}").unwrap();
+
+        let snapshot_dir = tempdir().unwrap();
+        let tarball = snapshot_dir.path().join("cache.tar");
+        assert_eq!(1, export_cache_snapshot(book_dir.path(), &tarball).unwrap());
+
+        let other_book = create_book("[preprocessor.plantuml]\n");
+        assert_eq!(
+            1,
+            import_cache_snapshot(other_book.path(), &tarball).unwrap()
+        );
+        assert_eq!(
+            "<svg>A</svg>",
+            fs::read_to_string(
+                other_book
+                    .path()
+                    .join(".mdbook-plantuml-cache")
+                    .join(&image)
+            )
+            .unwrap()
+        );
+        assert!(!other_book
+            .path()
+            .join(".mdbook-plantuml-cache/backend-detection.json")
+            .exists());
+
+        // Cached entries are kept
+        assert_eq!(
+            0,
+            import_cache_snapshot(other_book.path(), &tarball).unwrap()
+        );
+
+        // A truncated snapshot leaves no partial cache entries
+        let truncated_book = create_book("[preprocessor.plantuml]\n");
+        let archive = fs::read(&tarball).unwrap();
+        let truncated = snapshot_dir.path().join("truncated.tar");
+        fs::write(&truncated, &archive[..archive.len() / 2]).unwrap();
+        assert!(import_cache_snapshot(truncated_book.path(), &truncated).is_err());
+        assert!(!truncated_book
+            .path()
+            .join(".mdbook-plantuml-cache")
+            .join(&image)
+            .exists());
+
+        // Snapshots of another backend are rejected
+        let server_book =
+            create_book("[preprocessor.plantuml]\nplantuml-cmd = \"http://localhost:8080\"\n");
+        let error = import_cache_snapshot(server_book.path(), &tarball).unwrap_err();
+        assert!(format!("{error:#}").contains("exported for backend 'shell'"));
+    }
+
+    #[test]
+    fn test_check_compatible() {
+        let manifest = SnapshotManifest::new(Some(String::from("shell")));
+        assert!(manifest.check_compatible(Some("shell")).is_ok());
+        assert!(manifest.check_compatible(None).is_err());
+
+        let manifest = SnapshotManifest {
+            tool_version: String::from("0.1.0"),
+            ..SnapshotManifest::new(None)
+        };
+        assert!(manifest.check_compatible(None).is_err());
+
+        let manifest = SnapshotManifest {
+            hash_algorithm: String::from("sha256"),
+            ..SnapshotManifest::new(None)
+        };
+        assert!(manifest.check_compatible(None).is_err());
+    }
+
+    #[test]
+    fn test_import_rejects_unexpected_entries() {
+        let book_dir = create_book("[preprocessor.plantuml]\n");
+        let manifest =
+            serde_json::to_vec(&SnapshotManifest::new(Some(String::from("shell")))).unwrap();
+        let mut archive = tar::Builder::new(Vec::new());
+        append_tar_entry(
+            &mut archive,
+            MANIFEST_NAME,
+            manifest.len() as u64,
+            &manifest[..],
+        )
+        .unwrap();
+        // The tar crate does not add paths with `..`, so the name is set in the header itself
+        let mut header = tar::Header::new_ustar();
+        header.as_old_mut().name[..12].copy_from_slice(b"../book.toml");
+        header.set_size(0);
+        header.set_cksum();
+        archive.append(&header, &b""[..]).unwrap();
+
+        let snapshot_dir = tempdir().unwrap();
+        let tarball = snapshot_dir.path().join("cache.tar");
+        fs::write(&tarball, archive.into_inner().unwrap()).unwrap();
+        let error = import_cache_snapshot(book_dir.path(), &tarball).unwrap_err();
+        assert!(format!("{error:#}").contains("unexpected entry '../book.toml'"));
+    }
+}
@@ -0,0 +1,129 @@
+use std::fmt::Write;
+
+/// Make a PlantUML SVG image diff friendly, so committed images only change when the diagram
+/// changes. The comments (holding e.g. the source's MD5) and the PlantUML processing
+/// instructions (the PlantUML version and the encoded source) are removed, and the (partly
+/// random) ids are renumbered in order of appearance, including the references to them.
+pub fn stable_svg(svg: &str) -> String {
+    let svg = strip_between(svg, "<!--", "-->");
+    let svg = strip_between(&svg, "<?plantuml", "?>");
+    renumber_ids(&svg)
+}
+
+/// Remove all text from start up to and including end
+fn strip_between(text: &str, start: &str, end: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(pos) = rest.find(start) {
+        stripped.push_str(&rest[..pos]);
+        rest = match rest[pos..].find(end) {
+            Some(end_pos) => &rest[pos + end_pos + end.len()..],
+            None => "",
+        };
+    }
+    stripped.push_str(rest);
+
+    stripped
+}
+
+/// Returns true if the text ends with an id attribute's opening quote (`id="`)
+fn ends_with_id_attribute(text: &str) -> bool {
+    text.strip_suffix("id=\"")
+        .and_then(|head| head.chars().last())
+        .map_or(false, char::is_whitespace)
+}
+
+/// The element ids in the SVG, in order of appearance
+fn svg_ids(svg: &str) -> Vec<&str> {
+    let mut ids: Vec<&str> = vec![];
+    for (pos, _) in svg.match_indices('"') {
+        if ends_with_id_attribute(&svg[..=pos]) {
+            let value = &svg[pos + 1..];
+            let id = &value[..value.find('"').unwrap_or(value.len())];
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+    }
+
+    ids
+}
+
+/// Rename the ids to id1, id2, etc. (in order of appearance), the `"#id"` and `url(#id)`
+/// references are renamed too
+fn renumber_ids(svg: &str) -> String {
+    let ids = svg_ids(svg);
+    if ids.is_empty() {
+        return svg.to_string();
+    }
+
+    let mut renumbered = String::with_capacity(svg.len());
+    let mut rest = svg;
+    while let Some(pos) = rest.find(['"', '(']) {
+        renumbered.push_str(&rest[..=pos]);
+        rest = &rest[pos + 1..];
+
+        let is_reference = rest.starts_with('#');
+        let is_id = !is_reference && ends_with_id_attribute(&renumbered);
+        if is_id || is_reference {
+            if is_reference {
+                renumbered.push('#');
+                rest = &rest[1..];
+            }
+            let end = rest.find(['"', ')']).unwrap_or(rest.len());
+            let name = &rest[..end];
+            match ids.iter().position(|id| *id == name) {
+                Some(index) => {
+                    let _ = write!(renumbered, "id{}", index + 1);
+                }
+                None => renumbered.push_str(name),
+            }
+            rest = &rest[end..];
+        }
+    }
+    renumbered.push_str(rest);
+
+    renumbered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_stable_svg() {
+        let svg = "<?xml version=\"1.0\"?><svg><!--MD5=[0a1b]\nclass A--><defs>\
+                   <filter id=\"fz1x\"/><linearGradient id=\"gq7a\"/></defs>\
+                   <g id=\"elem_A\"><rect fill=\"url(#gq7a)\" filter=\"url(#fz1x)\" stroke=\"#181818\"/>\
+                   <use href=\"#elem_A\"/></g><?plantuml 1.2022.7?><?plantuml-src SoWkIImgAStDuN?></svg>";
+        assert_eq!(
+            "<?xml version=\"1.0\"?><svg><defs>\
+             <filter id=\"id1\"/><linearGradient id=\"id2\"/></defs>\
+             <g id=\"id3\"><rect fill=\"url(#id2)\" filter=\"url(#id1)\" stroke=\"#181818\"/>\
+             <use href=\"#id3\"/></g></svg>",
+            stable_svg(svg)
+        );
+
+        // Renders the same for differing random ids
+        assert_eq!(
+            stable_svg("<svg><filter id=\"abc\"/><rect filter=\"url(#abc)\"/></svg>"),
+            stable_svg("<svg><filter id=\"xyz\"/><rect filter=\"url(#xyz)\"/></svg>")
+        );
+    }
+
+    #[test]
+    fn test_strip_between() {
+        assert_eq!("ac", strip_between("a<!-- b -->c", "<!--", "-->"));
+        assert_eq!("a", strip_between("a<!-- b", "<!--", "-->"));
+        assert_eq!("abc", strip_between("abc", "<!--", "-->"));
+    }
+
+    #[test]
+    fn test_svg_ids() {
+        assert_eq!(
+            vec!["a", "b"],
+            svg_ids("<g id=\"a\"><g\nid=\"b\" data-id=\"c\"><g id=\"a\">")
+        );
+    }
+}
@@ -0,0 +1,168 @@
+use crate::failure::{tag, FailureKind};
+use crate::STATE_DIR;
+use anyhow::{format_err, Result};
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The crates.io API endpoint with the published versions of this crate
+#[cfg(any(feature = "plantuml-ssl-server", feature = "plantuml-server"))]
+const CRATES_IO_URL: &str = "https://crates.io/api/v1/crates/mdbook-plantuml";
+
+/// The file (in the cache's state dir) with the time of the last update check
+const LAST_CHECK_FILE: &str = "update-check";
+
+/// The minimum time between two update checks for the update notice
+const CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Check crates.io for a newer version, returns the newest version when it is newer than
+/// the running version.
+pub fn check_update() -> Result<Option<String>> {
+    let crate_info = download_crate_info().map_err(tag(FailureKind::Io))?;
+    let latest = latest_version(&crate_info)?;
+    Ok(is_newer(&latest, env!("CARGO_PKG_VERSION")).then_some(latest))
+}
+
+/// The message telling the user how to upgrade to the given version
+pub fn upgrade_instructions(version: &str) -> String {
+    format!(
+        "mdbook-plantuml {} is available (you are using {}), upgrade using \
+         'cargo install mdbook-plantuml' or download it from \
+         https://github.com/sytsereitsma/mdbook-plantuml/releases",
+        version,
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+/// Log a notice when a newer version is available. Checks at most once a day, and
+/// never on CI (the CI environment variable is set).
+/// # Arguments
+/// * `cache_dir` - The cache dir, the time of the last check is stored in its state dir
+/// * `env` - Environment variable lookup
+pub fn notify_update(cache_dir: &Path, env: &dyn Fn(&str) -> Option<String>) {
+    if env("CI").map_or(false, |ci| !ci.is_empty() && ci != "false") {
+        return;
+    }
+
+    let state_dir = cache_dir.join(STATE_DIR);
+    let last_check_file = state_dir.join(LAST_CHECK_FILE);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let last_check = fs::read_to_string(&last_check_file)
+        .ok()
+        .and_then(|secs| secs.trim().parse::<u64>().ok())
+        .map(Duration::from_secs);
+    if last_check.map_or(false, |last_check| now < last_check + CHECK_INTERVAL) {
+        return;
+    }
+
+    if let Err(e) = fs::create_dir_all(&state_dir)
+        .and_then(|_| fs::write(&last_check_file, now.as_secs().to_string()))
+    {
+        log::debug!("Failed to write {:?} ({})", last_check_file, e);
+    }
+    match check_update() {
+        Ok(Some(version)) => log::info!("{}", upgrade_instructions(&version)),
+        Ok(None) => {}
+        Err(e) => log::debug!("Failed to check for a newer version ({:#})", e),
+    }
+}
+
+#[cfg(any(feature = "plantuml-ssl-server", feature = "plantuml-server"))]
+fn download_crate_info() -> Result<String> {
+    let client = reqwest::blocking::Client::builder()
+        .user_agent(concat!("mdbook-plantuml/", env!("CARGO_PKG_VERSION")))
+        .timeout(Duration::from_secs(5))
+        .build()?;
+    let response = client.get(CRATES_IO_URL).send()?.error_for_status()?;
+    Ok(response.text()?)
+}
+
+#[cfg(not(any(feature = "plantuml-ssl-server", feature = "plantuml-server")))]
+fn download_crate_info() -> Result<String> {
+    anyhow::bail!(
+        "Checking for updates requires the plantuml-server or plantuml-ssl-server feature"
+    )
+}
+
+/// Get the newest stable version from the crates.io crate info
+fn latest_version(crate_info: &str) -> Result<String> {
+    let info: serde_json::Value = serde_json::from_str(crate_info)?;
+    info.pointer("/crate/max_stable_version")
+        .or_else(|| info.pointer("/crate/max_version"))
+        .and_then(|version| version.as_str())
+        .map(String::from)
+        .ok_or_else(|| format_err!("No version found in the crates.io response"))
+}
+
+/// Returns true if the candidate version is newer than the current version
+/// (comparing the major.minor.patch numbers)
+fn is_newer(candidate: &str, current: &str) -> bool {
+    let numbers = |version: &str| -> Vec<u64> {
+        version
+            .split(['-', '+'])
+            .next()
+            .unwrap_or_default()
+            .split('.')
+            .map(|n| n.parse().unwrap_or(0))
+            .collect()
+    };
+
+    numbers(candidate) > numbers(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_latest_version() {
+        assert_eq!(
+            "0.9.1",
+            latest_version(
+                r#"{"crate": {"max_version": "1.0.0-rc1", "max_stable_version": "0.9.1"}}"#
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            "0.9.1",
+            latest_version(r#"{"crate": {"max_version": "0.9.1"}}"#).unwrap()
+        );
+        assert!(latest_version(r#"{"errors": []}"#).is_err());
+        assert!(latest_version("<html>").is_err());
+    }
+
+    #[test]
+    fn test_is_newer() {
+        assert!(is_newer("0.8.1", "0.8.0"));
+        assert!(is_newer("0.10.0", "0.9.9"));
+        assert!(is_newer("1.0.0", "0.8.0-beta"));
+        assert!(!is_newer("0.8.0", "0.8.0"));
+        assert!(!is_newer("0.7.12", "0.8.0"));
+    }
+
+    #[test]
+    fn test_notify_update_skipped() {
+        let cache_dir = tempdir().unwrap();
+        let last_check_file = cache_dir.path().join(STATE_DIR).join(LAST_CHECK_FILE);
+
+        // Never on CI
+        notify_update(cache_dir.path(), &|name| {
+            (name == "CI").then(|| String::from("true"))
+        });
+        assert!(!last_check_file.exists());
+
+        // At most once a day
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        fs::create_dir_all(cache_dir.path().join(STATE_DIR)).unwrap();
+        fs::write(&last_check_file, now.as_secs().to_string()).unwrap();
+        notify_update(cache_dir.path(), &|_| None);
+        assert_eq!(
+            now.as_secs().to_string(),
+            fs::read_to_string(&last_check_file).unwrap()
+        );
+    }
+}
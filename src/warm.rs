@@ -0,0 +1,64 @@
+use crate::Preprocessor;
+use anyhow::{format_err, Context, Result};
+use mdbook::MDBook;
+use std::path::Path;
+
+/// Render all uncached diagrams of the book (as rendered for the html renderer),
+/// so a subsequent `mdbook build` only hits the cache. Fails when a diagram
+/// cannot be rendered.
+/// # Arguments
+/// * `book_dir` - The book's root directory (containing book.toml)
+/// * `revalidate` - Re-run the PlantUML command detection
+pub fn warm_cache(book_dir: &Path, revalidate: bool) -> Result<()> {
+    let mut md = MDBook::load(book_dir)
+        .map_err(|e| format_err!("{:#}", e))
+        .with_context(|| format!("Failed to load the book from {book_dir:?}"))?;
+
+    // Report the failing diagrams, instead of embedding the errors in the discarded chapters
+    md.config
+        .set("preprocessor.plantuml.fail-on-error", true)
+        .and_then(|_| {
+            md.config
+                .set("preprocessor.plantuml.missing-backend", "error")
+        })
+        .map_err(|e| format_err!("{:#}", e))?;
+
+    let preprocessor = Preprocessor {
+        revalidate,
+        ..Preprocessor::default()
+    };
+    preprocessor.process_book(&md.root, &md.config, "html", md.book.clone())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_warm_cache_reports_missing_backend() {
+        let book_dir = tempdir().unwrap();
+        let src_dir = book_dir.path().join("src");
+        fs::create_dir_all(&src_dir).unwrap();
+        fs::write(
+            book_dir.path().join("book.toml"),
+            "[preprocessor.plantuml]\n\
+             plantuml-cmd = \"surely-this-plantuml-does-not-exist\"\n\
+             missing-backend = \"placeholder\"\n",
+        )
+        .unwrap();
+        fs::write(
+            src_dir.join("SUMMARY.md"),
+            "# Summary\n\n- [Intro](intro.md)\n",
+        )
+        .unwrap();
+        fs::write(src_dir.join("intro.md"), "```plantuml\nfoo\n```\n").unwrap();
+
+        // The placeholder policy is ignored, warming the cache should fail loudly
+        let error = warm_cache(book_dir.path(), false).unwrap_err();
+        assert!(format!("{error:#}").contains("surely-this-plantuml-does-not-exist"));
+    }
+}
@@ -21,6 +21,14 @@ if __name__ == "__main__":
     else:
         img_filename += ".png"
 
+    def fake_image(content):
+        # mdbook-plantuml validates the image header, so make it look like the requested format
+        if "-tsvg" in sys.argv:
+            return ("<svg>" + content + "</svg>").encode()
+        elif "-tpng" in sys.argv:
+            return b"\x89PNG\r\n\x1a\n" + content.encode()
+        return content.encode()
+
     # Append command output to calls file
     try:
         prev_commands = json.load(open(file_locations.get_plantuml_calls_file()))
@@ -33,7 +41,7 @@ if __name__ == "__main__":
         # Dump the command line arguments and resulting image location in the
         # 'image file'. The tester can then check these for validity.
         # Do not use print, as this will append \r\n on windows and \n on Linux, which makes testing hard
-        sys.stdout.write(" ".join(sys.argv[1:]))
+        sys.stdout.buffer.write(fake_image(" ".join(sys.argv[1:])))
 
         prev_commands.append({
             "arguments": sys.argv[1:],
@@ -44,7 +52,7 @@ if __name__ == "__main__":
         # 'image file'. The tester can then check these for validity
         # We need to create an image file, so mdbook-plantuml has something to chew
         # on.
-        open(img_filename, "w").write(" ".join(sys.argv))
+        open(img_filename, "wb").write(fake_image(" ".join(sys.argv)))
 
         prev_commands.append({
             "arguments": sys.argv[1:],
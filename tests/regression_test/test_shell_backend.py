@@ -85,8 +85,8 @@ class TestShellBackend(unittest.TestCase):
                 img_type = "png"
 
             img_path = self.__get_image_path(call)
-            img_data = open(img_path).read()            
-            b64_data = base64.b64encode(img_data.encode()).decode()
+            img_data = open(img_path, "rb").read()
+            b64_data = base64.b64encode(img_data).decode()
 
             return f"data:image/{img_type};base64," + b64_data
         else: